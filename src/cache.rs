@@ -4,7 +4,7 @@
 //! Provides 5-10x speedup for repetitive pattern usage.
 //! Cache is capped at 256 entries; oldest entries are evicted when full.
 
-use ast_grep_core::Pattern;
+use ast_grep_core::{Pattern, PatternError};
 use ast_grep_language::SupportLang;
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -23,7 +23,20 @@ thread_local! {
 /// Patterns are cached thread-locally, capped at 256 entries.
 /// When the cap is reached, the cache is cleared and rebuilt on demand.
 /// Cache hits provide ~10x speedup over recompilation.
+///
+/// # Panics
+///
+/// Panics if the pattern does not compile; see [`try_get_or_compile_pattern`].
 pub fn get_or_compile_pattern(pattern_str: &str, lang: SupportLang) -> Pattern {
+    try_get_or_compile_pattern(pattern_str, lang).unwrap()
+}
+
+/// [`get_or_compile_pattern`] that reports invalid patterns instead of panicking.
+/// Failed compilations are not cached.
+pub fn try_get_or_compile_pattern(
+    pattern_str: &str,
+    lang: SupportLang,
+) -> Result<Pattern, PatternError> {
     // Include lang in key: same pattern string for different languages must not
     // collide (e.g., `$FOO` means different things in Rust vs Python).
     let cache_key = format!("{lang:?}:{pattern_str}");
@@ -33,7 +46,7 @@ pub fn get_or_compile_pattern(pattern_str: &str, lang: SupportLang) -> Pattern {
 
         // Check if pattern is already compiled
         if let Some(p) = cache.get(&cache_key) {
-            return p.clone();
+            return Ok(p.clone());
         }
 
         // Evict all if at capacity (simple but effective for batch workloads)
@@ -42,9 +55,9 @@ pub fn get_or_compile_pattern(pattern_str: &str, lang: SupportLang) -> Pattern {
         }
 
         // Compile and cache the pattern
-        let compiled = Pattern::new(pattern_str, lang);
+        let compiled = Pattern::try_new(pattern_str, lang)?;
         cache.insert(cache_key, compiled.clone());
-        compiled
+        Ok(compiled)
     })
 }
 
@@ -0,0 +1,183 @@
+//! Map compile errors back to the patches whose edits caused them.
+//!
+//! Backs `apply --check-build`: only the packages owning the touched files are
+//! checked, and every error span that falls inside text a patch wrote is
+//! attributed to that patch through its ledger record.
+
+use crate::compiler::diagnostic::CompileDiagnostic;
+use crate::ledger::{current_span, LedgerAction, LedgerRecord};
+use cargo_metadata::MetadataCommand;
+use std::path::{Path, PathBuf};
+
+/// A compile error located inside text written by a patch.
+#[derive(Debug, Clone)]
+pub struct BrokenPatch {
+    /// `meta.name` of the patch config
+    pub config: String,
+    pub patch_id: String,
+    /// File path, relative to the workspace root when inside it
+    pub file: PathBuf,
+    /// 1-based line of the error
+    pub line: usize,
+    pub diagnostic: CompileDiagnostic,
+}
+
+/// Names of the workspace packages that own `files`, in first-seen order.
+///
+/// Each file belongs to the package with the deepest manifest directory
+/// containing it. Files outside every package are ignored; an empty result
+/// (including when `cargo metadata` fails) means "check everything".
+pub fn owning_packages(workspace: &Path, files: &[PathBuf]) -> Vec<String> {
+    let Ok(metadata) = MetadataCommand::new()
+        .manifest_path(workspace.join("Cargo.toml"))
+        .no_deps()
+        .exec()
+    else {
+        return Vec::new();
+    };
+
+    let mut owners = Vec::new();
+    for file in files {
+        let file = workspace.join(file);
+        let file = file.canonicalize().unwrap_or(file);
+        let owner = metadata
+            .workspace_packages()
+            .into_iter()
+            .filter_map(|pkg| {
+                let dir = pkg.manifest_path.parent()?.as_std_path();
+                file.starts_with(dir)
+                    .then_some((dir.components().count(), pkg))
+            })
+            .max_by_key(|(depth, _)| *depth);
+        if let Some((_, pkg)) = owner {
+            if !owners.contains(&pkg.name) {
+                owners.push(pkg.name.clone());
+            }
+        }
+    }
+    owners
+}
+
+/// Split `diagnostics` into errors inside patched text and the rest.
+///
+/// `records` are the ledger records of the run being checked, in the order
+/// they were made; spans are replayed with [`current_span`] so they match the
+/// files as they are now. A diagnostic whose spans touch several patches is
+/// attributed to each of them.
+pub fn attribute_errors(
+    diagnostics: &[CompileDiagnostic],
+    records: &[LedgerRecord],
+    workspace: &Path,
+) -> (Vec<BrokenPatch>, Vec<CompileDiagnostic>) {
+    let mut broken: Vec<BrokenPatch> = Vec::new();
+    let mut unattributed = Vec::new();
+
+    for diagnostic in diagnostics {
+        let before = broken.len();
+        for span in &diagnostic.spans {
+            let file = span.file.strip_prefix(workspace).unwrap_or(&span.file);
+            for (index, record) in records.iter().enumerate() {
+                if record.action != LedgerAction::Apply || record.file != file {
+                    continue;
+                }
+                let Ok((start, end)) = current_span(records, index) else {
+                    continue;
+                };
+                // A deletion leaves an empty span; errors touching it count.
+                let overlaps = if start == end {
+                    span.byte_start <= start && start <= span.byte_end
+                } else {
+                    span.byte_start < end && start < span.byte_end
+                };
+                let already = broken[before..]
+                    .iter()
+                    .any(|b| b.config == record.config && b.patch_id == record.patch_id);
+                if overlaps && !already {
+                    broken.push(BrokenPatch {
+                        config: record.config.clone(),
+                        patch_id: record.patch_id.clone(),
+                        file: record.file.clone(),
+                        line: span.line_start,
+                        diagnostic: diagnostic.clone(),
+                    });
+                }
+            }
+        }
+        if broken.len() == before {
+            unattributed.push(diagnostic.clone());
+        }
+    }
+
+    (broken, unattributed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::diagnostic::SourceSpan;
+    use crate::ledger::hash_hex;
+    use cargo_metadata::diagnostic::DiagnosticLevel;
+
+    fn record(patch_id: &str, start: usize, end: usize, new_text: &str) -> LedgerRecord {
+        LedgerRecord {
+            config: "cfg".to_string(),
+            patch_id: patch_id.to_string(),
+            action: LedgerAction::Apply,
+            file: PathBuf::from("src/lib.rs"),
+            byte_start: start,
+            byte_end: end,
+            new_len: new_text.len(),
+            before_hash: hash_hex(b""),
+            after_hash: hash_hex(new_text.as_bytes()),
+            workspace_version: "0.1.0".to_string(),
+            timestamp: 0,
+        }
+    }
+
+    fn error_at(start: usize, end: usize, line: usize) -> CompileDiagnostic {
+        CompileDiagnostic {
+            code: Some("E0308".to_string()),
+            message: "mismatched types".to_string(),
+            level: DiagnosticLevel::Error,
+            spans: vec![SourceSpan {
+                file: PathBuf::from("/ws/src/lib.rs"),
+                byte_start: start,
+                byte_end: end,
+                line_start: line,
+                line_end: line,
+                column_start: 1,
+                column_end: 1,
+                is_macro_expansion: false,
+                text: None,
+            }],
+            suggestions: Vec::new(),
+            rendered: None,
+        }
+    }
+
+    #[test]
+    fn errors_are_attributed_to_the_covering_patch() {
+        // One batch, descending byte_start: "late" at 50..60 and "early" at
+        // 10..20, which grows by 10 bytes and shifts "late" to 60..75.
+        let records = [
+            record("late", 50, 60, "fifteen bytes!!"),
+            record("early", 10, 20, "twenty bytes long..."),
+        ];
+        let diagnostics = [
+            error_at(62, 64, 4),
+            error_at(15, 16, 2),
+            error_at(100, 101, 9),
+        ];
+
+        let (broken, unattributed) = attribute_errors(&diagnostics, &records, Path::new("/ws"));
+
+        let ids: Vec<_> = broken
+            .iter()
+            .map(|b| (b.patch_id.as_str(), b.line))
+            .collect();
+        assert_eq!(ids, [("late", 4), ("early", 2)]);
+        assert_eq!(broken[0].file, PathBuf::from("src/lib.rs"));
+        assert_eq!(unattributed.len(), 1);
+        assert_eq!(unattributed[0].spans[0].line_start, 9);
+    }
+}
@@ -6,6 +6,7 @@
 //! 1. Run `cargo check --message-format=json` and parse diagnostics
 //! 2. Apply machine-applicable compiler suggestions automatically
 //! 3. Generate fixes for common error patterns (E0063 missing fields)
+//! 4. Drive the check → autofix → write loop one [`fix_step`] at a time
 //!
 //! # Example
 //!
@@ -24,19 +25,23 @@
 //! let fixes = try_autofix_all(&diagnostics, workspace);
 //! ```
 
+pub mod attribute;
 pub mod autofix;
 pub mod diagnostic;
 
+pub use attribute::{attribute_errors, owning_packages, BrokenPatch};
 pub use autofix::{try_autofix, AutofixError, AutofixResult};
 pub use diagnostic::{CompileDiagnostic, DiagnosticError, SourceSpan, Suggestion};
 
 use cargo_metadata::diagnostic::DiagnosticLevel;
 use cargo_metadata::Message;
+use std::fs;
 use std::io::BufRead;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
-use crate::edit::Edit;
+use crate::edit::{commit_file, Edit};
+use crate::safety::WorkspaceGuard;
 
 /// Run `cargo check` and collect all error diagnostics.
 ///
@@ -51,6 +56,15 @@ use crate::edit::Edit;
 pub fn run_cargo_check(
     workspace: &Path,
     package: Option<&str>,
+) -> Result<Vec<CompileDiagnostic>, DiagnosticError> {
+    run_cargo_check_packages(workspace, package.as_slice())
+}
+
+/// [`run_cargo_check`] for several packages at once (`-p a -p b`); an empty
+/// slice checks the default members.
+pub fn run_cargo_check_packages(
+    workspace: &Path,
+    packages: &[&str],
 ) -> Result<Vec<CompileDiagnostic>, DiagnosticError> {
     let mut cmd = Command::new("cargo");
     cmd.current_dir(workspace)
@@ -58,7 +72,7 @@ pub fn run_cargo_check(
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
 
-    if let Some(pkg) = package {
+    for pkg in packages {
         cmd.args(["-p", pkg]);
     }
 
@@ -70,7 +84,17 @@ pub fn run_cargo_check(
         .map_err(|e| DiagnosticError::CargoFailed(format!("Failed to spawn cargo: {}", e)))?;
 
     let reader = std::io::BufReader::new(output.stdout.as_slice());
-    parse_cargo_output(reader, workspace)
+    let diagnostics = parse_cargo_output(reader, workspace)?;
+
+    // A failed build without compiler errors means cargo itself failed
+    // (bad manifest, unknown package, ...), not that the code is clean.
+    if diagnostics.is_empty() && !output.status.success() {
+        return Err(DiagnosticError::CargoFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(diagnostics)
 }
 
 /// Run `cargo check` on a specific file (using workspace context).
@@ -188,6 +212,99 @@ pub fn check_and_fix(
     }
 }
 
+/// A file rewritten by [`fix_step`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedFile {
+    pub path: PathBuf,
+    /// Contents before the fixes
+    pub original: String,
+    /// Contents as written
+    pub fixed: String,
+}
+
+/// Outcome of one [`fix_step`].
+#[derive(Debug, Default)]
+pub struct FixStep {
+    /// Every error `cargo check` reported; empty when the build is clean
+    pub diagnostics: Vec<CompileDiagnostic>,
+    /// Files rewritten with auto-fixes
+    pub fixed: Vec<FixedFile>,
+    /// Diagnostics no auto-fix strategy could handle
+    pub unfixable: Vec<CompileDiagnostic>,
+    /// Files whose fixes were generated but could not be written, with why
+    pub rejected: Vec<(PathBuf, String)>,
+}
+
+/// Run `cargo check` once, generate auto-fixes and write them.
+///
+/// Fixes are grouped by file and applied as one batch per file; duplicate
+/// edits (e.g. the same suggestion reported twice) are applied once. Every
+/// target goes through [`WorkspaceGuard`], so diagnostics pointing into
+/// dependencies are never "fixed". Callers loop until `diagnostics` is empty
+/// or a step writes nothing.
+pub fn fix_step(workspace: &Path, package: Option<&str>) -> Result<FixStep, DiagnosticError> {
+    let diagnostics = run_cargo_check(workspace, package)?;
+    if diagnostics.is_empty() {
+        return Ok(FixStep::default());
+    }
+
+    let (edits, unfixable) = try_autofix_all(&diagnostics, workspace);
+    let unfixable = unfixable.into_iter().cloned().collect();
+
+    let mut by_file: Vec<(PathBuf, Vec<Edit>)> = Vec::new();
+    for edit in edits {
+        match by_file.iter_mut().find(|(path, _)| *path == edit.file) {
+            Some((_, file_edits)) => {
+                let duplicate = file_edits.iter().any(|e| {
+                    e.byte_start == edit.byte_start
+                        && e.byte_end == edit.byte_end
+                        && e.new_text == edit.new_text
+                });
+                if !duplicate {
+                    file_edits.push(edit);
+                }
+            }
+            None => by_file.push((edit.file.clone(), vec![edit])),
+        }
+    }
+
+    let mut fixed = Vec::new();
+    let mut rejected = Vec::new();
+    let guard = WorkspaceGuard::new(workspace).map_err(|e| {
+        DiagnosticError::CargoFailed(format!("invalid workspace {}: {}", workspace.display(), e))
+    })?;
+    for (path, file_edits) in by_file {
+        let written = guard
+            .validate_path(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|path| {
+                let original = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+                let (patched, _) = Edit::apply_batch_in_memory(&original, file_edits)
+                    .map_err(|e| e.to_string())?;
+                if patched != original {
+                    commit_file(&path, patched.as_bytes()).map_err(|e| e.to_string())?;
+                }
+                Ok((original, patched))
+            });
+        match written {
+            Ok((original, fixed_text)) if original != fixed_text => fixed.push(FixedFile {
+                path,
+                original,
+                fixed: fixed_text,
+            }),
+            Ok(_) => {}
+            Err(reason) => rejected.push((path, reason)),
+        }
+    }
+
+    Ok(FixStep {
+        diagnostics,
+        fixed,
+        unfixable,
+        rejected,
+    })
+}
+
 /// Quick pass/fail check - returns true if cargo check succeeds.
 pub fn check_passes(workspace: &Path, package: Option<&str>) -> bool {
     let mut cmd = Command::new("cargo");
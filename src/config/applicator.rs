@@ -6,24 +6,67 @@
 //! - Applies patches using the appropriate locator (ast-grep, tree-sitter, toml)
 //! - Reports detailed results for each patch
 
-use crate::config::schema::{Operation, PatchConfig, PatchDefinition, Positioning, Query};
+use crate::config::dependencies::{patch_index, Outcomes};
+use crate::config::near_miss::{near_misses, source_files, NearMiss};
+use crate::config::schema::{
+    parse_function_context, ApplyMode, Occurrence, Operation, PatchConfig, PatchDefinition,
+    PatchVariant, Positioning, Query, Selection,
+};
 use crate::config::version::{matches_requirement, VersionError};
-use crate::edit::{Edit, EditError, EditResult, EditVerification};
-use crate::sg::PatternMatcher;
+use crate::edit::{commit_file, Edit, EditError, EditResult, EditVerification};
+use crate::journal::{Journal, JournalError, StagedFile};
+use crate::ledger::{self, hash_hex, Ledger, LedgerAction, LedgerRecord};
+use crate::safety::{SafetyError, WorkspaceGuard};
+use crate::sg::{PatternMatch, PatternMatcher};
 use crate::toml::{
     Constraints, KeyPath, SectionPath, TomlEditor, TomlOperation, TomlPlan, TomlQuery,
 };
 use crate::ts::StructuralTarget;
+use crate::validate::{ErrorLocation, ValidationError};
+use std::collections::HashMap;
 use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 
 /// Check if a patch should be skipped based on its per-patch version constraint.
 /// Returns `Some(reason)` if the patch should be skipped, `None` if it should be applied.
+///
+/// A patch still carrying variants here had none or several of them match
+/// (see [`select_variants`]).
 fn check_patch_version(
     patch: &PatchDefinition,
     workspace_version: &str,
 ) -> Result<Option<String>, ApplicationError> {
+    if !patch.variants.is_empty() {
+        let matched = patch.matching_variants(workspace_version)?;
+        let versions = |variants: &[Option<&PatchVariant>]| -> Vec<String> {
+            variants
+                .iter()
+                .map(|variant| match variant {
+                    Some(variant) => variant.version.clone(),
+                    None => patch.version.clone().unwrap_or_default(),
+                })
+                .collect()
+        };
+        return match matched.len() {
+            0 => {
+                let all: Vec<_> = std::iter::once(None)
+                    .chain(patch.variants.iter().map(Some))
+                    .collect();
+                Ok(Some(format!(
+                    "no variant matches workspace {} ({})",
+                    workspace_version,
+                    versions(&all).join("; ")
+                )))
+            }
+            1 => Ok(None),
+            _ => Err(ApplicationError::AmbiguousVariant {
+                version: workspace_version.to_string(),
+                matched: versions(&matched),
+            }),
+        };
+    }
+
     let version_req = match patch.version.as_deref() {
         Some(r) => r,
         None => return Ok(None),
@@ -85,10 +128,49 @@ pub enum ApplicationError {
     Edit(EditError),
     /// Query matched multiple locations (ambiguous)
     AmbiguousMatch { file: PathBuf, count: usize },
-    /// Query matched no locations
-    NoMatch { file: PathBuf },
+    /// Query matched no locations; `candidates` are the closest locations
+    /// found (see [`near_misses`])
+    NoMatch {
+        file: PathBuf,
+        candidates: Vec<NearMiss>,
+    },
+    /// Query found a different number of sites than `expected_count`
+    UnexpectedMatchCount {
+        file: PathBuf,
+        expected: usize,
+        found: usize,
+    },
+    /// A glob `file` matched fewer than `min_matches` or more than
+    /// `max_matches` files
+    FileMatchCount {
+        pattern: String,
+        min: usize,
+        max: Option<usize>,
+        found: usize,
+    },
+    /// A `requires` entry was not applied (it failed, was skipped or is not
+    /// loaded), so the patch was not attempted
+    PrerequisiteFailed { requirement: String, reason: String },
+    /// A `conflicts` entry is applied, so the patch was not attempted
+    ConflictingPatch { conflict: String },
+    /// More than one of a patch's variants matches the workspace version
+    AmbiguousVariant {
+        version: String,
+        matched: Vec<String>,
+    },
+    /// The target left `file` and more than one file of `search_paths` has it
+    AmbiguousRelocation { file: PathBuf, found: Vec<PathBuf> },
     /// TOML operation failed
     TomlOperation { file: PathBuf, reason: String },
+    /// Patch target failed workspace boundary checks
+    Safety(SafetyError),
+    /// Patch cannot be reverted because its original text is unknown
+    Irreversible { file: PathBuf, reason: String },
+    /// Patched file no longer parses; the whole file batch was refused
+    ParseErrorIntroduced {
+        file: PathBuf,
+        errors: Vec<ErrorLocation>,
+    },
 }
 
 impl fmt::Display for ApplicationError {
@@ -107,12 +189,105 @@ impl fmt::Display for ApplicationError {
                     count
                 )
             }
-            ApplicationError::NoMatch { file } => {
+            ApplicationError::NoMatch { file, .. } => {
                 write!(f, "query matched no locations in {}", file.display())
             }
+            ApplicationError::UnexpectedMatchCount {
+                file,
+                expected,
+                found,
+            } => {
+                write!(
+                    f,
+                    "query matched {} sites in {}, expected {}",
+                    found,
+                    file.display(),
+                    expected
+                )
+            }
+            ApplicationError::FileMatchCount {
+                pattern,
+                min,
+                max,
+                found,
+            } => {
+                write!(
+                    f,
+                    "file glob {} matched {} file(s), expected ",
+                    pattern, found
+                )?;
+                match max {
+                    Some(max) if max == min => write!(f, "{}", max),
+                    Some(max) => write!(f, "{} to {}", min, max),
+                    None => write!(f, "at least {}", min),
+                }
+            }
+            ApplicationError::PrerequisiteFailed {
+                requirement,
+                reason,
+            } => {
+                write!(f, "required {} was not applied: {}", requirement, reason)
+            }
+            ApplicationError::ConflictingPatch { conflict } => {
+                write!(f, "conflicts with {}, which is applied", conflict)
+            }
+            ApplicationError::AmbiguousVariant { version, matched } => {
+                write!(
+                    f,
+                    "workspace {} matches {} variants ({}), expected 1",
+                    version,
+                    matched.len(),
+                    matched.join("; ")
+                )
+            }
+            ApplicationError::AmbiguousRelocation { file, found } => {
+                let found: Vec<String> = found.iter().map(|f| f.display().to_string()).collect();
+                write!(
+                    f,
+                    "target moved out of {} into {} files ({}), expected 1",
+                    file.display(),
+                    found.len(),
+                    found.join(", ")
+                )
+            }
             ApplicationError::TomlOperation { file, reason } => {
                 write!(f, "TOML operation failed on {}: {}", file.display(), reason)
             }
+            ApplicationError::Safety(e) => write!(f, "safety check failed: {}", e),
+            ApplicationError::Irreversible { file, reason } => {
+                write!(f, "cannot revert patch on {}: {}", file.display(), reason)
+            }
+            ApplicationError::ParseErrorIntroduced { file, errors } => {
+                write!(
+                    f,
+                    "patched {} would not parse ({} new error(s))",
+                    file.display(),
+                    errors.len()
+                )
+            }
+        }
+    }
+}
+
+impl ApplicationError {
+    /// Stable snake_case name of the variant, used in machine-readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApplicationError::Version(_) => "version",
+            ApplicationError::Io { .. } => "io",
+            ApplicationError::Edit(_) => "edit",
+            ApplicationError::AmbiguousMatch { .. } => "ambiguous_match",
+            ApplicationError::NoMatch { .. } => "no_match",
+            ApplicationError::UnexpectedMatchCount { .. } => "unexpected_match_count",
+            ApplicationError::FileMatchCount { .. } => "file_match_count",
+            ApplicationError::PrerequisiteFailed { .. } => "prerequisite_failed",
+            ApplicationError::ConflictingPatch { .. } => "conflicting_patch",
+            ApplicationError::AmbiguousVariant { .. } => "ambiguous_variant",
+            ApplicationError::AmbiguousRelocation { .. } => "ambiguous_relocation",
+            ApplicationError::TomlOperation { .. } => "toml_operation",
+            ApplicationError::Safety(_) => "safety",
+            ApplicationError::Irreversible { .. } => "irreversible",
+            ApplicationError::ParseErrorIntroduced { .. } => "parse_error_introduced",
         }
     }
 }
@@ -123,11 +298,84 @@ impl std::error::Error for ApplicationError {
             ApplicationError::Version(e) => Some(e),
             ApplicationError::Io { source, .. } => Some(source),
             ApplicationError::Edit(e) => Some(e),
+            ApplicationError::Safety(e) => Some(e),
             _ => None,
         }
     }
 }
 
+impl Clone for ApplicationError {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Version(e) => Self::Version(e.clone()),
+            Self::Io { path, source } => Self::Io {
+                path: path.clone(),
+                source: std::io::Error::new(source.kind(), source.to_string()),
+            },
+            Self::Edit(e) => Self::Edit(e.clone()),
+            Self::AmbiguousMatch { file, count } => Self::AmbiguousMatch {
+                file: file.clone(),
+                count: *count,
+            },
+            Self::NoMatch { file, candidates } => Self::NoMatch {
+                file: file.clone(),
+                candidates: candidates.clone(),
+            },
+            Self::UnexpectedMatchCount {
+                file,
+                expected,
+                found,
+            } => Self::UnexpectedMatchCount {
+                file: file.clone(),
+                expected: *expected,
+                found: *found,
+            },
+            Self::FileMatchCount {
+                pattern,
+                min,
+                max,
+                found,
+            } => Self::FileMatchCount {
+                pattern: pattern.clone(),
+                min: *min,
+                max: *max,
+                found: *found,
+            },
+            Self::PrerequisiteFailed {
+                requirement,
+                reason,
+            } => Self::PrerequisiteFailed {
+                requirement: requirement.clone(),
+                reason: reason.clone(),
+            },
+            Self::ConflictingPatch { conflict } => Self::ConflictingPatch {
+                conflict: conflict.clone(),
+            },
+            Self::AmbiguousVariant { version, matched } => Self::AmbiguousVariant {
+                version: version.clone(),
+                matched: matched.clone(),
+            },
+            Self::AmbiguousRelocation { file, found } => Self::AmbiguousRelocation {
+                file: file.clone(),
+                found: found.clone(),
+            },
+            Self::TomlOperation { file, reason } => Self::TomlOperation {
+                file: file.clone(),
+                reason: reason.clone(),
+            },
+            Self::Safety(e) => Self::Safety(e.clone()),
+            Self::Irreversible { file, reason } => Self::Irreversible {
+                file: file.clone(),
+                reason: reason.clone(),
+            },
+            Self::ParseErrorIntroduced { file, errors } => Self::ParseErrorIntroduced {
+                file: file.clone(),
+                errors: errors.clone(),
+            },
+        }
+    }
+}
+
 impl From<VersionError> for ApplicationError {
     fn from(e: VersionError) -> Self {
         ApplicationError::Version(e)
@@ -140,6 +388,12 @@ impl From<EditError> for ApplicationError {
     }
 }
 
+impl From<SafetyError> for ApplicationError {
+    fn from(e: SafetyError) -> Self {
+        ApplicationError::Safety(e)
+    }
+}
+
 fn skip_all_patches(
     config: &PatchConfig,
     reason: String,
@@ -185,263 +439,841 @@ pub fn apply_patches(
     workspace_root: &Path,
     workspace_version: &str,
 ) -> Vec<(String, Result<PatchResult, ApplicationError>)> {
-    match matches_requirement(workspace_version, config.meta.version_range.as_deref()) {
-        Ok(true) => apply_patches_batched(config, workspace_root, workspace_version),
-        Ok(false) => {
-            let req = config.meta.version_range.as_deref().unwrap_or("").trim();
-            let reason = if req.is_empty() {
-                format!("workspace version {workspace_version} does not satisfy patch version constraints")
-            } else {
-                format!(
-                    "workspace version {workspace_version} does not satisfy version_range {req}"
-                )
-            };
-            skip_all_patches(config, reason)
-        }
-        Err(e) => error_all_patches(config, e),
-    }
+    apply_patches_detailed(config, workspace_root, workspace_version).results
+}
+
+/// [`apply_patches`], returning the whole run: the written buffers, ledger
+/// records and fuzzy match scores alongside the per-patch results.
+pub fn apply_patches_detailed(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+) -> Simulation {
+    let mut run = run_gated(
+        config,
+        workspace_root,
+        workspace_version,
+        RunMode::Apply,
+        &HashMap::new(),
+        LedgerAction::Apply,
+    );
+    run.ledger_error = record_in_ledger(workspace_root, &run.records);
+    run
 }
 
 /// Check patch status without mutating the workspace.
 ///
 /// This mirrors `apply_patches` result semantics (`Applied` means "would apply"),
-/// while running all edit operations against temporary files.
+/// while running all edit operations against in-memory buffers.
 pub fn check_patches(
     config: &PatchConfig,
     workspace_root: &Path,
     workspace_version: &str,
 ) -> Vec<(String, Result<PatchResult, ApplicationError>)> {
-    match matches_requirement(workspace_version, config.meta.version_range.as_deref()) {
-        Ok(true) => check_patches_batched(config, workspace_root, workspace_version),
-        Ok(false) => {
-            let req = config.meta.version_range.as_deref().unwrap_or("").trim();
-            let reason = if req.is_empty() {
-                format!("workspace version {workspace_version} does not satisfy patch version constraints")
-            } else {
-                format!(
-                    "workspace version {workspace_version} does not satisfy version_range {req}"
-                )
-            };
-            skip_all_patches(config, reason)
-        }
-        Err(e) => error_all_patches(config, e),
-    }
+    simulate_patches(config, workspace_root, workspace_version).results
+}
+
+/// Post-patch contents of a single file, produced without touching disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedFile {
+    /// Path the patches target
+    pub path: PathBuf,
+    /// File contents as read from disk
+    pub original: String,
+    /// File contents after every applicable patch has been spliced in
+    pub patched: String,
+}
+
+/// Outcome of a patch run (non-mutating unless it came from
+/// [`apply_patches_detailed`]).
+#[derive(Debug, Default)]
+pub struct Simulation {
+    /// Per-patch results, identical to what `apply_patches` would report
+    pub results: Vec<(String, Result<PatchResult, ApplicationError>)>,
+    /// In-memory post-patch buffers for every file that would change
+    pub files: Vec<SimulatedFile>,
+    /// Ledger records for every edit the run makes (appended to the ledger
+    /// only when the run writes to disk)
+    pub records: Vec<LedgerRecord>,
+    /// Similarity score of the match used by each patch that only matched
+    /// fuzzily, keyed by patch id
+    pub fuzzy_scores: HashMap<String, f64>,
+    /// Text each of those fuzzy matches found in the file, keyed by patch id
+    pub fuzzy_matches: HashMap<String, String>,
+    /// Declared `file` of each patch whose target was found in another file
+    /// (see `search_paths`), keyed by patch id
+    pub relocations: HashMap<String, PathBuf>,
+    /// Why the run's records could not be appended to the ledger (the files
+    /// were written regardless)
+    pub ledger_error: Option<String>,
+}
+
+/// Run a patch configuration entirely in memory.
+///
+/// Produces exactly the per-patch results `apply_patches` would, plus the
+/// post-patch buffer for each file that would change. The workspace is never
+/// written to, which makes this the backing implementation for
+/// `apply --dry-run`, `status` and `verify`.
+pub fn simulate_patches(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+) -> Simulation {
+    simulate_with_overlay(config, workspace_root, workspace_version, &HashMap::new())
+}
+
+/// [`simulate_patches`], reading files from `overlay` (keyed by canonical
+/// path) in preference to disk so several configs can be chained in memory.
+fn simulate_with_overlay(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+    overlay: &HashMap<PathBuf, String>,
+) -> Simulation {
+    run_gated(
+        config,
+        workspace_root,
+        workspace_version,
+        RunMode::Simulate,
+        overlay,
+        LedgerAction::Apply,
+    )
 }
 
-/// Read-only status evaluation that groups patches by file.
-fn check_patches_batched(
+/// Undo the patches in a configuration.
+///
+/// Each patch is turned into its inverse [`Edit`]: the replacement text is
+/// located and the original text is restored, with the same verification,
+/// workspace and parse checks as [`apply_patches`]. `Applied` means the patch
+/// was reverted and `AlreadyApplied` that it was not present. Patches whose
+/// original text cannot be derived from the config report
+/// [`ApplicationError::Irreversible`].
+pub fn revert_patches(
     config: &PatchConfig,
     workspace_root: &Path,
     workspace_version: &str,
 ) -> Vec<(String, Result<PatchResult, ApplicationError>)> {
-    use std::collections::HashMap;
+    revert_patches_detailed(config, workspace_root, workspace_version).results
+}
 
-    let mut patches_by_file: HashMap<PathBuf, Vec<&PatchDefinition>> = HashMap::new();
+/// [`revert_patches`], returning the whole run like
+/// [`apply_patches_detailed`].
+pub fn revert_patches_detailed(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+) -> Simulation {
+    let mut run = run_gated(
+        config,
+        workspace_root,
+        workspace_version,
+        RunMode::Apply,
+        &HashMap::new(),
+        LedgerAction::Revert,
+    );
+    run.ledger_error = record_in_ledger(workspace_root, &run.records);
+    run
+}
 
-    for patch in &config.patches {
-        let file_path = if config.meta.workspace_relative {
-            workspace_root.join(&patch.file)
-        } else {
-            PathBuf::from(&patch.file)
-        };
-        patches_by_file.entry(file_path).or_default().push(patch);
+/// [`revert_patches`] without touching disk; see [`simulate_patches`].
+pub fn simulate_revert(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+) -> Simulation {
+    run_gated(
+        config,
+        workspace_root,
+        workspace_version,
+        RunMode::Simulate,
+        &HashMap::new(),
+        LedgerAction::Revert,
+    )
+}
+
+/// Append the edits of a committed run to the workspace ledger.
+///
+/// The files are already written at this point, so a ledger failure is
+/// returned for the caller to report rather than failing the patches.
+fn record_in_ledger(workspace_root: &Path, records: &[LedgerRecord]) -> Option<String> {
+    Ledger::append(workspace_root, records)
+        .err()
+        .map(|e| e.to_string())
+}
+
+/// Apply the config-level `version_range` gate, then [`run_batched`].
+fn run_gated(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+    mode: RunMode,
+    overlay: &HashMap<PathBuf, String>,
+    action: LedgerAction,
+) -> Simulation {
+    match matches_requirement(workspace_version, config.meta.version_range.as_deref()) {
+        Ok(true) => run_batched(
+            config,
+            workspace_root,
+            workspace_version,
+            mode,
+            overlay,
+            action,
+        ),
+        Ok(false) => Simulation {
+            results: skip_all_patches(config, version_range_skip_reason(config, workspace_version)),
+            ..Simulation::default()
+        },
+        Err(e) => Simulation {
+            results: error_all_patches(config, e),
+            ..Simulation::default()
+        },
     }
+}
 
-    let mut all_results = Vec::new();
+/// Outcome of [`apply_patches_atomic`].
+#[derive(Debug)]
+pub struct AtomicApply {
+    /// Per-patch results for each config, in the order the configs were given
+    pub results: Vec<Vec<(String, Result<PatchResult, ApplicationError>)>>,
+    /// Fuzzy match scores for each config, parallel to `results`
+    pub fuzzy_scores: Vec<HashMap<String, f64>>,
+    /// Fuzzily matched text for each config, parallel to `results`
+    pub fuzzy_matches: Vec<HashMap<String, String>>,
+    /// Relocated patches for each config, parallel to `results`
+    pub relocations: Vec<HashMap<String, PathBuf>>,
+    /// Every file the transaction writes, with its original on-disk contents
+    pub files: Vec<SimulatedFile>,
+    /// Ledger records for every edit, in the order they were planned
+    pub records: Vec<LedgerRecord>,
+    /// Why the records could not be appended to the ledger after committing
+    pub ledger_error: Option<String>,
+    /// `Ok` once every file has been committed
+    pub outcome: Result<(), TransactionError>,
+}
 
-    for (file_path, patches) in patches_by_file {
-        if !file_path.exists() {
-            for patch in patches {
-                all_results.push((
-                    patch.id.clone(),
-                    Err(ApplicationError::NoMatch {
-                        file: file_path.clone(),
-                    }),
-                ));
+/// Why an atomic apply did not commit.
+#[derive(Debug)]
+pub enum TransactionError {
+    /// Some patches failed during planning; nothing was written
+    PatchesFailed { count: usize },
+    /// Staging or committing failed; files already written were restored
+    Journal(JournalError),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::PatchesFailed { count } => {
+                write!(f, "{} patch(es) failed; no files were written", count)
             }
-            continue;
+            TransactionError::Journal(e) => write!(f, "transaction aborted: {}", e),
         }
+    }
+}
 
-        let content = match fs::read_to_string(&file_path) {
-            Ok(c) => c,
-            Err(source) => {
-                let kind = source.kind();
-                let msg = source.to_string();
-                for patch in patches {
-                    all_results.push((
-                        patch.id.clone(),
-                        Err(ApplicationError::Io {
-                            path: file_path.clone(),
-                            source: std::io::Error::new(kind, msg.clone()),
-                        }),
-                    ));
-                }
-                continue;
-            }
-        };
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransactionError::Journal(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-        let mut edits_with_ids = Vec::new();
-        let mut immediate_results = Vec::new();
+impl From<JournalError> for TransactionError {
+    fn from(e: JournalError) -> Self {
+        TransactionError::Journal(e)
+    }
+}
 
-        for patch in patches {
-            match check_patch_version(patch, workspace_version) {
-                Err(e) => {
-                    immediate_results.push((patch.id.clone(), Err(e)));
-                    continue;
-                }
-                Ok(Some(reason)) => {
-                    immediate_results
-                        .push((patch.id.clone(), Ok(PatchResult::SkippedVersion { reason })));
-                    continue;
-                }
-                Ok(None) => {}
-            }
+/// Apply several patch configurations as a single all-or-nothing transaction.
+///
+/// Every config is first simulated in memory, each one seeing the files as
+/// patched by the configs before it. Configs are run in the given order (see
+/// [`order_patches`](crate::config::order_patches)) and gated by their
+/// `requires` / `conflicts`. If any patch fails nothing is written.
+/// Otherwise the original and patched contents of every touched file are
+/// staged in a [`Journal`] under `.codex-patcher/`, and all files are written
+/// together; if any write fails, the files already written are restored.
+/// The ledger is only updated once the transaction has committed.
+pub fn apply_patches_atomic(
+    configs: &[PatchConfig],
+    workspace_root: &Path,
+    workspace_version: &str,
+) -> AtomicApply {
+    apply_patches_atomic_with(
+        configs,
+        &mut Outcomes::new(configs),
+        workspace_root,
+        workspace_version,
+    )
+}
 
-            match compute_edit_for_patch(patch, &file_path, &content) {
-                Ok(edit) => edits_with_ids.push((patch.id.clone(), edit)),
-                Err(e) => immediate_results.push((patch.id.clone(), Err(e))),
-            }
-        }
+/// [`apply_patches_atomic`], gated by `outcomes` instead of by `configs`
+/// alone (e.g. seeded with [`Outcomes::record_deselected`]).
+pub fn apply_patches_atomic_with(
+    configs: &[PatchConfig],
+    outcomes: &mut Outcomes,
+    workspace_root: &Path,
+    workspace_version: &str,
+) -> AtomicApply {
+    let mut results = Vec::with_capacity(configs.len());
+    let mut fuzzy_scores = Vec::with_capacity(configs.len());
+    let mut fuzzy_matches = Vec::with_capacity(configs.len());
+    let mut relocations = Vec::with_capacity(configs.len());
+    let mut overlay: HashMap<PathBuf, String> = HashMap::new();
+    let mut files: Vec<(PathBuf, SimulatedFile)> = Vec::new();
+    let mut records = Vec::new();
 
-        if !edits_with_ids.is_empty() {
-            // Sort to match apply_batch's internal descending byte_start order so
-            // the zip in simulate_batch_edits correctly pairs IDs with results.
-            edits_with_ids.sort_by(|(_, a), (_, b)| b.byte_start.cmp(&a.byte_start));
-
-            match simulate_batch_edits(&file_path, &content, &edits_with_ids) {
-                Ok(results) => all_results.extend(results),
-                Err(err) => {
-                    let err_clone = err.clone();
-                    for (patch_id, _) in &edits_with_ids {
-                        all_results.push((
-                            patch_id.clone(),
-                            Err(ApplicationError::Edit(err_clone.clone())),
-                        ));
-                    }
-                }
+    let guard = WorkspaceGuard::new(workspace_root);
+    for config in configs {
+        let simulation = outcomes.run(config, |config| {
+            simulate_with_overlay(config, workspace_root, workspace_version, &overlay)
+        });
+        for simulated in simulation.files {
+            let Ok(canonical) = simulated.path.canonicalize() else {
+                continue;
+            };
+            overlay.insert(canonical.clone(), simulated.patched.clone());
+            match files.iter_mut().find(|(path, _)| *path == canonical) {
+                // Keep the on-disk original from the first config that touched it.
+                Some((_, staged)) => staged.patched = simulated.patched,
+                None => files.push((canonical, simulated)),
             }
         }
-
-        all_results.extend(immediate_results);
+        records.extend(simulation.records);
+        results.push(simulation.results);
+        fuzzy_scores.push(simulation.fuzzy_scores);
+        fuzzy_matches.push(simulation.fuzzy_matches);
+        relocations.push(simulation.relocations);
     }
+    let files_out: Vec<SimulatedFile> = files.iter().map(|(_, f)| f.clone()).collect();
 
-    // Restore config.patches order — HashMap iteration is unordered.
-    let patch_order: std::collections::HashMap<&str, usize> = config
-        .patches
+    let failed = results
         .iter()
-        .enumerate()
-        .map(|(i, p)| (p.id.as_str(), i))
-        .collect();
-    all_results.sort_by_key(|(id, _)| patch_order.get(id.as_str()).copied().unwrap_or(usize::MAX));
+        .flatten()
+        .filter(|(_, r)| matches!(r, Err(_) | Ok(PatchResult::Failed { .. })))
+        .count();
+    if failed > 0 {
+        return AtomicApply {
+            results,
+            fuzzy_scores,
+            fuzzy_matches,
+            relocations,
+            files: files_out,
+            records,
+            ledger_error: None,
+            outcome: Err(TransactionError::PatchesFailed { count: failed }),
+        };
+    }
 
-    all_results
+    let outcome = guard
+        .map_err(JournalError::from)
+        .and_then(|guard| {
+            let staged: Vec<StagedFile> = files
+                .into_iter()
+                .map(|(path, file)| StagedFile {
+                    path,
+                    original: file.original,
+                    patched: file.patched,
+                })
+                .collect();
+            Journal::begin(workspace_root, &staged)?.commit(&guard)
+        })
+        .map_err(TransactionError::from);
+    let ledger_error = outcome
+        .is_ok()
+        .then(|| record_in_ledger(workspace_root, &records))
+        .flatten();
+
+    AtomicApply {
+        results,
+        fuzzy_scores,
+        fuzzy_matches,
+        relocations,
+        files: files_out,
+        records,
+        ledger_error,
+        outcome,
+    }
 }
 
-/// Simulate a batch of edits against a temporary file, preserving result semantics.
-#[allow(clippy::type_complexity)]
-fn simulate_batch_edits(
-    file_path: &Path,
-    content: &str,
-    edits_with_ids: &[(String, Edit)],
-) -> Result<Vec<(String, Result<PatchResult, ApplicationError>)>, EditError> {
-    let temp_dir = tempfile::tempdir().map_err(EditError::Io)?;
-    let temp_file = temp_dir.path().join("patch-check.tmp");
-    fs::write(&temp_file, content).map_err(EditError::Io)?;
+fn version_range_skip_reason(config: &PatchConfig, workspace_version: &str) -> String {
+    let req = config.meta.version_range.as_deref().unwrap_or("").trim();
+    if req.is_empty() {
+        format!("workspace version {workspace_version} does not satisfy patch version constraints")
+    } else {
+        format!("workspace version {workspace_version} does not satisfy version_range {req}")
+    }
+}
+
+/// Whether a batched run writes its results back to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunMode {
+    /// Compute results and post-patch buffers only.
+    Simulate,
+    /// Additionally commit each changed file with an atomic write.
+    Apply,
+}
+
+/// Resolve a patch's target file against the workspace.
+fn resolve_patch_file(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    patch: &PatchDefinition,
+) -> PathBuf {
+    if config.meta.workspace_relative {
+        workspace_root.join(&patch.file)
+    } else {
+        PathBuf::from(&patch.file)
+    }
+}
 
-    let simulated_edits: Vec<Edit> = edits_with_ids
+/// `config` with every patch that has variants replaced by the one variant
+/// matching `workspace_version` (see [`PatchDefinition::with_variant`]).
+/// Patches where none or several match are kept for [`check_patch_version`]
+/// to skip or reject.
+fn select_variants(config: &PatchConfig, workspace_version: &str) -> PatchConfig {
+    let patches = config
+        .patches
         .iter()
-        .map(|(_, edit)| {
-            let mut simulated = edit.clone();
-            simulated.file = temp_file.clone();
-            simulated
+        .map(|patch| {
+            if patch.variants.is_empty() {
+                return patch.clone();
+            }
+            match patch.matching_variants(workspace_version).as_deref() {
+                Ok([variant]) => patch.with_variant(*variant),
+                _ => patch.clone(),
+            }
         })
         .collect();
+    PatchConfig {
+        meta: config.meta.clone(),
+        patches,
+    }
+}
 
-    let results = Edit::apply_batch(simulated_edits)?;
+/// The `version` of the variant that result `patch_id` of `config` came
+/// from, for patches with variants where exactly one matches
+/// `workspace_version`.
+pub fn variant_used(
+    config: &PatchConfig,
+    patch_id: &str,
+    workspace_version: &str,
+) -> Option<String> {
+    let patch = &config.patches[patch_index(config, patch_id)?];
+    if patch.variants.is_empty() {
+        return None;
+    }
+    match patch.matching_variants(workspace_version).as_deref() {
+        Ok([Some(variant)]) => Some(variant.version.clone()),
+        Ok([None]) => patch.version.clone(),
+        _ => None,
+    }
+}
 
-    Ok(edits_with_ids
-        .iter()
-        .zip(results.iter())
-        .map(|((patch_id, _), result)| {
-            let patch_result = match result {
-                EditResult::Applied { .. } => Ok(PatchResult::Applied {
-                    file: file_path.to_path_buf(),
-                }),
-                EditResult::AlreadyApplied { .. } => Ok(PatchResult::AlreadyApplied {
-                    file: file_path.to_path_buf(),
-                }),
-            };
-            (patch_id.clone(), patch_result)
-        })
-        .collect())
+/// A config with its glob `file` patches expanded into one patch per file.
+struct ExpandedConfig {
+    config: PatchConfig,
+    /// Every result id in config order: expanded patch ids and the ids of
+    /// glob patches reported in `errors`
+    order: Vec<String>,
+    /// Glob patches whose matched file count is out of bounds
+    errors: Vec<(String, Result<PatchResult, ApplicationError>)>,
 }
 
-/// Optimized batch application that groups patches by file.
+/// Expand glob `file` patterns against the workspace.
 ///
-/// All 4 query types (Text, AstGrep, TreeSitter, Toml) flow through
-/// `compute_edit_for_patch` → `Edit::apply_batch`. Each file is read once,
-/// all edits are computed, then applied atomically in a single write.
-fn apply_patches_batched(
+/// A file is matched when `planner` locates the patch's query in it; files
+/// where it finds nothing are left out instead of failing with `NoMatch`.
+/// Each matched file becomes a copy of the patch with id
+/// `<patch-id>@<file>`. When the number of matched files is outside
+/// `min_matches` (default 1) / `max_matches`, the patch is reported as
+/// [`ApplicationError::FileMatchCount`] and nothing is planned for it.
+/// Version-skipped glob patches are kept as they are so they report a
+/// single skip.
+fn expand_file_globs(
     config: &PatchConfig,
     workspace_root: &Path,
     workspace_version: &str,
-) -> Vec<(String, Result<PatchResult, ApplicationError>)> {
-    use std::collections::HashMap;
-
-    let mut patches_by_file: HashMap<PathBuf, Vec<&PatchDefinition>> = HashMap::new();
+    planner: Planner,
+    overlay: &HashMap<PathBuf, String>,
+    guard: &WorkspaceGuard,
+) -> ExpandedConfig {
+    let mut patches = Vec::with_capacity(config.patches.len());
+    let mut order = Vec::with_capacity(config.patches.len());
+    let mut errors = Vec::new();
 
     for patch in &config.patches {
-        let file_path = if config.meta.workspace_relative {
-            workspace_root.join(&patch.file)
+        if !patch.is_glob() || !matches!(check_patch_version(patch, workspace_version), Ok(None)) {
+            order.push(patch.id.clone());
+            patches.push(patch.clone());
+            continue;
+        }
+
+        let pattern = if config.meta.workspace_relative {
+            let root = glob::Pattern::escape(&workspace_root.to_string_lossy());
+            format!("{}/{}", root.trim_end_matches('/'), patch.file)
         } else {
-            PathBuf::from(&patch.file)
+            patch.file.clone()
         };
-        patches_by_file.entry(file_path).or_default().push(patch);
-    }
-
-    let mut all_results = Vec::new();
+        let mut candidates: Vec<PathBuf> = glob::glob(&pattern)
+            .map(|paths| paths.flatten().filter(|path| path.is_file()).collect())
+            .unwrap_or_default();
+        candidates.sort();
 
-    for (file_path, patches) in patches_by_file {
-        // Drain version-skipped patches before the file-existence check so a
-        // patch targeting a file removed in a newer version returns
-        // SkippedVersion instead of NoMatch.
-        let patches: Vec<_> = patches
+        let matched: Vec<PathBuf> = candidates
             .into_iter()
-            .filter(|patch| {
-                match check_patch_version(patch, workspace_version) {
-                    Err(e) => {
-                        all_results.push((patch.id.clone(), Err(e)));
-                        false
-                    }
-                    Ok(Some(reason)) => {
-                        all_results.push((
-                            patch.id.clone(),
-                            Ok(PatchResult::SkippedVersion { reason }),
-                        ));
-                        false
-                    }
-                    Ok(None) => true,
-                }
+            .filter(|path| {
+                // Unreadable or unsafe targets stay in so the run reports why.
+                let Some(content) = read_guarded(guard, overlay, path) else {
+                    return true;
+                };
+                !matches!(
+                    planner(patch, path, &content),
+                    Err(ApplicationError::NoMatch { .. })
+                )
             })
             .collect();
 
-        if patches.is_empty() {
+        let min = patch.min_matches.unwrap_or(1);
+        let found = matched.len();
+        if found < min || patch.max_matches.is_some_and(|max| found > max) {
+            order.push(patch.id.clone());
+            errors.push((
+                patch.id.clone(),
+                Err(ApplicationError::FileMatchCount {
+                    pattern: patch.file.clone(),
+                    min,
+                    max: patch.max_matches,
+                    found,
+                }),
+            ));
             continue;
         }
 
-        if !file_path.exists() {
-            for patch in patches {
-                all_results.push((
-                    patch.id.clone(),
-                    Err(ApplicationError::NoMatch {
+        for path in matched {
+            let file = match config.meta.workspace_relative {
+                true => path.strip_prefix(workspace_root).unwrap_or(&path),
+                false => &path,
+            }
+            .to_string_lossy()
+            .into_owned();
+            let id = format!("{}@{}", patch.id, file);
+            order.push(id.clone());
+            patches.push(PatchDefinition {
+                id,
+                file,
+                ..patch.clone()
+            });
+        }
+    }
+
+    ExpandedConfig {
+        config: PatchConfig {
+            meta: config.meta.clone(),
+            patches,
+        },
+        order,
+        errors,
+    }
+}
+
+/// Contents of `path` (from `overlay` when it has them), or `None` when the
+/// path is unsafe or unreadable.
+fn read_guarded(
+    guard: &WorkspaceGuard,
+    overlay: &HashMap<PathBuf, String>,
+    path: &Path,
+) -> Option<String> {
+    let guarded = guard.validate_path(path).ok()?;
+    match overlay.get(&guarded) {
+        Some(content) => Some(content.clone()),
+        None => fs::read_to_string(&guarded).ok(),
+    }
+}
+
+/// A config with patches whose target moved moved along with it.
+struct RelocatedConfig {
+    config: PatchConfig,
+    /// Declared file of each relocated patch, by patch id
+    relocations: HashMap<String, PathBuf>,
+    errors: Vec<(String, Result<PatchResult, ApplicationError>)>,
+}
+
+/// Follow structural targets that moved to another file.
+///
+/// A patch with `search_paths` (or `relocate`) whose `file` is gone or no
+/// longer has its query's target is looked for in the files of its
+/// [`PatchDefinition::relocation_globs`] under the workspace. When `planner`
+/// finds it in exactly one, the patch is moved to that file and its declared
+/// file recorded in `relocations`; when several have it, the patch is
+/// reported as [`ApplicationError::AmbiguousRelocation`] and not run. With
+/// no hit the patch is left to fail with `NoMatch`.
+fn relocate_moved_targets(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+    planner: Planner,
+    overlay: &HashMap<PathBuf, String>,
+    guard: &WorkspaceGuard,
+) -> RelocatedConfig {
+    let mut patches = Vec::with_capacity(config.patches.len());
+    let mut relocations = HashMap::new();
+    let mut errors = Vec::new();
+    // Listed once, on the first patch that needs it.
+    let mut sources: Option<Vec<PathBuf>> = None;
+
+    for patch in &config.patches {
+        let globs = patch.relocation_globs();
+        if globs.is_empty() || !matches!(check_patch_version(patch, workspace_version), Ok(None)) {
+            patches.push(patch.clone());
+            continue;
+        }
+
+        let declared = resolve_patch_file(config, workspace_root, patch);
+        let finds = |path: &Path| {
+            read_guarded(guard, overlay, path)
+                .is_some_and(|content| planner(patch, path, &content).is_ok())
+        };
+        // An unsafe or unreadable `file` stays so the run reports why.
+        let moved = !declared.exists()
+            || read_guarded(guard, overlay, &declared).is_some_and(|content| {
+                matches!(
+                    planner(patch, &declared, &content),
+                    Err(ApplicationError::NoMatch { .. })
+                )
+            });
+        if !moved {
+            patches.push(patch.clone());
+            continue;
+        }
+
+        let patterns: Vec<glob::Pattern> = globs
+            .iter()
+            .filter_map(|glob| glob::Pattern::new(glob).ok())
+            .collect();
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..glob::MatchOptions::new()
+        };
+        let found: Vec<PathBuf> = sources
+            .get_or_insert_with(|| source_files(workspace_root))
+            .iter()
+            .filter(|path| **path != declared)
+            .filter(|path| {
+                let relative = match config.meta.workspace_relative {
+                    true => path.strip_prefix(workspace_root).unwrap_or(path),
+                    false => path,
+                };
+                patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path_with(relative, options))
+            })
+            .filter(|path| finds(path))
+            .cloned()
+            .collect();
+
+        match found.as_slice() {
+            [] => patches.push(patch.clone()),
+            [path] => {
+                let file = match config.meta.workspace_relative {
+                    true => path.strip_prefix(workspace_root).unwrap_or(path),
+                    false => path,
+                }
+                .to_string_lossy()
+                .into_owned();
+                relocations.insert(patch.id.clone(), declared);
+                patches.push(PatchDefinition {
+                    file,
+                    ..patch.clone()
+                });
+            }
+            _ => errors.push((
+                patch.id.clone(),
+                Err(ApplicationError::AmbiguousRelocation {
+                    file: declared,
+                    found,
+                }),
+            )),
+        }
+    }
+
+    RelocatedConfig {
+        config: PatchConfig {
+            meta: config.meta.clone(),
+            patches,
+        },
+        relocations,
+        errors,
+    }
+}
+
+/// Group patches by target file, preserving first-appearance order.
+fn group_patches_by_file<'a>(
+    config: &'a PatchConfig,
+    workspace_root: &Path,
+) -> Vec<(PathBuf, Vec<&'a PatchDefinition>)> {
+    let mut groups: Vec<(PathBuf, Vec<&PatchDefinition>)> = Vec::new();
+
+    for patch in &config.patches {
+        let file_path = resolve_patch_file(config, workspace_root, patch);
+        match groups.iter_mut().find(|(path, _)| *path == file_path) {
+            Some((_, patches)) => patches.push(patch),
+            None => groups.push((file_path, vec![patch])),
+        }
+    }
+
+    groups
+}
+
+/// Plans the edits of one patch against a file's contents.
+type Planner = fn(&PatchDefinition, &Path, &str) -> Result<PlannedEdit, ApplicationError>;
+
+fn planner_for(action: LedgerAction) -> Planner {
+    match action {
+        LedgerAction::Apply => compute_edit_for_patch,
+        LedgerAction::Revert => |patch, file_path, content| {
+            compute_revert_edit(patch, file_path, content).map(|edits| PlannedEdit {
+                edits,
+                fuzzy_score: None,
+            })
+        },
+    }
+}
+
+/// Batched evaluation that groups patches by file.
+///
+/// All 4 query types (Text, AstGrep, TreeSitter, Toml) flow through
+/// `compute_edit_for_patch` (or `compute_revert_edit` for [`LedgerAction::Revert`])
+/// → `Edit::apply_batch_in_memory`. Each file is read
+/// once, all edits are computed and spliced in memory, and in
+/// [`RunMode::Apply`] the result is committed with a single atomic write.
+///
+/// Every target is resolved through [`WorkspaceGuard`] before it is read and
+/// re-validated immediately before it is written, so a patch cannot escape the
+/// workspace via `..`, symlinks or absolute paths.
+///
+/// The patched buffer must also still parse (see [`validate_patched`]);
+/// otherwise the whole file batch is refused in both modes.
+fn run_batched(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+    mode: RunMode,
+    overlay: &HashMap<PathBuf, String>,
+    action: LedgerAction,
+) -> Simulation {
+    let mut all_results = Vec::new();
+    let mut files = Vec::new();
+    let mut records = Vec::new();
+    // Score and matched text of each fuzzily matched patch
+    let mut fuzzy = HashMap::new();
+    let planner = planner_for(action);
+
+    let guard = match WorkspaceGuard::new(workspace_root) {
+        Ok(guard) => guard,
+        Err(e) => {
+            return Simulation {
+                results: config
+                    .patches
+                    .iter()
+                    .map(|patch| (patch.id.clone(), Err(ApplicationError::Safety(e.clone()))))
+                    .collect(),
+                files,
+                records,
+                ..Simulation::default()
+            };
+        }
+    };
+
+    let selected = select_variants(config, workspace_version);
+    let ExpandedConfig {
+        config: expanded,
+        order,
+        errors,
+    } = expand_file_globs(
+        &selected,
+        workspace_root,
+        workspace_version,
+        planner,
+        overlay,
+        &guard,
+    );
+    all_results.extend(errors);
+    let RelocatedConfig {
+        config: relocated,
+        relocations,
+        errors,
+    } = relocate_moved_targets(
+        &expanded,
+        workspace_root,
+        workspace_version,
+        planner,
+        overlay,
+        &guard,
+    );
+    let config = &relocated;
+    all_results.extend(errors);
+
+    for (file_path, patches) in group_patches_by_file(config, workspace_root) {
+        // Drain version-skipped patches before the file-existence check so a
+        // patch targeting a file removed in a newer version returns
+        // SkippedVersion instead of NoMatch.
+        let patches: Vec<_> = patches
+            .into_iter()
+            .filter(
+                |patch| match check_patch_version(patch, workspace_version) {
+                    Err(e) => {
+                        all_results.push((patch.id.clone(), Err(e)));
+                        false
+                    }
+                    Ok(Some(reason)) => {
+                        all_results
+                            .push((patch.id.clone(), Ok(PatchResult::SkippedVersion { reason })));
+                        false
+                    }
+                    Ok(None) => true,
+                },
+            )
+            .collect();
+
+        if patches.is_empty() {
+            continue;
+        }
+
+        if !file_path.exists() {
+            for patch in patches {
+                // The target may have moved to another file.
+                let candidates = match action {
+                    LedgerAction::Apply => near_misses(patch, &file_path, "", workspace_root),
+                    LedgerAction::Revert => Vec::new(),
+                };
+                all_results.push((
+                    patch.id.clone(),
+                    Err(ApplicationError::NoMatch {
                         file: file_path.clone(),
+                        candidates,
                     }),
                 ));
             }
             continue;
         }
 
-        let content = match fs::read_to_string(&file_path) {
+        let guarded_path = match guard.validate_path(&file_path) {
+            Ok(path) => path,
+            Err(e) => {
+                for patch in patches {
+                    all_results.push((patch.id.clone(), Err(ApplicationError::Safety(e.clone()))));
+                }
+                continue;
+            }
+        };
+
+        let content = match overlay
+            .get(&guarded_path)
+            .map_or_else(|| fs::read_to_string(&guarded_path), |c| Ok(c.clone()))
+        {
             Ok(c) => c,
             Err(source) => {
                 // Preserve kind + message; std::io::Error is not Clone so we
@@ -461,63 +1293,303 @@ fn apply_patches_batched(
             }
         };
 
-        let mut edits_with_ids = Vec::new();
         let mut patch_errors = Vec::new();
-
-        for patch in patches {
-            match compute_edit_for_patch(patch, &file_path, &content) {
-                Ok(edit) => edits_with_ids.push((patch.id.clone(), edit)),
-                Err(e) => patch_errors.push((patch.id.clone(), Err(e))),
+        let (patch_ids, outcome) = splice_file_patches(
+            config,
+            &patches,
+            action,
+            &file_path,
+            &content,
+            &mut fuzzy,
+            &mut patch_errors,
+        );
+        if action == LedgerAction::Apply {
+            for (patch_id, result) in &mut patch_errors {
+                let patch = patches.iter().find(|patch| patch.id == *patch_id);
+                if let (Some(patch), Err(ApplicationError::NoMatch { candidates, .. })) =
+                    (patch, result)
+                {
+                    *candidates = near_misses(patch, &file_path, &content, workspace_root);
+                }
             }
         }
 
-        if !edits_with_ids.is_empty() {
-            // apply_batch sorts by byte_start descending internally.
-            // Sort edits_with_ids the same way so zip() aligns correctly.
-            edits_with_ids.sort_by(|(_, a), (_, b)| b.byte_start.cmp(&a.byte_start));
-
-            let edits: Vec<Edit> = edits_with_ids.iter().map(|(_, e)| e.clone()).collect();
+        if !patch_ids.is_empty() {
+            let outcome = outcome.and_then(|(patched, spliced)| {
+                if patched != content {
+                    validate_patched(&file_path, &content, &patched)?;
+                }
+                if mode == RunMode::Apply && patched != content {
+                    // Close the TOCTOU window between read and write.
+                    let write_path = guard.revalidate(&guarded_path)?;
+                    commit_file(&write_path, patched.as_bytes())?;
+                }
+                Ok((patched, spliced))
+            });
 
-            match Edit::apply_batch(edits) {
-                Ok(results) => {
-                    for ((patch_id, _), result) in edits_with_ids.iter().zip(results.iter()) {
-                        let patch_result = match result {
-                            EditResult::Applied { .. } => Ok(PatchResult::Applied {
+            // A patch may contribute several edits (one per selected
+            // occurrence); it reports a single result, `Applied` if any of its
+            // edits changed the file.
+            let mut file_results: Vec<(String, Result<PatchResult, ApplicationError>)> = Vec::new();
+            match outcome {
+                Ok((patched, spliced)) => {
+                    let ledger_file = guarded_path
+                        .strip_prefix(guard.workspace_root())
+                        .unwrap_or(&guarded_path);
+                    for SplicedEdit {
+                        patch_id,
+                        edit,
+                        before_hash,
+                        result,
+                    } in spliced
+                    {
+                        // A chain of sequential patches can rewrite its own
+                        // output; if it ends where it started, nothing applied.
+                        let applied = matches!(result, EditResult::Applied { .. });
+                        let patch_result = if applied && patched != content {
+                            records.push(LedgerRecord {
+                                config: config.meta.name.clone(),
+                                patch_id: patch_id.clone(),
+                                action,
+                                file: ledger_file.to_path_buf(),
+                                byte_start: edit.byte_start,
+                                byte_end: edit.byte_end,
+                                new_len: edit.new_text.len(),
+                                before_hash,
+                                after_hash: hash_hex(edit.new_text.as_bytes()),
+                                workspace_version: workspace_version.to_string(),
+                                timestamp: ledger::now(),
+                            });
+                            Ok(PatchResult::Applied {
                                 file: file_path.clone(),
-                            }),
-                            EditResult::AlreadyApplied { .. } => Ok(PatchResult::AlreadyApplied {
+                            })
+                        } else {
+                            Ok(PatchResult::AlreadyApplied {
                                 file: file_path.clone(),
-                            }),
+                            })
                         };
-                        all_results.push((patch_id.clone(), patch_result));
+                        match file_results.iter_mut().find(|(id, _)| *id == patch_id) {
+                            Some((_, existing)) => {
+                                if matches!(patch_result, Ok(PatchResult::Applied { .. })) {
+                                    *existing = patch_result;
+                                }
+                            }
+                            None => file_results.push((patch_id, patch_result)),
+                        }
+                    }
+                    if patched != content {
+                        files.push(SimulatedFile {
+                            path: file_path.clone(),
+                            original: content,
+                            patched,
+                        });
                     }
                 }
                 Err(e) => {
                     // Reconstruct per-patch errors using Clone (kind+message preserved).
-                    let e_clone = e.clone();
-                    for (patch_id, _) in &edits_with_ids {
-                        all_results.push((
-                            patch_id.clone(),
-                            Err(ApplicationError::Edit(e_clone.clone())),
-                        ));
+                    for patch_id in patch_ids {
+                        file_results.push((patch_id, Err(e.clone())));
                     }
                 }
             }
+            all_results.extend(file_results);
         }
 
         all_results.extend(patch_errors);
     }
 
-    // Restore config.patches order — HashMap iteration is unordered.
-    let patch_order: std::collections::HashMap<&str, usize> = config
-        .patches
+    // Restore config.patches order — results are collected per file.
+    let patch_order: std::collections::HashMap<&str, usize> = order
         .iter()
         .enumerate()
-        .map(|(i, p)| (p.id.as_str(), i))
+        .map(|(i, id)| (id.as_str(), i))
         .collect();
     all_results.sort_by_key(|(id, _)| patch_order.get(id.as_str()).copied().unwrap_or(usize::MAX));
 
-    all_results
+    let (fuzzy_scores, fuzzy_matches) = fuzzy
+        .into_iter()
+        .map(|(id, (score, text))| ((id.clone(), score), (id, text)))
+        .unzip();
+    Simulation {
+        results: all_results,
+        files,
+        records,
+        fuzzy_scores,
+        fuzzy_matches,
+        relocations,
+        ledger_error: None,
+    }
+}
+
+/// An edit spliced into a file buffer by [`splice_file_patches`].
+struct SplicedEdit {
+    patch_id: String,
+    /// The edit, positioned in the buffer it was planned against
+    edit: Edit,
+    /// Hash of the text the edit replaced
+    before_hash: String,
+    result: EditResult,
+}
+
+/// The patched buffer and every edit spliced into it, or the error that
+/// failed the file.
+type SpliceOutcome = Result<(String, Vec<SplicedEdit>), ApplicationError>;
+
+/// Plan the patches of one file and splice their edits into `content`.
+///
+/// Patches are planned against the same buffer and spliced together, except
+/// that a [`ApplyMode::Sequential`] patch is planned against the output of
+/// every patch before it (after it, when reverting, since reverts run last
+/// patch first). Planning errors go to `patch_errors`. Returns the ids of
+/// the patches with edits, and the patched buffer or the first splice error,
+/// which fails the whole file.
+fn splice_file_patches(
+    config: &PatchConfig,
+    patches: &[&PatchDefinition],
+    action: LedgerAction,
+    file_path: &Path,
+    content: &str,
+    fuzzy: &mut HashMap<String, (f64, String)>,
+    patch_errors: &mut Vec<(String, Result<PatchResult, ApplicationError>)>,
+) -> (Vec<String>, SpliceOutcome) {
+    let planner = planner_for(action);
+    let mut ordered = patches.to_vec();
+    if action == LedgerAction::Revert {
+        ordered.reverse();
+    }
+
+    let mut patched = content.to_string();
+    let mut spliced = Vec::new();
+    let mut stage: Vec<(String, Edit)> = Vec::new();
+    let mut patch_ids = Vec::new();
+    let mut previous_sequential = false;
+    for (i, patch) in ordered.iter().enumerate() {
+        let sequential = patch.mode(&config.meta) == ApplyMode::Sequential;
+        let boundary = match action {
+            LedgerAction::Apply => sequential,
+            LedgerAction::Revert => previous_sequential,
+        };
+        previous_sequential = sequential;
+        if boundary {
+            if let Err(e) = splice_stage(&mut patched, &mut stage, &mut spliced) {
+                patch_ids.extend(ordered[i..].iter().map(|p| p.id.clone()));
+                return (patch_ids, Err(e));
+            }
+        }
+
+        match planner(patch, file_path, &patched) {
+            Ok(planned) => {
+                if let (Some(score), Some(edit)) = (planned.fuzzy_score, planned.edits.first()) {
+                    let matched = patched[edit.byte_start..edit.byte_end].to_string();
+                    fuzzy.insert(patch.id.clone(), (score, matched));
+                }
+                if !planned.edits.is_empty() {
+                    patch_ids.push(patch.id.clone());
+                }
+                for edit in planned.edits {
+                    stage.push((patch.id.clone(), edit));
+                }
+            }
+            Err(e) => patch_errors.push((patch.id.clone(), Err(e))),
+        }
+    }
+
+    let outcome = splice_stage(&mut patched, &mut stage, &mut spliced).map(|()| (patched, spliced));
+    (patch_ids, outcome)
+}
+
+/// Splice the edits planned against `patched` into it, draining `stage`.
+fn splice_stage(
+    patched: &mut String,
+    stage: &mut Vec<(String, Edit)>,
+    spliced: &mut Vec<SplicedEdit>,
+) -> Result<(), ApplicationError> {
+    if stage.is_empty() {
+        return Ok(());
+    }
+    // apply_batch_in_memory sorts by byte_start descending internally.
+    // Sort the stage the same way so zip() aligns correctly.
+    stage.sort_by_key(|(_, e)| std::cmp::Reverse(e.byte_start));
+    let edits: Vec<Edit> = stage.iter().map(|(_, e)| e.clone()).collect();
+    let (next, results) = Edit::apply_batch_in_memory(patched, edits)?;
+    for ((patch_id, edit), result) in stage.drain(..).zip(results) {
+        spliced.push(SplicedEdit {
+            before_hash: hash_hex(&patched.as_bytes()[edit.byte_start..edit.byte_end]),
+            patch_id,
+            edit,
+            result,
+        });
+    }
+    *patched = next;
+    Ok(())
+}
+
+/// Check that a patched buffer does not introduce syntax errors.
+///
+/// `.rs` files are compared against the original with tree-sitter so that
+/// pre-existing ERROR nodes are tolerated; `.toml` files must still parse
+/// unless the original was already invalid. Other file types are not checked.
+fn validate_patched(file: &Path, original: &str, patched: &str) -> Result<(), ApplicationError> {
+    let errors = match file.extension().and_then(|ext| ext.to_str()) {
+        Some("rs") => match crate::validate::pooled::validate_edit(original, patched) {
+            Ok(()) => return Ok(()),
+            Err(ValidationError::ParseErrorIntroduced { errors, .. }) => errors,
+            Err(e) => {
+                return Err(ApplicationError::Io {
+                    path: file.to_path_buf(),
+                    source: std::io::Error::other(e.to_string()),
+                })
+            }
+        },
+        Some("toml") => {
+            if crate::toml::validate_document(patched).is_ok()
+                || crate::toml::validate_document(original).is_err()
+            {
+                return Ok(());
+            }
+            vec![toml_error_location(patched)]
+        }
+        _ => return Ok(()),
+    };
+
+    Err(ApplicationError::ParseErrorIntroduced {
+        file: file.to_path_buf(),
+        errors,
+    })
+}
+
+/// Build an [`ErrorLocation`] for a TOML document that fails to parse.
+fn toml_error_location(content: &str) -> ErrorLocation {
+    let span = content
+        .parse::<toml_edit::DocumentMut>()
+        .err()
+        .and_then(|err| err.span())
+        .unwrap_or(0..0);
+    let byte_start = span.start.min(content.len());
+    let byte_end = span.end.clamp(byte_start, content.len());
+
+    let before = &content[..byte_start];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+
+    let context_start = floor_char_boundary(content, byte_start.saturating_sub(20));
+    let context_end = floor_char_boundary(content, (byte_end + 20).min(content.len()));
+    let context = content[context_start..context_end].replace('\n', "\\n");
+
+    ErrorLocation {
+        byte_start,
+        byte_end,
+        line,
+        column,
+        context,
+    }
+}
+
+fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
 }
 
 /// Convert a TOML patch into an `Edit` (or a sentinel no-op `Edit` when the
@@ -643,17 +1715,122 @@ fn compute_toml_edit(
     }
 }
 
+/// The [`Edit`]s planned for a patch (one per selected occurrence), with how
+/// its target was found.
+struct PlannedEdit {
+    edits: Vec<Edit>,
+    /// Similarity score when the target was only found by fuzzy matching
+    fuzzy_score: Option<f64>,
+}
+
+impl From<Edit> for PlannedEdit {
+    fn from(edit: Edit) -> Self {
+        Self {
+            edits: vec![edit],
+            fuzzy_score: None,
+        }
+    }
+}
+
+impl PlannedEdit {
+    /// Edits for the pending sites of an occurrence-selected patch; a no-op
+    /// edit when every selected site is already applied.
+    fn from_sites(file_path: &Path, edits: Vec<Edit>) -> Self {
+        if edits.is_empty() {
+            Edit::new(file_path, 0, 0, String::new(), "").into()
+        } else {
+            Self {
+                edits,
+                fuzzy_score: None,
+            }
+        }
+    }
+}
+
+/// A match of an occurrence-selected query, in file order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Site {
+    start: usize,
+    end: usize,
+    /// The span already holds the patch's replacement
+    applied: bool,
+}
+
+/// Merge a query's `pending` matches with the spans already holding its
+/// replacement, then apply `expected_count` and pick the selected sites.
+///
+/// Pending matches inside an applied span (a replacement that contains the
+/// search text) are the applied site, not a new one.
+fn select_sites(
+    file_path: &Path,
+    selection: &Selection,
+    occurrence: Occurrence,
+    pending: Vec<(usize, usize)>,
+    applied: Vec<(usize, usize)>,
+) -> Result<Vec<Site>, ApplicationError> {
+    let mut sites: Vec<Site> = applied
+        .iter()
+        .map(|&(start, end)| Site {
+            start,
+            end,
+            applied: true,
+        })
+        .collect();
+    for (start, end) in pending {
+        let inside_applied = applied.iter().any(|&(s, e)| start < e && s < end);
+        if !inside_applied {
+            sites.push(Site {
+                start,
+                end,
+                applied: false,
+            });
+        }
+    }
+    sites.sort_by_key(|site| site.start);
+
+    if let Some(expected) = selection.expected_count {
+        if sites.len() != expected {
+            return Err(ApplicationError::UnexpectedMatchCount {
+                file: file_path.to_path_buf(),
+                expected,
+                found: sites.len(),
+            });
+        }
+    }
+    let no_match = || ApplicationError::NoMatch {
+        file: file_path.to_path_buf(),
+        candidates: Vec::new(),
+    };
+    if sites.is_empty() {
+        return Err(no_match());
+    }
+
+    match occurrence {
+        Occurrence::All => Ok(sites),
+        Occurrence::First => Ok(vec![sites[0]]),
+        Occurrence::Last => Ok(vec![sites[sites.len() - 1]]),
+        Occurrence::Nth(n) => sites
+            .get(n - 1)
+            .map(|site| vec![*site])
+            .ok_or_else(no_match),
+    }
+}
+
 /// Compute an Edit for a patch without applying it.
 fn compute_edit_for_patch(
     patch: &PatchDefinition,
     file_path: &Path,
     content: &str,
-) -> Result<Edit, ApplicationError> {
+) -> Result<PlannedEdit, ApplicationError> {
+    if patch.operation.insertion_text().is_some() {
+        return compute_insert_edit(patch, file_path, content);
+    }
     match &patch.query {
         Query::Text {
             search,
             fuzzy_threshold,
             fuzzy_expansion,
+            selection,
         } => compute_text_edit(
             patch,
             file_path,
@@ -661,14 +1838,160 @@ fn compute_edit_for_patch(
             search,
             *fuzzy_threshold,
             *fuzzy_expansion,
+            selection,
         ),
-        Query::AstGrep { pattern } => {
-            compute_structural_edit(patch, file_path, content, pattern, true)
+        Query::AstGrep {
+            pattern, selection, ..
+        } => compute_structural_edit(patch, file_path, content, pattern, selection, true),
+        Query::TreeSitter {
+            pattern, selection, ..
+        } => compute_structural_edit(patch, file_path, content, pattern, selection, false),
+        Query::Toml { .. } => compute_toml_edit(patch, file_path, content).map(PlannedEdit::from),
+    }
+}
+
+/// Compute the inverse of a patch's edit (for `revert`).
+///
+/// The text the patch inserted is located (scoped by `function_context`) and
+/// replaced with the text it displaced: the `search` string for text queries,
+/// or a `verify.exact_match` expected text for structural queries. TOML
+/// section insertions are inverted by removing the inserted text. Patches with
+/// `occurrence = "all"` revert every occurrence of the inserted text.
+fn compute_revert_edit(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    content: &str,
+) -> Result<Vec<Edit>, ApplicationError> {
+    use crate::config::schema::Verify;
+
+    let irreversible = |reason: &str| ApplicationError::Irreversible {
+        file: file_path.to_path_buf(),
+        reason: reason.to_string(),
+    };
+    let verified_original = || match &patch.verify {
+        Some(Verify::ExactMatch { expected_text }) => Ok(expected_text.as_str()),
+        _ => Err(irreversible(
+            "structural patches need `verify.method = \"exact_match\"` to record the original text",
+        )),
+    };
+
+    if patch.operation.insertion_text().is_some() {
+        return compute_insert_revert(patch, file_path, content);
+    }
+
+    let (applied, original) = match (&patch.query, &patch.operation) {
+        (Query::Text { search, .. }, Operation::Replace { text }) => {
+            (text.as_str(), search.as_str())
+        }
+        (Query::AstGrep { .. }, Operation::Replace { text })
+            if !crate::sg::template_metavars(text).is_empty() =>
+        {
+            return Err(irreversible(
+                "replacement templates write different text at every match",
+            ))
+        }
+        (Query::AstGrep { .. } | Query::TreeSitter { .. }, Operation::Replace { text }) => {
+            (text.as_str(), verified_original()?)
+        }
+        (
+            Query::AstGrep { .. } | Query::TreeSitter { .. },
+            Operation::Delete {
+                insert_comment: Some(comment),
+            },
+        ) => (comment.as_str(), verified_original()?),
+        (
+            Query::Toml { .. },
+            Operation::InsertSection { text, .. } | Operation::AppendSection { text },
+        ) => (text.as_str(), ""),
+        _ => {
+            return Err(irreversible(
+                "operation does not record enough information to be undone",
+            ))
+        }
+    };
+    if applied.trim().is_empty() {
+        return Err(irreversible("patch inserted no text to locate"));
+    }
+
+    let scope = function_context_ranges(patch, file_path, content)?;
+    let regions = scope.clone().unwrap_or_else(|| vec![(0, content.len())]);
+    let find_all = |needle: &str| -> Vec<usize> {
+        regions
+            .iter()
+            .flat_map(|&(start, end)| {
+                content[start..end]
+                    .match_indices(needle)
+                    .map(move |(offset, _)| start + offset)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+    // Inserted text that is part of an unpatched `original` (a `.build()`
+    // left by search `.timeout(30).build()`) was not put there by the patch.
+    let find_applied = |applied: &str, original: &str| -> Vec<usize> {
+        let originals = if original.contains(applied) {
+            find_all(original)
+        } else {
+            Vec::new()
+        };
+        find_all(applied)
+            .into_iter()
+            .filter(|&at| {
+                !originals
+                    .iter()
+                    .any(|&start| start <= at && at + applied.len() <= start + original.len())
+            })
+            .collect()
+    };
+
+    // Structural replacements are aligned to the matched span's trailing
+    // newline, so the inserted text may appear without its final '\n'.
+    let mut applied = applied;
+    let mut original = original;
+    let mut occurrences = find_applied(applied, original);
+    if occurrences.is_empty() && applied.ends_with('\n') {
+        applied = applied.trim_end_matches('\n');
+        original = original.strip_suffix('\n').unwrap_or(original);
+        occurrences = find_applied(applied, original);
+    }
+
+    let revert_at = |byte_start: usize| {
+        let mut byte_end = byte_start + applied.len();
+        // Removing an inserted TOML section also drops its separating newline.
+        if original.is_empty() && content[byte_end..].starts_with('\n') {
+            byte_end += 1;
         }
-        Query::TreeSitter { pattern } => {
-            compute_structural_edit(patch, file_path, content, pattern, false)
+        Edit::with_verification(
+            file_path,
+            byte_start,
+            byte_end,
+            original.to_string(),
+            EditVerification::from_text(&content[byte_start..byte_end]),
+        )
+    };
+    let revert_all = patch
+        .query
+        .selection()
+        .is_some_and(|selection| selection.occurrence == Some(Occurrence::All));
+
+    match occurrences.len() {
+        0 => {
+            if original.is_empty() || scoped_contains(content, scope.as_deref(), original) {
+                // Not applied (or already reverted): no-op for idempotency.
+                Ok(vec![Edit::new(file_path, 0, 0, String::new(), "")])
+            } else {
+                Err(ApplicationError::NoMatch {
+                    file: file_path.to_path_buf(),
+                    candidates: Vec::new(),
+                })
+            }
         }
-        Query::Toml { .. } => compute_toml_edit(patch, file_path, content),
+        1 => Ok(vec![revert_at(occurrences[0])]),
+        _ if revert_all => Ok(occurrences.into_iter().map(revert_at).collect()),
+        count => Err(ApplicationError::AmbiguousMatch {
+            file: file_path.to_path_buf(),
+            count,
+        }),
     }
 }
 
@@ -680,30 +2003,84 @@ fn compute_text_edit(
     search: &str,
     fuzzy_threshold: Option<f64>,
     fuzzy_expansion: Option<usize>,
-) -> Result<Edit, ApplicationError> {
-    // Check if the search text exists in the file
-    if !content.contains(search) {
-        // Check if the replacement text already exists (idempotency)
-        if let Operation::Replace { text } = &patch.operation {
-            if content.contains(text.as_str()) {
-                // Return a no-op edit for idempotency
-                return Ok(Edit::new(file_path, 0, 0, String::new(), ""));
-            }
-        }
-
-        // Fuzzy fallback: only when the user has explicitly opted in via threshold or expansion.
+    selection: &Selection,
+) -> Result<PlannedEdit, ApplicationError> {
+    let scope = function_context_ranges(patch, file_path, content)?;
+    let regions = scope.clone().unwrap_or_else(|| vec![(0, content.len())]);
+    let occurrences: Vec<usize> = regions
+        .iter()
+        .flat_map(|&(start, end)| {
+            content[start..end]
+                .match_indices(search)
+                .map(move |(offset, _)| start + offset)
+        })
+        .collect();
+
+    if let Some(occurrence) = selection.occurrence {
+        let Operation::Replace { text } = &patch.operation else {
+            return Err(ApplicationError::TomlOperation {
+                file: file_path.to_path_buf(),
+                reason: "Text queries only support 'replace' operation".to_string(),
+            });
+        };
+        let pending = occurrences
+            .iter()
+            .map(|&start| (start, start + search.len()))
+            .collect();
+        let applied = scoped_matches(content, scope.as_deref(), text);
+        let sites = select_sites(file_path, selection, occurrence, pending, applied)?;
+        let verification = text_verification(patch, file_path, search)?;
+        let edits = sites
+            .into_iter()
+            .filter(|site| !site.applied)
+            .map(|site| {
+                Edit::with_verification(
+                    file_path,
+                    site.start,
+                    site.end,
+                    text.clone(),
+                    verification.clone(),
+                )
+            })
+            .collect();
+        return Ok(PlannedEdit::from_sites(file_path, edits));
+    }
+
+    // Check if the search text exists in the file (or function scope)
+    if occurrences.is_empty() {
+        // Check if the replacement text already exists (idempotency)
+        if let Operation::Replace { text } = &patch.operation {
+            if scoped_contains(content, scope.as_deref(), text) {
+                // Return a no-op edit for idempotency
+                return Ok(Edit::new(file_path, 0, 0, String::new(), "").into());
+            }
+        }
+
+        // Fuzzy fallback: only when the user has explicitly opted in via threshold or expansion.
         if fuzzy_threshold.is_none() && fuzzy_expansion.is_none() {
             return Err(ApplicationError::NoMatch {
                 file: file_path.to_path_buf(),
+                candidates: Vec::new(),
             });
         }
         let threshold = fuzzy_threshold.unwrap_or(0.85);
-        let fuzzy_result = match fuzzy_expansion {
-            Some(expansion) => {
-                crate::fuzzy::find_best_match_elastic(search, content, threshold, expansion)
-            }
-            None => crate::fuzzy::find_best_match(search, content, threshold),
-        };
+        let fuzzy_result = regions
+            .iter()
+            .filter_map(|&(start, end)| {
+                let region = &content[start..end];
+                let found = match fuzzy_expansion {
+                    Some(expansion) => {
+                        crate::fuzzy::find_best_match_elastic(search, region, threshold, expansion)
+                    }
+                    None => crate::fuzzy::find_best_match(search, region, threshold),
+                };
+                found.map(|mut fuzzy| {
+                    fuzzy.start += start;
+                    fuzzy.end += start;
+                    fuzzy
+                })
+            })
+            .max_by(|a, b| a.score.total_cmp(&b.score));
         if let Some(fuzzy) = fuzzy_result {
             eprintln!(
                 "  [fuzzy] patch '{}': exact match failed, using fuzzy match (score: {:.2})",
@@ -711,13 +2088,16 @@ fn compute_text_edit(
             );
 
             return match &patch.operation {
-                Operation::Replace { text } => Ok(Edit::new(
-                    file_path,
-                    fuzzy.start,
-                    fuzzy.end,
-                    text.clone(),
-                    fuzzy.matched_text,
-                )),
+                Operation::Replace { text } => Ok(PlannedEdit {
+                    edits: vec![Edit::new(
+                        file_path,
+                        fuzzy.start,
+                        fuzzy.end,
+                        text.clone(),
+                        fuzzy.matched_text,
+                    )],
+                    fuzzy_score: Some(fuzzy.score),
+                }),
                 _ => Err(ApplicationError::TomlOperation {
                     file: file_path.to_path_buf(),
                     reason: "Text queries only support 'replace' operation".to_string(),
@@ -727,48 +2107,33 @@ fn compute_text_edit(
 
         return Err(ApplicationError::NoMatch {
             file: file_path.to_path_buf(),
+            candidates: Vec::new(),
         });
     }
 
-    // O(1) ambiguity check: bail if more than one match exists
-    let mut occurrences = content.match_indices(search);
-    let first = occurrences.next();
-    if first.is_some() && occurrences.next().is_some() {
+    if occurrences.len() > 1 {
         return Err(ApplicationError::AmbiguousMatch {
             file: file_path.to_path_buf(),
-            count: content.matches(search).count(), // full count only for error message
+            count: occurrences.len(),
         });
     }
 
     // Create edit
     match &patch.operation {
         Operation::Replace { text } => {
-            let byte_start = first.expect("existence checked above").0;
+            let byte_start = occurrences[0];
             let byte_end = byte_start + search.len();
-            let verification = if let Some(verify) = &patch.verify {
-                match verify {
-                    crate::config::schema::Verify::ExactMatch { expected_text } => {
-                        EditVerification::ExactMatch(expected_text.clone())
-                    }
-                    crate::config::schema::Verify::Hash { expected, .. } => {
-                        let hash = u64::from_str_radix(expected.trim_start_matches("0x"), 16)
-                            .map_err(|_| ApplicationError::TomlOperation {
-                                file: file_path.to_path_buf(),
-                                reason: format!("invalid hash value: {}", expected),
-                            })?;
-                        EditVerification::Hash(hash)
-                    }
-                }
-            } else {
-                EditVerification::from_text(search)
-            };
-            Ok(Edit::with_verification(
-                file_path,
-                byte_start,
-                byte_end,
-                text.clone(),
-                verification,
-            ))
+            let verification = text_verification(patch, file_path, search)?;
+            Ok(
+                Edit::with_verification(
+                    file_path,
+                    byte_start,
+                    byte_end,
+                    text.clone(),
+                    verification,
+                )
+                .into(),
+            )
         }
         _ => Err(ApplicationError::TomlOperation {
             file: file_path.to_path_buf(),
@@ -777,54 +2142,166 @@ fn compute_text_edit(
     }
 }
 
+/// Verification for a text query's edit: `patch.verify` when set, otherwise
+/// the search text itself.
+fn text_verification(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    search: &str,
+) -> Result<EditVerification, ApplicationError> {
+    match &patch.verify {
+        Some(crate::config::schema::Verify::ExactMatch { expected_text }) => {
+            Ok(EditVerification::ExactMatch(expected_text.clone()))
+        }
+        Some(crate::config::schema::Verify::Hash { expected, .. }) => {
+            let hash =
+                u64::from_str_radix(expected.trim_start_matches("0x"), 16).map_err(|_| {
+                    ApplicationError::TomlOperation {
+                        file: file_path.to_path_buf(),
+                        reason: format!("invalid hash value: {}", expected),
+                    }
+                })?;
+            Ok(EditVerification::Hash(hash))
+        }
+        None => Ok(EditVerification::from_text(search)),
+    }
+}
+
 /// Compute a structural edit without applying it (for batching).
 fn compute_structural_edit(
     patch: &PatchDefinition,
     file_path: &Path,
     content: &str,
     pattern: &str,
+    selection: &Selection,
     use_ast_grep: bool,
-) -> Result<Edit, ApplicationError> {
-    fn align_trailing_newline(current_text: &str, replacement: &str) -> String {
-        // ast-grep spans typically exclude the following newline. Many patch definitions
-        // use triple-quoted strings that include a trailing '\n'. Align to the matched
-        // span so replace patches are idempotent.
-        match (current_text.ends_with('\n'), replacement.ends_with('\n')) {
-            (true, false) => {
-                let mut s = replacement.to_string();
-                s.push('\n');
-                s
-            }
-            (false, true) => replacement
-                .strip_suffix('\n')
-                .unwrap_or(replacement)
-                .to_string(),
-            _ => replacement.to_string(),
+) -> Result<PlannedEdit, ApplicationError> {
+    // ast-grep replacements may reference the match's captures.
+    let template = match &patch.operation {
+        Operation::Replace { text }
+            if use_ast_grep && !crate::sg::template_metavars(text).is_empty() =>
+        {
+            Some(text.as_str())
         }
-    }
+        _ => None,
+    };
+    let to_error = |reason| ApplicationError::TomlOperation {
+        file: file_path.to_path_buf(),
+        reason,
+    };
+    // With `capture`, each match is narrowed to that capture's span.
+    let capture = patch.query.capture();
 
-    // Find matches
-    let matches = if use_ast_grep {
-        find_ast_grep_matches(content, pattern)
+    // Find matches, expanding the template for each one
+    let mut expansions: HashMap<(usize, usize), String> = HashMap::new();
+    // Matches whose expansion the pattern would match again
+    let mut rematching: Vec<(usize, usize)> = Vec::new();
+    let mut matches = if use_ast_grep {
+        let found = find_ast_grep_matches(content, pattern).map_err(to_error)?;
+        found
+            .into_iter()
+            .filter_map(|m| {
+                let span = match capture {
+                    Some(name) => m.find_capture_span(name)?,
+                    None => (m.byte_start, m.byte_end),
+                };
+                if let Some(template) = template {
+                    let expanded = crate::sg::expand_template(template, &m.captures);
+                    if matches_whole(&expanded, pattern) {
+                        rematching.push(span);
+                    }
+                    expansions.insert(span, expanded);
+                }
+                Some(span)
+            })
+            .collect()
     } else {
-        find_tree_sitter_matches(content, pattern)
+        find_tree_sitter_matches(content, pattern, capture).map_err(to_error)?
+    };
+    let scope = function_context_ranges(patch, file_path, content)?;
+    if let Some(ranges) = &scope {
+        retain_within(&mut matches, ranges);
+    }
+
+    // An expanded template is recognised by reading the template itself as
+    // a pattern. A bare `$X` matches any node, so it recognises nothing.
+    let unwraps = template.is_some_and(is_bare_metavar);
+    let mut template_applied: Vec<(usize, usize)> = template
+        .filter(|_| !unwraps)
+        .and_then(|template| find_ast_grep_matches(content, template.trim_end_matches('\n')).ok())
+        .map(|found| found.iter().map(|m| (m.byte_start, m.byte_end)).collect())
+        .unwrap_or_default();
+    if let Some(ranges) = &scope {
+        retain_within(&mut template_applied, ranges);
+    }
+    // A match the replacement would rewrite into something the pattern no
+    // longer matches is unpatched, whatever template hits overlap it. Only a
+    // match whose expansion still matches the pattern can be a patched site,
+    // and then only a template hit tells the two apart.
+    let overlaps = |a: (usize, usize), b: (usize, usize)| a.0 < b.1 && b.0 < a.1;
+    template_applied.retain(|&hit| {
+        !matches
+            .iter()
+            .any(|&m| !rematching.contains(&m) && overlaps(m, hit))
+    });
+    matches.retain(|&m| {
+        !rematching.contains(&m) || !template_applied.iter().any(|&hit| overlaps(m, hit))
+    });
+
+    if let Some(occurrence) = selection.occurrence {
+        // Deletions without a comment, and templates that unwrap a capture,
+        // leave nothing to count; once no match is left they are done, like
+        // the single-match case below.
+        if matches.is_empty() && (unwraps || matches!(patch.operation, Operation::Delete { .. })) {
+            return Ok(Edit::new(file_path, 0, 0, String::new(), "").into());
+        }
+        let applied_text = match &patch.operation {
+            Operation::Replace { text } => Some(text.as_str()),
+            Operation::Delete { insert_comment } => insert_comment.as_deref(),
+            _ => None,
+        }
+        .map(|text| text.trim_end_matches('\n'))
+        .filter(|text| !text.is_empty());
+        let applied = match template {
+            Some(_) => template_applied,
+            None => applied_text
+                .map(|text| scoped_matches(content, scope.as_deref(), text))
+                .unwrap_or_default(),
+        };
+        let sites = select_sites(file_path, selection, occurrence, matches, applied)?;
+        let mut edits = Vec::new();
+        for site in sites.into_iter().filter(|site| !site.applied) {
+            let span = (site.start, site.end);
+            edits.extend(structural_edit_at(
+                patch,
+                file_path,
+                content,
+                span,
+                expansions.get(&span).map(String::as_str),
+            )?);
+        }
+        return Ok(PlannedEdit::from_sites(file_path, edits));
     }
-    .map_err(|e| ApplicationError::TomlOperation {
-        file: file_path.to_path_buf(),
-        reason: e,
-    })?;
 
     // Special handling for Delete operations
     if matches.is_empty() {
+        if unwraps || !template_applied.is_empty() {
+            return Ok(Edit::new(file_path, 0, 0, String::new(), "").into());
+        }
+
         // Structural replace patches can still be already applied if the target
         // shape changed but the replacement text is present in the file.
         if let Operation::Replace { text } = &patch.operation {
             let replacement = text.as_str();
             let replacement_without_trailing_newline = replacement.trim_end_matches('\n');
-            if content.contains(replacement)
-                || content.contains(replacement_without_trailing_newline)
+            if scoped_contains(content, scope.as_deref(), replacement)
+                || scoped_contains(
+                    content,
+                    scope.as_deref(),
+                    replacement_without_trailing_newline,
+                )
             {
-                return Ok(Edit::new(file_path, 0, 0, String::new(), ""));
+                return Ok(Edit::new(file_path, 0, 0, String::new(), "").into());
             }
         }
 
@@ -834,15 +2311,16 @@ fn compute_structural_edit(
                 // Check if the comment exists in the file
                 if content.contains(comment) {
                     // Return a no-op edit for idempotency
-                    return Ok(Edit::new(file_path, 0, 0, String::new(), ""));
+                    return Ok(Edit::new(file_path, 0, 0, String::new(), "").into());
                 }
             }
             // If no comment or comment not found, return no-op edit
-            return Ok(Edit::new(file_path, 0, 0, String::new(), ""));
+            return Ok(Edit::new(file_path, 0, 0, String::new(), "").into());
         }
 
         return Err(ApplicationError::NoMatch {
             file: file_path.to_path_buf(),
+            candidates: Vec::new(),
         });
     }
     if matches.len() > 1 {
@@ -852,7 +2330,40 @@ fn compute_structural_edit(
         });
     }
 
-    let (byte_start, byte_end) = matches[0];
+    let expansion = expansions.get(&matches[0]).map(String::as_str);
+    let edit = structural_edit_at(patch, file_path, content, matches[0], expansion)?
+        .unwrap_or_else(|| Edit::new(file_path, 0, 0, String::new(), ""));
+    Ok(edit.into())
+}
+
+/// The edit a structural patch makes to the match at `span`; `None` when the
+/// match already holds the replacement. `expansion` is the replacement
+/// template expanded for this match, used instead of the literal text.
+fn structural_edit_at(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    content: &str,
+    (byte_start, byte_end): (usize, usize),
+    expansion: Option<&str>,
+) -> Result<Option<Edit>, ApplicationError> {
+    fn align_trailing_newline(current_text: &str, replacement: &str) -> String {
+        // ast-grep spans typically exclude the following newline. Many patch definitions
+        // use triple-quoted strings that include a trailing '\n'. Align to the matched
+        // span so replace patches are idempotent.
+        match (current_text.ends_with('\n'), replacement.ends_with('\n')) {
+            (true, false) => {
+                let mut s = replacement.to_string();
+                s.push('\n');
+                s
+            }
+            (false, true) => replacement
+                .strip_suffix('\n')
+                .unwrap_or(replacement)
+                .to_string(),
+            _ => replacement.to_string(),
+        }
+    }
+
     let current_text = &content[byte_start..byte_end];
 
     // Build verification
@@ -879,7 +2390,9 @@ fn compute_structural_edit(
 
     // Get new text based on operation
     let new_text = match &patch.operation {
-        Operation::Replace { text } => align_trailing_newline(current_text, text.as_str()),
+        Operation::Replace { text } => {
+            align_trailing_newline(current_text, expansion.unwrap_or(text.as_str()))
+        }
         Operation::Delete { insert_comment } => {
             if let Some(comment) = insert_comment {
                 comment.clone()
@@ -897,19 +2410,414 @@ fn compute_structural_edit(
 
     // Check idempotency for Replace operation (after normalizing trailing newline).
     if matches!(patch.operation, Operation::Replace { .. }) && current_text == new_text {
-        return Ok(Edit::new(file_path, 0, 0, String::new(), ""));
+        return Ok(None);
     }
 
     // Create edit without applying
-    Ok(Edit {
+    Ok(Some(Edit {
         file: file_path.to_path_buf(),
         byte_start,
         byte_end,
         new_text,
         expected_before: verification,
+    }))
+}
+
+/// Where an insert operation places its text relative to the located span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placement {
+    Before,
+    After,
+    BodyStart,
+    BodyEnd,
+}
+
+/// Text an insert operation adds at `pos`, computed for one anchor.
+struct Insertion {
+    pos: usize,
+    text: String,
+    /// The inserted text, whitespace aside, already sits at `pos`.
+    applied: bool,
+    /// Inside of a body holding nothing but what inserting the text into an
+    /// empty `{}` writes; reverting clears all of it.
+    filled_empty_body: Option<(usize, usize)>,
+}
+
+/// Spans an insert operation anchors on: the query's matches (narrowed to
+/// `capture` for structural queries), scoped by `function_context`.
+fn locate_anchors(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    content: &str,
+) -> Result<Vec<(usize, usize)>, ApplicationError> {
+    let to_error = |reason| ApplicationError::TomlOperation {
+        file: file_path.to_path_buf(),
+        reason,
+    };
+    let mut anchors = match &patch.query {
+        Query::Text { search, .. } => scoped_matches(content, None, search),
+        Query::AstGrep {
+            pattern, capture, ..
+        } => find_ast_grep_matches(content, pattern)
+            .map_err(to_error)?
+            .iter()
+            .filter_map(|m| match capture {
+                Some(name) => m.find_capture_span(name),
+                None => Some((m.byte_start, m.byte_end)),
+            })
+            .collect(),
+        Query::TreeSitter {
+            pattern, capture, ..
+        } => find_tree_sitter_matches(content, pattern, capture.as_deref()).map_err(to_error)?,
+        Query::Toml { .. } => {
+            return Err(to_error(
+                "toml queries do not support insert operations".to_string(),
+            ))
+        }
+    };
+    if let Some(ranges) = function_context_ranges(patch, file_path, content)? {
+        retain_within(&mut anchors, &ranges);
+    }
+    Ok(anchors)
+}
+
+/// Plan an insert operation at every anchor, then select sites like a
+/// replacement: anchors that already have the text next to them are applied.
+fn compute_insert_edit(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    content: &str,
+) -> Result<PlannedEdit, ApplicationError> {
+    let anchors = locate_anchors(patch, file_path, content)?;
+    let mut insertions = HashMap::new();
+    let (mut pending, mut applied) = (Vec::new(), Vec::new());
+    for span in anchors {
+        let insertion = plan_insertion(patch, file_path, content, span)?;
+        if insertion.applied {
+            applied.push(span);
+        } else {
+            pending.push(span);
+        }
+        insertions.insert(span, insertion);
+    }
+    let edit_at = |start: usize, end: usize| {
+        let insertion = &insertions[&(start, end)];
+        Edit::new(
+            file_path,
+            insertion.pos,
+            insertion.pos,
+            insertion.text.clone(),
+            "",
+        )
+    };
+
+    let selection = patch.query.selection().copied().unwrap_or_default();
+    if let Some(occurrence) = selection.occurrence {
+        let sites = select_sites(file_path, &selection, occurrence, pending, applied)?;
+        let edits = sites
+            .into_iter()
+            .filter(|site| !site.applied)
+            .map(|site| edit_at(site.start, site.end))
+            .collect();
+        return Ok(PlannedEdit::from_sites(file_path, edits));
+    }
+
+    match pending.len() + applied.len() {
+        0 => Err(ApplicationError::NoMatch {
+            file: file_path.to_path_buf(),
+            candidates: Vec::new(),
+        }),
+        1 => Ok(match pending.first() {
+            Some(&(start, end)) => edit_at(start, end),
+            None => Edit::new(file_path, 0, 0, String::new(), ""),
+        }
+        .into()),
+        count => Err(ApplicationError::AmbiguousMatch {
+            file: file_path.to_path_buf(),
+            count,
+        }),
+    }
+}
+
+/// Remove the text an insert operation added next to its anchors.
+///
+/// Only text exactly as the patch would insert it is removed; if it was
+/// edited since, the patch is [`ApplicationError::Irreversible`].
+fn compute_insert_revert(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    content: &str,
+) -> Result<Vec<Edit>, ApplicationError> {
+    let anchors = locate_anchors(patch, file_path, content)?;
+    let occurrence = patch.query.selection().and_then(|s| s.occurrence);
+    let anchors = match occurrence {
+        None if anchors.len() > 1 => {
+            return Err(ApplicationError::AmbiguousMatch {
+                file: file_path.to_path_buf(),
+                count: anchors.len(),
+            })
+        }
+        None | Some(Occurrence::All) => anchors,
+        Some(Occurrence::First) => anchors.into_iter().take(1).collect(),
+        Some(Occurrence::Last) => anchors.into_iter().last().into_iter().collect(),
+        Some(Occurrence::Nth(n)) => anchors.into_iter().skip(n - 1).take(1).collect(),
+    };
+    if anchors.is_empty() {
+        return Err(ApplicationError::NoMatch {
+            file: file_path.to_path_buf(),
+            candidates: Vec::new(),
+        });
+    }
+
+    let mut edits = Vec::new();
+    for span in anchors {
+        let insertion = plan_insertion(patch, file_path, content, span)?;
+        if !insertion.applied {
+            continue;
+        }
+        let len = insertion.text.len();
+        let range = if inserts_before(patch) {
+            (insertion.pos >= len && content[..insertion.pos].ends_with(&insertion.text))
+                .then(|| (insertion.pos - len, insertion.pos))
+        } else {
+            content[insertion.pos..]
+                .starts_with(&insertion.text)
+                .then(|| (insertion.pos, insertion.pos + len))
+        };
+        let Some((start, end)) = range else {
+            return Err(ApplicationError::Irreversible {
+                file: file_path.to_path_buf(),
+                reason: "inserted text was edited after it was applied".to_string(),
+            });
+        };
+        // The line breaks around text inserted into an empty body go too.
+        let (start, end) = insertion.filled_empty_body.unwrap_or((start, end));
+        edits.push(Edit::new(
+            file_path,
+            start,
+            end,
+            String::new(),
+            &content[start..end],
+        ));
+    }
+    if edits.is_empty() {
+        // Not applied (or already reverted): no-op for idempotency.
+        edits.push(Edit::new(file_path, 0, 0, String::new(), ""));
+    }
+    Ok(edits)
+}
+
+fn placement(operation: &Operation) -> Option<Placement> {
+    match operation {
+        Operation::InsertBefore { .. } => Some(Placement::Before),
+        Operation::InsertAfter { .. } => Some(Placement::After),
+        Operation::PrependToBody { .. } => Some(Placement::BodyStart),
+        Operation::AppendToBody { .. } => Some(Placement::BodyEnd),
+        _ => None,
+    }
+}
+
+/// Whether the patch's inserted text ends at its insertion point (rather
+/// than starting there).
+fn inserts_before(patch: &PatchDefinition) -> bool {
+    matches!(
+        placement(&patch.operation),
+        Some(Placement::Before | Placement::BodyEnd)
+    )
+}
+
+/// Where and what an insert operation adds for the anchor at `span`.
+///
+/// An anchor that starts (or ends) its line gets the text on lines of its
+/// own, indented like the anchor; otherwise the text is inserted inline.
+/// Body placements indent like the body's existing lines, or one level
+/// deeper than the item when the body is empty.
+fn plan_insertion(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    content: &str,
+    (start, end): (usize, usize),
+) -> Result<Insertion, ApplicationError> {
+    let (Some(placement), Some(text)) = (
+        placement(&patch.operation),
+        patch.operation.insertion_text(),
+    ) else {
+        return Err(ApplicationError::TomlOperation {
+            file: file_path.to_path_buf(),
+            reason: "not an insert operation".to_string(),
+        });
+    };
+    let text = text.trim_end_matches('\n');
+
+    let mut filled_empty_body = None;
+    let (pos, text) = match placement {
+        Placement::Before => {
+            let line = line_start(content, start);
+            if content[line..start].trim().is_empty() {
+                let indent = &content[line..start];
+                (line, format!("{}\n", indent_lines(text, indent)))
+            } else {
+                (start, text.to_string())
+            }
+        }
+        Placement::After => {
+            let indent = line_indent(content, start);
+            let rest = &content[end..];
+            let line_end = rest.find('\n');
+            if end > 0 && content.as_bytes()[end - 1] == b'\n' {
+                (end, format!("{}\n", indent_lines(text, indent)))
+            } else if rest[..line_end.unwrap_or(rest.len())].trim().is_empty() {
+                match line_end {
+                    Some(offset) => (
+                        end + offset + 1,
+                        format!("{}\n", indent_lines(text, indent)),
+                    ),
+                    None => (content.len(), format!("\n{}", indent_lines(text, indent))),
+                }
+            } else {
+                (end, text.to_string())
+            }
+        }
+        Placement::BodyStart | Placement::BodyEnd => {
+            let (body_start, body_end, item_start) =
+                enclosing_body(file_path, content, (start, end))?;
+            let item_indent = line_indent(content, item_start);
+            let open = body_start + 1;
+            let close = body_end - 1;
+            let inner = &content[open..close];
+            let indent = inner
+                .lines()
+                .skip(1)
+                .find(|line| !line.trim().is_empty())
+                .map(|line| &line[..line.len() - line.trim_start().len()])
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{item_indent}    "));
+            let lines = indent_lines(text, &indent);
+            if inner == format!("\n{lines}\n{item_indent}") {
+                filled_empty_body = Some((open, close));
+            }
+
+            if placement == Placement::BodyStart {
+                match inner.find('\n') {
+                    Some(offset) if inner[..offset].trim().is_empty() => {
+                        (open + offset + 1, format!("{lines}\n"))
+                    }
+                    _ => (open, format!("\n{lines}\n{item_indent}")),
+                }
+            } else {
+                let line = line_start(content, close);
+                if line > open && content[line..close].trim().is_empty() {
+                    (line, format!("{lines}\n"))
+                } else {
+                    (close, format!("\n{lines}\n{item_indent}"))
+                }
+            }
+        }
+    };
+
+    let applied = match placement {
+        Placement::Before | Placement::BodyEnd => {
+            ends_with_ignoring_whitespace(&content[..pos], &text)
+        }
+        Placement::After | Placement::BodyStart => {
+            starts_with_ignoring_whitespace(&content[pos..], &text)
+        }
+    };
+    Ok(Insertion {
+        pos,
+        text,
+        applied,
+        filled_empty_body,
     })
 }
 
+/// The body (`{ ... }`) of the innermost function, impl, trait or module
+/// whose header contains `span`, with the item's start offset.
+fn enclosing_body(
+    file_path: &Path,
+    content: &str,
+    (start, end): (usize, usize),
+) -> Result<(usize, usize, usize), ApplicationError> {
+    use crate::ts::locator::pooled;
+
+    let query = r#"[
+        (function_item body: (block) @body)
+        (impl_item body: (declaration_list) @body)
+        (trait_item body: (declaration_list) @body)
+        (mod_item body: (declaration_list) @body)
+    ] @item"#
+        .to_string();
+    let items = pooled::locate_all(content, &StructuralTarget::Custom { query }).map_err(|e| {
+        ApplicationError::TomlOperation {
+            file: file_path.to_path_buf(),
+            reason: format!("tree-sitter error: {}", e),
+        }
+    })?;
+
+    items
+        .iter()
+        .filter_map(|m| {
+            let item = m.captures.get("item")?;
+            let body = m.captures.get("body")?;
+            (item.byte_start <= start && start < body.byte_start && end <= item.byte_end)
+                .then_some((body.byte_start, body.byte_end, item.byte_start))
+        })
+        .max_by_key(|&(_, _, item_start)| item_start)
+        .ok_or_else(|| ApplicationError::TomlOperation {
+            file: file_path.to_path_buf(),
+            reason: "body insertion needs a function, impl, trait or module match".to_string(),
+        })
+}
+
+/// Byte offset of the start of the line containing `pos`.
+fn line_start(content: &str, pos: usize) -> usize {
+    content[..pos].rfind('\n').map_or(0, |i| i + 1)
+}
+
+/// Leading whitespace of the line containing `pos`.
+fn line_indent(content: &str, pos: usize) -> &str {
+    let line = &content[line_start(content, pos)..];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Re-indent `text` to `indent`: its common leading indentation is removed
+/// and `indent` prefixed to every non-blank line.
+fn indent_lines(text: &str, indent: &str) -> String {
+    let common = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    text.lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                String::new()
+            } else {
+                format!("{indent}{}", &line[common..])
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn starts_with_ignoring_whitespace(haystack: &str, needle: &str) -> bool {
+    let mut rest = haystack.chars().filter(|c| !c.is_whitespace());
+    needle
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .all(|c| rest.next() == Some(c))
+}
+
+fn ends_with_ignoring_whitespace(haystack: &str, needle: &str) -> bool {
+    let mut rest = haystack.chars().rev().filter(|c| !c.is_whitespace());
+    needle
+        .chars()
+        .rev()
+        .filter(|c| !c.is_whitespace())
+        .all(|c| rest.next() == Some(c))
+}
+
 /// Convert config::Positioning to toml::Positioning.
 ///
 /// Positioning validation (at-most-one directive) is enforced at load time via
@@ -935,18 +2843,33 @@ fn convert_positioning(pos: &Positioning) -> Result<crate::toml::Positioning, St
     }
 }
 
+/// Whether `pattern` matches all of `source` (surrounding whitespace aside).
+fn matches_whole(source: &str, pattern: &str) -> bool {
+    let source = source.trim();
+    find_ast_grep_matches(source, pattern).is_ok_and(|found| {
+        found
+            .iter()
+            .any(|m| m.byte_start == 0 && m.byte_end == source.len())
+    })
+}
+
+/// Whether an ast-grep replacement is a single metavariable such as `$X`,
+/// which unwraps a capture out of its match.
+fn is_bare_metavar(template: &str) -> bool {
+    let template = template.trim();
+    match crate::sg::template_metavars(template).as_slice() {
+        [var] => var.start == 0 && var.end == template.len(),
+        _ => false,
+    }
+}
+
 /// Find matches using ast-grep
-fn find_ast_grep_matches(content: &str, pattern: &str) -> Result<Vec<(usize, usize)>, String> {
+fn find_ast_grep_matches(content: &str, pattern: &str) -> Result<Vec<PatternMatch>, String> {
     let matcher = PatternMatcher::new(content);
-    let matches = matcher
+    matcher
         .find_all(pattern)
-        .map_err(|e| format!("ast-grep pattern error: {}", e))?;
-
-    Ok(matches
-        .into_iter()
-        .map(|m| (m.byte_start, m.byte_end))
-        .collect())
-}
+        .map_err(|e| format!("ast-grep pattern error: {}", e))
+}
 
 /// Parse a tree-sitter pattern string into a `StructuralTarget`.
 ///
@@ -971,7 +2894,7 @@ fn find_ast_grep_matches(content: &str, pattern: &str) -> Result<Vec<(usize, usi
 /// | `impl Type` | `Impl { type_name }` |
 /// | `impl Trait for Type` | `ImplTrait { trait_name, type_name }` |
 /// | `use path_pattern` | `Use { path_pattern }` |
-fn parse_tree_sitter_pattern(pattern: &str) -> Result<StructuralTarget, String> {
+pub(crate) fn parse_tree_sitter_pattern(pattern: &str) -> Result<StructuralTarget, String> {
     let pattern = pattern.trim();
 
     // Raw S-expression tree-sitter query
@@ -1062,11 +2985,121 @@ fn parse_tree_sitter_pattern(pattern: &str) -> Result<StructuralTarget, String>
     ))
 }
 
+/// Resolve a patch's `constraint.function_context` to the byte ranges of the
+/// matching function bodies.
+///
+/// Returns `Ok(None)` when the patch is not scoped. A context that names no
+/// function in the file is a [`ApplicationError::NoMatch`].
+fn function_context_ranges(
+    patch: &PatchDefinition,
+    file_path: &Path,
+    content: &str,
+) -> Result<Option<Vec<(usize, usize)>>, ApplicationError> {
+    use crate::ts::locator::pooled;
+
+    let Some(context) = patch
+        .constraint
+        .as_ref()
+        .and_then(|c| c.function_context.as_deref())
+    else {
+        return Ok(None);
+    };
+    let (type_name, name) =
+        parse_function_context(context).ok_or_else(|| ApplicationError::TomlOperation {
+            file: file_path.to_path_buf(),
+            reason: format!("invalid function_context '{}'", context),
+        })?;
+
+    let function = format!(
+        r#"(function_item
+            name: (identifier) @name
+            (#eq? @name "{name}")
+            body: (block) @body
+        ) @function"#
+    );
+    let query = match type_name {
+        Some(_) => format!(r#"(impl_item type: (_) @type body: (declaration_list {function}))"#),
+        None => function,
+    };
+
+    let matches =
+        pooled::locate_all(content, &StructuralTarget::Custom { query }).map_err(|e| {
+            ApplicationError::TomlOperation {
+                file: file_path.to_path_buf(),
+                reason: format!("tree-sitter error: {}", e),
+            }
+        })?;
+
+    let ranges: Vec<(usize, usize)> = matches
+        .iter()
+        .filter(|m| match (type_name, m.captures.get("type")) {
+            // `impl Foo<T>` should still match `Foo::method`.
+            (Some(type_name), Some(ty)) => {
+                ty.text == type_name || ty.text.starts_with(&format!("{type_name}<"))
+            }
+            (Some(_), None) => false,
+            (None, _) => true,
+        })
+        .filter_map(|m| m.captures.get("body"))
+        .map(|body| (body.byte_start, body.byte_end))
+        .collect();
+
+    if ranges.is_empty() {
+        return Err(ApplicationError::NoMatch {
+            file: file_path.to_path_buf(),
+            candidates: Vec::new(),
+        });
+    }
+    Ok(Some(ranges))
+}
+
+/// `content.contains(needle)`, restricted to `scope` when one is given.
+fn scoped_contains(content: &str, scope: Option<&[(usize, usize)]>, needle: &str) -> bool {
+    match scope {
+        Some(ranges) => ranges
+            .iter()
+            .any(|&(start, end)| content[start..end].contains(needle)),
+        None => content.contains(needle),
+    }
+}
+
+/// Spans of every occurrence of `needle` within `scope` (or the whole file).
+fn scoped_matches(
+    content: &str,
+    scope: Option<&[(usize, usize)]>,
+    needle: &str,
+) -> Vec<(usize, usize)> {
+    let whole = [(0, content.len())];
+    scope
+        .unwrap_or(&whole)
+        .iter()
+        .flat_map(|&(start, end)| {
+            content[start..end]
+                .match_indices(needle)
+                .map(move |(offset, _)| (start + offset, start + offset + needle.len()))
+        })
+        .collect()
+}
+
+/// Keep only the matches that lie entirely inside one of `ranges`.
+fn retain_within(matches: &mut Vec<(usize, usize)>, ranges: &[(usize, usize)]) {
+    matches.retain(|&(start, end)| {
+        ranges
+            .iter()
+            .any(|&(range_start, range_end)| start >= range_start && end <= range_end)
+    });
+}
+
 /// Find matches using tree-sitter (pooled parser for performance).
 ///
 /// Accepts the DSL shorthand or raw S-expression syntax described in
-/// [`parse_tree_sitter_pattern`].
-fn find_tree_sitter_matches(content: &str, pattern: &str) -> Result<Vec<(usize, usize)>, String> {
+/// [`parse_tree_sitter_pattern`]. With `capture`, each match is narrowed to
+/// that capture's node; matches without it are dropped.
+fn find_tree_sitter_matches(
+    content: &str,
+    pattern: &str,
+    capture: Option<&str>,
+) -> Result<Vec<(usize, usize)>, String> {
     use crate::ts::locator::pooled;
 
     let target = parse_tree_sitter_pattern(pattern)?;
@@ -1079,6 +3112,14 @@ fn find_tree_sitter_matches(content: &str, pattern: &str) -> Result<Vec<(usize,
     let results =
         pooled::locate_all(content, &target).map_err(|e| format!("tree-sitter error: {}", e))?;
 
+    if let Some(name) = capture {
+        return Ok(results
+            .iter()
+            .filter_map(|r| r.captures.get(name))
+            .map(|c| (c.byte_start, c.byte_end))
+            .collect());
+    }
+
     Ok(results
         .into_iter()
         .map(|r| {
@@ -1216,6 +3257,7 @@ mod tests {
                 description: None,
                 version_range: Some(">=0.88.0".to_string()),
                 workspace_relative: true,
+                ..Default::default()
             },
             patches: vec![],
         };
@@ -1225,26 +3267,1567 @@ mod tests {
     }
 
     #[test]
-    fn test_patch_result_display() {
-        let applied = PatchResult::Applied {
-            file: PathBuf::from("/tmp/test.rs"),
+    fn test_simulate_patches_leaves_workspace_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn hello() {}\n").unwrap();
+
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "test".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![PatchDefinition {
+                id: "greet".to_string(),
+                file: "lib.rs".to_string(),
+                query: Query::Text {
+                    search: "fn hello() {}".to_string(),
+                    fuzzy_threshold: None,
+                    fuzzy_expansion: None,
+                    selection: Selection::default(),
+                },
+                operation: Operation::Replace {
+                    text: "fn hello() { greet(); }".to_string(),
+                },
+                verify: None,
+                constraint: None,
+                version: None,
+                min_matches: None,
+                max_matches: None,
+                requires: Vec::new(),
+                conflicts: Vec::new(),
+                mode: None,
+                tags: Vec::new(),
+                variants: Vec::new(),
+                search_paths: Vec::new(),
+                relocate: false,
+            }],
         };
-        assert!(applied.to_string().contains("Applied"));
 
-        let already = PatchResult::AlreadyApplied {
-            file: PathBuf::from("/tmp/test.rs"),
+        let simulation = simulate_patches(&config, dir.path(), "0.1.0");
+        assert_eq!(simulation.results.len(), 1);
+        assert!(matches!(
+            simulation.results[0].1,
+            Ok(PatchResult::Applied { .. })
+        ));
+        assert_eq!(simulation.files.len(), 1);
+        assert_eq!(simulation.files[0].original, "fn hello() {}\n");
+        assert_eq!(simulation.files[0].patched, "fn hello() { greet(); }\n");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "fn hello() {}\n");
+
+        let applied = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(applied[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            simulation.files[0].patched
+        );
+    }
+
+    fn escape_config(file: &str, workspace_relative: bool) -> PatchConfig {
+        PatchConfig {
+            meta: Metadata {
+                name: "escape".to_string(),
+                workspace_relative,
+                ..Default::default()
+            },
+            patches: vec![PatchDefinition {
+                id: "escape".to_string(),
+                file: file.to_string(),
+                query: Query::Text {
+                    search: "fn outside() {}".to_string(),
+                    fuzzy_threshold: None,
+                    fuzzy_expansion: None,
+                    selection: Selection::default(),
+                },
+                operation: Operation::Replace {
+                    text: "fn pwned() {}".to_string(),
+                },
+                verify: None,
+                constraint: None,
+                version: None,
+                min_matches: None,
+                max_matches: None,
+                requires: Vec::new(),
+                conflicts: Vec::new(),
+                mode: None,
+                tags: Vec::new(),
+                variants: Vec::new(),
+                search_paths: Vec::new(),
+                relocate: false,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_apply_rejects_parent_dir_escape() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("workspace");
+        fs::create_dir(&workspace).unwrap();
+        let outside = dir.path().join("outside.rs");
+        fs::write(&outside, "fn outside() {}\n").unwrap();
+
+        let config = escape_config("../outside.rs", true);
+        let results = apply_patches(&config, &workspace, "0.1.0");
+
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0].1, Err(ApplicationError::Safety(_))));
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "fn outside() {}\n");
+    }
+
+    #[test]
+    fn test_apply_rejects_absolute_path_outside_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("workspace");
+        fs::create_dir(&workspace).unwrap();
+        let outside = dir.path().join("outside.rs");
+        fs::write(&outside, "fn outside() {}\n").unwrap();
+
+        let config = escape_config(outside.to_str().unwrap(), false);
+        let results = apply_patches(&config, &workspace, "0.1.0");
+
+        assert!(matches!(results[0].1, Err(ApplicationError::Safety(_))));
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "fn outside() {}\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_rejects_symlink_escape() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = dir.path().join("workspace");
+        fs::create_dir(&workspace).unwrap();
+        let outside = dir.path().join("outside.rs");
+        fs::write(&outside, "fn outside() {}\n").unwrap();
+        std::os::unix::fs::symlink(&outside, workspace.join("link.rs")).unwrap();
+
+        let config = escape_config("link.rs", true);
+        let simulation = simulate_patches(&config, &workspace, "0.1.0");
+        assert!(matches!(
+            simulation.results[0].1,
+            Err(ApplicationError::Safety(_))
+        ));
+
+        let results = apply_patches(&config, &workspace, "0.1.0");
+        assert!(matches!(results[0].1, Err(ApplicationError::Safety(_))));
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "fn outside() {}\n");
+    }
+
+    fn text_patch(id: &str, file: &str, search: &str, replace: &str) -> PatchDefinition {
+        PatchDefinition {
+            id: id.to_string(),
+            file: file.to_string(),
+            query: Query::Text {
+                search: search.to_string(),
+                fuzzy_threshold: None,
+                fuzzy_expansion: None,
+                selection: Selection::default(),
+            },
+            operation: Operation::Replace {
+                text: replace.to_string(),
+            },
+            verify: None,
+            constraint: None,
+            version: None,
+            min_matches: None,
+            max_matches: None,
+            requires: Vec::new(),
+            conflicts: Vec::new(),
+            mode: None,
+            tags: Vec::new(),
+            variants: Vec::new(),
+            search_paths: Vec::new(),
+            relocate: false,
+        }
+    }
+
+    #[test]
+    fn test_apply_refuses_batch_that_breaks_rust_syntax() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn a() {}\nfn b() {}\n";
+        fs::write(&file, original).unwrap();
+
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "broken".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![
+                text_patch("good", "lib.rs", "fn a() {}", "fn a() { 1; }"),
+                text_patch("bad", "lib.rs", "fn b() {}", "fn b() {"),
+            ],
         };
-        assert!(already.to_string().contains("Already applied"));
 
-        let skipped = PatchResult::SkippedVersion {
-            reason: "version too old".to_string(),
+        let simulation = simulate_patches(&config, dir.path(), "0.1.0");
+        assert!(simulation.files.is_empty());
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert_eq!(results.len(), 2);
+        for (_, result) in &results {
+            match result {
+                Err(ApplicationError::ParseErrorIntroduced { errors, .. }) => {
+                    assert!(!errors.is_empty());
+                }
+                other => panic!("expected ParseErrorIntroduced, got {:?}", other),
+            }
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), original);
+    }
+
+    #[test]
+    fn test_apply_tolerates_preexisting_rust_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn broken( {}\nfn a() {}\n").unwrap();
+
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "preexisting".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![text_patch("good", "lib.rs", "fn a() {}", "fn a() { 1; }")],
         };
-        assert!(skipped.to_string().contains("Skipped"));
 
-        let failed = PatchResult::Failed {
-            file: PathBuf::from("/tmp/test.rs"),
-            reason: "parse error".to_string(),
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+    }
+
+    #[test]
+    fn test_apply_refuses_batch_that_breaks_toml_syntax() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("Cargo.toml");
+        let original = "[package]\nname = \"demo\"\n";
+        fs::write(&file, original).unwrap();
+
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "broken-toml".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![text_patch(
+                "bad",
+                "Cargo.toml",
+                "name = \"demo\"",
+                "name = \"demo",
+            )],
         };
-        assert!(failed.to_string().contains("Failed"));
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        match &results[0].1 {
+            Err(ApplicationError::ParseErrorIntroduced { errors, .. }) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].line, 2);
+            }
+            other => panic!("expected ParseErrorIntroduced, got {:?}", other),
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), original);
+    }
+
+    fn scoped_config(patch: PatchDefinition, function_context: &str) -> PatchConfig {
+        PatchConfig {
+            meta: Metadata {
+                name: "scoped".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![PatchDefinition {
+                constraint: Some(crate::config::schema::Constraints {
+                    function_context: Some(function_context.to_string()),
+                    ..Default::default()
+                }),
+                ..patch
+            }],
+        }
+    }
+
+    const SCOPED_SOURCE: &str = r#"fn first() {
+    let timeout = 30;
+}
+
+struct Client;
+
+impl Client {
+    pub fn connect(&self) -> u32 {
+        let timeout = 30;
+        timeout
+    }
+}
+
+impl Server {
+    fn connect(&self) {
+        let timeout = 30;
+    }
+}
+"#;
+
+    #[test]
+    fn test_function_context_scopes_text_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, SCOPED_SOURCE).unwrap();
+
+        let patch = text_patch("t", "lib.rs", "let timeout = 30;", "let timeout = 60;");
+
+        // Unscoped, the search text is ambiguous.
+        let unscoped = PatchConfig {
+            patches: vec![patch.clone()],
+            ..scoped_config(patch.clone(), "first")
+        };
+        let results = check_patches(&unscoped, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::AmbiguousMatch { count: 3, .. })
+        ));
+
+        let results = apply_patches(&scoped_config(patch.clone(), "first"), dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        let patched = fs::read_to_string(&file).unwrap();
+        assert!(patched.starts_with("fn first() {\n    let timeout = 60;"));
+        assert_eq!(patched.matches("let timeout = 30;").count(), 2);
+
+        // Re-running is idempotent within the scope.
+        let results = apply_patches(&scoped_config(patch, "first"), dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+    }
+
+    #[test]
+    fn test_function_context_type_method_scopes_ast_grep_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, SCOPED_SOURCE).unwrap();
+
+        let patch = PatchDefinition {
+            query: Query::AstGrep {
+                pattern: "let timeout = 30;".to_string(),
+                capture: None,
+                selection: Selection::default(),
+            },
+            operation: Operation::Replace {
+                text: "let timeout = 90;".to_string(),
+            },
+            ..text_patch("a", "lib.rs", "", "")
+        };
+
+        let results = apply_patches(
+            &scoped_config(patch.clone(), "Client::connect"),
+            dir.path(),
+            "0.1.0",
+        );
+        assert!(
+            matches!(results[0].1, Ok(PatchResult::Applied { .. })),
+            "{:?}",
+            results[0].1
+        );
+        let patched = fs::read_to_string(&file).unwrap();
+        let client = patched.find("impl Client").unwrap();
+        let server = patched.find("impl Server").unwrap();
+        let changed = patched.find("let timeout = 90;").unwrap();
+        assert!(changed > client && changed < server);
+
+        // `connect` alone names both methods.
+        fs::write(&file, SCOPED_SOURCE).unwrap();
+        let results = check_patches(&scoped_config(patch, "connect"), dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::AmbiguousMatch { count: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_function_context_missing_function_is_no_match() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), SCOPED_SOURCE).unwrap();
+
+        let patch = text_patch("t", "lib.rs", "let timeout = 30;", "let timeout = 60;");
+        let results = check_patches(
+            &scoped_config(patch, "Missing::connect"),
+            dir.path(),
+            "0.1.0",
+        );
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::NoMatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_apply_patches_atomic_chains_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.path().join("b.rs"), "fn b() {}\n").unwrap();
+
+        let config = |name: &str, patches| PatchConfig {
+            meta: Metadata {
+                name: name.to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches,
+        };
+        // The second config depends on the first one's output.
+        let configs = vec![
+            config(
+                "one",
+                vec![text_patch("a1", "a.rs", "fn a() {}", "fn a() { 1; }")],
+            ),
+            config(
+                "two",
+                vec![
+                    text_patch("a2", "a.rs", "fn a() { 1; }", "fn a() { 2; }"),
+                    text_patch("b1", "b.rs", "fn b() {}", "fn b() { 1; }"),
+                ],
+            ),
+        ];
+
+        let outcome = apply_patches_atomic(&configs, dir.path(), "0.1.0");
+        assert!(outcome.outcome.is_ok(), "{:?}", outcome.outcome);
+        assert_eq!(outcome.results.len(), 2);
+        assert_eq!(outcome.files.len(), 2);
+        assert_eq!(outcome.files[0].original, "fn a() {}\n");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a.rs")).unwrap(),
+            "fn a() { 2; }\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("b.rs")).unwrap(),
+            "fn b() { 1; }\n"
+        );
+        assert!(crate::journal::Journal::open(dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_apply_patches_atomic_writes_nothing_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "fn a() {}\n").unwrap();
+
+        let configs = vec![PatchConfig {
+            meta: Metadata {
+                name: "partial".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![
+                text_patch("ok", "a.rs", "fn a() {}", "fn a() { 1; }"),
+                text_patch("missing", "a.rs", "fn gone() {}", "fn back() {}"),
+            ],
+        }];
+
+        let outcome = apply_patches_atomic(&configs, dir.path(), "0.1.0");
+        assert!(matches!(
+            outcome.outcome,
+            Err(TransactionError::PatchesFailed { count: 1 })
+        ));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a.rs")).unwrap(),
+            "fn a() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_revert_text_patch_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn hello() {}\nfn other() {}\n";
+        fs::write(&file, original).unwrap();
+
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "revertible".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![text_patch(
+                "greet",
+                "lib.rs",
+                "fn hello() {}",
+                "fn hello() { greet(); }",
+            )],
+        };
+
+        apply_patches(&config, dir.path(), "0.1.0");
+        assert_ne!(fs::read_to_string(&file).unwrap(), original);
+
+        let simulation = simulate_revert(&config, dir.path(), "0.1.0");
+        assert_eq!(simulation.files[0].patched, original);
+        assert_ne!(fs::read_to_string(&file).unwrap(), original);
+
+        let results = revert_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(fs::read_to_string(&file).unwrap(), original);
+
+        // Reverting again is a no-op.
+        let results = revert_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+    }
+
+    #[test]
+    fn test_revert_ignores_replacement_inside_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn c() {\n    builder().timeout(30).build();\n}\n";
+        fs::write(&file, original).unwrap();
+
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "revertible".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![text_patch(
+                "no-timeout",
+                "lib.rs",
+                ".timeout(30).build()",
+                ".build()",
+            )],
+        };
+
+        // Never applied: the `.build()` in the original is not the patch's.
+        for _ in 0..2 {
+            let results = revert_patches(&config, dir.path(), "0.1.0");
+            assert!(matches!(
+                results[0].1,
+                Ok(PatchResult::AlreadyApplied { .. })
+            ));
+            assert_eq!(fs::read_to_string(&file).unwrap(), original);
+        }
+
+        apply_patches(&config, dir.path(), "0.1.0");
+        for _ in 0..2 {
+            revert_patches(&config, dir.path(), "0.1.0");
+            assert_eq!(fs::read_to_string(&file).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn test_revert_structural_patch_requires_exact_match_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn answer() -> u32 {\n    41\n}\n";
+        fs::write(&file, original).unwrap();
+
+        let toml = r#"
+[meta]
+name = "structural"
+workspace_relative = true
+
+[[patches]]
+id = "answer"
+file = "lib.rs"
+
+[patches.query]
+type = "ast-grep"
+pattern = "fn answer() -> u32 { $$$BODY }"
+
+[patches.operation]
+type = "replace"
+text = """
+fn answer() -> u32 {
+    42
+}
+"""
+"#;
+        let unverified = crate::config::load_from_str(toml).unwrap();
+        apply_patches(&unverified, dir.path(), "0.1.0");
+        let results = revert_patches(&unverified, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::Irreversible { .. })
+        ));
+
+        let verified = crate::config::load_from_str(&format!(
+            "{toml}\n[patches.verify]\nmethod = \"exact_match\"\nexpected_text = \"\"\"\n{original}\"\"\"\n"
+        ))
+        .unwrap();
+        let results = revert_patches(&verified, dir.path(), "0.1.0");
+        assert!(
+            matches!(results[0].1, Ok(PatchResult::Applied { .. })),
+            "{:?}",
+            results[0].1
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), original);
+    }
+
+    #[test]
+    fn test_revert_toml_insert_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("Cargo.toml");
+        let original = "[package]\nname = \"demo\"\n\n[dependencies]\nserde = \"1\"\n";
+        fs::write(&file, original).unwrap();
+
+        let config = crate::config::load_from_str(
+            r#"
+[meta]
+name = "toml"
+workspace_relative = true
+
+[[patches]]
+id = "add-profile"
+file = "Cargo.toml"
+
+[patches.query]
+type = "toml"
+section = "profile.release"
+
+[patches.operation]
+type = "insert-section"
+text = """
+[profile.release]
+lto = true
+"""
+at_end = true
+"#,
+        )
+        .unwrap();
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert!(fs::read_to_string(&file)
+            .unwrap()
+            .contains("[profile.release]"));
+
+        let results = revert_patches(&config, dir.path(), "0.1.0");
+        assert!(
+            matches!(results[0].1, Ok(PatchResult::Applied { .. })),
+            "{:?}",
+            results[0].1
+        );
+        let reverted = fs::read_to_string(&file).unwrap();
+        assert!(!reverted.contains("profile.release"));
+        assert_eq!(reverted.trim_end(), original.trim_end());
+    }
+
+    #[test]
+    fn test_unwritable_ledger_is_returned_not_printed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn a() {}\n").unwrap();
+        // A file where the state directory belongs.
+        fs::write(dir.path().join(".codex-patcher"), "").unwrap();
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "ledgered".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![text_patch("a", "lib.rs", "fn a() {}", "fn a() { 1; }")],
+        };
+
+        let run = apply_patches_detailed(&config, dir.path(), "0.1.0");
+        assert!(matches!(run.results[0].1, Ok(PatchResult::Applied { .. })));
+        assert!(run.ledger_error.is_some());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "fn a() { 1; }\n");
+
+        let run = revert_patches_detailed(&config, dir.path(), "0.1.0");
+        assert!(matches!(run.results[0].1, Ok(PatchResult::Applied { .. })));
+        assert!(run.ledger_error.is_some());
+        assert!(simulate_patches(&config, dir.path(), "0.1.0")
+            .ledger_error
+            .is_none());
+    }
+
+    #[test]
+    fn test_apply_and_revert_are_recorded_in_ledger() {
+        use crate::ledger::LedgerState;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn a() {}\nfn b() {}\n").unwrap();
+
+        let config = PatchConfig {
+            meta: Metadata {
+                name: "ledgered".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![
+                text_patch("a", "lib.rs", "fn a() {}", "fn a() { 1; }"),
+                text_patch("b", "lib.rs", "fn b() {}", "fn b() { 2; }"),
+            ],
+        };
+
+        // Simulation never touches the ledger.
+        assert_eq!(
+            simulate_patches(&config, dir.path(), "0.1.0").records.len(),
+            2
+        );
+        assert!(Ledger::load(dir.path()).unwrap().records().is_empty());
+
+        apply_patches(&config, dir.path(), "0.1.0");
+        // Re-applying records nothing new.
+        apply_patches(&config, dir.path(), "0.1.0");
+
+        let ledger = Ledger::load(dir.path()).unwrap();
+        assert_eq!(ledger.records().len(), 2);
+        let record = &ledger.records()[0];
+        assert_eq!(record.config, "ledgered");
+        assert_eq!(record.file, PathBuf::from("lib.rs"));
+        assert_eq!(record.workspace_version, "0.1.0");
+        assert!(ledger
+            .entries(dir.path())
+            .iter()
+            .all(|e| e.state == LedgerState::Intact));
+
+        // Hand-edit the region patch "b" wrote.
+        let edited = fs::read_to_string(&file)
+            .unwrap()
+            .replace("{ 2; }", "{ 3; }");
+        fs::write(&file, edited).unwrap();
+        let states: HashMap<String, LedgerState> = ledger
+            .entries(dir.path())
+            .into_iter()
+            .map(|e| (e.record.patch_id, e.state))
+            .collect();
+        assert_eq!(states["a"], LedgerState::Intact);
+        assert_eq!(states["b"], LedgerState::Drifted);
+
+        let revert_only_a = PatchConfig {
+            patches: config.patches[..1].to_vec(),
+            ..config.clone()
+        };
+        revert_patches(&revert_only_a, dir.path(), "0.1.0");
+        let ledger = Ledger::load(dir.path()).unwrap();
+        let states: HashMap<String, LedgerState> = ledger
+            .entries(dir.path())
+            .into_iter()
+            .map(|e| (e.record.patch_id, e.state))
+            .collect();
+        assert_eq!(states["a"], LedgerState::Reverted);
+        assert_eq!(states["b"], LedgerState::Drifted);
+    }
+
+    fn selected(
+        patch: PatchDefinition,
+        occurrence: Occurrence,
+        expected: Option<usize>,
+    ) -> PatchConfig {
+        let selection = Selection {
+            occurrence: Some(occurrence),
+            expected_count: expected,
+        };
+        let query = match patch.query {
+            Query::Text { search, .. } => Query::Text {
+                search,
+                fuzzy_threshold: None,
+                fuzzy_expansion: None,
+                selection,
+            },
+            Query::AstGrep {
+                pattern, capture, ..
+            } => Query::AstGrep {
+                pattern,
+                capture,
+                selection,
+            },
+            other => other,
+        };
+        PatchConfig {
+            meta: Metadata {
+                name: "selected".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![PatchDefinition { query, ..patch }],
+        }
+    }
+
+    const URLS: &str = "const A: &str = \"http://old\";\nconst B: &str = \"http://old\";\nconst C: &str = \"http://old\";\n";
+
+    #[test]
+    fn test_occurrence_all_edits_every_site_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, URLS).unwrap();
+        let config = selected(
+            text_patch("urls", "lib.rs", "http://old", "https://new"),
+            Occurrence::All,
+            Some(3),
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        let patched = fs::read_to_string(&file).unwrap();
+        assert_eq!(patched.matches("https://new").count(), 3);
+        assert_eq!(Ledger::load(dir.path()).unwrap().records().len(), 3);
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+
+        // A site restored by hand is re-applied on its own.
+        fs::write(&file, patched.replacen("https://new", "http://old", 1)).unwrap();
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(fs::read_to_string(&file).unwrap(), patched);
+        assert_eq!(Ledger::load(dir.path()).unwrap().records().len(), 4);
+    }
+
+    #[test]
+    fn test_expected_count_fails_when_sites_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let grown = format!("{URLS}const D: &str = \"http://old\";\n");
+        fs::write(&file, &grown).unwrap();
+        let config = selected(
+            text_patch("urls", "lib.rs", "http://old", "https://new"),
+            Occurrence::All,
+            Some(3),
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::UnexpectedMatchCount {
+                expected: 3,
+                found: 4,
+                ..
+            })
+        ));
+        assert_eq!(fs::read_to_string(&file).unwrap(), grown);
+    }
+
+    #[test]
+    fn test_occurrence_picks_first_last_and_nth_site() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let patch = text_patch("url", "lib.rs", "http://old", "https://new");
+
+        for (occurrence, expected_line) in [
+            (Occurrence::First, 0),
+            (Occurrence::Last, 2),
+            (Occurrence::Nth(2), 1),
+        ] {
+            fs::write(&file, URLS).unwrap();
+            let config = selected(patch.clone(), occurrence, None);
+            let results = apply_patches(&config, dir.path(), "0.1.0");
+            assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+            let patched = fs::read_to_string(&file).unwrap();
+            let changed: Vec<usize> = patched
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| line.contains("https://new"))
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(changed, [expected_line], "{:?}", occurrence);
+        }
+
+        fs::write(&file, URLS).unwrap();
+        let results = check_patches(
+            &selected(patch, Occurrence::Nth(4), None),
+            dir.path(),
+            "0.1.0",
+        );
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::NoMatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_occurrence_all_on_structural_query_and_revert() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn a() {\n    log(1);\n}\n\nfn b() {\n    log(2);\n}\n";
+        fs::write(&file, original).unwrap();
+        let config = selected(
+            PatchDefinition {
+                query: Query::AstGrep {
+                    pattern: "log($X)".to_string(),
+                    capture: None,
+                    selection: Selection::default(),
+                },
+                operation: Operation::Replace {
+                    text: "trace()".to_string(),
+                },
+                verify: Some(crate::config::schema::Verify::ExactMatch {
+                    expected_text: "log(1)".to_string(),
+                }),
+                ..text_patch("logs", "lib.rs", "", "")
+            },
+            Occurrence::All,
+            Some(2),
+        );
+
+        // exact_match pins one original text, so the second site is refused.
+        let results = check_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Err(ApplicationError::Edit(_))));
+
+        let config = PatchConfig {
+            patches: vec![PatchDefinition {
+                verify: None,
+                ..config.patches[0].clone()
+            }],
+            ..config
+        };
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn a() {\n    trace();\n}\n\nfn b() {\n    trace();\n}\n"
+        );
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+
+        let text_config = selected(
+            text_patch("t", "lib.rs", "log(1)", "trace()"),
+            Occurrence::All,
+            None,
+        );
+        fs::write(&file, "fn a() {\n    trace();\n    trace();\n}\n").unwrap();
+        let results = revert_patches(&text_config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn a() {\n    log(1);\n    log(1);\n}\n"
+        );
+    }
+
+    fn query_patch(query: Query, text: &str) -> PatchConfig {
+        PatchConfig {
+            meta: Metadata {
+                name: "structural".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![PatchDefinition {
+                query,
+                ..text_patch("t", "lib.rs", "", text)
+            }],
+        }
+    }
+
+    fn template_patch(pattern: &str, text: &str) -> PatchConfig {
+        query_patch(
+            Query::AstGrep {
+                pattern: pattern.to_string(),
+                capture: None,
+                selection: Selection::default(),
+            },
+            text,
+        )
+    }
+
+    fn insert_patch(query: Query, operation: Operation) -> PatchConfig {
+        let mut config = query_patch(query, "");
+        config.patches[0].operation = operation;
+        config
+    }
+
+    fn text_query(search: &str) -> Query {
+        Query::Text {
+            search: search.to_string(),
+            fuzzy_threshold: None,
+            fuzzy_expansion: None,
+            selection: Selection::default(),
+        }
+    }
+
+    #[test]
+    fn test_insert_after_and_before_indent_like_anchor() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn main() {\n    let cfg = load();\n    run(cfg);\n}\n";
+        fs::write(&file, original).unwrap();
+        let after = insert_patch(
+            text_query("let cfg = load();"),
+            Operation::InsertAfter {
+                text: "cfg.validate()?;\n".to_string(),
+            },
+        );
+        let before = insert_patch(
+            Query::AstGrep {
+                pattern: "run($CFG)".to_string(),
+                capture: None,
+                selection: Selection::default(),
+            },
+            Operation::InsertBefore {
+                text: "  // Telemetry stays off.\n  disable_telemetry();".to_string(),
+            },
+        );
+        let expected = "fn main() {\n    let cfg = load();\n    cfg.validate()?;\n    \
+                        // Telemetry stays off.\n    disable_telemetry();\n    run(cfg);\n}\n";
+
+        for config in [&after, &before] {
+            let results = apply_patches(config, dir.path(), "0.1.0");
+            assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), expected);
+        for config in [&after, &before] {
+            let results = apply_patches(config, dir.path(), "0.1.0");
+            assert!(matches!(
+                results[0].1,
+                Ok(PatchResult::AlreadyApplied { .. })
+            ));
+        }
+
+        for config in [&before, &after] {
+            let results = revert_patches(config, dir.path(), "0.1.0");
+            assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), original);
+    }
+
+    #[test]
+    fn test_insert_inline_when_anchor_shares_its_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn f() { call(a); }\n").unwrap();
+        let config = insert_patch(
+            text_query("a)"),
+            Operation::InsertBefore {
+                text: "ctx, ".to_string(),
+            },
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn f() { call(ctx, a); }\n"
+        );
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+    }
+
+    #[test]
+    fn test_prepend_and_append_to_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(
+            &file,
+            "mod net {\n    fn connect() {\n        open();\n    }\n\n    impl Client {}\n}\n",
+        )
+        .unwrap();
+        let prepend = insert_patch(
+            Query::TreeSitter {
+                pattern: "fn connect".to_string(),
+                capture: None,
+                selection: Selection::default(),
+            },
+            Operation::PrependToBody {
+                text: "check_offline()?;".to_string(),
+            },
+        );
+        let append = insert_patch(
+            text_query("impl Client"),
+            Operation::AppendToBody {
+                text: "fn offline(&self) -> bool {\n    true\n}".to_string(),
+            },
+        );
+
+        for config in [&prepend, &append] {
+            let results = apply_patches(config, dir.path(), "0.1.0");
+            assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+            let results = apply_patches(config, dir.path(), "0.1.0");
+            assert!(matches!(
+                results[0].1,
+                Ok(PatchResult::AlreadyApplied { .. })
+            ));
+        }
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "mod net {\n    fn connect() {\n        check_offline()?;\n        open();\n    }\n\n    \
+             impl Client {\n        fn offline(&self) -> bool {\n            true\n        }\n    }\n}\n"
+        );
+
+        // A statement inside a body is not a function, impl or module header.
+        let inside = insert_patch(
+            text_query("open();"),
+            Operation::AppendToBody {
+                text: "x();".to_string(),
+            },
+        );
+        let results = apply_patches(&inside, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::TomlOperation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_insert_into_empty_body_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn setup() {}\n\nimpl Client {\n    fn reset(&self) {}\n}\n";
+        fs::write(&file, original).unwrap();
+        let append = insert_patch(
+            Query::TreeSitter {
+                pattern: "fn setup".to_string(),
+                capture: None,
+                selection: Selection::default(),
+            },
+            Operation::AppendToBody {
+                text: "init();".to_string(),
+            },
+        );
+        let prepend = insert_patch(
+            Query::TreeSitter {
+                pattern: "fn Client::reset".to_string(),
+                capture: None,
+                selection: Selection::default(),
+            },
+            Operation::PrependToBody {
+                text: "self.clear();".to_string(),
+            },
+        );
+
+        for config in [&append, &prepend] {
+            let results = apply_patches(config, dir.path(), "0.1.0");
+            assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        }
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn setup() {\n    init();\n}\n\nimpl Client {\n    fn reset(&self) {\n        \
+             self.clear();\n    }\n}\n"
+        );
+        for config in [&append, &prepend] {
+            let results = revert_patches(config, dir.path(), "0.1.0");
+            assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), original);
+        let results = revert_patches(&append, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sequential_patch_targets_earlier_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        let original = "fn run() {\n    start();\n}\n";
+        fs::write(&file, original).unwrap();
+
+        let mut config = PatchConfig {
+            meta: Metadata {
+                name: "layers".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![
+                text_patch("wait", "lib.rs", "start();", ""),
+                text_patch("trace", "lib.rs", "wait();", ""),
+            ],
+        };
+        for (patch, text) in config.patches.iter_mut().zip(["wait();", "trace();"]) {
+            patch.operation = Operation::InsertAfter {
+                text: text.to_string(),
+            };
+        }
+
+        // Batched, `trace` only sees the original file.
+        let results = simulate_patches(&config, dir.path(), "0.1.0").results;
+        assert!(matches!(
+            results[1].1,
+            Err(ApplicationError::NoMatch { .. })
+        ));
+
+        config.patches[1].mode = Some(ApplyMode::Sequential);
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(results
+            .iter()
+            .all(|(_, r)| matches!(r, Ok(PatchResult::Applied { .. }))));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn run() {\n    start();\n    wait();\n    trace();\n}\n"
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(results
+            .iter()
+            .all(|(_, r)| matches!(r, Ok(PatchResult::AlreadyApplied { .. }))));
+
+        // Reverts unwind the chain last patch first.
+        let results = revert_patches(&config, dir.path(), "0.1.0");
+        assert!(results
+            .iter()
+            .all(|(_, r)| matches!(r, Ok(PatchResult::Applied { .. }))));
+        assert_eq!(fs::read_to_string(&file).unwrap(), original);
+    }
+
+    #[test]
+    fn test_variant_matching_workspace_version_is_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn endpoint() -> &'static str { \"https://old\" }\n").unwrap();
+
+        let mut patch = text_patch("endpoint", "lib.rs", "\"https://new\"", "\"\"");
+        patch.version = Some(">=0.108.0-alpha.1".to_string());
+        let older = text_patch("endpoint", "lib.rs", "\"https://old\"", "\"\"");
+        patch.variants.push(PatchVariant {
+            version: ">=0.105.0, <0.108.0-alpha.1".to_string(),
+            query: older.query,
+            operation: older.operation,
+            verify: None,
+        });
+        let mut config = PatchConfig {
+            meta: Metadata {
+                name: "privacy".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![patch],
+        };
+
+        // The current search text is not in the file; the older variant is.
+        let results = simulate_patches(&config, dir.path(), "0.108.0").results;
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::NoMatch { .. })
+        ));
+        let results = apply_patches(&config, dir.path(), "0.106.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn endpoint() -> &'static str { \"\" }\n"
+        );
+        assert_eq!(
+            variant_used(&config, "endpoint", "0.106.0").as_deref(),
+            Some(">=0.105.0, <0.108.0-alpha.1")
+        );
+        assert_eq!(
+            variant_used(&config, "endpoint", "0.110.0").as_deref(),
+            Some(">=0.108.0-alpha.1")
+        );
+
+        let results = simulate_patches(&config, dir.path(), "0.99.0").results;
+        match &results[0].1 {
+            Ok(PatchResult::SkippedVersion { reason }) => {
+                assert!(
+                    reason.starts_with("no variant matches workspace 0.99.0"),
+                    "{reason}"
+                )
+            }
+            other => panic!("expected SkippedVersion, got {other:?}"),
+        }
+
+        config.patches[0].variants[0].version = ">=0.105.0".to_string();
+        let results = simulate_patches(&config, dir.path(), "0.108.0").results;
+        assert!(matches!(
+            &results[0].1,
+            Err(ApplicationError::AmbiguousVariant { matched, .. }) if matched.len() == 2
+        ));
+        assert_eq!(variant_used(&config, "endpoint", "0.108.0"), None);
+    }
+
+    #[test]
+    fn test_glob_file_patches_each_matching_file() {
+        let dir = tempfile::tempdir().unwrap();
+        for (krate, dependency) in [
+            ("core", "reqwest = \"0.12\"\n"),
+            ("login", "reqwest = \"0.12\"\n"),
+            ("tui", "ratatui = \"0.29\"\n"),
+        ] {
+            fs::create_dir(dir.path().join(krate)).unwrap();
+            fs::write(
+                dir.path().join(krate).join("Cargo.toml"),
+                format!("[dependencies]\n{dependency}"),
+            )
+            .unwrap();
+        }
+        let mut config = query_patch(text_query("reqwest = \"0.12\""), "reqwest = \"0.13\"");
+        config.patches[0].file = "*/Cargo.toml".to_string();
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        let ids: Vec<_> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["t@core/Cargo.toml", "t@login/Cargo.toml"]);
+        assert!(results
+            .iter()
+            .all(|(_, r)| matches!(r, Ok(PatchResult::Applied { .. }))));
+        let patched = fs::read_to_string(dir.path().join("login/Cargo.toml")).unwrap();
+        assert!(patched.contains("reqwest = \"0.13\""));
+
+        // Already-applied files still count as matched.
+        config.patches[0].max_matches = Some(2);
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|(_, r)| matches!(r, Ok(PatchResult::AlreadyApplied { .. }))));
+
+        config.patches[0].min_matches = Some(3);
+        config.patches[0].max_matches = None;
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "t");
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::FileMatchCount {
+                min: 3,
+                max: None,
+                found: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_capture_narrows_ast_grep_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn f() {\n    retry(30, 30);\n}\n").unwrap();
+        let config = query_patch(
+            Query::AstGrep {
+                pattern: "retry($ATTEMPTS, $DELAY)".to_string(),
+                capture: Some("DELAY".to_string()),
+                selection: Selection::default(),
+            },
+            "5",
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn f() {\n    retry(30, 5);\n}\n"
+        );
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+    }
+
+    #[test]
+    fn test_capture_narrows_tree_sitter_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(
+            &file,
+            "const TIMEOUT: u64 = 30;\nconst RETRIES: u64 = 30;\n",
+        )
+        .unwrap();
+        let config = query_patch(
+            Query::TreeSitter {
+                pattern: r#"(const_item
+                    name: (identifier) @name (#eq? @name "TIMEOUT")
+                    value: (_) @value)"#
+                    .to_string(),
+                capture: Some("value".to_string()),
+                selection: Selection::default(),
+            },
+            "120",
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "const TIMEOUT: u64 = 120;\nconst RETRIES: u64 = 30;\n"
+        );
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ast_grep_template_keeps_captures() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(
+            &file,
+            "fn c() -> Client {\n    reqwest::Client::builder().timeout(T).build().unwrap()\n}\n",
+        )
+        .unwrap();
+        let config = template_patch(
+            "reqwest::Client::builder().$$$REST",
+            "crate::http::client_builder().$$$REST",
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn c() -> Client {\n    crate::http::client_builder().timeout(T).build().unwrap()\n}\n"
+        );
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+        let results = revert_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Err(ApplicationError::Irreversible { .. })
+        ));
+    }
+
+    #[test]
+    fn test_template_still_matching_pattern_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn a() {\n    work(1, 2);\n}\n").unwrap();
+        // The replacement still matches the pattern; it must not nest.
+        let config = template_patch("work($$$ARGS)", "work(trace(), $$$ARGS)");
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn a() {\n    work(trace(), 1, 2);\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_bare_capture_template_unwraps_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(
+            &file,
+            "fn a() -> Vec<u8> {\n    let v = vec![1];\n    *Box::new(v)\n}\n",
+        )
+        .unwrap();
+        // `$X` read as a pattern matches any node; that is no sign of a
+        // patched site.
+        let config = template_patch("Box::new($X)", "$X");
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(
+            matches!(results[0].1, Ok(PatchResult::Applied { .. })),
+            "{:?}",
+            results[0].1
+        );
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn a() -> Vec<u8> {\n    let v = vec![1];\n    *v\n}\n"
+        );
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+    }
+
+    #[test]
+    fn test_template_output_inside_match_is_not_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lib.rs");
+        fs::write(&file, "fn a() {\n    take(Box::new(Some(1)));\n}\n").unwrap();
+        let config = template_patch("Box::new($X)", "Some($X)");
+
+        let results = apply_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(results[0].1, Ok(PatchResult::Applied { .. })));
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "fn a() {\n    take(Some(Some(1)));\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_patch_result_display() {
+        let applied = PatchResult::Applied {
+            file: PathBuf::from("/tmp/test.rs"),
+        };
+        assert!(applied.to_string().contains("Applied"));
+
+        let already = PatchResult::AlreadyApplied {
+            file: PathBuf::from("/tmp/test.rs"),
+        };
+        assert!(already.to_string().contains("Already applied"));
+
+        let skipped = PatchResult::SkippedVersion {
+            reason: "version too old".to_string(),
+        };
+        assert!(skipped.to_string().contains("Skipped"));
+
+        let failed = PatchResult::Failed {
+            file: PathBuf::from("/tmp/test.rs"),
+            reason: "parse error".to_string(),
+        };
+        assert!(failed.to_string().contains("Failed"));
+    }
+
+    #[test]
+    fn test_relocates_structural_target_to_unique_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("core/src");
+        fs::create_dir_all(src.join("exec")).unwrap();
+        fs::write(src.join("exec.rs"), "mod runner;\n").unwrap();
+        fs::write(src.join("exec/runner.rs"), "fn spawn() {\n    run();\n}\n").unwrap();
+
+        let mut config = PatchConfig {
+            meta: Metadata {
+                name: "sandbox".to_string(),
+                workspace_relative: true,
+                ..Default::default()
+            },
+            patches: vec![PatchDefinition {
+                query: Query::TreeSitter {
+                    pattern: "fn spawn".to_string(),
+                    capture: None,
+                    selection: Selection::default(),
+                },
+                operation: Operation::PrependToBody {
+                    text: "sandbox();".to_string(),
+                },
+                ..text_patch("guard-spawn", "core/src/exec.rs", "", "")
+            }],
+        };
+
+        // Without search paths the patch fails where it was declared.
+        let run = simulate_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            run.results[0].1,
+            Err(ApplicationError::NoMatch { .. })
+        ));
+        assert!(run.relocations.is_empty());
+
+        config.patches[0].search_paths = vec!["core/src/**/*.rs".to_string()];
+        let run = apply_patches_detailed(&config, dir.path(), "0.1.0");
+        match &run.results[0].1 {
+            Ok(PatchResult::Applied { file }) => assert!(file.ends_with("exec/runner.rs")),
+            other => panic!("expected relocated apply, got {other:?}"),
+        }
+        assert_eq!(run.relocations["guard-spawn"], src.join("exec.rs"));
+        assert_eq!(
+            fs::read_to_string(src.join("exec/runner.rs")).unwrap(),
+            "fn spawn() {\n    sandbox();\n    run();\n}\n"
+        );
+
+        // The declared file may be gone entirely; `relocate` searches everywhere.
+        fs::remove_file(src.join("exec.rs")).unwrap();
+        config.patches[0].search_paths.clear();
+        config.patches[0].relocate = true;
+        let run = simulate_patches(&config, dir.path(), "0.1.0");
+        assert!(matches!(
+            run.results[0].1,
+            Ok(PatchResult::AlreadyApplied { .. })
+        ));
+
+        fs::write(src.join("spawn.rs"), "fn spawn() {}\n").unwrap();
+        let run = simulate_patches(&config, dir.path(), "0.1.0");
+        match &run.results[0].1 {
+            Err(ApplicationError::AmbiguousRelocation { found, .. }) => {
+                assert_eq!(found.len(), 2)
+            }
+            other => panic!("expected ambiguous relocation, got {other:?}"),
+        }
+        assert!(run.files.is_empty());
     }
 }
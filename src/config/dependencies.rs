@@ -0,0 +1,575 @@
+//! Ordering and gating patches by their `requires` / `conflicts` entries.
+//!
+//! [`order_patches`] resolves the references across every loaded config and
+//! splits the configs into [`Step`]s in dependency order. [`Outcomes`] then
+//! runs the steps one by one, refusing patches whose prerequisites were not
+//! applied or that conflict with a patch that was.
+
+use crate::config::applicator::{ApplicationError, PatchResult, Simulation};
+use crate::config::schema::{PatchConfig, PatchDefinition, PatchRef};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Why a set of patch configs cannot be ordered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DependencyError {
+    /// A reference names a patch its config does not define
+    UnknownPatch { from: String, reference: String },
+    /// `requires` entries form a cycle between these patches
+    Cycle { patches: Vec<String> },
+}
+
+impl fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DependencyError::UnknownPatch { from, reference } => write!(
+                f,
+                "{} references {}, which is not a patch of that config",
+                from, reference
+            ),
+            DependencyError::Cycle { patches } => {
+                write!(f, "requires form a cycle between {}", patches.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for DependencyError {}
+
+/// Patches of one config that can be applied together: none of them
+/// requires another patch in the same step.
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// Index of the config in the slice given to [`order_patches`]
+    pub index: usize,
+    /// The config, reduced to this step's patches
+    pub config: PatchConfig,
+}
+
+/// `config:patch` name of a patch, as used in references and errors.
+fn qualified(config: &PatchConfig, patch: &PatchDefinition) -> String {
+    format!("{}:{}", config.meta.name, patch.id)
+}
+
+/// Every `requires` (or `conflicts`) entry of a patch, config-level first.
+pub(crate) fn references<'a>(
+    config: &'a PatchConfig,
+    patch: &'a PatchDefinition,
+    conflicts: bool,
+) -> impl Iterator<Item = PatchRef<'a>> {
+    let (meta, own) = match conflicts {
+        false => (&config.meta.requires, &patch.requires),
+        true => (&config.meta.conflicts, &patch.conflicts),
+    };
+    meta.iter().chain(own).filter_map(|r| PatchRef::parse(r))
+}
+
+/// Order the patches of `configs` so every patch comes after the patches it
+/// requires, keeping the given order otherwise.
+///
+/// References to configs that are not loaded are left to [`Outcomes`],
+/// which reports them when the patch runs. A reference to a patch its
+/// (loaded) config does not define, or a cycle, is an error.
+pub fn order_patches(configs: &[PatchConfig]) -> Result<Vec<Step>, DependencyError> {
+    let nodes: Vec<(usize, usize)> = configs
+        .iter()
+        .enumerate()
+        .flat_map(|(ci, config)| (0..config.patches.len()).map(move |pi| (ci, pi)))
+        .collect();
+
+    let resolve = |from: (usize, usize), reference: PatchRef| {
+        let mut targets = Vec::new();
+        for (ci, config) in configs.iter().enumerate() {
+            if config.meta.name != reference.config {
+                continue;
+            }
+            for (pi, patch) in config.patches.iter().enumerate() {
+                if reference.patch.is_none_or(|id| id == patch.id) {
+                    targets.push((ci, pi));
+                }
+            }
+        }
+        let config_loaded = configs.iter().any(|c| c.meta.name == reference.config);
+        if config_loaded && reference.patch.is_some() && targets.is_empty() {
+            let (ci, pi) = from;
+            return Err(DependencyError::UnknownPatch {
+                from: qualified(&configs[ci], &configs[ci].patches[pi]),
+                reference: reference.to_string(),
+            });
+        }
+        Ok(targets)
+    };
+
+    let mut requires: HashMap<(usize, usize), Vec<(usize, usize)>> = HashMap::new();
+    let mut dependents: HashMap<(usize, usize), Vec<(usize, usize)>> = HashMap::new();
+    for &node in &nodes {
+        let (config, patch) = (&configs[node.0], &configs[node.0].patches[node.1]);
+        for reference in references(config, patch, true) {
+            resolve(node, reference)?;
+        }
+        let mut deps = Vec::new();
+        for reference in references(config, patch, false) {
+            for target in resolve(node, reference)? {
+                if target != node && !deps.contains(&target) {
+                    deps.push(target);
+                    dependents.entry(target).or_default().push(node);
+                }
+            }
+        }
+        requires.insert(node, deps);
+    }
+
+    // Kahn's algorithm, always taking the earliest ready patch.
+    let mut pending: HashMap<(usize, usize), usize> =
+        nodes.iter().map(|n| (*n, requires[n].len())).collect();
+    let mut ready: BTreeSet<(usize, usize)> =
+        nodes.iter().copied().filter(|n| pending[n] == 0).collect();
+    let mut order = Vec::with_capacity(nodes.len());
+    while let Some(node) = ready.pop_first() {
+        order.push(node);
+        for dependent in dependents.get(&node).into_iter().flatten() {
+            let count = pending.get_mut(dependent).expect("every node has a count");
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(*dependent);
+            }
+        }
+    }
+    if order.len() < nodes.len() {
+        let patches = nodes
+            .iter()
+            .filter(|n| !order.contains(n))
+            .map(|&(ci, pi)| qualified(&configs[ci], &configs[ci].patches[pi]))
+            .collect();
+        return Err(DependencyError::Cycle { patches });
+    }
+
+    let mut steps: Vec<(usize, Vec<usize>)> = Vec::new();
+    for (ci, pi) in order {
+        match steps.last_mut() {
+            Some((index, patches))
+                if *index == ci
+                    && !requires[&(ci, pi)]
+                        .iter()
+                        .any(|&(dci, dpi)| dci == ci && patches.contains(&dpi)) =>
+            {
+                patches.push(pi)
+            }
+            _ => steps.push((ci, vec![pi])),
+        }
+    }
+    Ok(steps
+        .into_iter()
+        .map(|(index, patches)| Step {
+            index,
+            config: PatchConfig {
+                meta: configs[index].meta.clone(),
+                patches: patches
+                    .into_iter()
+                    .map(|pi| configs[index].patches[pi].clone())
+                    .collect(),
+            },
+        })
+        .collect())
+}
+
+/// Results of the patches run so far, used to gate the patches after them.
+#[derive(Debug, Default)]
+pub struct Outcomes {
+    /// Patch ids of every loaded config, by config name
+    configs: HashMap<String, Vec<String>>,
+    /// (config, patch id) -> `None` when applied, otherwise why not
+    results: HashMap<(String, String), Option<String>>,
+    /// (declaring patch, reference) for the `conflicts` of applied patches
+    conflicts: Vec<(String, String)>,
+}
+
+impl Outcomes {
+    pub fn new(configs: &[PatchConfig]) -> Self {
+        let mut outcomes = Self::default();
+        for config in configs {
+            let ids = outcomes
+                .configs
+                .entry(config.meta.name.clone())
+                .or_default();
+            for patch in &config.patches {
+                if !ids.contains(&patch.id) {
+                    ids.push(patch.id.clone());
+                }
+            }
+        }
+        outcomes
+    }
+
+    /// Run `config` through `run` (e.g. `apply_patches_detailed`), after
+    /// refusing the patches whose `requires` or `conflicts` are not met.
+    ///
+    /// Refused patches are reported with [`ApplicationError::PrerequisiteFailed`]
+    /// or [`ApplicationError::ConflictingPatch`] among the results, in config
+    /// order, and every result is recorded for the steps after this one.
+    pub fn run(
+        &mut self,
+        config: &PatchConfig,
+        run: impl FnOnce(&PatchConfig) -> Simulation,
+    ) -> Simulation {
+        let mut refused = Vec::new();
+        let runnable: Vec<PatchDefinition> = config
+            .patches
+            .iter()
+            .filter(|patch| match self.check(config, patch) {
+                Ok(()) => true,
+                Err(e) => {
+                    refused.push((patch.id.clone(), Err(e)));
+                    false
+                }
+            })
+            .cloned()
+            .collect();
+
+        let mut simulation = if runnable.is_empty() {
+            Simulation::default()
+        } else {
+            run(&PatchConfig {
+                meta: config.meta.clone(),
+                patches: runnable,
+            })
+        };
+        if !refused.is_empty() {
+            simulation.results.extend(refused);
+            simulation
+                .results
+                .sort_by_key(|(id, _)| patch_index(config, id));
+        }
+        self.record(config, &simulation.results);
+        simulation
+    }
+
+    /// Whether `patch` may run given the results recorded so far.
+    pub fn check(
+        &self,
+        config: &PatchConfig,
+        patch: &PatchDefinition,
+    ) -> Result<(), ApplicationError> {
+        for reference in references(config, patch, false) {
+            if let Some(reason) = self.unmet(reference) {
+                return Err(ApplicationError::PrerequisiteFailed {
+                    requirement: reference.to_string(),
+                    reason,
+                });
+            }
+        }
+        for reference in references(config, patch, true) {
+            if self.any_applied(reference) {
+                return Err(ApplicationError::ConflictingPatch {
+                    conflict: reference.to_string(),
+                });
+            }
+        }
+        // Conflicts are mutual: an applied patch may have declared one on us.
+        for (declared_by, reference) in &self.conflicts {
+            let Some(reference) = PatchRef::parse(reference) else {
+                continue;
+            };
+            if reference.names(&config.meta.name, &patch.id) {
+                return Err(ApplicationError::ConflictingPatch {
+                    conflict: declared_by.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a run's results; ids of glob patches (`<patch-id>@<file>`)
+    /// count towards their patch.
+    pub fn record(
+        &mut self,
+        config: &PatchConfig,
+        results: &[(String, Result<PatchResult, ApplicationError>)],
+    ) {
+        self.record_outcomes(
+            config,
+            results.iter().map(|(id, result)| {
+                let outcome = match result {
+                    Ok(PatchResult::Applied { .. } | PatchResult::AlreadyApplied { .. }) => None,
+                    Ok(PatchResult::SkippedVersion { reason }) => {
+                        Some(format!("skipped ({reason})"))
+                    }
+                    Ok(PatchResult::Failed { reason, .. }) => Some(format!("failed ({reason})")),
+                    Err(e) => Some(format!("failed ({e})")),
+                };
+                (id, outcome)
+            }),
+        );
+    }
+
+    /// Record patches a selection left out of the run, from a simulation of
+    /// them against the workspace: those already applied on disk count as
+    /// applied, the rest as not selected.
+    pub fn record_deselected(
+        &mut self,
+        config: &PatchConfig,
+        results: &[(String, Result<PatchResult, ApplicationError>)],
+    ) {
+        self.record_outcomes(
+            config,
+            results.iter().map(|(id, result)| {
+                let outcome = match result {
+                    Ok(PatchResult::AlreadyApplied { .. }) => None,
+                    _ => Some("not selected and not applied".to_string()),
+                };
+                (id, outcome)
+            }),
+        );
+    }
+
+    fn record_outcomes<'r>(
+        &mut self,
+        config: &PatchConfig,
+        outcomes: impl Iterator<Item = (&'r String, Option<String>)>,
+    ) {
+        for (id, outcome) in outcomes {
+            let Some(patch) = patch_index(config, id).map(|i| &config.patches[i]) else {
+                continue;
+            };
+            let key = (config.meta.name.clone(), patch.id.clone());
+            let entry = self.results.entry(key).or_insert(None);
+            if entry.is_none() {
+                *entry = outcome;
+            }
+        }
+        for patch in &config.patches {
+            let key = (config.meta.name.clone(), patch.id.clone());
+            if matches!(self.results.get(&key), Some(None)) {
+                for reference in references(config, patch, true) {
+                    self.conflicts
+                        .push((qualified(config, patch), reference.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Why `reference` is not applied; `None` when all of it is.
+    fn unmet(&self, reference: PatchRef) -> Option<String> {
+        let Some(ids) = self.configs.get(reference.config) else {
+            return Some(format!("config {} is not loaded", reference.config));
+        };
+        if let Some(patch) = reference.patch {
+            if !ids.iter().any(|id| id == patch) {
+                return Some(format!("{} is not loaded", reference));
+            }
+        }
+        ids.iter()
+            .filter(|id| reference.patch.is_none_or(|patch| patch == id.as_str()))
+            .find_map(|id| {
+                match self
+                    .results
+                    .get(&(reference.config.to_string(), id.clone()))
+                {
+                    Some(None) => None,
+                    Some(Some(reason)) => Some(reason.clone()),
+                    None => Some(format!("{} has not run", id)),
+                }
+            })
+    }
+
+    /// Whether any patch `reference` names is applied.
+    fn any_applied(&self, reference: PatchRef) -> bool {
+        self.results
+            .iter()
+            .any(|((config, id), outcome)| outcome.is_none() && reference.names(config, id))
+    }
+}
+
+/// Position in `config` of the patch a result id belongs to.
+pub(crate) fn patch_index(config: &PatchConfig, id: &str) -> Option<usize> {
+    config.patches.iter().position(|patch| {
+        id == patch.id
+            || id
+                .strip_prefix(patch.id.as_str())
+                .is_some_and(|rest| rest.starts_with('@'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_from_str;
+
+    fn config(name: &str, meta: &str, patches: &[(&str, &str)]) -> PatchConfig {
+        let mut toml = format!("[meta]\nname = \"{name}\"\n{meta}\n");
+        for (id, extra) in patches {
+            toml.push_str(&format!(
+                r#"
+[[patches]]
+id = "{id}"
+file = "lib.rs"
+{extra}
+
+[patches.query]
+type = "text"
+search = "{id}"
+
+[patches.operation]
+type = "replace"
+text = "{id}!"
+"#
+            ));
+        }
+        load_from_str(&toml).unwrap()
+    }
+
+    fn step_ids(steps: &[Step]) -> Vec<String> {
+        steps
+            .iter()
+            .map(|step| {
+                let ids: Vec<_> = step.config.patches.iter().map(|p| p.id.as_str()).collect();
+                format!("{}:{}", step.config.meta.name, ids.join(","))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn orders_required_configs_first() {
+        let configs = [
+            config(
+                "approvals",
+                "requires = [\"undo\"]",
+                &[("ui", ""), ("keys", "")],
+            ),
+            config("undo", "", &[("command", "")]),
+        ];
+        let steps = order_patches(&configs).unwrap();
+        assert_eq!(step_ids(&steps), ["undo:command", "approvals:ui,keys"]);
+        assert_eq!(steps[0].index, 1);
+    }
+
+    #[test]
+    fn splits_a_config_around_an_inner_requirement() {
+        let configs = [config(
+            "privacy",
+            "",
+            &[("b", "requires = [\"privacy:a\"]"), ("a", ""), ("c", "")],
+        )];
+        let steps = order_patches(&configs).unwrap();
+        assert_eq!(step_ids(&steps), ["privacy:a", "privacy:b,c"]);
+    }
+
+    #[test]
+    fn rejects_cycles_and_unknown_patches() {
+        let cycle = [
+            config("a", "", &[("x", "requires = [\"b:y\"]")]),
+            config("b", "", &[("y", "requires = [\"a\"]")]),
+        ];
+        assert_eq!(
+            order_patches(&cycle).unwrap_err(),
+            DependencyError::Cycle {
+                patches: vec!["a:x".to_string(), "b:y".to_string()]
+            }
+        );
+
+        let unknown = [
+            config("a", "", &[("x", "conflicts = [\"b:nope\"]")]),
+            config("b", "", &[("y", "")]),
+        ];
+        assert!(matches!(
+            order_patches(&unknown),
+            Err(DependencyError::UnknownPatch { .. })
+        ));
+
+        // Configs that are not loaded are reported when the patch runs.
+        assert!(order_patches(&[config("a", "requires = [\"gone\"]", &[("x", "")])]).is_ok());
+    }
+
+    fn results(outcomes: &[(&str, Result<PatchResult, ApplicationError>)]) -> Simulation {
+        Simulation {
+            results: outcomes
+                .iter()
+                .map(|(id, r)| (id.to_string(), r.clone()))
+                .collect(),
+            ..Simulation::default()
+        }
+    }
+
+    fn applied() -> Result<PatchResult, ApplicationError> {
+        Ok(PatchResult::AlreadyApplied {
+            file: "lib.rs".into(),
+        })
+    }
+
+    #[test]
+    fn refuses_patches_with_unmet_requirements() {
+        let undo = config("undo", "", &[("command", "")]);
+        let approvals = config(
+            "approvals",
+            "",
+            &[("ui", "requires = [\"undo:command\"]"), ("other", "")],
+        );
+        let mut outcomes = Outcomes::new(&[undo.clone(), approvals.clone()]);
+
+        outcomes.run(&undo, |_| {
+            results(&[(
+                "command",
+                Ok(PatchResult::SkippedVersion {
+                    reason: "too new".to_string(),
+                }),
+            )])
+        });
+        let run = outcomes.run(&approvals, |config| {
+            assert_eq!(config.patches.len(), 1);
+            results(&[("other", applied())])
+        });
+
+        assert_eq!(run.results[0].0, "ui");
+        match &run.results[0].1 {
+            Err(ApplicationError::PrerequisiteFailed {
+                requirement,
+                reason,
+            }) => {
+                assert_eq!(requirement, "undo:command");
+                assert_eq!(reason, "skipped (too new)");
+            }
+            other => panic!("expected PrerequisiteFailed, got {other:?}"),
+        }
+        assert!(run.results[1].1.is_ok());
+    }
+
+    #[test]
+    fn conflicts_are_mutual() {
+        let a = config("a", "", &[("x", "conflicts = [\"b\"]")]);
+        let b = config("b", "", &[("y", "")]);
+        let mut outcomes = Outcomes::new(&[a.clone(), b.clone()]);
+
+        outcomes.run(&a, |_| results(&[("x", applied())]));
+        let run = outcomes.run(&b, |_| panic!("conflicting patch must not run"));
+        assert!(matches!(
+            &run.results[0].1,
+            Err(ApplicationError::ConflictingPatch { conflict }) if conflict == "a:x"
+        ));
+    }
+
+    #[test]
+    fn deselected_patches_count_when_applied_on_disk() {
+        let undo = config("undo", "", &[("command", ""), ("redo", "")]);
+        let ui = config("ui", "", &[("button", "requires = [\"undo\"]")]);
+        let mut outcomes = Outcomes::new(&[undo.clone(), ui.clone()]);
+
+        outcomes.record_deselected(
+            &undo,
+            &[
+                ("command".to_string(), applied()),
+                (
+                    "redo".to_string(),
+                    Ok(PatchResult::Applied {
+                        file: "lib.rs".into(),
+                    }),
+                ),
+            ],
+        );
+        let run = outcomes.run(&ui, |_| panic!("unmet requirement must not run"));
+        assert!(matches!(
+            &run.results[0].1,
+            Err(ApplicationError::PrerequisiteFailed { reason, .. })
+                if reason == "not selected and not applied"
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+//! Selecting which patches a command runs: `--only`, `--exclude`, `--tag`
+//! and named profiles.
+//!
+//! Profiles live in a project config file (`codex-patcher.toml`):
+//!
+//! ```toml
+//! [profiles.team]
+//! only = ["privacy-patches", "undo-slash-command"]
+//! tags = ["telemetry"]
+//! exclude = ["privacy-patches:drop-endpoint"]
+//! ```
+//!
+//! The same file picks where the workspace version comes from; see
+//! [`crate::config::version_source`].
+
+use crate::config::loader::ConfigError;
+use crate::config::schema::{Metadata, PatchConfig, PatchDefinition, PatchRef};
+use crate::config::version_source::VersionSettings;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Name of the project config file holding `[profiles.<name>]` tables.
+pub const PROJECT_CONFIG_FILE: &str = "codex-patcher.toml";
+
+/// Which patches to run.
+///
+/// `only` and `exclude` entries are a config name, a patch id, or
+/// `config:patch-id`. A patch is selected when it matches an `only` entry
+/// (or `only` is empty), carries one of `tags` (or `tags` is empty), and
+/// matches no `exclude` entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchFilter {
+    #[serde(default)]
+    pub only: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl PatchFilter {
+    /// Whether the filter selects every patch.
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.exclude.is_empty() && self.tags.is_empty()
+    }
+
+    /// Add the entries of `other` (e.g. a profile) to this filter.
+    pub fn extend(&mut self, other: &PatchFilter) {
+        self.only.extend(other.only.iter().cloned());
+        self.exclude.extend(other.exclude.iter().cloned());
+        self.tags.extend(other.tags.iter().cloned());
+    }
+
+    pub fn accepts(&self, meta: &Metadata, patch: &PatchDefinition) -> bool {
+        let names = |entry: &String| match entry.contains(':') {
+            true => PatchRef::parse(entry).is_some_and(|r| r.names(&meta.name, &patch.id)),
+            false => *entry == meta.name || *entry == patch.id,
+        };
+        (self.only.is_empty() || self.only.iter().any(names))
+            && (self.tags.is_empty()
+                || patch
+                    .tags(meta)
+                    .any(|tag| self.tags.iter().any(|t| t == tag)))
+            && !self.exclude.iter().any(names)
+    }
+
+    /// `config` reduced to the patches this filter selects; `None` when it
+    /// selects none of them.
+    pub fn select(&self, config: &PatchConfig) -> Option<PatchConfig> {
+        let patches: Vec<PatchDefinition> = config
+            .patches
+            .iter()
+            .filter(|patch| self.accepts(&config.meta, patch))
+            .cloned()
+            .collect();
+        (!patches.is_empty()).then(|| PatchConfig {
+            meta: config.meta.clone(),
+            patches,
+        })
+    }
+}
+
+/// The project config file ([`PROJECT_CONFIG_FILE`]).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Named selections, by profile name
+    #[serde(default)]
+    pub profiles: BTreeMap<String, PatchFilter>,
+    /// Where the workspace version comes from
+    #[serde(default)]
+    pub version: VersionSettings,
+}
+
+impl ProjectConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml_edit::de::from_str(&contents).map_err(|source| ConfigError::Toml {
+            path: Some(path.to_path_buf()),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_from_str;
+
+    fn config() -> PatchConfig {
+        load_from_str(
+            r#"
+[meta]
+name = "privacy"
+tags = ["privacy"]
+
+[[patches]]
+id = "statsig"
+file = "a.rs"
+tags = ["telemetry"]
+[patches.query]
+type = "text"
+search = "a"
+[patches.operation]
+type = "replace"
+text = "b"
+
+[[patches]]
+id = "endpoint"
+file = "a.rs"
+[patches.query]
+type = "text"
+search = "c"
+[patches.operation]
+type = "replace"
+text = "d"
+"#,
+        )
+        .unwrap()
+    }
+
+    fn selected(filter: &PatchFilter) -> Vec<String> {
+        filter
+            .select(&config())
+            .map(|c| c.patches.into_iter().map(|p| p.id).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn selects_by_name_id_and_tag() {
+        let filter = |only: &[&str], exclude: &[&str], tags: &[&str]| PatchFilter {
+            only: only.iter().map(|s| s.to_string()).collect(),
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+            tags: tags.iter().map(|s| s.to_string()).collect(),
+        };
+
+        assert_eq!(selected(&PatchFilter::default()), ["statsig", "endpoint"]);
+        assert_eq!(
+            selected(&filter(&["privacy"], &[], &[])),
+            ["statsig", "endpoint"]
+        );
+        assert_eq!(selected(&filter(&["endpoint"], &[], &[])), ["endpoint"]);
+        assert_eq!(
+            selected(&filter(&["privacy:statsig"], &[], &[])),
+            ["statsig"]
+        );
+        assert_eq!(selected(&filter(&[], &["statsig"], &[])), ["endpoint"]);
+        assert_eq!(selected(&filter(&[], &[], &["telemetry"])), ["statsig"]);
+        assert_eq!(
+            selected(&filter(&[], &[], &["privacy"])),
+            ["statsig", "endpoint"]
+        );
+        assert!(filter(&["other"], &[], &[]).select(&config()).is_none());
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let project: ProjectConfig = toml_edit::de::from_str(
+            r#"
+[profiles.team]
+only = ["privacy"]
+tags = ["telemetry"]
+
+[profiles.personal]
+exclude = ["privacy:endpoint"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(selected(&project.profiles["team"]), ["statsig"]);
+        assert_eq!(selected(&project.profiles["personal"]), ["statsig"]);
+        assert!(toml_edit::de::from_str::<ProjectConfig>("[profiles.x]\nonly = 1\n").is_err());
+    }
+}
@@ -1,12 +1,35 @@
 pub mod applicator;
+pub mod dependencies;
+pub mod filter;
 pub mod loader;
+pub mod near_miss;
+pub mod overlap;
+pub mod refresh;
 pub mod schema;
 pub mod version;
+pub mod version_source;
 
-pub use applicator::{apply_patches, check_patches, ApplicationError, PatchResult};
+pub use applicator::{
+    apply_patches, apply_patches_atomic, apply_patches_atomic_with, apply_patches_detailed,
+    check_patches, revert_patches, revert_patches_detailed, simulate_patches, simulate_revert,
+    variant_used, ApplicationError, AtomicApply, PatchResult, SimulatedFile, Simulation,
+    TransactionError,
+};
+pub use dependencies::{order_patches, DependencyError, Outcomes, Step};
+pub use filter::{PatchFilter, ProjectConfig, PROJECT_CONFIG_FILE};
 pub use loader::{load_from_path, load_from_str, ConfigError};
+pub use near_miss::{near_misses, NearMiss};
+pub use overlap::{find_overlaps, EditSpan, Overlap, OverlapKind};
+pub use refresh::{
+    plan_refresh, search_refreshes, widen_requirement, write_refreshes, Refresh, RefreshError,
+};
 pub use schema::{
-    Constraints, HashAlgorithm, Metadata, Operation, PatchConfig, PatchDefinition, Positioning,
-    Query, RelativePosition, ValidationError, ValidationIssue, Verify,
+    ApplyMode, Constraints, HashAlgorithm, Metadata, Occurrence, Operation, PatchConfig,
+    PatchDefinition, PatchVariant, Positioning, Query, RelativePosition, Selection,
+    ValidationError, ValidationIssue, Verify,
 };
 pub use version::{matches_requirement, VersionError};
+pub use version_source::{
+    resolve_version, CargoMetadata, Detection, Explicit, GitTag, ResolvedVersion, SourceKind,
+    VersionFile, VersionSettings, VersionSource, VersionSourceError,
+};
@@ -0,0 +1,480 @@
+//! Explaining a query that matched nothing.
+//!
+//! When a patch's query finds no location, [`near_misses`] looks for what
+//! upstream probably turned the target into: the closest text windows for a
+//! `text` query, items with the same or a similar name for a tree-sitter
+//! `fn`/`struct`/`enum`/`const`/`static` target (in the file, or moved to
+//! another file of the workspace), and matches of an `ast-grep` pattern
+//! with its argument lists and bodies relaxed. The applicator attaches them
+//! to [`ApplicationError::NoMatch`](crate::config::ApplicationError::NoMatch).
+
+use crate::config::applicator::parse_tree_sitter_pattern;
+use crate::config::schema::{PatchDefinition, Query};
+use crate::fuzzy::{find_top_matches, find_top_partial_matches};
+use crate::pool;
+use crate::sg::PatternMatcher;
+use crate::ts::{QueryEngine, StructuralTarget};
+use similar::{ChangeTag, TextDiff};
+use std::fs;
+use std::path::{Path, PathBuf};
+use strsim::normalized_levenshtein;
+use walkdir::WalkDir;
+
+/// Most candidates reported for one query.
+pub const MAX_NEAR_MISSES: usize = 3;
+
+/// Lowest similarity for a text window to count as a near miss.
+const MIN_TEXT_SCORE: f64 = 0.6;
+
+/// Lowest name similarity for an item to count as renamed.
+const MIN_NAME_SCORE: f64 = 0.75;
+
+/// A location that almost matched a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearMiss {
+    /// File holding the candidate
+    pub file: PathBuf,
+    /// 1-based line the candidate starts on
+    pub line: usize,
+    /// Similarity to the query, 0.0 to 1.0
+    pub score: f64,
+    /// How the candidate differs from the query
+    pub reason: String,
+    /// The candidate's text; for items, their signature
+    pub text: String,
+    /// Line diff from the query's `search` text to `text` (`text` queries)
+    pub diff: Option<String>,
+}
+
+/// Up to [`MAX_NEAR_MISSES`] candidates for `patch`'s query, which matched
+/// nothing in `content` (the contents of `file`, empty when it is missing),
+/// best first. Other files are searched under `workspace_root`.
+pub fn near_misses(
+    patch: &PatchDefinition,
+    file: &Path,
+    content: &str,
+    workspace_root: &Path,
+) -> Vec<NearMiss> {
+    match &patch.query {
+        Query::Text { search, .. } => text_near_misses(search, file, content),
+        Query::TreeSitter { pattern, .. } => match parse_tree_sitter_pattern(pattern)
+            .ok()
+            .and_then(|target| ItemTarget::from_structural(&target))
+        {
+            Some(target) => item_near_misses(&target, file, content, workspace_root),
+            None => Vec::new(),
+        },
+        Query::AstGrep { pattern, .. } => pattern_near_misses(pattern, file, content),
+        Query::Toml { .. } => Vec::new(),
+    }
+}
+
+/// Whole-line windows, plus for a single-line `search` the closest stretch of
+/// each line, so that a fragment of a longer line is found too.
+fn text_near_misses(search: &str, file: &Path, content: &str) -> Vec<NearMiss> {
+    let mut found = find_top_matches(search, content, MIN_TEXT_SCORE, MAX_NEAR_MISSES);
+    for partial in find_top_partial_matches(search, content, MIN_TEXT_SCORE, MAX_NEAR_MISSES) {
+        let same_line = found
+            .iter()
+            .position(|m| line_of(content, m.start) == line_of(content, partial.start));
+        match same_line {
+            Some(i) if found[i].score >= partial.score => {}
+            Some(i) => found[i] = partial,
+            None => found.push(partial),
+        }
+    }
+    found.sort_by(|a, b| b.score.total_cmp(&a.score));
+    found.truncate(MAX_NEAR_MISSES);
+    found
+        .into_iter()
+        .map(|m| NearMiss {
+            file: file.to_path_buf(),
+            line: line_of(content, m.start),
+            score: m.score,
+            reason: "similar text".to_string(),
+            diff: Some(line_diff(search, &m.matched_text)),
+            text: m.matched_text,
+        })
+        .collect()
+}
+
+/// `-`/`+`/` ` prefixed lines turning `old` into `new`.
+fn line_diff(old: &str, new: &str) -> String {
+    let mut diff = String::new();
+    for change in TextDiff::from_lines(old, new).iter_all_changes() {
+        diff.push(match change.tag() {
+            ChangeTag::Delete => '-',
+            ChangeTag::Insert => '+',
+            ChangeTag::Equal => ' ',
+        });
+        diff.push_str(change.value().trim_end_matches('\n'));
+        diff.push('\n');
+    }
+    diff
+}
+
+fn line_of(content: &str, byte: usize) -> usize {
+    content[..byte].matches('\n').count() + 1
+}
+
+/// A named item a tree-sitter DSL pattern looks for.
+struct ItemTarget {
+    kind: &'static str,
+    name: String,
+    /// `Type` of `fn Type::method`
+    owner: Option<String>,
+}
+
+impl ItemTarget {
+    fn from_structural(target: &StructuralTarget) -> Option<Self> {
+        let (kind, name, owner) = match target {
+            StructuralTarget::Function { name } => ("fn", name, None),
+            StructuralTarget::Method {
+                type_name,
+                method_name,
+            } => ("fn", method_name, Some(type_name.clone())),
+            StructuralTarget::Struct { name } => ("struct", name, None),
+            StructuralTarget::Enum { name } => ("enum", name, None),
+            StructuralTarget::Const { name } => ("const", name, None),
+            StructuralTarget::Static { name } => ("static", name, None),
+            _ => return None,
+        };
+        Some(Self {
+            kind,
+            name: name.clone(),
+            owner,
+        })
+    }
+
+    fn describe(&self) -> String {
+        match &self.owner {
+            Some(owner) => format!("{} {}::{}", self.kind, owner, self.name),
+            None => format!("{} {}", self.kind, self.name),
+        }
+    }
+}
+
+/// An item declared in a file.
+struct Item {
+    kind: &'static str,
+    name: String,
+    /// Type of the `impl` block a `fn` is declared in
+    owner: Option<String>,
+    line: usize,
+    signature: String,
+}
+
+const ITEM_QUERY: &str = r#"[
+    (function_item name: (identifier) @name)
+    (struct_item name: (type_identifier) @name)
+    (enum_item name: (type_identifier) @name)
+    (const_item name: (identifier) @name)
+    (static_item name: (identifier) @name)
+] @item"#;
+
+const IMPL_QUERY: &str = r#"(impl_item type: (_) @type body: (declaration_list) @body)"#;
+
+/// The items of `content`; empty when it does not parse.
+fn items(content: &str) -> Vec<Item> {
+    let found = pool::with_parser(|parser| {
+        let parsed = parser.parse_with_source(content).ok()?;
+        let items = QueryEngine::new(ITEM_QUERY).ok()?.find_all(&parsed);
+        let impls = QueryEngine::new(IMPL_QUERY).ok()?.find_all(&parsed);
+        Some((items, impls))
+    });
+    let Ok(Some((items, impls))) = found else {
+        return Vec::new();
+    };
+
+    items
+        .into_iter()
+        .filter_map(|m| {
+            let item = m.captures.get("item")?;
+            let name = m.captures.get("name")?;
+            let kind = match item.kind.as_str() {
+                "function_item" => "fn",
+                "struct_item" => "struct",
+                "enum_item" => "enum",
+                "const_item" => "const",
+                "static_item" => "static",
+                _ => return None,
+            };
+            // The innermost impl block around the item, if any.
+            let owner = impls
+                .iter()
+                .filter_map(|i| Some((i.captures.get("type")?, i.captures.get("body")?)))
+                .filter(|(_, body)| {
+                    body.byte_start <= item.byte_start && item.byte_end <= body.byte_end
+                })
+                .min_by_key(|(_, body)| body.byte_end - body.byte_start)
+                .map(|(ty, _)| base_type(&ty.text).to_string());
+            let head = item.text.split(['{', '=', ';']).next().unwrap_or_default();
+            Some(Item {
+                kind,
+                name: name.text.clone(),
+                owner,
+                line: line_of(content, item.byte_start),
+                signature: head.split_whitespace().collect::<Vec<_>>().join(" "),
+            })
+        })
+        .collect()
+}
+
+/// `Foo` for `Foo<T>`.
+fn base_type(ty: &str) -> &str {
+    ty.split('<').next().unwrap_or(ty).trim()
+}
+
+fn item_near_misses(
+    target: &ItemTarget,
+    file: &Path,
+    content: &str,
+    workspace_root: &Path,
+) -> Vec<NearMiss> {
+    let owner_matches = |item: &Item| match &target.owner {
+        Some(owner) => item.owner.as_deref() == Some(base_type(owner)),
+        None => true,
+    };
+    let candidate = |path: &Path, item: &Item, score: f64, reason: String| NearMiss {
+        file: path.to_path_buf(),
+        line: item.line,
+        score,
+        reason,
+        text: item.signature.clone(),
+        diff: None,
+    };
+
+    let mut found = Vec::new();
+    for item in items(content) {
+        if item.name == target.name {
+            let reason = if item.kind != target.kind {
+                format!(
+                    "`{}` is declared as `{}`, not `{}`",
+                    item.name, item.kind, target.kind
+                )
+            } else if !owner_matches(&item) {
+                match &item.owner {
+                    Some(owner) => format!("`{}` is declared in `impl {}`", item.name, owner),
+                    None => format!("`{}` is not in an `impl` block", item.name),
+                }
+            } else {
+                continue;
+            };
+            found.push(candidate(file, &item, 1.0, reason));
+        } else if item.kind == target.kind && owner_matches(&item) {
+            let score = normalized_levenshtein(&target.name, &item.name);
+            if score >= MIN_NAME_SCORE {
+                let reason = format!("similar name `{}`", item.name);
+                found.push(candidate(file, &item, score, reason));
+            }
+        }
+    }
+
+    // The item may have moved to another file.
+    let mut moved = 0;
+    for path in source_files(workspace_root) {
+        if moved == MAX_NEAR_MISSES {
+            break;
+        }
+        if path == file {
+            continue;
+        }
+        let Ok(other) = fs::read_to_string(&path) else {
+            continue;
+        };
+        if !other.contains(&target.name) {
+            continue;
+        }
+        for item in items(&other) {
+            if item.name == target.name && item.kind == target.kind && owner_matches(&item) {
+                let reason = format!("`{}` is declared in another file", target.describe());
+                found.push(candidate(&path, &item, 1.0, reason));
+                moved += 1;
+            }
+        }
+    }
+
+    found.sort_by(|a, b| b.score.total_cmp(&a.score));
+    found.truncate(MAX_NEAR_MISSES);
+    found
+}
+
+/// Every `.rs` file under `root`, skipping hidden directories and `target/`,
+/// in a stable order.
+pub(crate) fn source_files(root: &Path) -> Vec<PathBuf> {
+    WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            entry.depth() == 0 || !(name.starts_with('.') || name == "target")
+        })
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "rs"))
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+fn pattern_near_misses(pattern: &str, file: &Path, content: &str) -> Vec<NearMiss> {
+    let Some(relaxed) = relax_pattern(pattern) else {
+        return Vec::new();
+    };
+    let Ok(matches) = PatternMatcher::new(content).find_all(&relaxed) else {
+        return Vec::new();
+    };
+    let mut found: Vec<NearMiss> = matches
+        .into_iter()
+        .map(|m| NearMiss {
+            file: file.to_path_buf(),
+            line: line_of(content, m.byte_start),
+            score: normalized_levenshtein(pattern, &m.text),
+            reason: "matches with arguments and bodies ignored".to_string(),
+            text: m.text,
+            diff: None,
+        })
+        .collect();
+    found.sort_by(|a, b| b.score.total_cmp(&a.score));
+    found.truncate(MAX_NEAR_MISSES);
+    found
+}
+
+/// `pattern` with the contents of every outermost `(...)` and `{...}`
+/// replaced by `$$$`; `None` when that changes nothing.
+fn relax_pattern(pattern: &str) -> Option<String> {
+    let mut relaxed = String::with_capacity(pattern.len());
+    let mut depth = 0usize;
+    for c in pattern.chars() {
+        match c {
+            '(' | '{' => {
+                if depth == 0 {
+                    relaxed.push_str(if c == '(' { "($$$" } else { "{ $$$ " });
+                }
+                depth += 1;
+            }
+            ')' | '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    relaxed.push(c);
+                }
+            }
+            _ if depth == 0 => relaxed.push(c),
+            _ => {}
+        }
+    }
+    (depth == 0 && relaxed != pattern).then_some(relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_from_str;
+
+    fn patch(query: &str) -> PatchDefinition {
+        let toml = format!(
+            "[[patches]]\nid = \"p\"\nfile = \"a.rs\"\n[patches.query]\n{query}\n\
+             [patches.operation]\ntype = \"replace\"\ntext = \"x\"\n"
+        );
+        load_from_str(&toml).unwrap().patches.remove(0)
+    }
+
+    #[test]
+    fn text_candidates_carry_a_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = "fn main() {\n    let total = a + c;\n}\n";
+        let found = near_misses(
+            &patch("type = \"text\"\nsearch = \"    let total = a + b;\""),
+            &dir.path().join("a.rs"),
+            content,
+            dir.path(),
+        );
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].line, 2);
+        assert_eq!(
+            found[0].diff.as_deref(),
+            Some("-    let total = a + b;\n+    let total = a + c;\n")
+        );
+    }
+
+    #[test]
+    fn text_candidates_inside_longer_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = "fn c() -> Result<Client> {\n    \
+                       let client = Client::builder().timeout(Duration::from_secs(30)).build()?;\n\
+                       }\n";
+        let found = near_misses(
+            &patch("type = \"text\"\nsearch = \"timeout(Duration::from_secs(60))\""),
+            &dir.path().join("a.rs"),
+            content,
+            dir.path(),
+        );
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].line, 2);
+        assert_eq!(found[0].text, "timeout(Duration::from_secs(30))");
+        assert_eq!(
+            found[0].diff.as_deref(),
+            Some("-timeout(Duration::from_secs(60))\n+timeout(Duration::from_secs(30))\n")
+        );
+    }
+
+    #[test]
+    fn item_candidates_in_file_and_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("b.rs"),
+            "impl Client {\n    fn send(&self) {}\n}\n",
+        )
+        .unwrap();
+        let content = "impl Server {\n    fn send(&self, x: u8) {}\n    fn sends(&self) {}\n}\n";
+        let found = near_misses(
+            &patch("type = \"tree-sitter\"\npattern = \"fn Client::send\""),
+            &dir.path().join("a.rs"),
+            content,
+            dir.path(),
+        );
+        let reasons: Vec<&str> = found.iter().map(|m| m.reason.as_str()).collect();
+        assert_eq!(
+            reasons,
+            [
+                "`send` is declared in `impl Server`",
+                "`fn Client::send` is declared in another file",
+            ]
+        );
+        assert_eq!(found[0].text, "fn send(&self, x: u8)");
+        assert_eq!(found[1].file, dir.path().join("b.rs"));
+
+        let found = near_misses(
+            &patch("type = \"tree-sitter\"\npattern = \"struct Server\""),
+            &dir.path().join("a.rs"),
+            "enum Server { A }\nstruct Servers;\n",
+            dir.path(),
+        );
+        let reasons: Vec<&str> = found.iter().map(|m| m.reason.as_str()).collect();
+        assert_eq!(
+            reasons,
+            [
+                "`Server` is declared as `enum`, not `struct`",
+                "similar name `Servers`"
+            ]
+        );
+    }
+
+    #[test]
+    fn pattern_candidates_relax_arguments() {
+        assert_eq!(
+            relax_pattern("client.post(url, body)").as_deref(),
+            Some("client.post($$$)")
+        );
+        assert_eq!(relax_pattern("foo.bar"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let found = near_misses(
+            &patch("type = \"ast-grep\"\npattern = \"client.post(url, body)\""),
+            &dir.path().join("a.rs"),
+            "fn f() { client.post(url, body, headers); }\n",
+            dir.path(),
+        );
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].text, "client.post(url, body, headers)");
+    }
+}
@@ -0,0 +1,215 @@
+//! Edits from different configs that touch the same part of a file.
+//!
+//! Configs are applied one after another, so when two of them edit the same
+//! bytes the result depends on which runs first. [`find_overlaps`] simulates
+//! every config against the workspace as it is and reports the edits of
+//! different configs that overlap or touch.
+
+use crate::config::applicator::simulate_patches;
+use crate::config::dependencies::{patch_index, references};
+use crate::config::schema::{PatchConfig, PatchDefinition};
+use crate::ledger::LedgerRecord;
+use std::path::{Path, PathBuf};
+
+/// One side of an [`Overlap`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditSpan {
+    /// `meta.name` of the patch config
+    pub config: String,
+    pub patch_id: String,
+    /// Replaced byte range, in the file as read
+    pub byte_start: usize,
+    pub byte_end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapKind {
+    /// The edits replace some of the same bytes
+    Overlapping,
+    /// One edit starts where the other ends (or both insert at one point)
+    Adjacent,
+}
+
+/// Two edits from different configs that overlap or touch in one file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    /// Target file, relative to the workspace root when inside it
+    pub file: PathBuf,
+    pub kind: OverlapKind,
+    pub first: EditSpan,
+    pub second: EditSpan,
+}
+
+/// Simulate every config against `workspace_root` and report the pairs of
+/// edits from different configs that overlap or are adjacent, by file and
+/// position.
+///
+/// Patches that declare a `requires` or `conflicts` on each other are left
+/// out: their order (or exclusion) is already decided. Already-applied
+/// patches make no edits and are never reported.
+pub fn find_overlaps(
+    configs: &[PatchConfig],
+    workspace_root: &Path,
+    workspace_version: &str,
+) -> Vec<Overlap> {
+    let records: Vec<(usize, LedgerRecord)> = configs
+        .iter()
+        .enumerate()
+        .flat_map(|(index, config)| {
+            simulate_patches(config, workspace_root, workspace_version)
+                .records
+                .into_iter()
+                .map(move |record| (index, record))
+        })
+        .collect();
+
+    let mut overlaps = Vec::new();
+    for (i, (first_index, first)) in records.iter().enumerate() {
+        for (second_index, second) in &records[i + 1..] {
+            if first_index == second_index || first.file != second.file {
+                continue;
+            }
+            let kind = if first.byte_start < second.byte_end && second.byte_start < first.byte_end {
+                OverlapKind::Overlapping
+            } else if first.byte_start == second.byte_end || second.byte_start == first.byte_end {
+                OverlapKind::Adjacent
+            } else {
+                continue;
+            };
+            if declared(configs, *first_index, first, *second_index, second) {
+                continue;
+            }
+            let (first, second) = if first.byte_start <= second.byte_start {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            overlaps.push(Overlap {
+                file: first.file.clone(),
+                kind,
+                first: span(first),
+                second: span(second),
+            });
+        }
+    }
+    overlaps.sort_by(|a, b| {
+        (&a.file, a.first.byte_start, a.second.byte_start).cmp(&(
+            &b.file,
+            b.first.byte_start,
+            b.second.byte_start,
+        ))
+    });
+    overlaps
+}
+
+fn span(record: &LedgerRecord) -> EditSpan {
+    EditSpan {
+        config: record.config.clone(),
+        patch_id: record.patch_id.clone(),
+        byte_start: record.byte_start,
+        byte_end: record.byte_end,
+    }
+}
+
+/// Whether either patch `requires` or `conflicts` with the other.
+fn declared(
+    configs: &[PatchConfig],
+    first_index: usize,
+    first: &LedgerRecord,
+    second_index: usize,
+    second: &LedgerRecord,
+) -> bool {
+    let patch = |index: usize, record: &LedgerRecord| -> Option<&PatchDefinition> {
+        let config = &configs[index];
+        patch_index(config, &record.patch_id).map(|i| &config.patches[i])
+    };
+    let (Some(a), Some(b)) = (patch(first_index, first), patch(second_index, second)) else {
+        return false;
+    };
+    let (a_config, b_config) = (&configs[first_index], &configs[second_index]);
+    let names = |config: &PatchConfig, patch: &PatchDefinition, target: &PatchConfig, id: &str| {
+        [false, true].into_iter().any(|conflicts| {
+            references(config, patch, conflicts).any(|r| r.names(&target.meta.name, id))
+        })
+    };
+    names(a_config, a, b_config, &b.id) || names(b_config, b, a_config, &a.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_from_str;
+    use std::fs;
+
+    fn config(name: &str, patches: &[(&str, &str, &str)]) -> PatchConfig {
+        let mut toml = format!("[meta]\nname = \"{name}\"\nworkspace_relative = true\n");
+        for (id, search, extra) in patches {
+            toml.push_str(&format!(
+                r#"
+[[patches]]
+id = "{id}"
+file = "notes.txt"
+{extra}
+
+[patches.query]
+type = "text"
+search = "{search}"
+
+[patches.operation]
+type = "replace"
+text = "X"
+"#
+            ));
+        }
+        load_from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn reports_overlapping_and_adjacent_edits_across_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "abcdefgh").unwrap();
+        let configs = [
+            config("privacy", &[("p1", "bcd", ""), ("p2", "g", "")]),
+            config("analytics", &[("a1", "cde", ""), ("a2", "h", "")]),
+        ];
+
+        let overlaps = find_overlaps(&configs, dir.path(), "0.1.0");
+        let pairs: Vec<_> = overlaps
+            .iter()
+            .map(|o| {
+                (
+                    o.kind,
+                    o.first.patch_id.as_str(),
+                    o.second.patch_id.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                (OverlapKind::Overlapping, "p1", "a1"),
+                (OverlapKind::Adjacent, "p2", "a2"),
+            ]
+        );
+        assert_eq!(overlaps[0].file, PathBuf::from("notes.txt"));
+        assert_eq!(
+            (overlaps[0].first.byte_start, overlaps[0].first.byte_end),
+            (1, 4)
+        );
+    }
+
+    #[test]
+    fn skips_patches_that_declare_each_other() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "abcdefgh").unwrap();
+        let configs = [
+            config(
+                "privacy",
+                &[("p1", "bcd", "conflicts = [\"analytics:a1\"]")],
+            ),
+            config("analytics", &[("a1", "cde", "")]),
+        ];
+
+        assert!(find_overlaps(&configs, dir.path(), "0.1.0").is_empty());
+    }
+}
@@ -0,0 +1,403 @@
+//! Bringing patch configs up to date with the workspace.
+//!
+//! A text query that only matched through its fuzzy fallback keeps working
+//! until upstream drifts further, and every run pays for the fuzzy search.
+//! [`search_refreshes`] turns those matches into new `search` strings, and
+//! [`plan_refresh`] can also widen version requirements that exclude a
+//! workspace the patch still applies to. [`write_refreshes`] edits the
+//! config file with `toml_edit`, so comments and layout are kept.
+
+use crate::config::applicator::{simulate_patches, variant_used, PatchResult, Simulation};
+use crate::config::dependencies::patch_index;
+use crate::config::schema::{PatchConfig, PatchDefinition, Query};
+use crate::config::version::matches_requirement;
+use crate::edit::{commit_file, EditError};
+use semver::{Op, VersionReq};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, Table};
+
+/// A change to a patch config file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Refresh {
+    /// Replace a text query's `search` with the text its fuzzy match found.
+    /// `variant` is the `version` of the variant the query belongs to.
+    Search {
+        patch_id: String,
+        variant: Option<String>,
+        score: f64,
+        old: String,
+        new: String,
+    },
+    /// Widen a version requirement to include the workspace version;
+    /// `patch_id` is `None` for `meta.version_range`.
+    Version {
+        patch_id: Option<String>,
+        old: String,
+        new: String,
+    },
+}
+
+/// Errors while rewriting a patch config file
+#[derive(Debug)]
+pub enum RefreshError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Toml {
+        path: PathBuf,
+        source: toml_edit::TomlError,
+    },
+    /// The patch, or its variant, is not in the file
+    MissingPatch {
+        path: PathBuf,
+        patch_id: String,
+    },
+    Write(EditError),
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshError::Io { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)
+            }
+            RefreshError::Toml { path, source } => {
+                write!(f, "failed to parse {}: {}", path.display(), source)
+            }
+            RefreshError::MissingPatch { path, patch_id } => {
+                write!(f, "patch '{}' not found in {}", patch_id, path.display())
+            }
+            RefreshError::Write(e) => write!(f, "failed to write patch config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RefreshError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RefreshError::Io { source, .. } => Some(source),
+            RefreshError::Toml { source, .. } => Some(source),
+            RefreshError::Write(e) => Some(e),
+            RefreshError::MissingPatch { .. } => None,
+        }
+    }
+}
+
+/// A [`Refresh::Search`] for every text patch of `config` that matched
+/// fuzzily in `simulation`, in config order. Glob patches are left alone:
+/// each file may have matched different text.
+pub fn search_refreshes(
+    config: &PatchConfig,
+    simulation: &Simulation,
+    workspace_version: &str,
+) -> Vec<Refresh> {
+    simulation
+        .results
+        .iter()
+        .filter_map(|(id, _)| {
+            let new = simulation.fuzzy_matches.get(id)?;
+            let patch = config.patches.iter().find(|patch| patch.id == *id)?;
+            let variant = variant_used(config, id, workspace_version);
+            let query = match &variant {
+                Some(version) => patch
+                    .variants
+                    .iter()
+                    .find(|v| v.version == *version)
+                    .map_or(&patch.query, |v| &v.query),
+                None => &patch.query,
+            };
+            let Query::Text { search, .. } = query else {
+                return None;
+            };
+            (search != new).then(|| Refresh::Search {
+                patch_id: id.clone(),
+                variant,
+                score: simulation.fuzzy_scores.get(id).copied().unwrap_or_default(),
+                old: search.clone(),
+                new: new.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Everything `refresh` would change in `config`: [`search_refreshes`] and,
+/// with `widen`, a [`Refresh::Version`] for each version requirement that
+/// excludes `workspace_version` although the patch applies (or is applied).
+/// Patches with variants keep their requirements.
+pub fn plan_refresh(
+    config: &PatchConfig,
+    workspace_root: &Path,
+    workspace_version: &str,
+    widen: bool,
+) -> Vec<Refresh> {
+    if !widen {
+        let simulation = simulate_patches(config, workspace_root, workspace_version);
+        return search_refreshes(config, &simulation, workspace_version);
+    }
+
+    // Simulate as if no version requirement excluded the workspace.
+    let mut ungated = config.clone();
+    ungated.meta.version_range = None;
+    for patch in ungated.patches.iter_mut().filter(|p| p.variants.is_empty()) {
+        patch.version = None;
+    }
+    let simulation = simulate_patches(&ungated, workspace_root, workspace_version);
+
+    let excludes = |requirement: Option<&str>| {
+        matches!(
+            matches_requirement(workspace_version, requirement),
+            Ok(false)
+        )
+    };
+    let mut refreshes = Vec::new();
+    let mut any_matched = false;
+    for (id, result) in &simulation.results {
+        if !matches!(
+            result,
+            Ok(PatchResult::Applied { .. } | PatchResult::AlreadyApplied { .. })
+        ) {
+            continue;
+        }
+        any_matched = true;
+        let Some(patch) = patch_index(config, id).map(|i| &config.patches[i]) else {
+            continue;
+        };
+        let already = refreshes
+            .iter()
+            .any(|r| matches!(r, Refresh::Version { patch_id: Some(p), .. } if *p == patch.id));
+        if already || !patch.variants.is_empty() || !excludes(patch.version.as_deref()) {
+            continue;
+        }
+        if let Some(refresh) = widen_patch(patch, workspace_version) {
+            refreshes.push(refresh);
+        }
+    }
+    if let Some(range) = config.meta.version_range.as_deref() {
+        if any_matched && excludes(Some(range)) {
+            if let Some(new) = widen_requirement(range, workspace_version) {
+                refreshes.insert(
+                    0,
+                    Refresh::Version {
+                        patch_id: None,
+                        old: range.to_string(),
+                        new,
+                    },
+                );
+            }
+        }
+    }
+
+    refreshes.extend(search_refreshes(config, &simulation, workspace_version));
+    refreshes
+}
+
+fn widen_patch(patch: &PatchDefinition, workspace_version: &str) -> Option<Refresh> {
+    let old = patch.version.clone()?;
+    let new = widen_requirement(&old, workspace_version)?;
+    Some(Refresh::Version {
+        patch_id: Some(patch.id.clone()),
+        old,
+        new,
+    })
+}
+
+/// `requirement` with the bounds that exclude `version` moved to include
+/// it: `>=0.105.0, <0.108.0` widened to 0.109.0 is `>=0.105.0, <=0.109.0`.
+/// `None` when a comparator other than `<`, `<=`, `>` or `>=` excludes it.
+pub fn widen_requirement(requirement: &str, version: &str) -> Option<String> {
+    let req = VersionReq::parse(requirement).ok()?;
+    let comparators = req
+        .comparators
+        .iter()
+        .map(|comparator| {
+            if matches_requirement(version, Some(&comparator.to_string())).ok()? {
+                return Some(comparator.to_string());
+            }
+            match comparator.op {
+                Op::Less | Op::LessEq => Some(format!("<={}", version)),
+                Op::Greater | Op::GreaterEq => Some(format!(">={}", version)),
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<String>>>()?;
+    let widened = comparators.join(", ");
+    matches_requirement(version, Some(&widened))
+        .ok()?
+        .then_some(widened)
+}
+
+/// Apply `refreshes` to the patch config at `path`, keeping its comments
+/// and layout. A refreshed `search` also replaces a `verify.expected_text`
+/// that held the old search text.
+pub fn write_refreshes(path: &Path, refreshes: &[Refresh]) -> Result<(), RefreshError> {
+    if refreshes.is_empty() {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(path).map_err(|source| RefreshError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut doc: DocumentMut = contents.parse().map_err(|source| RefreshError::Toml {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    for refresh in refreshes {
+        let missing = |patch_id: &str| RefreshError::MissingPatch {
+            path: path.to_path_buf(),
+            patch_id: patch_id.to_string(),
+        };
+        match refresh {
+            Refresh::Search {
+                patch_id,
+                variant,
+                old,
+                new,
+                ..
+            } => {
+                let table = patch_table(&mut doc, patch_id, variant.as_deref())
+                    .ok_or_else(|| missing(patch_id))?;
+                let search = table
+                    .get_mut("query")
+                    .and_then(|query| query.get_mut("search"))
+                    .ok_or_else(|| missing(patch_id))?;
+                set_string(search, new);
+                if let Some(expected) = table
+                    .get_mut("verify")
+                    .and_then(|verify| verify.get_mut("expected_text"))
+                    .filter(|expected| expected.as_str() == Some(old.as_str()))
+                {
+                    set_string(expected, new);
+                }
+            }
+            Refresh::Version {
+                patch_id: Some(patch_id),
+                new,
+                ..
+            } => {
+                let table =
+                    patch_table(&mut doc, patch_id, None).ok_or_else(|| missing(patch_id))?;
+                set_string(&mut table["version"], new);
+            }
+            Refresh::Version {
+                patch_id: None,
+                new,
+                ..
+            } => set_string(&mut doc["meta"]["version_range"], new),
+        }
+    }
+
+    commit_file(path, doc.to_string().as_bytes()).map_err(RefreshError::Write)
+}
+
+/// The `[[patches]]` table with id `patch_id` or, for `variant`, the
+/// `[[patches.variants]]` table with that `version` (the patch's own table
+/// when its `version` is `variant`).
+fn patch_table<'a>(
+    doc: &'a mut DocumentMut,
+    patch_id: &str,
+    variant: Option<&str>,
+) -> Option<&'a mut Table> {
+    let patch = doc
+        .get_mut("patches")?
+        .as_array_of_tables_mut()?
+        .iter_mut()
+        .find(|table| table.get("id").and_then(Item::as_str) == Some(patch_id))?;
+    let Some(version) = variant else {
+        return Some(patch);
+    };
+    if patch.get("version").and_then(Item::as_str) == Some(version) {
+        return Some(patch);
+    }
+    patch
+        .get_mut("variants")?
+        .as_array_of_tables_mut()?
+        .iter_mut()
+        .find(|table| table.get("version").and_then(Item::as_str) == Some(version))
+}
+
+/// Replace a string value, keeping the comments and spacing around it.
+fn set_string(item: &mut Item, text: &str) {
+    let decor = item.as_value().map(|value| value.decor().clone());
+    *item = toml_edit::value(text);
+    if let (Some(decor), Some(value)) = (decor, item.as_value_mut()) {
+        *value.decor_mut() = decor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widens_only_the_excluding_bound() {
+        assert_eq!(
+            widen_requirement(">=0.105.0, <0.108.0", "0.109.0").as_deref(),
+            Some(">=0.105.0, <=0.109.0")
+        );
+        assert_eq!(
+            widen_requirement(">=0.105.0", "0.101.0").as_deref(),
+            Some(">=0.101.0")
+        );
+        assert_eq!(
+            widen_requirement(">=0.105.0, <0.108.0", "0.106.0").as_deref(),
+            Some(">=0.105.0, <0.108.0")
+        );
+        assert_eq!(widen_requirement("=0.105.0", "0.109.0"), None);
+        assert_eq!(widen_requirement("not a range", "0.109.0"), None);
+    }
+
+    #[test]
+    fn writes_variant_search_and_expected_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("p.toml");
+        fs::write(
+            &path,
+            r#"[[patches]]
+id = "p"
+version = ">=0.100.0"
+
+[patches.query]
+type = "text"
+search = "own"
+
+[[patches.variants]]
+version = "<0.100.0"
+# old layout
+query = { type = "text", search = "old" }
+verify = { method = "exact_match", expected_text = "old" }
+"#,
+        )
+        .unwrap();
+
+        write_refreshes(
+            &path,
+            &[Refresh::Search {
+                patch_id: "p".to_string(),
+                variant: Some("<0.100.0".to_string()),
+                score: 0.9,
+                old: "old".to_string(),
+                new: "new".to_string(),
+            }],
+        )
+        .unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.contains("search = \"own\""));
+        assert!(written.contains("# old layout"));
+        assert!(written.contains("search = \"new\""));
+        assert!(written.contains("expected_text = \"new\""));
+
+        let missing = write_refreshes(
+            &path,
+            &[Refresh::Version {
+                patch_id: Some("q".to_string()),
+                old: String::new(),
+                new: String::new(),
+            }],
+        );
+        assert!(matches!(missing, Err(RefreshError::MissingPatch { .. })));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::version::{matches_requirement, VersionError};
 use serde::Deserialize;
 use std::fmt;
 
@@ -16,6 +17,22 @@ impl PatchConfig {
         if self.patches.is_empty() {
             issues.push(ValidationIssue::EmptyPatchList);
         }
+        for reference in self.meta.requires.iter().chain(&self.meta.conflicts) {
+            if PatchRef::parse(reference).is_none() {
+                issues.push(ValidationIssue::InvalidCombo {
+                    patch_id: None,
+                    message: format!(
+                        "invalid reference \"{reference}\": expected \"config\" or \"config:patch-id\""
+                    ),
+                });
+            }
+        }
+        if self.meta.tags.iter().any(|tag| tag.trim().is_empty()) {
+            issues.push(ValidationIssue::InvalidCombo {
+                patch_id: None,
+                message: "tags must not be empty".to_string(),
+            });
+        }
 
         for patch in &self.patches {
             if patch.id.trim().is_empty() {
@@ -30,7 +47,77 @@ impl PatchConfig {
                     field: "file",
                 });
             }
+            for reference in patch.requires.iter().chain(&patch.conflicts) {
+                if PatchRef::parse(reference).is_none() {
+                    issues.push(ValidationIssue::InvalidCombo {
+                        patch_id: Some(patch.id.clone()),
+                        message: format!(
+                            "invalid reference \"{reference}\": expected \"config\" or \"config:patch-id\""
+                        ),
+                    });
+                }
+            }
+            if patch.tags.iter().any(|tag| tag.trim().is_empty()) {
+                issues.push(ValidationIssue::InvalidCombo {
+                    patch_id: Some(patch.id.clone()),
+                    message: "tags must not be empty".to_string(),
+                });
+            }
+            if let Some(both) = patch.requires.iter().find(|r| patch.conflicts.contains(r)) {
+                issues.push(ValidationIssue::InvalidCombo {
+                    patch_id: Some(patch.id.clone()),
+                    message: format!("\"{both}\" is both required and conflicting"),
+                });
+            }
+            if let Err(message) = validate_file_matches(patch) {
+                issues.push(ValidationIssue::InvalidCombo {
+                    patch_id: Some(patch.id.clone()),
+                    message,
+                });
+            }
+            if let Err(message) = validate_relocation(patch) {
+                issues.push(ValidationIssue::InvalidCombo {
+                    patch_id: Some(patch.id.clone()),
+                    message,
+                });
+            }
+
+            if let Some(context) = patch
+                .constraint
+                .as_ref()
+                .and_then(|c| c.function_context.as_deref())
+            {
+                if parse_function_context(context).is_none() {
+                    issues.push(ValidationIssue::InvalidCombo {
+                        patch_id: Some(patch.id.clone()),
+                        message: format!(
+                            "function_context must be `name` or `Type::method`, got '{context}'"
+                        ),
+                    });
+                }
+            }
+
+            if !patch.variants.is_empty() && patch.version.is_none() {
+                issues.push(ValidationIssue::InvalidCombo {
+                    patch_id: Some(patch.id.clone()),
+                    message: "a patch with variants needs its own `version`".to_string(),
+                });
+            }
+            if patch.variants.iter().any(|v| v.version.trim().is_empty()) {
+                issues.push(ValidationIssue::MissingField {
+                    patch_id: Some(patch.id.clone()),
+                    field: "variants.version",
+                });
+            }
+        }
 
+        // The query and operation checks run on every variant.
+        let versions: Vec<PatchDefinition> = self
+            .patches
+            .iter()
+            .flat_map(PatchDefinition::versions)
+            .collect();
+        for patch in &versions {
             match &patch.query {
                 Query::Toml {
                     section,
@@ -57,19 +144,36 @@ impl PatchConfig {
                                 .to_string(),
                         });
                     }
+                    if patch
+                        .constraint
+                        .as_ref()
+                        .is_some_and(|c| c.function_context.is_some())
+                    {
+                        issues.push(ValidationIssue::InvalidCombo {
+                            patch_id: Some(patch.id.clone()),
+                            message: "function_context is not supported on toml queries"
+                                .to_string(),
+                        });
+                    }
                 }
-                Query::AstGrep { pattern } | Query::TreeSitter { pattern } => {
+                Query::AstGrep { pattern, .. } | Query::TreeSitter { pattern, .. } => {
                     if pattern.trim().is_empty() {
                         issues.push(ValidationIssue::MissingField {
                             patch_id: Some(patch.id.clone()),
                             field: "query.pattern",
                         });
+                    } else if let Err(message) = validate_capture(&patch.query) {
+                        issues.push(ValidationIssue::InvalidCombo {
+                            patch_id: Some(patch.id.clone()),
+                            message,
+                        });
                     }
                 }
                 Query::Text {
                     search,
                     fuzzy_threshold,
                     fuzzy_expansion,
+                    selection,
                 } => {
                     if search.trim().is_empty() {
                         issues.push(ValidationIssue::MissingField {
@@ -99,6 +203,15 @@ impl PatchConfig {
                             });
                         }
                     }
+                    if selection.occurrence.is_some()
+                        && (fuzzy_threshold.is_some() || fuzzy_expansion.is_some())
+                    {
+                        issues.push(ValidationIssue::InvalidCombo {
+                            patch_id: Some(patch.id.clone()),
+                            message: "fuzzy matching cannot be combined with occurrence"
+                                .to_string(),
+                        });
+                    }
                 }
             }
 
@@ -170,27 +283,72 @@ impl PatchConfig {
                     }
                 }
                 Operation::Delete { insert_comment: _ } => {}
+                Operation::InsertBefore { text }
+                | Operation::InsertAfter { text }
+                | Operation::PrependToBody { text }
+                | Operation::AppendToBody { text } => {
+                    if text.trim().is_empty() {
+                        issues.push(ValidationIssue::MissingField {
+                            patch_id: Some(patch.id.clone()),
+                            field: "operation.text",
+                        });
+                    }
+                    if patch.verify.is_some() {
+                        issues.push(ValidationIssue::InvalidCombo {
+                            patch_id: Some(patch.id.clone()),
+                            message: "verify is not supported on insert operations".to_string(),
+                        });
+                    }
+                    if let Query::Text {
+                        fuzzy_threshold,
+                        fuzzy_expansion,
+                        ..
+                    } = &patch.query
+                    {
+                        if fuzzy_threshold.is_some() || fuzzy_expansion.is_some() {
+                            issues.push(ValidationIssue::InvalidCombo {
+                                patch_id: Some(patch.id.clone()),
+                                message: "fuzzy matching cannot anchor insert operations"
+                                    .to_string(),
+                            });
+                        }
+                    }
+                }
+            }
+
+            if let Some(selection) = patch.query.selection() {
+                if let Err(message) = selection.validate() {
+                    issues.push(ValidationIssue::InvalidCombo {
+                        patch_id: Some(patch.id.clone()),
+                        message,
+                    });
+                }
+            }
+
+            if let (Query::AstGrep { pattern, .. }, Operation::Replace { text }) =
+                (&patch.query, &patch.operation)
+            {
+                if let Err(message) = validate_template(pattern, text) {
+                    issues.push(ValidationIssue::InvalidCombo {
+                        patch_id: Some(patch.id.clone()),
+                        message,
+                    });
+                }
             }
 
-            let query_kind = match &patch.query {
-                Query::Toml { .. } => "toml",
-                Query::AstGrep { .. } => "ast-grep",
-                Query::TreeSitter { .. } => "tree-sitter",
-                Query::Text { .. } => "text",
-            };
-            let operation_kind = match &patch.operation {
-                Operation::InsertSection { .. } => "insert-section",
-                Operation::AppendSection { .. } => "append-section",
-                Operation::ReplaceValue { .. } => "replace-value",
-                Operation::DeleteSection => "delete-section",
-                Operation::ReplaceKey { .. } => "replace-key",
-                Operation::Replace { .. } => "replace",
-                Operation::Delete { .. } => "delete",
-            };
+            let query_kind = patch.query.type_name();
+            let operation_kind = patch.operation.type_name();
 
             let supports_combo = matches!(
                 (&patch.query, &patch.operation),
                 (Query::Text { .. }, Operation::Replace { .. })
+                    | (
+                        Query::Text { .. } | Query::AstGrep { .. } | Query::TreeSitter { .. },
+                        Operation::InsertBefore { .. }
+                            | Operation::InsertAfter { .. }
+                            | Operation::PrependToBody { .. }
+                            | Operation::AppendToBody { .. }
+                    )
                     | (
                         Query::AstGrep { .. } | Query::TreeSitter { .. },
                         Operation::Replace { .. } | Operation::Delete { .. }
@@ -233,6 +391,19 @@ pub struct Metadata {
     pub version_range: Option<String>,
     #[serde(default)]
     pub workspace_relative: bool,
+    /// Configs or patches (`"config"` / `"config:patch-id"`) every patch in
+    /// this config needs applied first.
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Configs or patches that must not be applied alongside this config.
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    /// How the patches of a file are planned (default: batched).
+    #[serde(default)]
+    pub mode: ApplyMode,
+    /// Labels shared by every patch in this config, for `--tag` selection.
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -249,6 +420,167 @@ pub struct PatchDefinition {
     /// If specified, patch is skipped when workspace version doesn't match.
     #[serde(default)]
     pub version: Option<String>,
+    /// With a glob `file`: the fewest files the query must match (default 1).
+    #[serde(default)]
+    pub min_matches: Option<usize>,
+    /// With a glob `file`: the most files the query may match.
+    #[serde(default)]
+    pub max_matches: Option<usize>,
+    /// Configs or patches (`"config"` / `"config:patch-id"`) this patch
+    /// needs applied first.
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Configs or patches that must not be applied alongside this patch.
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    /// Overrides `meta.mode` for this patch.
+    #[serde(default)]
+    pub mode: Option<ApplyMode>,
+    /// Labels for `--tag` selection, in addition to `meta.tags`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Alternatives for other upstream versions; see [`PatchVariant`].
+    #[serde(default)]
+    pub variants: Vec<PatchVariant>,
+    /// Globs searched for a structural query's target when `file` no longer
+    /// has it; see [`PatchDefinition::relocation_globs`].
+    #[serde(default)]
+    pub search_paths: Vec<String>,
+    /// Search every `.rs` file of the workspace when `file` no longer has
+    /// the structural query's target.
+    #[serde(default)]
+    pub relocate: bool,
+}
+
+/// A `[[patches.variants]]` entry: the patch's query and operation for the
+/// workspace versions `version` accepts. The patch's own `version`, query
+/// and operation count as one more variant; exactly one may match a given
+/// workspace version.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PatchVariant {
+    pub version: String,
+    pub query: Query,
+    pub operation: Operation,
+    #[serde(default)]
+    pub verify: Option<Verify>,
+}
+
+/// A `requires` / `conflicts` entry: a whole config (by `meta.name`) or one
+/// patch in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchRef<'a> {
+    pub config: &'a str,
+    pub patch: Option<&'a str>,
+}
+
+impl<'a> PatchRef<'a> {
+    /// Parse `"config"` or `"config:patch-id"`; `None` when a part is empty.
+    pub fn parse(reference: &'a str) -> Option<Self> {
+        let (config, patch) = match reference.split_once(':') {
+            Some((config, patch)) => (config.trim(), Some(patch.trim())),
+            None => (reference.trim(), None),
+        };
+        if config.is_empty() || patch.is_some_and(str::is_empty) {
+            return None;
+        }
+        Some(Self { config, patch })
+    }
+
+    /// Whether this reference covers patch `patch_id` of config `config`.
+    pub fn names(&self, config: &str, patch_id: &str) -> bool {
+        self.config == config && self.patch.is_none_or(|patch| patch == patch_id)
+    }
+}
+
+impl fmt::Display for PatchRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.patch {
+            Some(patch) => write!(f, "{}:{}", self.config, patch),
+            None => write!(f, "{}", self.config),
+        }
+    }
+}
+
+/// How a patch is planned relative to the other patches of its file.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApplyMode {
+    /// Planned against the file as read, together with the patches around
+    /// it; overlapping edits are rejected.
+    #[default]
+    Batched,
+    /// Planned against the output of every earlier patch of the file, so it
+    /// can target code those patches introduced.
+    Sequential,
+}
+
+impl PatchDefinition {
+    /// The mode this patch runs in: its own `mode`, else the config's.
+    pub fn mode(&self, meta: &Metadata) -> ApplyMode {
+        self.mode.unwrap_or(meta.mode)
+    }
+
+    /// `meta.tags` followed by this patch's own tags.
+    pub fn tags<'a>(&'a self, meta: &'a Metadata) -> impl Iterator<Item = &'a str> {
+        meta.tags.iter().chain(&self.tags).map(String::as_str)
+    }
+
+    /// Whether `file` is a glob pattern (`*`, `?` or `[...]`) rather than a
+    /// single path.
+    pub fn is_glob(&self) -> bool {
+        self.file.contains(['*', '?', '['])
+    }
+
+    /// Where to look for the query's target when `file` no longer has it:
+    /// `search_paths`, or every `.rs` file (`**/*.rs`) with `relocate`.
+    /// Empty when the patch does not relocate.
+    pub fn relocation_globs(&self) -> Vec<String> {
+        match (self.search_paths.is_empty(), self.relocate) {
+            (true, true) => vec!["**/*.rs".to_string()],
+            _ => self.search_paths.clone(),
+        }
+    }
+
+    /// The variants whose `version` accepts `workspace_version`; `None`
+    /// stands for the patch's own query and operation.
+    pub fn matching_variants(
+        &self,
+        workspace_version: &str,
+    ) -> Result<Vec<Option<&PatchVariant>>, VersionError> {
+        let mut matched = Vec::new();
+        if matches_requirement(workspace_version, self.version.as_deref())? {
+            matched.push(None);
+        }
+        for variant in &self.variants {
+            if matches_requirement(workspace_version, Some(&variant.version))? {
+                matched.push(Some(variant));
+            }
+        }
+        Ok(matched)
+    }
+
+    /// This patch with `variant`'s version, query, operation and verify in
+    /// place of its own (or just without variants, for `None`).
+    pub fn with_variant(&self, variant: Option<&PatchVariant>) -> PatchDefinition {
+        let mut patch = PatchDefinition {
+            variants: Vec::new(),
+            ..self.clone()
+        };
+        if let Some(variant) = variant {
+            patch.version = Some(variant.version.clone());
+            patch.query = variant.query.clone();
+            patch.operation = variant.operation.clone();
+            patch.verify = variant.verify.clone();
+        }
+        patch
+    }
+
+    /// The patch as written, then once per variant.
+    pub fn versions(&self) -> impl Iterator<Item = PatchDefinition> + '_ {
+        std::iter::once(None)
+            .chain(self.variants.iter().map(Some))
+            .map(|variant| self.with_variant(variant))
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -266,9 +598,21 @@ pub enum Query {
     },
     AstGrep {
         pattern: String,
+        /// Edit only this metavariable's span (`capture = "ARG"` for `$ARG`)
+        /// instead of the whole match.
+        #[serde(default)]
+        capture: Option<String>,
+        #[serde(flatten)]
+        selection: Selection,
     },
     TreeSitter {
         pattern: String,
+        /// Edit only this capture's node (`capture = "value"` for `@value`)
+        /// instead of the whole match. S-expression patterns only.
+        #[serde(default)]
+        capture: Option<String>,
+        #[serde(flatten)]
+        selection: Selection,
     },
     /// Simple text search - finds exact string match (with optional fuzzy fallback)
     Text {
@@ -284,10 +628,109 @@ pub enum Query {
         /// Default: None (fixed window = needle size, current behavior).
         #[serde(default)]
         fuzzy_expansion: Option<usize>,
+        #[serde(flatten)]
+        selection: Selection,
     },
 }
 
+/// Which matches of a text or structural query a patch edits.
+///
+/// Without `occurrence` a query must match exactly once. With it, every
+/// selected match becomes its own edit, and matches that already hold the
+/// replacement count as applied, so the patch stays idempotent per site.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Selection {
+    #[serde(default)]
+    pub occurrence: Option<Occurrence>,
+    /// Number of sites (pending plus already applied) the query must find;
+    /// anything else fails with `UnexpectedMatchCount`.
+    #[serde(default)]
+    pub expected_count: Option<usize>,
+}
+
+impl Selection {
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(expected) = self.expected_count else {
+            return Ok(());
+        };
+        match self.occurrence {
+            None => Err("expected_count requires occurrence".to_string()),
+            _ if expected == 0 => Err("expected_count must be at least 1".to_string()),
+            Some(Occurrence::Nth(n)) if n > expected => Err(format!(
+                "occurrence {n} is out of range for expected_count {expected}"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `occurrence = "all" | "first" | "last" | <n>` (1-based, in file order).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Occurrence {
+    All,
+    First,
+    Last,
+    Nth(usize),
+}
+
+impl<'de> Deserialize<'de> for Occurrence {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Keyword(String),
+            Index(i64),
+        }
+
+        let invalid = || {
+            serde::de::Error::custom(
+                "occurrence must be \"all\", \"first\", \"last\" or a positive index",
+            )
+        };
+        match Raw::deserialize(deserializer)? {
+            Raw::Keyword(keyword) => match keyword.as_str() {
+                "all" => Ok(Occurrence::All),
+                "first" => Ok(Occurrence::First),
+                "last" => Ok(Occurrence::Last),
+                _ => Err(invalid()),
+            },
+            Raw::Index(n) if n >= 1 => Ok(Occurrence::Nth(n as usize)),
+            Raw::Index(_) => Err(invalid()),
+        }
+    }
+}
+
 impl Query {
+    /// The `type` tag this query is written with in a patch file.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Query::Toml { .. } => "toml",
+            Query::AstGrep { .. } => "ast-grep",
+            Query::TreeSitter { .. } => "tree-sitter",
+            Query::Text { .. } => "text",
+        }
+    }
+
+    /// The capture a structural query narrows its edit to, if any.
+    pub fn capture(&self) -> Option<&str> {
+        match self {
+            Query::AstGrep { capture, .. } | Query::TreeSitter { capture, .. } => {
+                capture.as_deref()
+            }
+            Query::Toml { .. } | Query::Text { .. } => None,
+        }
+    }
+
+    /// Occurrence selection; `None` for TOML queries.
+    pub fn selection(&self) -> Option<&Selection> {
+        match self {
+            Query::Toml { .. } => None,
+            Query::AstGrep { selection, .. }
+            | Query::TreeSitter { selection, .. }
+            | Query::Text { selection, .. } => Some(selection),
+        }
+    }
+
     pub fn is_key_query(&self) -> bool {
         matches!(self, Query::Toml { key: Some(_), .. })
     }
@@ -328,6 +771,56 @@ pub enum Operation {
         #[serde(default)]
         insert_comment: Option<String>,
     },
+    /// Insert `text` before the located span, on its own line(s) at the
+    /// anchor's indentation when the anchor starts its line.
+    InsertBefore {
+        text: String,
+    },
+    /// Insert `text` after the located span, on the following line(s) when
+    /// the anchor ends its line.
+    InsertAfter {
+        text: String,
+    },
+    /// Insert `text` at the start of the body of the matched function, impl,
+    /// trait or module.
+    PrependToBody {
+        text: String,
+    },
+    /// Insert `text` at the end of the body of the matched function, impl,
+    /// trait or module.
+    AppendToBody {
+        text: String,
+    },
+}
+
+impl Operation {
+    /// The `type` tag this operation is written with in a patch file.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Operation::InsertSection { .. } => "insert-section",
+            Operation::AppendSection { .. } => "append-section",
+            Operation::ReplaceValue { .. } => "replace-value",
+            Operation::DeleteSection => "delete-section",
+            Operation::ReplaceKey { .. } => "replace-key",
+            Operation::Replace { .. } => "replace",
+            Operation::Delete { .. } => "delete",
+            Operation::InsertBefore { .. } => "insert-before",
+            Operation::InsertAfter { .. } => "insert-after",
+            Operation::PrependToBody { .. } => "prepend-to-body",
+            Operation::AppendToBody { .. } => "append-to-body",
+        }
+    }
+
+    /// The text an insert operation adds; `None` for every other operation.
+    pub fn insertion_text(&self) -> Option<&str> {
+        match self {
+            Operation::InsertBefore { text }
+            | Operation::InsertAfter { text }
+            | Operation::PrependToBody { text }
+            | Operation::AppendToBody { text } => Some(text),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
@@ -395,6 +888,134 @@ pub struct Constraints {
     pub function_context: Option<String>,
 }
 
+/// Split a `function_context` into an optional impl type and a function name.
+///
+/// Accepts `name` (any function or method with that name) and `Type::method`
+/// (a method defined in an `impl Type` block). Returns `None` if either part
+/// is not a plain identifier.
+pub(crate) fn parse_function_context(context: &str) -> Option<(Option<&str>, &str)> {
+    fn is_ident(s: &str) -> bool {
+        let mut chars = s.chars();
+        matches!(chars.next(), Some(c) if c == '_' || c.is_alphabetic())
+            && chars.all(|c| c == '_' || c.is_alphanumeric())
+    }
+
+    let context = context.trim();
+    let (type_name, name) = match context.split_once("::") {
+        Some((type_name, name)) => (Some(type_name), name),
+        None => (None, context),
+    };
+    if type_name.is_some_and(|t| !is_ident(t)) || !is_ident(name) {
+        return None;
+    }
+    Some((type_name, name))
+}
+
+/// Check a glob `file` pattern and its `min_matches` / `max_matches` bounds.
+fn validate_file_matches(patch: &PatchDefinition) -> Result<(), String> {
+    if !patch.is_glob() {
+        return match (patch.min_matches, patch.max_matches) {
+            (None, None) => Ok(()),
+            _ => Err("min_matches/max_matches require a glob pattern in file".to_string()),
+        };
+    }
+    glob::Pattern::new(&patch.file).map_err(|e| format!("invalid file glob: {e}"))?;
+    match (patch.min_matches, patch.max_matches) {
+        (Some(min), Some(max)) if min > max => Err(format!(
+            "min_matches {min} is greater than max_matches {max}"
+        )),
+        (_, Some(0)) => Err("max_matches must be at least 1".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Check `search_paths` / `relocate`: structural queries only (in every
+/// variant), a single `file`, and valid globs.
+fn validate_relocation(patch: &PatchDefinition) -> Result<(), String> {
+    if patch.relocation_globs().is_empty() {
+        return Ok(());
+    }
+    let structural =
+        |query: &Query| matches!(query, Query::AstGrep { .. } | Query::TreeSitter { .. });
+    if !structural(&patch.query) || !patch.variants.iter().all(|v| structural(&v.query)) {
+        return Err("search_paths/relocate require an ast-grep or tree-sitter query".to_string());
+    }
+    if patch.is_glob() {
+        return Err("search_paths/relocate cannot be combined with a glob file".to_string());
+    }
+    for path in &patch.search_paths {
+        glob::Pattern::new(path).map_err(|e| format!("invalid search_paths glob '{path}': {e}"))?;
+    }
+    Ok(())
+}
+
+/// Check that a structural query's `capture` names a capture its pattern
+/// defines.
+fn validate_capture(query: &Query) -> Result<(), String> {
+    match query {
+        Query::AstGrep {
+            pattern,
+            capture: Some(capture),
+            ..
+        } => {
+            let defined = crate::sg::pattern_metavars(pattern)
+                .map_err(|e| format!("ast-grep pattern does not compile: {e}"))?;
+            if defined.iter().any(|name| name == capture) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "capture \"{capture}\" is not a metavariable of the pattern"
+                ))
+            }
+        }
+        Query::TreeSitter {
+            pattern,
+            capture: Some(capture),
+            ..
+        } => {
+            if !pattern.trim_start().starts_with('(') {
+                return Err("capture requires an S-expression tree-sitter pattern".to_string());
+            }
+            let engine = crate::ts::QueryEngine::new(pattern)
+                .map_err(|e| format!("tree-sitter pattern does not compile: {e}"))?;
+            if engine.capture_names().iter().any(|name| name == capture) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "capture \"{capture}\" is not a capture of the pattern"
+                ))
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that every `$NAME` / `$$$NAME` an ast-grep replacement references
+/// is captured by its pattern. Replacements without references are literal.
+fn validate_template(pattern: &str, text: &str) -> Result<(), String> {
+    let vars = crate::sg::template_metavars(text);
+    if vars.is_empty() {
+        return Ok(());
+    }
+    let defined = crate::sg::pattern_metavars(pattern)
+        .map_err(|e| format!("ast-grep pattern does not compile: {e}"))?;
+
+    let mut missing: Vec<&str> = Vec::new();
+    for var in vars {
+        if !defined.iter().any(|name| name == var.name) && !missing.contains(&var.name) {
+            missing.push(var.name);
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let missing: Vec<String> = missing.iter().map(|name| format!("${name}")).collect();
+    Err(format!(
+        "replacement references {} not captured by the pattern",
+        missing.join(", ")
+    ))
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "method", rename_all = "snake_case")]
 pub enum Verify {
@@ -0,0 +1,407 @@
+//! Where the workspace version comes from.
+//!
+//! Codex's workspace `Cargo.toml` usually carries a placeholder version
+//! (`0.0.0`); the real version lives in release tags such as
+//! `rust-v0.93.0-alpha.4`. A [`VersionSource`] reads the version one way;
+//! [`resolve_version`] tries several in order. The order is chosen per
+//! project in the `[version]` table of the project config file:
+//!
+//! ```toml
+//! [version]
+//! sources = ["file", "git-tag", "cargo"]
+//! file = "CODEX_VERSION"
+//! tag_prefix = "rust-v"
+//! ```
+
+use semver::Version;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Version file read by the `file` source unless `[version] file` is set.
+pub const DEFAULT_VERSION_FILE: &str = "VERSION";
+
+/// Tag prefix matched by the `git-tag` source unless `[version] tag_prefix`
+/// is set.
+pub const DEFAULT_TAG_PREFIX: &str = "rust-v";
+
+/// Version cargo packages carry when the real one is set at release time.
+const PLACEHOLDER_VERSION: &str = "0.0.0";
+
+/// Errors while resolving the workspace version
+#[derive(Debug, Clone)]
+pub enum VersionSourceError {
+    /// A source found a value that is not a semver version
+    InvalidVersion { source: String, value: String },
+    /// No source produced a version; one line per source tried
+    Unresolved { tried: Vec<String> },
+}
+
+impl fmt::Display for VersionSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionSourceError::InvalidVersion { source, value } => {
+                write!(
+                    f,
+                    "{} gave '{}', which is not a semver version",
+                    source, value
+                )
+            }
+            VersionSourceError::Unresolved { tried } => {
+                write!(f, "no version source matched ({})", tried.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for VersionSourceError {}
+
+/// The workspace version and where it was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedVersion {
+    pub version: String,
+    /// Human-readable origin, e.g. `git tag rust-v0.93.0`
+    pub source: String,
+}
+
+/// What one source found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Detection {
+    Found(ResolvedVersion),
+    /// Nothing usable here; the reason is shown if no source matches
+    Missing(String),
+}
+
+/// One way of reading the workspace version.
+pub trait VersionSource {
+    /// Short name used in messages, e.g. `cargo metadata`
+    fn name(&self) -> String;
+
+    fn detect(&self, workspace: &Path) -> Detection;
+}
+
+/// Try `sources` in order and return the first version found.
+///
+/// A source that finds a value which does not parse as semver stops the
+/// search: a broken tag or version file should be fixed, not skipped.
+pub fn resolve_version(
+    sources: &[Box<dyn VersionSource>],
+    workspace: &Path,
+) -> Result<ResolvedVersion, VersionSourceError> {
+    let mut tried = Vec::new();
+    for source in sources {
+        match source.detect(workspace) {
+            Detection::Found(resolved) => {
+                if Version::parse(&resolved.version).is_err() {
+                    return Err(VersionSourceError::InvalidVersion {
+                        source: resolved.source,
+                        value: resolved.version,
+                    });
+                }
+                return Ok(resolved);
+            }
+            Detection::Missing(reason) => tried.push(format!("{}: {}", source.name(), reason)),
+        }
+    }
+    Err(VersionSourceError::Unresolved { tried })
+}
+
+/// A version given on the command line (`--version`).
+#[derive(Debug, Clone)]
+pub struct Explicit(pub String);
+
+impl VersionSource for Explicit {
+    fn name(&self) -> String {
+        "--version".to_string()
+    }
+
+    fn detect(&self, _workspace: &Path) -> Detection {
+        Detection::Found(ResolvedVersion {
+            version: self.0.trim().to_string(),
+            source: self.name(),
+        })
+    }
+}
+
+/// The version of the workspace's cargo packages. The placeholder `0.0.0`
+/// counts as no version.
+#[derive(Debug, Clone, Default)]
+pub struct CargoMetadata;
+
+impl VersionSource for CargoMetadata {
+    fn name(&self) -> String {
+        "cargo metadata".to_string()
+    }
+
+    fn detect(&self, workspace: &Path) -> Detection {
+        let manifest_path = workspace.join("Cargo.toml");
+        let found = match cargo_version(&manifest_path) {
+            Ok(version) => version,
+            Err(reason) => return Detection::Missing(reason),
+        };
+        if found == PLACEHOLDER_VERSION {
+            return Detection::Missing(format!("placeholder version {}", found));
+        }
+        Detection::Found(ResolvedVersion {
+            version: found,
+            source: format!("cargo metadata ({})", manifest_path.display()),
+        })
+    }
+}
+
+fn cargo_version(manifest_path: &Path) -> Result<String, String> {
+    use cargo_metadata::MetadataCommand;
+
+    // Prefer `cargo metadata` when available since it handles workspaces and
+    // inherited workspace.package versions correctly. However, it can fail in
+    // minimal / mock workspaces (tests) or when the manifest is incomplete.
+    if let Ok(metadata) = MetadataCommand::new().manifest_path(manifest_path).exec() {
+        // Try workspace packages first (for multi-crate workspaces)
+        if let Some(pkg) = metadata.workspace_packages().first() {
+            return Ok(pkg.version.to_string());
+        }
+
+        // Try root package (for single-crate projects)
+        if let Some(resolve) = &metadata.resolve {
+            if let Some(root) = &resolve.root {
+                if let Some(pkg) = metadata.packages.iter().find(|p| &p.id == root) {
+                    return Ok(pkg.version.to_string());
+                }
+            }
+        }
+
+        // Fallback: use first package
+        if let Some(pkg) = metadata.packages.first() {
+            return Ok(pkg.version.to_string());
+        }
+    }
+
+    // Fallback: parse Cargo.toml directly (works for partial fixtures).
+    let manifest = fs::read_to_string(manifest_path)
+        .map_err(|e| format!("cannot read {}: {}", manifest_path.display(), e))?;
+    let doc = manifest
+        .parse::<toml_edit::DocumentMut>()
+        .map_err(|e| format!("failed to parse {}: {}", manifest_path.display(), e))?;
+
+    doc.get("package")
+        .and_then(|pkg| pkg.get("version"))
+        .or_else(|| {
+            doc.get("workspace")
+                .and_then(|ws| ws.get("package"))
+                .and_then(|pkg| pkg.get("version"))
+        })
+        .and_then(|v| v.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| format!("no version in {}", manifest_path.display()))
+}
+
+/// The nearest `git describe` tag starting with `prefix`, e.g.
+/// `rust-v0.93.0-alpha.4` gives `0.93.0-alpha.4`.
+#[derive(Debug, Clone)]
+pub struct GitTag {
+    pub prefix: String,
+}
+
+impl Default for GitTag {
+    fn default() -> Self {
+        Self {
+            prefix: DEFAULT_TAG_PREFIX.to_string(),
+        }
+    }
+}
+
+impl VersionSource for GitTag {
+    fn name(&self) -> String {
+        "git tag".to_string()
+    }
+
+    fn detect(&self, workspace: &Path) -> Detection {
+        let output = Command::new("git")
+            .args(["describe", "--tags", "--abbrev=0", "--match"])
+            .arg(format!("{}*", self.prefix))
+            .current_dir(workspace)
+            .output();
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            Ok(_) => return Detection::Missing(format!("no {}* tag", self.prefix)),
+            Err(e) => return Detection::Missing(format!("cannot run git: {}", e)),
+        };
+        let tag = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        match parse_tag(&tag, &self.prefix) {
+            Some(version) => Detection::Found(ResolvedVersion {
+                version: version.to_string(),
+                source: format!("git tag {}", tag),
+            }),
+            None => Detection::Missing(format!("tag {} does not start with {}", tag, self.prefix)),
+        }
+    }
+}
+
+/// The version part of a release tag: `rust-v0.93.0` with prefix `rust-v`
+/// gives `0.93.0`.
+pub fn parse_tag<'a>(tag: &'a str, prefix: &str) -> Option<&'a str> {
+    tag.strip_prefix(prefix)
+        .filter(|version| !version.is_empty())
+}
+
+/// A file holding the version, e.g. written by a release script. Relative
+/// paths are resolved against the workspace; a leading `v` is ignored.
+#[derive(Debug, Clone)]
+pub struct VersionFile(pub PathBuf);
+
+impl VersionSource for VersionFile {
+    fn name(&self) -> String {
+        format!("version file {}", self.0.display())
+    }
+
+    fn detect(&self, workspace: &Path) -> Detection {
+        let path = workspace.join(&self.0);
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let version = contents.trim();
+                Detection::Found(ResolvedVersion {
+                    version: version.strip_prefix('v').unwrap_or(version).to_string(),
+                    source: format!("version file {}", path.display()),
+                })
+            }
+            Err(e) => Detection::Missing(format!("cannot read {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// A source named in `[version] sources`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceKind {
+    Cargo,
+    GitTag,
+    File,
+}
+
+/// The `[version]` table of the project config file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VersionSettings {
+    /// Sources to try, in order
+    #[serde(default = "default_sources")]
+    pub sources: Vec<SourceKind>,
+    /// File read by the `file` source, relative to the workspace
+    #[serde(default = "default_file")]
+    pub file: PathBuf,
+    /// Tag prefix matched by the `git-tag` source
+    #[serde(default = "default_tag_prefix")]
+    pub tag_prefix: String,
+}
+
+fn default_sources() -> Vec<SourceKind> {
+    vec![SourceKind::Cargo, SourceKind::GitTag]
+}
+
+fn default_file() -> PathBuf {
+    PathBuf::from(DEFAULT_VERSION_FILE)
+}
+
+fn default_tag_prefix() -> String {
+    DEFAULT_TAG_PREFIX.to_string()
+}
+
+impl Default for VersionSettings {
+    fn default() -> Self {
+        Self {
+            sources: default_sources(),
+            file: default_file(),
+            tag_prefix: default_tag_prefix(),
+        }
+    }
+}
+
+impl VersionSettings {
+    /// The configured sources, preceded by `explicit` (`--version`) when
+    /// given.
+    pub fn sources(&self, explicit: Option<&str>) -> Vec<Box<dyn VersionSource>> {
+        let configured = self.sources.iter().map(|kind| -> Box<dyn VersionSource> {
+            match kind {
+                SourceKind::Cargo => Box::new(CargoMetadata),
+                SourceKind::GitTag => Box::new(GitTag {
+                    prefix: self.tag_prefix.clone(),
+                }),
+                SourceKind::File => Box::new(VersionFile(self.file.clone())),
+            }
+        });
+        explicit
+            .map(|version| Box::new(Explicit(version.to_string())) as Box<dyn VersionSource>)
+            .into_iter()
+            .chain(configured)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn parses_release_tags() {
+        assert_eq!(parse_tag("rust-v0.93.0", "rust-v"), Some("0.93.0"));
+        assert_eq!(
+            parse_tag("rust-v0.93.0-alpha.4", "rust-v"),
+            Some("0.93.0-alpha.4")
+        );
+        assert_eq!(parse_tag("v0.93.0", "rust-v"), None);
+        assert_eq!(parse_tag("rust-v", "rust-v"), None);
+    }
+
+    #[test]
+    fn skips_placeholder_cargo_version_for_git_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cargo.toml"),
+            "[workspace.package]\nversion = \"0.0.0\"\n",
+        )
+        .unwrap();
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["commit", "-q", "--allow-empty", "-m", "init"]);
+        git(dir.path(), &["tag", "rust-v0.93.0-alpha.4"]);
+
+        let settings = VersionSettings::default();
+        let resolved = resolve_version(&settings.sources(None), dir.path()).unwrap();
+        assert_eq!(resolved.version, "0.93.0-alpha.4");
+        assert_eq!(resolved.source, "git tag rust-v0.93.0-alpha.4");
+
+        let resolved = resolve_version(&settings.sources(Some("0.90.0")), dir.path()).unwrap();
+        assert_eq!(resolved.version, "0.90.0");
+        assert_eq!(resolved.source, "--version");
+    }
+
+    #[test]
+    fn reads_version_file_and_rejects_bad_values() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("VERSION"), "v0.91.2\n").unwrap();
+        let settings: VersionSettings = toml_edit::de::from_str("sources = [\"file\"]").unwrap();
+
+        let resolved = resolve_version(&settings.sources(None), dir.path()).unwrap();
+        assert_eq!(resolved.version, "0.91.2");
+
+        assert!(matches!(
+            resolve_version(&settings.sources(Some("latest")), dir.path()),
+            Err(VersionSourceError::InvalidVersion { .. })
+        ));
+
+        let empty = tempfile::tempdir().unwrap();
+        let err = resolve_version(&settings.sources(None), empty.path()).unwrap_err();
+        assert!(matches!(err, VersionSourceError::Unresolved { ref tried } if tried.len() == 1));
+    }
+}
@@ -244,12 +244,8 @@ impl Edit {
         // Validate resulting content is valid UTF-8
         std::str::from_utf8(&new_content).map_err(|_| EditError::InvalidUtf8Edit)?;
 
-        // Atomic write: tempfile in same directory, fsync, rename
-        atomic_write(&self.file, &new_content)?;
-
-        // Update mtime to invalidate incremental compilation
-        let now = filetime::FileTime::now();
-        filetime::set_file_mtime(&self.file, now)?;
+        // Atomic write (tempfile + fsync + rename), then bump mtime
+        commit_file(&self.file, &new_content)?;
 
         Ok(EditResult::Applied {
             file: self.file.clone(),
@@ -301,6 +297,22 @@ impl Edit {
 
         Ok(results)
     }
+
+    /// Apply a batch of edits for a single file to an in-memory buffer.
+    ///
+    /// Performs the same validation, overlap and idempotency checks as
+    /// [`Edit::apply_batch`] but never touches the filesystem; `content` is the
+    /// current file contents. Results are returned in byte_start-descending
+    /// order, matching `apply_batch`.
+    pub fn apply_batch_in_memory(
+        content: &str,
+        mut edits: Vec<Edit>,
+    ) -> Result<(String, Vec<EditResult>), EditError> {
+        edits.sort_by_key(|e| std::cmp::Reverse(e.byte_start));
+        let (new_content, results) = splice_edits(content.as_bytes(), &edits)?;
+        let new_content = String::from_utf8(new_content).map_err(|_| EditError::InvalidUtf8Edit)?;
+        Ok((new_content, results))
+    }
 }
 
 /// Apply multiple edits to a single file atomically.
@@ -314,9 +326,24 @@ fn apply_file_edits(edits: &[Edit]) -> Result<Vec<EditResult>, EditError> {
     let file = &edits[0].file;
     let original_content = fs::read(file)?;
 
+    let (new_content, results) = splice_edits(&original_content, edits)?;
+
+    commit_file(file, &new_content)?;
+
+    Ok(results)
+}
+
+/// Validate and splice a batch of same-file edits into `original_content`
+/// without touching the filesystem.
+///
+/// Assumes edits are sorted by byte_start descending.
+fn splice_edits(
+    original_content: &[u8],
+    edits: &[Edit],
+) -> Result<(Vec<u8>, Vec<EditResult>), EditError> {
     // Validate all edits first
     for edit in edits {
-        edit.validate(&original_content)?;
+        edit.validate(original_content)?;
     }
 
     // Verify descending sort invariant before applying
@@ -340,7 +367,7 @@ fn apply_file_edits(edits: &[Edit]) -> Result<Vec<EditResult>, EditError> {
     }
 
     // Apply edits bottom-to-top (already sorted descending)
-    let mut new_content = original_content.clone();
+    let mut new_content = original_content.to_vec();
     let mut results = Vec::with_capacity(edits.len());
 
     for edit in edits {
@@ -370,14 +397,18 @@ fn apply_file_edits(edits: &[Edit]) -> Result<Vec<EditResult>, EditError> {
     // Validate resulting content is valid UTF-8
     std::str::from_utf8(&new_content).map_err(|_| EditError::InvalidUtf8Edit)?;
 
-    // Atomic write
-    atomic_write(file, &new_content)?;
+    Ok((new_content, results))
+}
+
+/// Atomically replace `path` with `content` and bump its mtime so cargo's
+/// incremental compilation notices the change.
+pub(crate) fn commit_file(path: &Path, content: &[u8]) -> Result<(), EditError> {
+    atomic_write(path, content)?;
 
-    // Update mtime
     let now = filetime::FileTime::now();
-    filetime::set_file_mtime(file, now)?;
+    filetime::set_file_mtime(path, now)?;
 
-    Ok(results)
+    Ok(())
 }
 
 /// Atomic file write: tempfile + fsync + rename.
@@ -511,4 +542,31 @@ mod tests {
         let content = fs::read_to_string(&file_path).unwrap();
         assert_eq!(content, "LINE1\nLINE2\nLINE3\n");
     }
+
+    #[test]
+    fn test_batch_edits_in_memory() {
+        let content = "line1\nline2\nline3\n";
+        let edits = vec![
+            Edit::new("test.txt", 0, 5, "LINE1", "line1"),
+            Edit::new("test.txt", 12, 17, "line3", "line3"),
+        ];
+
+        let (patched, results) = Edit::apply_batch_in_memory(content, edits).unwrap();
+        assert_eq!(patched, "LINE1\nline2\nline3\n");
+        // Descending byte_start order, matching apply_batch
+        assert!(matches!(results[0], EditResult::AlreadyApplied { .. }));
+        assert!(matches!(results[1], EditResult::Applied { .. }));
+    }
+
+    #[test]
+    fn test_batch_edits_in_memory_rejects_overlap() {
+        let content = "hello world";
+        let edits = vec![
+            Edit::new("test.txt", 0, 7, "HELLO W", "hello w"),
+            Edit::new("test.txt", 6, 11, "WORLD", "world"),
+        ];
+
+        let result = Edit::apply_batch_in_memory(content, edits);
+        assert!(matches!(result, Err(EditError::InvalidByteRange { .. })));
+    }
 }
@@ -76,8 +76,126 @@ pub fn find_best_match_elastic(
     best
 }
 
+/// The `limit` best-scoring windows of `needle.lines().count()` lines at or
+/// above `threshold`, best first. Windows do not overlap; a lower-scoring
+/// window overlapping a better one is dropped.
+///
+/// Used to suggest near misses when a search matches nothing, so the
+/// threshold is typically well below the one used for applying.
+pub fn find_top_matches(
+    needle: &str,
+    haystack: &str,
+    threshold: f64,
+    limit: usize,
+) -> Vec<FuzzyMatch> {
+    let window_size = needle.lines().count();
+    let (lines, offsets) = build_haystack_info(haystack);
+    if window_size == 0 || lines.len() < window_size {
+        return Vec::new();
+    }
+
+    let mut scored: Vec<FuzzyMatch> = (0..=lines.len() - window_size)
+        .filter_map(|i| {
+            let window = lines[i..i + window_size].join("\n");
+            let score = normalized_levenshtein(needle, &window);
+            (score >= threshold).then(|| FuzzyMatch {
+                start: offsets[i],
+                end: window_end(haystack, &offsets, i + window_size),
+                matched_text: window,
+                score,
+            })
+        })
+        .collect();
+    // Stable: equal scores keep file order.
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let mut picked: Vec<FuzzyMatch> = Vec::new();
+    for candidate in scored {
+        if picked.len() == limit {
+            break;
+        }
+        if picked
+            .iter()
+            .all(|p| candidate.end <= p.start || candidate.start >= p.end)
+        {
+            picked.push(candidate);
+        }
+    }
+    picked
+}
+
+/// The `limit` best stretches of a single line as long as the single-line
+/// `needle` (surrounding whitespace aside) at or above `threshold`, best
+/// first, at most one per line.
+///
+/// Catches a search that is a fragment of a longer line, which whole-line
+/// windows ([`find_top_matches`]) score too low. Stretches start on token
+/// boundaries.
+pub fn find_top_partial_matches(
+    needle: &str,
+    haystack: &str,
+    threshold: f64,
+    limit: usize,
+) -> Vec<FuzzyMatch> {
+    let needle = needle.trim();
+    let width = needle.chars().count();
+    if width == 0 || needle.contains('\n') {
+        return Vec::new();
+    }
+    let (lines, offsets) = build_haystack_info(haystack);
+
+    let mut scored: Vec<FuzzyMatch> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let chars: Vec<(usize, char)> = line.char_indices().collect();
+            if chars.len() <= width {
+                return None;
+            }
+            (0..=chars.len() - width)
+                .filter(|&c| {
+                    let current = chars[c].1;
+                    let starts_token = c == 0 || !is_word(chars[c - 1].1) || !is_word(current);
+                    starts_token && !current.is_whitespace()
+                })
+                .map(|c| {
+                    let start = chars[c].0;
+                    let end = chars.get(c + width).map_or(line.len(), |&(b, _)| b);
+                    (
+                        normalized_levenshtein(needle, &line[start..end]),
+                        start,
+                        end,
+                    )
+                })
+                // First of equal scores: the leftmost stretch.
+                .fold(
+                    None,
+                    |best: Option<(f64, usize, usize)>, candidate| match best {
+                        Some(b) if b.0 >= candidate.0 => Some(b),
+                        _ => Some(candidate),
+                    },
+                )
+                .filter(|&(score, _, _)| score >= threshold)
+                .map(|(score, start, end)| FuzzyMatch {
+                    start: offsets[i] + start,
+                    end: offsets[i] + end,
+                    matched_text: line[start..end].to_string(),
+                    score,
+                })
+        })
+        .collect();
+    // Stable: equal scores keep file order.
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scored.truncate(limit);
+    scored
+}
+
 // ── Internal helpers ──────────────────────────────────────────────────────────
 
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
 /// Pre-compute the line slice vector and byte-offset table for `haystack`.
 ///
 /// `line_offsets[i]` is the byte index of the start of line `i`.
@@ -96,6 +214,17 @@ fn build_haystack_info(haystack: &str) -> (Vec<&str>, Vec<usize>) {
     (lines, offsets)
 }
 
+/// End byte of a window ending before line `next_line`: the start of that
+/// line minus the preceding '\n'. The sentinel entry in `line_offsets`
+/// guarantees the index always exists.
+fn window_end(haystack: &str, line_offsets: &[usize], next_line: usize) -> usize {
+    if next_line < line_offsets.len() {
+        line_offsets[next_line].saturating_sub(1)
+    } else {
+        haystack.len()
+    }
+}
+
 /// Slide a window of exactly `window_size` lines over `haystack_lines`,
 /// scoring each window against `needle` with normalized Levenshtein distance.
 /// Returns the best-scoring window that is at or above `threshold`.
@@ -118,18 +247,9 @@ fn slide_window(
         let score = normalized_levenshtein(needle, &window);
 
         if score >= threshold && best.as_ref().is_none_or(|b| score > b.score) {
-            let start = line_offsets[i];
-            // End byte: start of the line *after* our window, minus the preceding '\n'.
-            // The sentinel entry in `line_offsets` guarantees this index always exists.
-            let end = if i + window_size < line_offsets.len() {
-                line_offsets[i + window_size].saturating_sub(1)
-            } else {
-                haystack.len()
-            };
-
             best = Some(FuzzyMatch {
-                start,
-                end,
+                start: line_offsets[i],
+                end: window_end(haystack, line_offsets, i + window_size),
                 matched_text: window,
                 score,
             });
@@ -270,4 +390,34 @@ mod tests {
         // haystack has 2 lines, needle has 5 — no window fits even at expansion=0
         assert!(find_best_match_elastic(needle, haystack, 0.5, 20).is_none());
     }
+
+    #[test]
+    fn top_matches_are_ranked_and_disjoint() {
+        let needle = "let total = a + b;";
+        let haystack = "let total = a + c;\nlet other = 1;\nlet totals = a + b;\nfn f() {}\n";
+        let matches = find_top_matches(needle, haystack, 0.7, 3);
+        let found: Vec<&str> = matches.iter().map(|m| &haystack[m.start..m.end]).collect();
+        assert_eq!(found, ["let totals = a + b;", "let total = a + c;"]);
+        assert!(matches[0].score > matches[1].score);
+        assert_eq!(find_top_matches(needle, haystack, 0.7, 1).len(), 1);
+        assert!(find_top_matches(needle, "", 0.5, 3).is_empty());
+    }
+
+    #[test]
+    fn partial_matches_find_fragments_of_longer_lines() {
+        let needle = "timeout(Duration::from_secs(60))";
+        let haystack = "let c = Client::builder().timeout(Duration::from_secs(30)).build()?;\n\
+                        let retries = 3;\n";
+        // The whole line is far too long to score.
+        assert!(find_top_matches(needle, haystack, 0.6, 3).is_empty());
+
+        let matches = find_top_partial_matches(needle, haystack, 0.6, 3);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].matched_text, "timeout(Duration::from_secs(30))");
+        assert_eq!(
+            &haystack[matches[0].start..matches[0].end],
+            matches[0].matched_text
+        );
+        assert!(find_top_partial_matches("a\nb", haystack, 0.1, 3).is_empty());
+    }
 }
@@ -0,0 +1,418 @@
+//! Write-ahead journal for transactional multi-file applies.
+//!
+//! `Edit::apply_batch` is atomic per file, but a patch config can touch many
+//! files. A [`Journal`] stages the original and patched contents of every file
+//! under `<workspace>/.codex-patcher/journal/` before anything is written, so
+//! that a failed commit can be rolled back and an interrupted one recovered.
+//!
+//! Layout:
+//! - `journal.json`: state plus one entry per staged file
+//! - `<n>.orig` / `<n>.new`: original and patched contents of entry `n`
+
+use crate::edit::{commit_file, EditError};
+use crate::safety::{SafetyError, WorkspaceGuard};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Directory (relative to the workspace root) holding codex-patcher state.
+pub const STATE_DIR: &str = ".codex-patcher";
+
+const JOURNAL_DIR: &str = "journal";
+const MANIFEST: &str = "journal.json";
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("an interrupted transaction journal exists at {0}; run `codex-patcher recover`")]
+    Pending(PathBuf),
+
+    #[error("journal I/O error on {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("corrupt journal manifest {path}: {source}")]
+    Corrupt {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error("{path} changed since the journal was written; refusing to overwrite it")]
+    Drift { path: PathBuf },
+
+    #[error(transparent)]
+    Edit(#[from] EditError),
+
+    #[error(transparent)]
+    Safety(#[from] SafetyError),
+
+    #[error("commit failed ({cause}); rollback also failed ({rollback})")]
+    RollbackFailed {
+        cause: Box<JournalError>,
+        rollback: Box<JournalError>,
+    },
+}
+
+/// How far a journaled transaction got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalState {
+    /// Contents are staged; no workspace file has been written yet.
+    Prepared,
+    /// Workspace files are being written; some may already be patched.
+    Committing,
+}
+
+/// One staged file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Canonical path of the workspace file
+    pub path: PathBuf,
+    /// xxh3 hash of the original contents
+    pub original_hash: u64,
+    /// xxh3 hash of the patched contents
+    pub patched_hash: u64,
+}
+
+/// A file to be written as part of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagedFile {
+    pub path: PathBuf,
+    pub original: String,
+    pub patched: String,
+}
+
+/// What recovery should do with an interrupted journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoverAction {
+    /// Restore every file to its original contents.
+    Rollback,
+    /// Finish the transaction by writing every patched file.
+    Replay,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    state: JournalState,
+    entries: Vec<JournalEntry>,
+}
+
+/// An on-disk transaction journal.
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    state: JournalState,
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Journal directory for a workspace.
+    pub fn dir_for(workspace_root: &Path) -> PathBuf {
+        workspace_root.join(STATE_DIR).join(JOURNAL_DIR)
+    }
+
+    /// Stage `files` into a new journal.
+    ///
+    /// Fails with [`JournalError::Pending`] if an earlier journal was never
+    /// completed; it must be recovered first.
+    pub fn begin(workspace_root: &Path, files: &[StagedFile]) -> Result<Self, JournalError> {
+        let dir = Self::dir_for(workspace_root);
+        if dir.join(MANIFEST).exists() {
+            return Err(JournalError::Pending(dir));
+        }
+        fs::create_dir_all(&dir).map_err(|source| JournalError::Io {
+            path: dir.clone(),
+            source,
+        })?;
+
+        let mut entries = Vec::with_capacity(files.len());
+        for (index, file) in files.iter().enumerate() {
+            write_blob(&dir.join(format!("{index}.orig")), &file.original)?;
+            write_blob(&dir.join(format!("{index}.new")), &file.patched)?;
+            entries.push(JournalEntry {
+                path: file.path.clone(),
+                original_hash: xxh3_64(file.original.as_bytes()),
+                patched_hash: xxh3_64(file.patched.as_bytes()),
+            });
+        }
+
+        let journal = Self {
+            dir,
+            state: JournalState::Prepared,
+            entries,
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Open the pending journal for a workspace, if there is one.
+    pub fn open(workspace_root: &Path) -> Result<Option<Self>, JournalError> {
+        let dir = Self::dir_for(workspace_root);
+        let manifest_path = dir.join(MANIFEST);
+        let raw = match fs::read_to_string(&manifest_path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => {
+                return Err(JournalError::Io {
+                    path: manifest_path,
+                    source,
+                })
+            }
+        };
+        let manifest: Manifest =
+            serde_json::from_str(&raw).map_err(|source| JournalError::Corrupt {
+                path: manifest_path,
+                source,
+            })?;
+        Ok(Some(Self {
+            dir,
+            state: manifest.state,
+            entries: manifest.entries,
+        }))
+    }
+
+    pub fn state(&self) -> JournalState {
+        self.state
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Write every staged file, rolling all of them back if any write fails.
+    ///
+    /// On success the journal is removed. If the rollback itself fails the
+    /// journal is kept so that [`Journal::recover`] can finish the job.
+    pub fn commit(mut self, guard: &WorkspaceGuard) -> Result<(), JournalError> {
+        self.state = JournalState::Committing;
+        self.save()?;
+
+        let mut written = 0;
+        let outcome = self
+            .entries
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, entry)| {
+                let path = guard.revalidate(&entry.path)?;
+                let patched = self.read_blob(index, "new")?;
+                commit_file(&path, patched.as_bytes())?;
+                written += 1;
+                Ok(())
+            });
+
+        match outcome {
+            Ok(()) => self.finish(),
+            Err(cause) => match self.restore(guard, RecoverAction::Rollback, false, written) {
+                Ok(()) => {
+                    self.finish()?;
+                    Err(cause)
+                }
+                Err(rollback) => Err(JournalError::RollbackFailed {
+                    cause: Box::new(cause),
+                    rollback: Box::new(rollback),
+                }),
+            },
+        }
+    }
+
+    /// Complete an interrupted journal by rolling back or replaying it.
+    ///
+    /// Files whose current contents match neither the original nor the
+    /// patched hash are reported as [`JournalError::Drift`] unless `force`.
+    pub fn recover(
+        self,
+        guard: &WorkspaceGuard,
+        action: RecoverAction,
+        force: bool,
+    ) -> Result<(), JournalError> {
+        self.restore(guard, action, force, self.entries.len())?;
+        self.finish()
+    }
+
+    /// Bring the first `count` entries to the state selected by `action`.
+    fn restore(
+        &self,
+        guard: &WorkspaceGuard,
+        action: RecoverAction,
+        force: bool,
+        count: usize,
+    ) -> Result<(), JournalError> {
+        for (index, entry) in self.entries.iter().enumerate().take(count) {
+            let (blob, wanted, other_hash) = match action {
+                RecoverAction::Rollback => ("orig", entry.original_hash, entry.patched_hash),
+                RecoverAction::Replay => ("new", entry.patched_hash, entry.original_hash),
+            };
+            let current = fs::read(&entry.path).ok().map(|c| xxh3_64(&c));
+            if current == Some(wanted) {
+                continue;
+            }
+            if current != Some(other_hash) && !force {
+                return Err(JournalError::Drift {
+                    path: entry.path.clone(),
+                });
+            }
+            let path = match guard.revalidate(&entry.path) {
+                Ok(path) => path,
+                // A deleted file cannot be canonicalized; validate its parent.
+                Err(SafetyError::Canonicalize(_)) if current.is_none() => {
+                    let parent = entry.path.parent().unwrap_or(&entry.path);
+                    guard
+                        .validate_path(parent)?
+                        .join(entry.path.file_name().unwrap_or(entry.path.as_os_str()))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let contents = self.read_blob(index, blob)?;
+            commit_file(&path, contents.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), JournalError> {
+        let manifest = Manifest {
+            state: self.state,
+            entries: self.entries.clone(),
+        };
+        let raw =
+            serde_json::to_string_pretty(&manifest).map_err(|source| JournalError::Corrupt {
+                path: self.dir.join(MANIFEST),
+                source,
+            })?;
+        write_blob(&self.dir.join(MANIFEST), &raw)
+    }
+
+    fn read_blob(&self, index: usize, kind: &str) -> Result<String, JournalError> {
+        let path = self.dir.join(format!("{index}.{kind}"));
+        fs::read_to_string(&path).map_err(|source| JournalError::Io { path, source })
+    }
+
+    fn finish(self) -> Result<(), JournalError> {
+        fs::remove_dir_all(&self.dir).map_err(|source| JournalError::Io {
+            path: self.dir,
+            source,
+        })
+    }
+}
+
+fn write_blob(path: &Path, contents: &str) -> Result<(), JournalError> {
+    commit_file(path, contents.as_bytes()).map_err(JournalError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(dir: &Path, name: &str, original: &str, patched: &str) -> StagedFile {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, original).unwrap();
+        StagedFile {
+            path: path.canonicalize().unwrap(),
+            original: original.to_string(),
+            patched: patched.to_string(),
+        }
+    }
+
+    #[test]
+    fn commit_writes_all_files_and_removes_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = WorkspaceGuard::new(dir.path()).unwrap();
+        let files = vec![
+            stage(dir.path(), "a.rs", "a", "A"),
+            stage(dir.path(), "b/b.rs", "b", "B"),
+        ];
+
+        let journal = Journal::begin(dir.path(), &files).unwrap();
+        assert_eq!(journal.state(), JournalState::Prepared);
+        journal.commit(&guard).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "A");
+        assert_eq!(fs::read_to_string(dir.path().join("b/b.rs")).unwrap(), "B");
+        assert!(Journal::open(dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_commit_rolls_back_written_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = WorkspaceGuard::new(dir.path()).unwrap();
+        let files = vec![
+            stage(dir.path(), "a.rs", "a", "A"),
+            stage(dir.path(), "gone/b.rs", "b", "B"),
+        ];
+
+        let journal = Journal::begin(dir.path(), &files).unwrap();
+        fs::remove_dir_all(dir.path().join("gone")).unwrap();
+
+        assert!(journal.commit(&guard).is_err());
+        assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "a");
+        assert!(Journal::open(dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn pending_journal_blocks_new_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = vec![stage(dir.path(), "a.rs", "a", "A")];
+
+        let _journal = Journal::begin(dir.path(), &files).unwrap();
+        assert!(matches!(
+            Journal::begin(dir.path(), &files),
+            Err(JournalError::Pending(_))
+        ));
+    }
+
+    #[test]
+    fn recover_rolls_back_or_replays_interrupted_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = WorkspaceGuard::new(dir.path()).unwrap();
+        let files = vec![
+            stage(dir.path(), "a.rs", "a", "A"),
+            stage(dir.path(), "b.rs", "b", "B"),
+        ];
+
+        // Simulate a crash after the first file was written.
+        Journal::begin(dir.path(), &files).unwrap();
+        fs::write(dir.path().join("a.rs"), "A").unwrap();
+
+        let journal = Journal::open(dir.path()).unwrap().unwrap();
+        journal
+            .recover(&guard, RecoverAction::Rollback, false)
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.path().join("b.rs")).unwrap(), "b");
+        assert!(Journal::open(dir.path()).unwrap().is_none());
+
+        Journal::begin(dir.path(), &files).unwrap();
+        fs::write(dir.path().join("a.rs"), "A").unwrap();
+        let journal = Journal::open(dir.path()).unwrap().unwrap();
+        journal
+            .recover(&guard, RecoverAction::Replay, false)
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "A");
+        assert_eq!(fs::read_to_string(dir.path().join("b.rs")).unwrap(), "B");
+    }
+
+    #[test]
+    fn recover_refuses_drifted_files_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = WorkspaceGuard::new(dir.path()).unwrap();
+        let files = vec![stage(dir.path(), "a.rs", "a", "A")];
+
+        Journal::begin(dir.path(), &files).unwrap();
+        fs::write(dir.path().join("a.rs"), "edited by hand").unwrap();
+
+        let journal = Journal::open(dir.path()).unwrap().unwrap();
+        assert!(matches!(
+            journal.recover(&guard, RecoverAction::Rollback, false),
+            Err(JournalError::Drift { .. })
+        ));
+
+        let journal = Journal::open(dir.path()).unwrap().unwrap();
+        journal
+            .recover(&guard, RecoverAction::Rollback, true)
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("a.rs")).unwrap(), "a");
+    }
+}
@@ -0,0 +1,349 @@
+//! Persistent record of the edits codex-patcher has made to a workspace.
+//!
+//! Every successful apply (and revert) appends one [`LedgerRecord`] per edit to
+//! `<workspace>/.codex-patcher/ledger.jsonl`. The ledger answers "which patches
+//! are in this tree, and did we put them there" without re-running queries,
+//! and detects hand edits to a patched region by hash.
+//!
+//! Each record stores its span in the coordinates of the file as it was just
+//! before that edit. To find where a patched region lives today, the edits
+//! recorded after it in the same file are replayed over its span.
+
+use crate::journal::STATE_DIR;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use xxhash_rust::xxh3::xxh3_64;
+
+const LEDGER_FILE: &str = "ledger.jsonl";
+
+#[derive(Error, Debug)]
+pub enum LedgerError {
+    #[error("ledger I/O error on {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("corrupt ledger record at {path}:{line}: {source}")]
+    Corrupt {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+/// Whether a record adds or removes a patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerAction {
+    Apply,
+    Revert,
+}
+
+/// One edit made to a workspace file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerRecord {
+    /// `meta.name` of the patch config
+    pub config: String,
+    pub patch_id: String,
+    pub action: LedgerAction,
+    /// File path, relative to the workspace root when inside it
+    pub file: PathBuf,
+    /// Start of the replaced span, in the file as it was before this edit
+    pub byte_start: usize,
+    /// End of the replaced span, in the file as it was before this edit
+    pub byte_end: usize,
+    /// Length of the text written in place of the span
+    pub new_len: usize,
+    /// xxh3 of the replaced text (`0x`-prefixed hex)
+    pub before_hash: String,
+    /// xxh3 of the written text (`0x`-prefixed hex)
+    pub after_hash: String,
+    pub workspace_version: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl LedgerRecord {
+    /// Byte span of the written text, in the file as it was right after this edit.
+    pub fn written_span(&self) -> (usize, usize) {
+        (self.byte_start, self.byte_start + self.new_len)
+    }
+
+    fn delta(&self) -> isize {
+        self.new_len as isize - (self.byte_end - self.byte_start) as isize
+    }
+}
+
+/// Format an xxh3 hash the way the ledger stores it.
+pub fn hash_hex(bytes: &[u8]) -> String {
+    format!("{:#018x}", xxh3_64(bytes))
+}
+
+/// Current seconds since the Unix epoch, for [`LedgerRecord::timestamp`].
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// State of a patched region according to the ledger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LedgerState {
+    /// The written text is still in place.
+    Intact,
+    /// The last recorded action for the patch was a revert.
+    Reverted,
+    /// The region no longer hashes to what was written.
+    Drifted,
+    /// A later recorded edit replaced part of the region.
+    Overwritten { by: String },
+    /// The file no longer exists.
+    Missing,
+}
+
+/// The latest record for a patch and what the workspace looks like now.
+#[derive(Debug, Clone)]
+pub struct LedgerEntry {
+    pub record: LedgerRecord,
+    pub state: LedgerState,
+}
+
+/// An apply ledger loaded from disk.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    records: Vec<LedgerRecord>,
+}
+
+impl Ledger {
+    /// Ledger file for a workspace.
+    pub fn path_for(workspace_root: &Path) -> PathBuf {
+        workspace_root.join(STATE_DIR).join(LEDGER_FILE)
+    }
+
+    /// Append records to the workspace ledger, creating it if needed.
+    pub fn append(workspace_root: &Path, records: &[LedgerRecord]) -> Result<(), LedgerError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let path = Self::path_for(workspace_root);
+        let io_err = |source| LedgerError::Io {
+            path: path.clone(),
+            source,
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_err)?;
+        }
+        let mut lines = String::new();
+        for record in records {
+            // Serializing plain data into a String cannot fail.
+            lines.push_str(&serde_json::to_string(record).expect("ledger record serializes"));
+            lines.push('\n');
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(io_err)?;
+        file.write_all(lines.as_bytes()).map_err(io_err)?;
+        file.sync_all().map_err(io_err)
+    }
+
+    /// Load the workspace ledger; a missing ledger is empty.
+    pub fn load(workspace_root: &Path) -> Result<Self, LedgerError> {
+        let path = Self::path_for(workspace_root);
+        let raw = match fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(source) => return Err(LedgerError::Io { path, source }),
+        };
+
+        let mut records = Vec::new();
+        for (index, line) in raw.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(line).map_err(|source| LedgerError::Corrupt {
+                path: path.clone(),
+                line: index + 1,
+                source,
+            })?;
+            records.push(record);
+        }
+        Ok(Self { records })
+    }
+
+    pub fn records(&self) -> &[LedgerRecord] {
+        &self.records
+    }
+
+    /// The latest record for every patch, with its current state.
+    ///
+    /// Entries are ordered by when each patch was last recorded.
+    pub fn entries(&self, workspace_root: &Path) -> Vec<LedgerEntry> {
+        let mut latest: HashMap<(&str, &str, &Path), usize> = HashMap::new();
+        for (index, record) in self.records.iter().enumerate() {
+            latest.insert((&record.config, &record.patch_id, &record.file), index);
+        }
+        let mut indices: Vec<usize> = latest.into_values().collect();
+        indices.sort_unstable();
+
+        let mut contents: HashMap<&Path, Option<String>> = HashMap::new();
+        indices
+            .into_iter()
+            .map(|index| {
+                let record = &self.records[index];
+                let content = contents
+                    .entry(&record.file)
+                    .or_insert_with(|| fs::read_to_string(workspace_root.join(&record.file)).ok());
+                let state = match (record.action, content) {
+                    (LedgerAction::Revert, _) => LedgerState::Reverted,
+                    (LedgerAction::Apply, None) => LedgerState::Missing,
+                    (LedgerAction::Apply, Some(content)) => self.state_of(index, content),
+                };
+                LedgerEntry {
+                    record: record.clone(),
+                    state,
+                }
+            })
+            .collect()
+    }
+
+    fn state_of(&self, index: usize, content: &str) -> LedgerState {
+        let (start, end) = match current_span(&self.records, index) {
+            Ok(span) => span,
+            Err(by) => return LedgerState::Overwritten { by: by.to_string() },
+        };
+
+        match content.as_bytes().get(start..end) {
+            Some(bytes) if hash_hex(bytes) == self.records[index].after_hash => LedgerState::Intact,
+            _ => LedgerState::Drifted,
+        }
+    }
+}
+
+/// Byte span of the text written by `records[index]`, after replaying every
+/// later record for the same file over it.
+///
+/// Returns the id of the patch that overwrote part of the text when a later
+/// record overlaps it. Records of one batch must be in descending
+/// `byte_start` order, as the applicator writes them.
+pub fn current_span(records: &[LedgerRecord], index: usize) -> Result<(usize, usize), &str> {
+    let record = &records[index];
+    let (mut start, mut end) = record.written_span();
+
+    for later in &records[index + 1..] {
+        if later.file != record.file {
+            continue;
+        }
+        if later.byte_start >= end {
+            continue;
+        }
+        // Entirely before the region (an insertion at its start included).
+        if later.byte_end <= start {
+            start = start.saturating_add_signed(later.delta());
+            end = end.saturating_add_signed(later.delta());
+            continue;
+        }
+        return Err(&later.patch_id);
+    }
+
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(patch_id: &str, start: usize, end: usize, new_text: &str) -> LedgerRecord {
+        LedgerRecord {
+            config: "cfg".to_string(),
+            patch_id: patch_id.to_string(),
+            action: LedgerAction::Apply,
+            file: PathBuf::from("lib.rs"),
+            byte_start: start,
+            byte_end: end,
+            new_len: new_text.len(),
+            before_hash: hash_hex(b""),
+            after_hash: hash_hex(new_text.as_bytes()),
+            workspace_version: "0.1.0".to_string(),
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn append_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Ledger::load(dir.path()).unwrap().records().is_empty());
+
+        let records = [record("a", 0, 1, "x"), record("b", 2, 3, "y")];
+        Ledger::append(dir.path(), &records[..1]).unwrap();
+        Ledger::append(dir.path(), &records[1..]).unwrap();
+
+        assert_eq!(Ledger::load(dir.path()).unwrap().records(), &records[..]);
+    }
+
+    #[test]
+    fn entries_track_shifts_and_drift() {
+        let dir = tempfile::tempdir().unwrap();
+        // "fn a() {}" -> "fn a() { A }" at 0..9, then an insertion before it.
+        let records = vec![
+            record("a", 0, 9, "fn a() { A }"),
+            record("header", 0, 0, "// header\n"),
+        ];
+        Ledger::append(dir.path(), &records).unwrap();
+        fs::write(dir.path().join("lib.rs"), "// header\nfn a() { A }\n").unwrap();
+
+        let ledger = Ledger::load(dir.path()).unwrap();
+        let states: Vec<_> = ledger
+            .entries(dir.path())
+            .into_iter()
+            .map(|e| (e.record.patch_id, e.state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("a".to_string(), LedgerState::Intact),
+                ("header".to_string(), LedgerState::Intact),
+            ]
+        );
+
+        fs::write(dir.path().join("lib.rs"), "// header\nfn a() { B }\n").unwrap();
+        let entries = ledger.entries(dir.path());
+        assert_eq!(entries[0].state, LedgerState::Drifted);
+        assert_eq!(entries[1].state, LedgerState::Intact);
+    }
+
+    #[test]
+    fn entries_report_reverts_and_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), "fn a() { C }").unwrap();
+
+        let mut revert = record("b", 0, 1, "x");
+        revert.action = LedgerAction::Revert;
+        let records = vec![
+            record("a", 0, 9, "fn a() { A }"),
+            record("c", 7, 10, " C "),
+            record("b", 0, 0, "x"),
+            revert,
+        ];
+        Ledger::append(dir.path(), &records).unwrap();
+
+        let entries = Ledger::load(dir.path()).unwrap().entries(dir.path());
+        assert_eq!(
+            entries[0].state,
+            LedgerState::Overwritten {
+                by: "c".to_string()
+            }
+        );
+        assert_eq!(entries[2].state, LedgerState::Reverted);
+    }
+}
@@ -43,7 +43,10 @@ pub mod compiler;
 pub mod config;
 pub mod edit;
 pub mod fuzzy;
+pub mod journal;
+pub mod ledger;
 pub mod pool;
+pub mod report;
 pub mod safety;
 pub mod sg;
 pub mod toml;
@@ -1,13 +1,27 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use codex_patcher::compiler::{
+    attribute_errors, fix_step, owning_packages, run_cargo_check_packages, CompileDiagnostic,
+};
 use codex_patcher::config::{
-    apply_patches, check_patches, load_from_path, ApplicationError, PatchResult,
+    apply_patches_atomic_with, apply_patches_detailed, find_overlaps, load_from_path,
+    matches_requirement, order_patches, plan_refresh, resolve_version, revert_patches_detailed,
+    search_refreshes, simulate_patches, simulate_revert, variant_used, write_refreshes,
+    ApplicationError, AtomicApply, EditSpan, Metadata, NearMiss, Outcomes, Overlap, OverlapKind,
+    PatchConfig, PatchDefinition, PatchFilter, PatchResult, ProjectConfig, Refresh,
+    ResolvedVersion, Simulation, Step, TransactionError, VersionSourceError, PROJECT_CONFIG_FILE,
+};
+use codex_patcher::journal::{Journal, JournalError, RecoverAction};
+use codex_patcher::ledger::{Ledger, LedgerAction, LedgerRecord, LedgerState};
+use codex_patcher::report::{
+    BrokenPatchReport, BuildCheckReport, BuildErrorReport, ConfigReport, Event, LedgerReport,
+    ListedPatchReport, OverlapReport, PatchReport, RefreshReport, Report, Summary,
+    TransactionReport, SCHEMA_VERSION,
 };
+use codex_patcher::WorkspaceGuard;
 use colored::Colorize;
 use similar::{ChangeTag, TextDiff};
-use std::collections::HashMap;
 use std::env;
-use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use walkdir::WalkDir;
@@ -17,10 +31,25 @@ use walkdir::WalkDir;
 #[command(about = "Automated code patching system for Rust", long_about = None)]
 #[command(version)]
 struct Cli {
+    /// Output format (json and ndjson follow the schema in docs/api.md)
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// How command results are written to stdout.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Colored human-readable text
+    Text,
+    /// One JSON document once the command finishes
+    Json,
+    /// One JSON event per line, streamed as patches are processed
+    Ndjson,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Apply patches to a workspace
@@ -40,6 +69,32 @@ enum Commands {
         /// Show unified diff of changes
         #[arg(short, long)]
         diff: bool,
+
+        /// Apply all patch files as one transaction: write every file or none
+        #[arg(long)]
+        atomic: bool,
+
+        /// Run `cargo check` on the packages owning the patched files afterwards
+        #[arg(long, conflicts_with = "dry_run")]
+        check_build: bool,
+
+        /// With --check-build: revert the patches whose code fails to compile
+        #[arg(long, requires = "check_build")]
+        revert_broken: bool,
+
+        /// Refuse to apply when edits of different patch files overlap
+        #[arg(long)]
+        deny_overlaps: bool,
+
+        /// Rewrite the `search` text of fuzzily matched patches in their patch files
+        #[arg(long, conflicts_with = "dry_run")]
+        refresh_patches: bool,
+
+        #[command(flatten)]
+        select: SelectArgs,
+
+        #[command(flatten)]
+        version: VersionArgs,
     },
 
     /// Check status of patches without applying
@@ -47,6 +102,16 @@ enum Commands {
         /// Path to workspace root (auto-detected if not specified)
         #[arg(short, long)]
         workspace: Option<PathBuf>,
+
+        /// Report only from the apply ledger, without evaluating patch queries
+        #[arg(long, conflicts_with_all = ["only", "exclude", "tag", "profile", "version"])]
+        ledger: bool,
+
+        #[command(flatten)]
+        select: SelectArgs,
+
+        #[command(flatten)]
+        version: VersionArgs,
     },
 
     /// Verify patches are applicable to current workspace
@@ -54,10 +119,127 @@ enum Commands {
         /// Path to workspace root (auto-detected if not specified)
         #[arg(short, long)]
         workspace: Option<PathBuf>,
+
+        #[command(flatten)]
+        select: SelectArgs,
+
+        #[command(flatten)]
+        version: VersionArgs,
+    },
+
+    /// Find edits of different patch files that overlap in the same file
+    Conflicts {
+        /// Path to workspace root (auto-detected if not specified)
+        #[arg(short, long)]
+        workspace: Option<PathBuf>,
+
+        #[command(flatten)]
+        version: VersionArgs,
     },
 
     /// List available patches and their version constraints
-    List,
+    List {
+        /// Path to workspace root (auto-detected if not specified)
+        #[arg(short, long)]
+        workspace: Option<PathBuf>,
+
+        /// Specific patch file to list (otherwise lists all in patches/)
+        #[arg(short, long)]
+        patches: Option<PathBuf>,
+
+        /// Only patches whose target file contains this path
+        #[arg(long, value_name = "PATH")]
+        file: Option<String>,
+
+        /// Only patches using this query type
+        #[arg(long, value_parser = ["text", "ast-grep", "tree-sitter", "toml"])]
+        query_type: Option<String>,
+
+        /// Only patches whose version constraints accept this version
+        #[arg(long, value_name = "VERSION")]
+        applies_to: Option<String>,
+
+        #[command(flatten)]
+        select: SelectArgs,
+    },
+
+    /// Rewrite stale `search` text (and, optionally, version ranges) in patch files
+    Refresh {
+        /// Path to workspace root (auto-detected if not specified)
+        #[arg(short, long)]
+        workspace: Option<PathBuf>,
+
+        /// Specific patch file to refresh (otherwise refreshes all in patches/)
+        #[arg(short, long)]
+        patches: Option<PathBuf>,
+
+        /// Dry run - show what would be changed without modifying patch files
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Widen `version` requirements that exclude the workspace version
+        /// when the patch still applies
+        #[arg(long)]
+        widen_version: bool,
+
+        #[command(flatten)]
+        select: SelectArgs,
+
+        #[command(flatten)]
+        version: VersionArgs,
+    },
+
+    /// Undo applied patches, restoring the original code
+    Revert {
+        /// Path to workspace root (auto-detected if not specified)
+        #[arg(short, long)]
+        workspace: Option<PathBuf>,
+
+        /// Specific patch file to revert (otherwise reverts all in patches/)
+        #[arg(short, long)]
+        patches: Option<PathBuf>,
+
+        /// Dry run - show what would be reverted without modifying files
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Show unified diff of changes
+        #[arg(short, long)]
+        diff: bool,
+
+        #[command(flatten)]
+        version: VersionArgs,
+    },
+
+    /// Run `cargo check` and apply compiler auto-fixes until the build is clean
+    Fix {
+        /// Path to workspace root (auto-detected if not specified)
+        #[arg(short, long)]
+        workspace: Option<PathBuf>,
+
+        /// Only check this package (passed to `cargo check -p`)
+        #[arg(short = 'P', long)]
+        package: Option<String>,
+
+        /// Give up after this many check/fix rounds
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        max_iterations: u32,
+    },
+
+    /// Finish or undo an interrupted `apply --atomic` transaction
+    Recover {
+        /// Path to workspace root (auto-detected if not specified)
+        #[arg(short, long)]
+        workspace: Option<PathBuf>,
+
+        /// Complete the transaction instead of rolling it back
+        #[arg(long)]
+        replay: bool,
+
+        /// Overwrite files that changed since the journal was written
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 fn main() -> Result<()> {
@@ -69,13 +251,329 @@ fn main() -> Result<()> {
             patches,
             dry_run,
             diff,
-        } => cmd_apply(workspace, patches, dry_run, diff),
+            atomic,
+            check_build,
+            revert_broken,
+            deny_overlaps,
+            refresh_patches,
+            select,
+            version,
+        } => {
+            let gate = match (check_build, revert_broken) {
+                (false, _) => BuildGate::Off,
+                (true, false) => BuildGate::Check,
+                (true, true) => BuildGate::CheckAndRevert,
+            };
+            let options = ApplyOptions {
+                dry_run,
+                show_diff: diff,
+                atomic,
+                gate,
+                deny_overlaps,
+                refresh_patches,
+            };
+            cmd_apply(workspace, patches, &options, &select, &version, cli.format)
+        }
+
+        Commands::Conflicts { workspace, version } => {
+            cmd_conflicts(workspace, &version, cli.format)
+        }
+
+        Commands::Status {
+            workspace,
+            ledger,
+            select,
+            version,
+        } => cmd_status(workspace, ledger, &select, &version, cli.format),
+
+        Commands::Verify {
+            workspace,
+            select,
+            version,
+        } => cmd_verify(workspace, &select, &version, cli.format),
+
+        Commands::List {
+            workspace,
+            patches,
+            file,
+            query_type,
+            applies_to,
+            select,
+        } => {
+            let filter = ListFilter {
+                file,
+                query_type,
+                applies_to,
+            };
+            cmd_list(workspace, patches, &filter, &select, cli.format)
+        }
+
+        Commands::Revert {
+            workspace,
+            patches,
+            dry_run,
+            diff,
+            version,
+        } => cmd_revert(workspace, patches, dry_run, diff, &version, cli.format),
+
+        Commands::Refresh {
+            workspace,
+            patches,
+            dry_run,
+            widen_version,
+            select,
+            version,
+        } => cmd_refresh(
+            workspace,
+            patches,
+            dry_run,
+            widen_version,
+            &select,
+            &version,
+            cli.format,
+        ),
+
+        Commands::Fix {
+            workspace,
+            package,
+            max_iterations,
+        } => {
+            if cli.format != Format::Text {
+                anyhow::bail!("fix only supports --format text");
+            }
+            cmd_fix(workspace, package.as_deref(), max_iterations)
+        }
+
+        Commands::Recover {
+            workspace,
+            replay,
+            force,
+        } => {
+            if cli.format != Format::Text {
+                anyhow::bail!("recover only supports --format text");
+            }
+            cmd_recover(workspace, replay, force)
+        }
+    }
+}
+
+/// Patch selection flags shared by `apply`, `status`, `verify` and `list`.
+#[derive(Args)]
+struct SelectArgs {
+    /// Only these configs or patches (`config`, `patch-id` or `config:patch-id`)
+    #[arg(long, value_name = "REF", value_delimiter = ',')]
+    only: Vec<String>,
+
+    /// Skip these configs or patches
+    #[arg(long, value_name = "REF", value_delimiter = ',')]
+    exclude: Vec<String>,
+
+    /// Only patches carrying one of these tags
+    #[arg(long, value_name = "TAG", value_delimiter = ',')]
+    tag: Vec<String>,
+
+    /// Add the selection of a profile from codex-patcher.toml
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+}
+
+impl SelectArgs {
+    /// Combine the flags with the requested profile, read from the project
+    /// config file in the workspace or the current directory.
+    fn filter(&self, workspace: &Path) -> Result<PatchFilter> {
+        let mut filter = PatchFilter {
+            only: self.only.clone(),
+            exclude: self.exclude.clone(),
+            tags: self.tag.clone(),
+        };
+        let Some(name) = &self.profile else {
+            return Ok(filter);
+        };
+
+        let Some(path) = find_project_config(workspace) else {
+            anyhow::bail!(
+                "--profile {} needs a {} in {} or the current directory",
+                name,
+                PROJECT_CONFIG_FILE,
+                workspace.display()
+            );
+        };
+        let project = ProjectConfig::load(&path)?;
+        let Some(profile) = project.profiles.get(name) else {
+            let known: Vec<&str> = project.profiles.keys().map(String::as_str).collect();
+            anyhow::bail!(
+                "unknown profile {} in {} (available: {})",
+                name,
+                path.display(),
+                known.join(", ")
+            );
+        };
+        filter.extend(profile);
+        Ok(filter)
+    }
+}
+
+/// Version flag shared by the commands that match `version_range`.
+#[derive(Args)]
+struct VersionArgs {
+    /// Use this workspace version instead of detecting it
+    #[arg(long, value_name = "VERSION")]
+    version: Option<String>,
+}
 
-        Commands::Status { workspace } => cmd_status(workspace),
+impl VersionArgs {
+    /// Resolve the workspace version from `--version` or the sources in the
+    /// project config file (cargo metadata, then git tags, by default).
+    /// Falls back to 0.0.0 with a warning when no source has a version.
+    fn resolve(&self, workspace: &Path) -> Result<ResolvedVersion> {
+        let settings = match find_project_config(workspace) {
+            Some(path) => ProjectConfig::load(&path)?.version,
+            None => Default::default(),
+        };
+        match resolve_version(&settings.sources(self.version.as_deref()), workspace) {
+            Ok(resolved) => Ok(resolved),
+            Err(VersionSourceError::Unresolved { tried }) => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Warning: Could not determine the workspace version ({}), using 0.0.0",
+                        tried.join("; ")
+                    )
+                    .yellow()
+                );
+                Ok(ResolvedVersion {
+                    version: "0.0.0".to_string(),
+                    source: "fallback".to_string(),
+                })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The project config file in the workspace, else in the current directory.
+fn find_project_config(workspace: &Path) -> Option<PathBuf> {
+    std::iter::once(workspace.join(PROJECT_CONFIG_FILE))
+        .chain(
+            env::current_dir()
+                .ok()
+                .map(|cwd| cwd.join(PROJECT_CONFIG_FILE)),
+        )
+        .find(|path| path.exists())
+}
+
+/// Load `patch_files`, keeping the patches `filter` selects with the file
+/// each config came from. Fails when a non-empty filter selects nothing,
+/// which is usually a typo.
+fn load_selected(
+    patch_files: &[PathBuf],
+    filter: &PatchFilter,
+) -> Result<Vec<(PathBuf, PatchConfig)>> {
+    let mut selected = Vec::new();
+    for patch_file in patch_files {
+        if let Some(config) = filter.select(&load_from_path(patch_file)?) {
+            selected.push((patch_file.clone(), config));
+        }
+    }
+    if selected.is_empty() && !filter.is_empty() {
+        anyhow::bail!("no patches match the selection");
+    }
+    Ok(selected)
+}
+
+/// Flags of `apply`.
+struct ApplyOptions {
+    dry_run: bool,
+    show_diff: bool,
+    atomic: bool,
+    gate: BuildGate,
+    /// Refuse to apply when [`find_overlaps`] reports anything
+    deny_overlaps: bool,
+    /// Write [`search_refreshes`] back to the patch files
+    refresh_patches: bool,
+}
+
+/// Post-apply compile check requested with `apply --check-build`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BuildGate {
+    Off,
+    Check,
+    /// Also revert the patches whose written code fails to compile
+    CheckAndRevert,
+}
+
+/// Writes report events to stdout in the selected machine-readable format.
+///
+/// In text mode events are dropped; commands print their own output when
+/// [`Reporter::human`] is true.
+struct Reporter {
+    format: Format,
+    report: Report,
+}
+
+impl Reporter {
+    fn new(format: Format, command: &str) -> Self {
+        Self {
+            format,
+            report: Report::new(command),
+        }
+    }
+
+    fn human(&self) -> bool {
+        self.format == Format::Text
+    }
+
+    fn start(
+        &mut self,
+        workspace: &Path,
+        workspace_version: Option<&ResolvedVersion>,
+        dry_run: bool,
+    ) {
+        let command = self.report.command.clone();
+        self.emit(Event::Start {
+            schema_version: SCHEMA_VERSION,
+            command,
+            workspace: workspace.to_path_buf(),
+            workspace_version: workspace_version.map(|v| v.version.clone()),
+            version_source: workspace_version.map(|v| v.source.clone()),
+            dry_run,
+        });
+    }
 
-        Commands::Verify { workspace } => cmd_verify(workspace),
+    fn emit(&mut self, event: Event) {
+        match self.format {
+            Format::Text => {}
+            Format::Json => self.report.push(event),
+            // Serializing plain data into a String cannot fail.
+            Format::Ndjson => println!(
+                "{}",
+                serde_json::to_string(&event).expect("report event serializes")
+            ),
+        }
+    }
+
+    /// Report a problem that does not fail the run.
+    fn warn(&mut self, message: String) {
+        if self.human() {
+            eprintln!("  {}", format!("warning: {}", message).yellow());
+        }
+        self.emit(Event::Warning { message });
+    }
+
+    /// Report a ledger append that failed after the files were written.
+    fn ledger_error(&mut self, error: Option<&String>) {
+        if let Some(error) = error {
+            self.warn(format!("ledger not updated: {}", error));
+        }
+    }
 
-        Commands::List => cmd_list(),
+    /// Emit the closing summary and, for `json`, print the whole report.
+    fn finish(mut self, summary: Summary, ok: bool) -> Result<()> {
+        self.emit(Event::Summary { summary, ok });
+        if self.format == Format::Json {
+            println!("{}", serde_json::to_string_pretty(&self.report)?);
+        }
+        Ok(())
     }
 }
 
@@ -152,7 +650,7 @@ fn resolve_workspace(cli_workspace: Option<PathBuf>) -> Result<PathBuf> {
 
     // 3. Auto-detect from current directory
     if let Some(path) = auto_detect_workspace() {
-        println!(
+        eprintln!(
             "{}",
             format!("Auto-detected workspace: {}", path.display()).dimmed()
         );
@@ -161,7 +659,7 @@ fn resolve_workspace(cli_workspace: Option<PathBuf>) -> Result<PathBuf> {
 
     // 4. Git remote detection
     if let Some(path) = find_codex_via_git() {
-        println!(
+        eprintln!(
             "{}",
             format!("Found Codex workspace via git: {}", path.display()).dimmed()
         );
@@ -255,62 +753,6 @@ fn find_codex_via_git() -> Option<PathBuf> {
     }
 }
 
-/// Helper: Read workspace version from Cargo.toml
-fn read_workspace_version(workspace: &Path) -> Result<String> {
-    use cargo_metadata::MetadataCommand;
-
-    let manifest_path = workspace.join("Cargo.toml");
-
-    // Prefer `cargo metadata` when available since it handles workspaces and
-    // inherited workspace.package versions correctly. However, it can fail in
-    // minimal / mock workspaces (tests) or when the manifest is incomplete.
-    if let Ok(metadata) = MetadataCommand::new().manifest_path(&manifest_path).exec() {
-        // Try workspace packages first (for multi-crate workspaces)
-        if let Some(pkg) = metadata.workspace_packages().first() {
-            return Ok(pkg.version.to_string());
-        }
-
-        // Try root package (for single-crate projects)
-        if let Some(resolve) = &metadata.resolve {
-            if let Some(root) = &resolve.root {
-                if let Some(pkg) = metadata.packages.iter().find(|p| &p.id == root) {
-                    return Ok(pkg.version.to_string());
-                }
-            }
-        }
-
-        // Fallback: use first package
-        if let Some(pkg) = metadata.packages.first() {
-            return Ok(pkg.version.to_string());
-        }
-    }
-
-    // Fallback: parse Cargo.toml directly (works for partial fixtures).
-    let manifest = fs::read_to_string(&manifest_path)?;
-    let doc = manifest
-        .parse::<toml_edit::DocumentMut>()
-        .map_err(|e| anyhow::anyhow!("failed to parse {}: {e}", manifest_path.display()))?;
-
-    if let Some(version) = doc
-        .get("package")
-        .and_then(|pkg| pkg.get("version"))
-        .and_then(|v| v.as_str())
-    {
-        return Ok(version.to_string());
-    }
-
-    if let Some(version) = doc
-        .get("workspace")
-        .and_then(|ws| ws.get("package"))
-        .and_then(|pkg| pkg.get("version"))
-        .and_then(|v| v.as_str())
-    {
-        return Ok(version.to_string());
-    }
-
-    anyhow::bail!("Could not read version from {}", manifest_path.display())
-}
-
 /// Helper: Show unified diff between original and modified content
 fn display_diff(file: &Path, original: &str, modified: &str) {
     println!(
@@ -334,11 +776,25 @@ fn display_diff(file: &Path, original: &str, modified: &str) {
 fn cmd_apply(
     workspace: Option<PathBuf>,
     patches: Option<PathBuf>,
-    dry_run: bool,
-    show_diff: bool,
+    options: &ApplyOptions,
+    select: &SelectArgs,
+    version: &VersionArgs,
+    format: Format,
 ) -> Result<()> {
+    let mut out = Reporter::new(format, "apply");
+    let ApplyOptions {
+        dry_run,
+        atomic,
+        gate,
+        deny_overlaps,
+        refresh_patches,
+        ..
+    } = *options;
+    let show_diff = options.show_diff && out.human();
+
     // 1. Resolve workspace path
     let workspace = resolve_workspace(workspace)?;
+    let filter = select.filter(&workspace)?;
 
     // 2. Determine patch files to load
     let patch_files = if let Some(path) = patches {
@@ -348,203 +804,927 @@ fn cmd_apply(
     };
 
     // 3. Determine workspace version
-    let workspace_version = read_workspace_version(&workspace).unwrap_or_else(|_| {
-        eprintln!(
-            "{}",
-            "Warning: Could not read workspace version from Cargo.toml, using 0.0.0".yellow()
-        );
-        "0.0.0".to_string()
-    });
+    let workspace_version = version.resolve(&workspace)?;
 
-    println!("Workspace: {}", workspace.display());
-    println!("Version: {}", workspace_version);
-    println!();
+    out.start(&workspace, Some(&workspace_version), dry_run);
+    if out.human() {
+        println!("Workspace: {}", workspace.display());
+        println!(
+            "Version: {} ({})",
+            workspace_version.version, workspace_version.source
+        );
+        println!();
+    }
 
     // 4. Load and apply each patch file
-    let mut total_applied = 0;
-    let mut total_already_applied = 0;
-    let mut total_skipped = 0;
-    let mut total_failed = 0;
+    let mut summary = Summary::default();
+    // Ledger records of every edit written, for attributing build errors.
+    let mut written = Vec::new();
 
-    for patch_file in patch_files {
-        println!("Loading patches from {}...", patch_file.display());
+    let mut configs = Vec::with_capacity(patch_files.len());
+    for patch_file in &patch_files {
+        configs.push(load_from_path(patch_file)?);
+    }
+    // Patches run after the patches they require; a config may be split
+    // into several steps around another config's patches. Ordering sees
+    // every patch, so deselecting one does not hide it from references.
+    let steps: Vec<Step> = order_patches(&configs)?
+        .into_iter()
+        .filter_map(|step| {
+            filter.select(&step.config).map(|config| Step {
+                index: step.index,
+                config,
+            })
+        })
+        .collect();
+    if steps.is_empty() && !filter.is_empty() {
+        anyhow::bail!("no patches match the selection");
+    }
+    // Deselected patches do not run, but the patches referencing them see
+    // whether they are applied on disk.
+    let mut outcomes = Outcomes::new(&configs);
+    if !filter.is_empty() {
+        for config in &configs {
+            let deselected = PatchConfig {
+                meta: config.meta.clone(),
+                patches: config
+                    .patches
+                    .iter()
+                    .filter(|patch| !filter.accepts(&config.meta, patch))
+                    .cloned()
+                    .collect(),
+            };
+            if !deselected.patches.is_empty() {
+                let simulation =
+                    simulate_patches(&deselected, &workspace, &workspace_version.version);
+                outcomes.record_deselected(&deselected, &simulation.results);
+            }
+        }
+    }
+    let configs: Vec<PatchConfig> = configs.iter().filter_map(|c| filter.select(c)).collect();
+    let ordered: Vec<PatchConfig> = steps.iter().map(|step| step.config.clone()).collect();
+
+    // Edits of different configs on the same bytes make the result depend on
+    // the order the configs run in.
+    let overlaps = find_overlaps(&configs, &workspace, &workspace_version.version);
+    report_overlaps(&mut out, &overlaps);
+    if deny_overlaps && !overlaps.is_empty() {
+        if out.human() {
+            eprintln!(
+                "{}",
+                "REFUSED: Patch files overlap; nothing was applied (see `conflicts`)".red()
+            );
+        }
+        out.finish(Summary::default(), false)?;
+        std::process::exit(1);
+    }
 
-        let config = load_from_path(&patch_file)?;
+    // In atomic mode every config is planned and committed up front; the loop
+    // below only reports the results.
+    let transaction = (atomic && !dry_run).then(|| {
+        apply_patches_atomic_with(
+            &ordered,
+            &mut outcomes,
+            &workspace,
+            &workspace_version.version,
+        )
+    });
+    let mut atomic_results = transaction
+        .as_ref()
+        .map(|t| {
+            (0..t.results.len())
+                .map(|i| Simulation {
+                    results: t.results[i].clone(),
+                    fuzzy_scores: t.fuzzy_scores[i].clone(),
+                    fuzzy_matches: t.fuzzy_matches[i].clone(),
+                    relocations: t.relocations[i].clone(),
+                    ..Simulation::default()
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
+        .into_iter();
+
+    for step in &steps {
+        let (patch_file, config) = (&patch_files[step.index], &step.config);
+        out.emit(Event::Config(ConfigReport::new(patch_file, &config.meta)));
+        if out.human() {
+            println!("Loading patches from {}...", patch_file.display());
+        }
+
+        let planned = atomic_results.next();
 
         if config.patches.is_empty() {
-            println!("{}", "  No patches found in file".yellow());
+            if out.human() {
+                println!("{}", "  No patches found in file".yellow());
+            }
             continue;
         }
 
-        // Capture file contents before applying (for diff output).
-        // Only read files that the patches will touch, to avoid reading
-        // unrelated files in large workspaces.
-        let mut file_contents_before: HashMap<PathBuf, String> = HashMap::new();
-        if show_diff {
-            let target_files: std::collections::HashSet<PathBuf> = config
-                .patches
-                .iter()
-                .map(|p| {
-                    if config.meta.workspace_relative {
-                        workspace.join(&p.file)
-                    } else {
-                        PathBuf::from(&p.file)
-                    }
-                })
-                .collect();
-            for file_path in target_files {
-                if file_path.exists() {
-                    if let Ok(content) = fs::read_to_string(&file_path) {
-                        file_contents_before.insert(file_path, content);
-                    }
-                }
+        // Apply patches, or simulate them in memory for a dry run. Either way
+        // the run reports the before/after buffers of every changed file.
+        let run = if let Some(run) = planned {
+            run
+        } else if dry_run {
+            if out.human() {
+                println!("{}", "  [DRY RUN - showing what would be applied]".cyan());
             }
-        }
-
-        // Apply patches (or dry-run)
-        let results = if dry_run {
-            println!("{}", "  [DRY RUN - showing what would be applied]".cyan());
-            println!(
-                "{}",
-                "  Note: Patches are idempotent, so this actually applies them to check".dimmed()
-            );
-            apply_patches(&config, &workspace, &workspace_version)
+            outcomes.run(config, |config| {
+                simulate_patches(config, &workspace, &workspace_version.version)
+            })
         } else {
-            apply_patches(&config, &workspace, &workspace_version)
+            outcomes.run(config, |config| {
+                apply_patches_detailed(config, &workspace, &workspace_version.version)
+            })
         };
+        if !dry_run {
+            written.extend(run.records.iter().cloned());
+        }
+        out.ledger_error(run.ledger_error.as_ref());
 
         // 5. Report results
-        for (patch_id, result) in results {
-            match result {
-                Ok(PatchResult::Applied { ref file }) => {
-                    if dry_run {
-                        println!(
-                            "{} {}: Would apply to {}",
-                            "✓".green(),
-                            patch_id,
-                            file.display()
-                        );
-                    } else {
-                        println!(
-                            "{} {}: Applied to {}",
-                            "✓".green(),
-                            patch_id,
-                            file.display()
-                        );
-                    }
-                    total_applied += 1;
-
-                    if show_diff {
-                        if let Some(before) = file_contents_before.get(file) {
-                            if let Ok(after) = fs::read_to_string(file) {
-                                if before != &after {
-                                    display_diff(file, before, &after);
-                                }
-                            }
-                        }
-                    }
+        for (patch_id, result) in &run.results {
+            let report = PatchReport::new(
+                &config.meta.name,
+                patch_id,
+                result,
+                run.fuzzy_scores.get(patch_id).copied(),
+                variant_used(config, patch_id, &workspace_version.version),
+                run.relocations.get(patch_id).cloned(),
+            );
+            summary.record(report.result);
+            if out.human() {
+                print_apply_result(patch_id, result, dry_run);
+                if let Some(variant) = &report.variant {
+                    println!("  {}", format!("variant for {}", variant).dimmed());
                 }
-                Ok(PatchResult::AlreadyApplied { file }) => {
+                if let (Some(from), Some(to)) = (&report.relocated_from, &report.file) {
                     println!(
-                        "{} {}: Already applied to {}",
-                        "⊙".yellow(),
-                        patch_id,
-                        file.display()
+                        "  {}",
+                        format!(
+                            "target moved from {}; update `file` to {}",
+                            from.display(),
+                            to.display()
+                        )
+                        .yellow()
                     );
-                    total_already_applied += 1;
                 }
-                Ok(PatchResult::SkippedVersion { reason }) => {
-                    println!("{} {}: Skipped ({})", "⊘".cyan(), patch_id, reason);
-                    total_skipped += 1;
+            }
+            out.emit(Event::Patch(report));
+        }
+
+        if show_diff {
+            for simulated in &run.files {
+                display_diff(&simulated.path, &simulated.original, &simulated.patched);
+            }
+        }
+
+        // Only refresh patch files whose edits were actually written.
+        let committed = transaction.as_ref().is_none_or(|t| t.outcome.is_ok());
+        if refresh_patches && committed {
+            let refreshes = search_refreshes(config, &run, &workspace_version.version);
+            write_refreshes(patch_file, &refreshes)?;
+            report_refreshes(&mut out, patch_file, &refreshes, false);
+        }
+
+        if out.human() {
+            println!();
+        }
+    }
+
+    let mut transaction_failed = false;
+    if let Some(transaction) = &transaction {
+        transaction_failed = transaction.outcome.is_err();
+        if !transaction_failed {
+            written.extend(transaction.records.iter().cloned());
+        }
+        out.ledger_error(transaction.ledger_error.as_ref());
+        out.emit(Event::Transaction(TransactionReport {
+            committed: !transaction_failed,
+            files: transaction.files.iter().map(|f| f.path.clone()).collect(),
+            error: transaction.outcome.as_ref().err().map(|e| e.to_string()),
+        }));
+        if out.human() {
+            print_transaction(transaction, show_diff);
+        }
+    }
+
+    // 6. Optional compile gate over what was just written
+    let mut build_failed = false;
+    if gate != BuildGate::Off {
+        if written.is_empty() {
+            if out.human() {
+                println!("{}", "No files changed; skipping build check".dimmed());
+                println!();
+            }
+        } else {
+            build_failed = !check_build(
+                &mut out,
+                &workspace,
+                &workspace_version.version,
+                &configs,
+                &written,
+                gate == BuildGate::CheckAndRevert,
+            );
+        }
+    }
+
+    // 7. Summary
+    if out.human() {
+        println!("{}", "Summary:".bold());
+        println!("  {} applied", format!("{}", summary.applied).green());
+        println!(
+            "  {} already applied",
+            format!("{}", summary.already_applied).yellow()
+        );
+        println!(
+            "  {} skipped",
+            format!("{}", summary.skipped_version).cyan()
+        );
+        println!("  {} failed", format!("{}", summary.unsuccessful()).red());
+    }
+
+    let ok = summary.unsuccessful() == 0 && !transaction_failed && !build_failed;
+    out.finish(summary, ok)?;
+    if !ok {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Helper: `cargo check` the packages owning the files in `written` and
+/// attribute every error to the patch whose text contains it, optionally
+/// reverting those patches. Returns whether the build is clean.
+fn check_build(
+    out: &mut Reporter,
+    workspace: &Path,
+    workspace_version: &str,
+    configs: &[PatchConfig],
+    written: &[LedgerRecord],
+    revert_broken: bool,
+) -> bool {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for record in written {
+        if !files.contains(&record.file) {
+            files.push(record.file.clone());
+        }
+    }
+    let packages = owning_packages(workspace, &files);
+    if out.human() {
+        let scope = if packages.is_empty() {
+            "workspace".to_string()
+        } else {
+            packages.join(", ")
+        };
+        println!("{}", format!("Checking build ({})...", scope).bold());
+    }
+
+    let package_refs: Vec<&str> = packages.iter().map(String::as_str).collect();
+    let diagnostics = match run_cargo_check_packages(workspace, &package_refs) {
+        Ok(diagnostics) => diagnostics,
+        Err(e) => {
+            if out.human() {
+                eprintln!("{} Build check could not run: {}", "✗".red(), e);
+                println!();
+            }
+            out.emit(Event::BuildCheck(BuildCheckReport {
+                packages,
+                clean: false,
+                broken: Vec::new(),
+                unattributed: Vec::new(),
+                error: Some(e.to_string()),
+            }));
+            return false;
+        }
+    };
+    let (broken, unattributed) = attribute_errors(&diagnostics, written, workspace);
+
+    // Revert each broken patch once, config by config.
+    let mut reverted: Vec<(String, String)> = Vec::new();
+    if revert_broken {
+        for config in configs {
+            let patches: Vec<_> = config
+                .patches
+                .iter()
+                .filter(|p| {
+                    broken
+                        .iter()
+                        .any(|b| b.config == config.meta.name && b.patch_id == p.id)
+                })
+                .cloned()
+                .collect();
+            if patches.is_empty() {
+                continue;
+            }
+            let subset = PatchConfig {
+                patches,
+                ..config.clone()
+            };
+            let run = revert_patches_detailed(&subset, workspace, workspace_version);
+            out.ledger_error(run.ledger_error.as_ref());
+            for (patch_id, result) in run.results {
+                match result {
+                    Ok(PatchResult::Applied { .. }) => {
+                        reverted.push((config.meta.name.clone(), patch_id));
+                    }
+                    other if out.human() => {
+                        let reason = match other {
+                            Err(e) => e.to_string(),
+                            Ok(result) => result.to_string(),
+                        };
+                        eprintln!(
+                            "  {} {}: Could not revert - {}",
+                            "✗".red(),
+                            patch_id,
+                            reason
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    let was_reverted =
+        |config: &str, patch_id: &str| reverted.iter().any(|(c, p)| c == config && p == patch_id);
+
+    if out.human() {
+        if diagnostics.is_empty() {
+            println!("{} Build check passed", "✓".green());
+        }
+        for b in &broken {
+            eprintln!(
+                "{} patch {} broke the build at {}:{}{}",
+                "✗".red(),
+                b.patch_id,
+                b.file.display(),
+                b.line,
+                if was_reverted(&b.config, &b.patch_id) {
+                    " (reverted)"
+                } else {
+                    ""
+                }
+            );
+            print_rendered(&b.diagnostic);
+        }
+        if !unattributed.is_empty() {
+            eprintln!(
+                "{} {} error(s) outside patched code:",
+                "✗".red(),
+                unattributed.len()
+            );
+            for diagnostic in &unattributed {
+                print_rendered(diagnostic);
+            }
+        }
+        println!();
+    }
+
+    out.emit(Event::BuildCheck(BuildCheckReport {
+        packages,
+        clean: diagnostics.is_empty(),
+        broken: broken
+            .iter()
+            .map(|b| BrokenPatchReport {
+                config: b.config.clone(),
+                patch_id: b.patch_id.clone(),
+                file: b.file.clone(),
+                line: b.line,
+                reverted: was_reverted(&b.config, &b.patch_id),
+                error: BuildErrorReport::new(&b.diagnostic, workspace),
+            })
+            .collect(),
+        unattributed: unattributed
+            .iter()
+            .map(|d| BuildErrorReport::new(d, workspace))
+            .collect(),
+        error: None,
+    }));
+
+    diagnostics.is_empty()
+}
+
+/// Helper: Print rustc's rendered diagnostic (or its message), indented.
+fn print_rendered(diagnostic: &CompileDiagnostic) {
+    let rendered = diagnostic
+        .rendered
+        .as_deref()
+        .unwrap_or(&diagnostic.message);
+    for line in rendered.trim_end().lines() {
+        eprintln!("    {}", line);
+    }
+}
+
+/// Helper: Print one `apply` result, with conflict diagnostics for errors.
+fn print_apply_result(
+    patch_id: &str,
+    result: &Result<PatchResult, ApplicationError>,
+    dry_run: bool,
+) {
+    match result {
+        Ok(PatchResult::Applied { file }) => {
+            let verb = if dry_run { "Would apply" } else { "Applied" };
+            println!(
+                "{} {}: {} to {}",
+                "✓".green(),
+                patch_id,
+                verb,
+                file.display()
+            );
+        }
+        Ok(PatchResult::AlreadyApplied { file }) => {
+            println!(
+                "{} {}: Already applied to {}",
+                "⊙".yellow(),
+                patch_id,
+                file.display()
+            );
+        }
+        Ok(PatchResult::SkippedVersion { reason }) => {
+            println!("{} {}: Skipped ({})", "⊘".cyan(), patch_id, reason);
+        }
+        Ok(PatchResult::Failed { file, reason }) => {
+            eprintln!("{} {}: Failed - {}", "✗".red(), patch_id, reason);
+            eprintln!("  File: {}", file.display());
+        }
+        Err(e) => {
+            eprintln!("{} {}: Error - {}", "✗".red(), patch_id, e);
+
+            // Provide helpful conflict diagnostics
+            match e {
+                ApplicationError::NoMatch { file, candidates } => {
+                    eprintln!("  {}", "CONFLICT: Query matched no locations".red());
+                    eprintln!("  File: {}", file.display());
+                    if candidates.is_empty() {
+                        eprintln!("  Possible causes:");
+                        eprintln!("    - Function/struct was renamed or removed");
+                        eprintln!("    - Signature changed");
+                        eprintln!("    - Code was moved to different file");
+                    } else {
+                        print_near_misses(candidates);
+                    }
+                }
+                ApplicationError::AmbiguousMatch { file, count } => {
+                    eprintln!(
+                        "  {}",
+                        format!("CONFLICT: Query matched {} locations (expected 1)", count).red()
+                    );
+                    eprintln!("  File: {}", file.display());
+                    eprintln!("  Action: Refine the query pattern to be more specific");
+                }
+                ApplicationError::UnexpectedMatchCount {
+                    file,
+                    expected,
+                    found,
+                } => {
+                    eprintln!(
+                        "  {}",
+                        format!(
+                            "CONFLICT: Query found {} sites (expected_count = {})",
+                            found, expected
+                        )
+                        .red()
+                    );
+                    eprintln!("  File: {}", file.display());
+                    eprintln!(
+                        "  Action: Review the new or removed sites, then update expected_count"
+                    );
+                }
+                ApplicationError::FileMatchCount { pattern, found, .. } => {
+                    eprintln!(
+                        "  {}",
+                        format!("CONFLICT: {} matched in {} file(s)", pattern, found).red()
+                    );
+                    eprintln!(
+                        "  Action: Check which crates changed, then update min_matches/max_matches"
+                    );
+                }
+                ApplicationError::PrerequisiteFailed { requirement, .. } => {
+                    eprintln!(
+                        "  {}",
+                        format!("REFUSED: Requires {}, which was not applied", requirement).red()
+                    );
+                    eprintln!("  Action: Fix or enable the required patch first");
+                }
+                ApplicationError::ConflictingPatch { conflict } => {
+                    eprintln!(
+                        "  {}",
+                        format!("REFUSED: Conflicts with {}, which is applied", conflict).red()
+                    );
+                    eprintln!("  Action: Apply only one of the two patches");
+                }
+                ApplicationError::Edit(edit_err) => {
+                    eprintln!("  Edit error: {}", edit_err);
+                }
+                ApplicationError::ParseErrorIntroduced { file, errors } => {
+                    eprintln!(
+                        "  {}",
+                        "REFUSED: Patched file would not parse; no edits written".red()
+                    );
+                    eprintln!("  File: {}", file.display());
+                    for error in errors {
+                        eprintln!(
+                            "    {}:{}: near `{}`",
+                            error.line, error.column, error.context
+                        );
+                    }
+                }
+                ApplicationError::Safety(_) => {
+                    eprintln!(
+                        "  {}",
+                        "REFUSED: Patch target is outside the workspace or protected".red()
+                    );
+                    eprintln!(
+                        "  Action: Check the patch `file` path and workspace_relative setting"
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Helper: Print the closest locations to a query that matched nothing.
+fn print_near_misses(candidates: &[NearMiss]) {
+    eprintln!("  Closest candidates:");
+    for candidate in candidates {
+        eprintln!(
+            "    {}:{} ({:.2}) {}",
+            candidate.file.display(),
+            candidate.line,
+            candidate.score,
+            candidate.reason
+        );
+        match &candidate.diff {
+            Some(diff) => {
+                for line in diff.lines() {
+                    let line = match line.chars().next() {
+                        Some('-') => line.red(),
+                        Some('+') => line.green(),
+                        _ => line.normal(),
+                    };
+                    eprintln!("      {}", line);
+                }
+            }
+            None => {
+                for line in candidate.text.lines() {
+                    eprintln!("      {}", line.dimmed());
+                }
+            }
+        }
+    }
+}
+
+/// Helper: Print the outcome of an `apply --atomic` transaction.
+fn print_transaction(transaction: &AtomicApply, show_diff: bool) {
+    match &transaction.outcome {
+        Ok(()) => {
+            println!(
+                "{} Transaction committed ({} file(s) written)",
+                "✓".green(),
+                transaction.files.len()
+            );
+            if show_diff {
+                for file in &transaction.files {
+                    display_diff(&file.path, &file.original, &file.patched);
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("{} {}", "✗".red(), e.to_string().red());
+            if let TransactionError::Journal(JournalError::RollbackFailed { .. }) = e {
+                eprintln!("  Journal kept; run `codex-patcher recover` to finish the rollback");
+            } else {
+                eprintln!("  No changes were left in the workspace");
+            }
+        }
+    }
+    println!();
+}
+
+fn cmd_revert(
+    workspace: Option<PathBuf>,
+    patches: Option<PathBuf>,
+    dry_run: bool,
+    show_diff: bool,
+    version: &VersionArgs,
+    format: Format,
+) -> Result<()> {
+    let mut out = Reporter::new(format, "revert");
+    let show_diff = show_diff && out.human();
+
+    let workspace = resolve_workspace(workspace)?;
+
+    let patch_files = if let Some(path) = patches {
+        vec![path]
+    } else {
+        discover_patch_files(&workspace)?
+    };
+
+    let workspace_version = version.resolve(&workspace)?;
+
+    out.start(&workspace, Some(&workspace_version), dry_run);
+    if out.human() {
+        println!("Workspace: {}", workspace.display());
+        println!(
+            "Version: {} ({})",
+            workspace_version.version, workspace_version.source
+        );
+        println!();
+    }
+
+    let mut summary = Summary::default();
+
+    // Undo in the reverse of apply order so later patches come off first.
+    for patch_file in patch_files.iter().rev() {
+        let config = load_from_path(patch_file)?;
+        out.emit(Event::Config(ConfigReport::new(patch_file, &config.meta)));
+        if out.human() {
+            println!("Reverting patches from {}...", patch_file.display());
+        }
+
+        let simulation = simulate_revert(&config, &workspace, &workspace_version.version);
+        let results = if dry_run {
+            if out.human() {
+                println!("{}", "  [DRY RUN - showing what would be reverted]".cyan());
+            }
+            simulation.results
+        } else {
+            let run = revert_patches_detailed(&config, &workspace, &workspace_version.version);
+            out.ledger_error(run.ledger_error.as_ref());
+            run.results
+        };
+
+        for (patch_id, result) in results {
+            let report = PatchReport::new(
+                &config.meta.name,
+                &patch_id,
+                &result,
+                None,
+                variant_used(&config, &patch_id, &workspace_version.version),
+                simulation.relocations.get(&patch_id).cloned(),
+            );
+            summary.record(report.result);
+            out.emit(Event::Patch(report));
+            if !out.human() {
+                continue;
+            }
+            match result {
+                Ok(PatchResult::Applied { file }) => {
+                    let verb = if dry_run { "Would revert" } else { "Reverted" };
+                    println!(
+                        "{} {}: {} in {}",
+                        "↺".green(),
+                        patch_id,
+                        verb,
+                        file.display()
+                    );
+                }
+                Ok(PatchResult::AlreadyApplied { file }) => {
+                    println!(
+                        "{} {}: Not applied in {}",
+                        "⊙".yellow(),
+                        patch_id,
+                        file.display()
+                    );
+                }
+                Ok(PatchResult::SkippedVersion { reason }) => {
+                    println!("{} {}: Skipped ({})", "⊘".cyan(), patch_id, reason);
                 }
                 Ok(PatchResult::Failed { file, reason }) => {
                     eprintln!("{} {}: Failed - {}", "✗".red(), patch_id, reason);
                     eprintln!("  File: {}", file.display());
-                    total_failed += 1;
                 }
                 Err(e) => {
                     eprintln!("{} {}: Error - {}", "✗".red(), patch_id, e);
-                    total_failed += 1;
-
-                    // Provide helpful conflict diagnostics
-                    match &e {
-                        ApplicationError::NoMatch { file } => {
-                            eprintln!("  {}", "CONFLICT: Query matched no locations".red());
-                            eprintln!("  File: {}", file.display());
-                            eprintln!("  Possible causes:");
-                            eprintln!("    - Function/struct was renamed or removed");
-                            eprintln!("    - Signature changed");
-                            eprintln!("    - Code was moved to different file");
-                        }
-                        ApplicationError::AmbiguousMatch { file, count } => {
-                            eprintln!(
-                                "  {}",
-                                format!("CONFLICT: Query matched {} locations (expected 1)", count)
-                                    .red()
-                            );
-                            eprintln!("  File: {}", file.display());
-                            eprintln!("  Action: Refine the query pattern to be more specific");
-                        }
-                        ApplicationError::Edit(edit_err) => {
-                            eprintln!("  Edit error: {}", edit_err);
-                        }
-                        _ => {}
-                    }
                 }
             }
         }
 
-        println!();
+        if show_diff {
+            for simulated in &simulation.files {
+                display_diff(&simulated.path, &simulated.original, &simulated.patched);
+            }
+        }
+
+        if out.human() {
+            println!();
+        }
     }
 
-    // 6. Summary
-    println!("{}", "Summary:".bold());
-    println!("  {} applied", format!("{}", total_applied).green());
-    println!(
-        "  {} already applied",
-        format!("{}", total_already_applied).yellow()
-    );
-    println!("  {} skipped", format!("{}", total_skipped).cyan());
-    println!("  {} failed", format!("{}", total_failed).red());
+    if out.human() {
+        println!("{}", "Summary:".bold());
+        println!("  {} reverted", format!("{}", summary.applied).green());
+        println!(
+            "  {} not applied",
+            format!("{}", summary.already_applied).yellow()
+        );
+        println!(
+            "  {} skipped",
+            format!("{}", summary.skipped_version).cyan()
+        );
+        println!("  {} failed", format!("{}", summary.unsuccessful()).red());
+    }
 
-    if total_failed > 0 {
+    let ok = summary.unsuccessful() == 0;
+    out.finish(summary, ok)?;
+    if !ok {
         std::process::exit(1);
     }
 
     Ok(())
 }
 
-fn cmd_status(workspace: Option<PathBuf>) -> Result<()> {
+fn cmd_recover(workspace: Option<PathBuf>, replay: bool, force: bool) -> Result<()> {
+    let workspace = resolve_workspace(workspace)?;
+
+    let Some(journal) = Journal::open(&workspace)? else {
+        println!("No interrupted transaction in {}", workspace.display());
+        return Ok(());
+    };
+
+    let action = if replay {
+        RecoverAction::Replay
+    } else {
+        RecoverAction::Rollback
+    };
+    println!(
+        "Recovering transaction ({:?}, {} file(s)): {}",
+        journal.state(),
+        journal.entries().len(),
+        if replay { "replaying" } else { "rolling back" }
+    );
+    for entry in journal.entries() {
+        println!("  - {}", entry.path.display());
+    }
+
+    let guard = WorkspaceGuard::new(&workspace)?;
+    journal.recover(&guard, action, force)?;
+    println!("{} Recovery complete", "✓".green());
+
+    Ok(())
+}
+
+fn cmd_fix(workspace: Option<PathBuf>, package: Option<&str>, max_iterations: u32) -> Result<()> {
+    let workspace = resolve_workspace(workspace)?;
+
+    println!("Workspace: {}", workspace.display());
+    if let Some(package) = package {
+        println!("Package: {}", package);
+    }
+    println!();
+
+    let mut total_fixed = 0;
+    let mut last_step = None;
+    for iteration in 1..=max_iterations {
+        println!(
+            "{}",
+            format!("Iteration {}: cargo check...", iteration).bold()
+        );
+        let step = fix_step(&workspace, package)?;
+
+        if step.diagnostics.is_empty() {
+            println!(
+                "{} Build is clean ({} file(s) fixed)",
+                "✓".green(),
+                total_fixed
+            );
+            return Ok(());
+        }
+
+        println!(
+            "  {} error(s), {} unfixable, {} file(s) fixed",
+            step.diagnostics.len(),
+            step.unfixable.len(),
+            step.fixed.len()
+        );
+        for fixed in &step.fixed {
+            display_diff(&fixed.path, &fixed.original, &fixed.fixed);
+        }
+        for (path, reason) in &step.rejected {
+            eprintln!(
+                "  {} Could not write fixes to {}: {}",
+                "✗".red(),
+                path.display(),
+                reason
+            );
+        }
+        println!();
+
+        total_fixed += step.fixed.len();
+        let stuck = step.fixed.is_empty();
+        last_step = Some(step);
+        if stuck {
+            break;
+        }
+    }
+
+    // Either no fix could be made, or the iteration limit ran out.
+    let step = last_step.unwrap_or_default();
+    if step.fixed.is_empty() {
+        eprintln!(
+            "{} Stuck: {} error(s) remain and none could be auto-fixed",
+            "✗".red(),
+            step.diagnostics.len()
+        );
+    } else {
+        eprintln!(
+            "{} Gave up after {} iteration(s) with errors remaining",
+            "✗".red(),
+            max_iterations
+        );
+    }
+
+    if !step.unfixable.is_empty() {
+        eprintln!();
+        eprintln!("{}", "Unfixable diagnostics:".bold());
+        for diagnostic in &step.unfixable {
+            match &diagnostic.rendered {
+                Some(rendered) => eprintln!("{}", rendered.trim_end()),
+                None => eprintln!(
+                    "error[{}]: {}",
+                    diagnostic.code.as_deref().unwrap_or("-"),
+                    diagnostic.message
+                ),
+            }
+            eprintln!();
+        }
+    }
+
+    std::process::exit(1);
+}
+
+fn cmd_status(
+    workspace: Option<PathBuf>,
+    ledger_only: bool,
+    select: &SelectArgs,
+    version: &VersionArgs,
+    format: Format,
+) -> Result<()> {
+    let mut out = Reporter::new(format, "status");
+
     // 1. Resolve workspace path
     let workspace = resolve_workspace(workspace)?;
+    let filter = select.filter(&workspace)?;
+
+    let ledger = Ledger::load(&workspace)?;
+    if ledger_only {
+        out.start(&workspace, None, false);
+        if out.human() {
+            println!("{}", "Patch Ledger Report".bold());
+            println!("Workspace: {}", workspace.display());
+            println!();
+            if ledger.records().is_empty() {
+                println!(
+                    "No patches recorded in {}",
+                    Ledger::path_for(&workspace).display()
+                );
+            } else {
+                print_ledger(&ledger, &workspace);
+            }
+        }
+        emit_ledger(&mut out, &ledger, &workspace);
+        return out.finish(Summary::default(), true);
+    }
 
     // 2. Discover patch files
     let patch_files = discover_patch_files(&workspace)?;
 
     // 3. Determine workspace version
-    let workspace_version = read_workspace_version(&workspace).unwrap_or_else(|_| {
-        eprintln!(
-            "{}",
-            "Warning: Could not read workspace version from Cargo.toml, using 0.0.0".yellow()
-        );
-        "0.0.0".to_string()
-    });
+    let workspace_version = version.resolve(&workspace)?;
 
-    println!("{}", "Patch Status Report".bold());
-    println!("Workspace: {}", workspace.display());
-    println!("Version: {}", workspace_version);
-    println!();
+    out.start(&workspace, Some(&workspace_version), false);
+    if out.human() {
+        println!("{}", "Patch Status Report".bold());
+        println!("Workspace: {}", workspace.display());
+        println!(
+            "Version: {} ({})",
+            workspace_version.version, workspace_version.source
+        );
+        println!();
+    }
 
     let mut applied = Vec::new();
     let mut not_applied = Vec::new();
     let mut skipped = Vec::new();
+    let mut summary = Summary::default();
 
     // 4. Check status of all patches (read-only; does not mutate workspace files)
-    for patch_file in patch_files {
-        let config = load_from_path(&patch_file)?;
-        let results = check_patches(&config, &workspace, &workspace_version);
-
-        for (patch_id, result) in results {
+    for (patch_file, config) in load_selected(&patch_files, &filter)? {
+        out.emit(Event::Config(ConfigReport::new(&patch_file, &config.meta)));
+        let simulation = simulate_patches(&config, &workspace, &workspace_version.version);
+
+        for (patch_id, result) in simulation.results {
+            let report = PatchReport::new(
+                &config.meta.name,
+                &patch_id,
+                &result,
+                simulation.fuzzy_scores.get(&patch_id).copied(),
+                variant_used(&config, &patch_id, &workspace_version.version),
+                simulation.relocations.get(&patch_id).cloned(),
+            );
+            summary.record(report.result);
+            out.emit(Event::Patch(report));
             match result {
                 Ok(PatchResult::Applied { .. }) => {
                     // Patch target exists and would be changed if applied.
@@ -566,6 +1746,11 @@ fn cmd_status(workspace: Option<PathBuf>) -> Result<()> {
         }
     }
 
+    emit_ledger(&mut out, &ledger, &workspace);
+    if !out.human() {
+        return out.finish(summary, true);
+    }
+
     // 5. Report grouped by status
     if !applied.is_empty() {
         println!(
@@ -606,44 +1791,132 @@ fn cmd_status(workspace: Option<PathBuf>) -> Result<()> {
         println!();
     }
 
+    if !ledger.records().is_empty() {
+        print_ledger(&ledger, &workspace);
+    }
+
     Ok(())
 }
 
-fn cmd_verify(workspace: Option<PathBuf>) -> Result<()> {
+/// Helper: Emit a report event for every ledger entry.
+fn emit_ledger(out: &mut Reporter, ledger: &Ledger, workspace: &Path) {
+    for entry in ledger.entries(workspace) {
+        out.emit(Event::Ledger(LedgerReport::from(&entry)));
+    }
+}
+
+/// Helper: Print the latest ledger entry for every recorded patch.
+fn print_ledger(ledger: &Ledger, workspace: &Path) {
+    let entries = ledger.entries(workspace);
+    let drifted = entries
+        .iter()
+        .filter(|e| e.state == LedgerState::Drifted)
+        .count();
+
+    println!(
+        "{} {} ({} patches, {} drifted)",
+        "≡".blue(),
+        "LEDGER".blue().bold(),
+        entries.len(),
+        drifted
+    );
+    let now = codex_patcher::ledger::now();
+    for entry in &entries {
+        let record = &entry.record;
+        let state = match &entry.state {
+            LedgerState::Intact => "intact".green(),
+            LedgerState::Reverted => "reverted".cyan(),
+            LedgerState::Drifted => "DRIFTED".red().bold(),
+            LedgerState::Overwritten { by } => format!("overwritten by {}", by).yellow(),
+            LedgerState::Missing => "file missing".red(),
+        };
+        println!(
+            "  - {} [{}] {}: {}",
+            record.patch_id,
+            record.config,
+            record.file.display(),
+            state
+        );
+        println!(
+            "    {}",
+            format!(
+                "{} at version {}, {}",
+                match record.action {
+                    LedgerAction::Apply => "applied",
+                    LedgerAction::Revert => "reverted",
+                },
+                record.workspace_version,
+                format_age(now.saturating_sub(record.timestamp))
+            )
+            .dimmed()
+        );
+    }
+    println!();
+}
+
+/// Helper: Render an age in seconds as a short relative time.
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+fn cmd_verify(
+    workspace: Option<PathBuf>,
+    select: &SelectArgs,
+    version: &VersionArgs,
+    format: Format,
+) -> Result<()> {
+    let mut out = Reporter::new(format, "verify");
+
     // 1. Resolve workspace path
     let workspace = resolve_workspace(workspace)?;
+    let filter = select.filter(&workspace)?;
 
     // 2. Discover patch files
     let patch_files = discover_patch_files(&workspace)?;
 
     // 3. Determine workspace version
-    let workspace_version = read_workspace_version(&workspace).unwrap_or_else(|_| {
-        eprintln!(
-            "{}",
-            "Warning: Could not read workspace version from Cargo.toml, using 0.0.0".yellow()
-        );
-        "0.0.0".to_string()
-    });
+    let workspace_version = version.resolve(&workspace)?;
 
-    println!("{}", "Verifying patches...".bold());
-    println!("Workspace: {}", workspace.display());
-    println!("Version: {}", workspace_version);
-    println!();
+    out.start(&workspace, Some(&workspace_version), false);
+    if out.human() {
+        println!("{}", "Verifying patches...".bold());
+        println!("Workspace: {}", workspace.display());
+        println!(
+            "Version: {} ({})",
+            workspace_version.version, workspace_version.source
+        );
+        println!();
+    }
 
-    let mut verified = 0;
-    let mut mismatch = 0;
-    let mut skipped = 0;
+    let mut summary = Summary::default();
 
     // 4. Check verification for all patches
-    for patch_file in patch_files {
-        let config = load_from_path(&patch_file)?;
-        let results = check_patches(&config, &workspace, &workspace_version);
-
-        for (patch_id, result) in results {
+    for (patch_file, config) in load_selected(&patch_files, &filter)? {
+        out.emit(Event::Config(ConfigReport::new(&patch_file, &config.meta)));
+        let simulation = simulate_patches(&config, &workspace, &workspace_version.version);
+
+        for (patch_id, result) in simulation.results {
+            let report = PatchReport::new(
+                &config.meta.name,
+                &patch_id,
+                &result,
+                simulation.fuzzy_scores.get(&patch_id).copied(),
+                variant_used(&config, &patch_id, &workspace_version.version),
+                simulation.relocations.get(&patch_id).cloned(),
+            );
+            summary.record(report.result);
+            out.emit(Event::Patch(report));
+            if !out.human() {
+                continue;
+            }
             match result {
                 Ok(PatchResult::AlreadyApplied { .. }) => {
                     println!("{} {}: Verified (already applied)", "✓".green(), patch_id);
-                    verified += 1;
                 }
                 Ok(PatchResult::Applied { file }) => {
                     // This means it wasn't already applied, so verification failed
@@ -651,11 +1924,9 @@ fn cmd_verify(workspace: Option<PathBuf>) -> Result<()> {
                     eprintln!("  Expected: patch already applied");
                     eprintln!("  Found: patch not yet applied");
                     eprintln!("  Location: {}", file.display());
-                    mismatch += 1;
                 }
                 Ok(PatchResult::SkippedVersion { reason }) => {
                     println!("{} {}: Skipped ({})", "⊘".cyan(), patch_id, reason);
-                    skipped += 1;
                 }
                 Ok(PatchResult::Failed {
                     ref file,
@@ -664,23 +1935,37 @@ fn cmd_verify(workspace: Option<PathBuf>) -> Result<()> {
                     eprintln!("{} {}: MISMATCH", "✗".red(), patch_id);
                     eprintln!("  Error: {}", reason);
                     eprintln!("  Location: {}", file.display());
-                    mismatch += 1;
                 }
                 Err(ref e) => {
                     eprintln!("{} {}: MISMATCH", "✗".red(), patch_id);
                     eprintln!("  Error: {}", e);
-                    mismatch += 1;
+                    if let ApplicationError::NoMatch { candidates, .. } = e {
+                        if !candidates.is_empty() {
+                            print_near_misses(candidates);
+                        }
+                    }
                 }
             }
         }
     }
 
-    println!();
-    println!("{}", "Summary:".bold());
-    println!("  {} verified", format!("{}", verified).green());
-    println!("  {} mismatch", format!("{}", mismatch).red());
-    println!("  {} skipped", format!("{}", skipped).cyan());
+    // Anything that would still apply, or could not be evaluated, is a mismatch.
+    let mismatch = summary.applied + summary.unsuccessful();
+    if out.human() {
+        println!();
+        println!("{}", "Summary:".bold());
+        println!(
+            "  {} verified",
+            format!("{}", summary.already_applied).green()
+        );
+        println!("  {} mismatch", format!("{}", mismatch).red());
+        println!(
+            "  {} skipped",
+            format!("{}", summary.skipped_version).cyan()
+        );
+    }
 
+    out.finish(summary, mismatch == 0)?;
     if mismatch > 0 {
         std::process::exit(1);
     }
@@ -688,7 +1973,321 @@ fn cmd_verify(workspace: Option<PathBuf>) -> Result<()> {
     Ok(())
 }
 
-fn cmd_list() -> Result<()> {
-    println!("List command - not yet implemented");
+/// Emit `overlaps` and, for humans, print them as warnings.
+fn report_overlaps(out: &mut Reporter, overlaps: &[Overlap]) {
+    for overlap in overlaps {
+        out.emit(Event::Overlap(OverlapReport::from(overlap)));
+        if !out.human() {
+            continue;
+        }
+        let verb = match overlap.kind {
+            OverlapKind::Overlapping => "overlaps",
+            OverlapKind::Adjacent => "is adjacent to",
+        };
+        let side = |span: &EditSpan| {
+            format!(
+                "{}:{} ({}..{})",
+                span.config, span.patch_id, span.byte_start, span.byte_end
+            )
+        };
+        eprintln!(
+            "{} {}: {} {} {}",
+            "⚠".yellow(),
+            overlap.file.display(),
+            side(&overlap.first),
+            verb,
+            side(&overlap.second)
+        );
+    }
+    if out.human() && !overlaps.is_empty() {
+        eprintln!(
+            "  {}",
+            "The result depends on which patch file runs first; add `requires` to order them or `conflicts` to keep one".yellow()
+        );
+        eprintln!();
+    }
+}
+
+fn cmd_conflicts(workspace: Option<PathBuf>, version: &VersionArgs, format: Format) -> Result<()> {
+    let mut out = Reporter::new(format, "conflicts");
+
+    let workspace = resolve_workspace(workspace)?;
+    let patch_files = discover_patch_files(&workspace)?;
+    let workspace_version = version.resolve(&workspace)?;
+
+    out.start(&workspace, Some(&workspace_version), false);
+    if out.human() {
+        println!("{}", "Checking patch files for overlapping edits...".bold());
+        println!("Workspace: {}", workspace.display());
+        println!(
+            "Version: {} ({})",
+            workspace_version.version, workspace_version.source
+        );
+        println!();
+    }
+
+    let mut configs = Vec::with_capacity(patch_files.len());
+    for patch_file in &patch_files {
+        let config = load_from_path(patch_file)?;
+        out.emit(Event::Config(ConfigReport::new(patch_file, &config.meta)));
+        configs.push(config);
+    }
+
+    let overlaps = find_overlaps(&configs, &workspace, &workspace_version.version);
+    report_overlaps(&mut out, &overlaps);
+    if out.human() {
+        if overlaps.is_empty() {
+            println!("{} No overlapping edits", "✓".green());
+        } else {
+            println!("{} overlapping edit pair(s)", overlaps.len());
+        }
+    }
+
+    out.finish(Summary::default(), overlaps.is_empty())?;
+    if !overlaps.is_empty() {
+        std::process::exit(1);
+    }
+
     Ok(())
 }
+
+fn cmd_refresh(
+    workspace: Option<PathBuf>,
+    patches: Option<PathBuf>,
+    dry_run: bool,
+    widen_version: bool,
+    select: &SelectArgs,
+    version: &VersionArgs,
+    format: Format,
+) -> Result<()> {
+    let mut out = Reporter::new(format, "refresh");
+
+    let workspace = resolve_workspace(workspace)?;
+    let filter = select.filter(&workspace)?;
+    let patch_files = if let Some(path) = patches {
+        vec![path]
+    } else {
+        discover_patch_files(&workspace)?
+    };
+    let workspace_version = version.resolve(&workspace)?;
+
+    out.start(&workspace, Some(&workspace_version), dry_run);
+    if out.human() {
+        println!("{}", "Refreshing patch files...".bold());
+        println!("Workspace: {}", workspace.display());
+        println!(
+            "Version: {} ({})",
+            workspace_version.version, workspace_version.source
+        );
+        if dry_run {
+            println!("{}", "[DRY RUN - patch files are not modified]".cyan());
+        }
+        println!();
+    }
+
+    let mut total = 0;
+    for (patch_file, config) in load_selected(&patch_files, &filter)? {
+        out.emit(Event::Config(ConfigReport::new(&patch_file, &config.meta)));
+        let refreshes = plan_refresh(
+            &config,
+            &workspace,
+            &workspace_version.version,
+            widen_version,
+        );
+        if !dry_run {
+            write_refreshes(&patch_file, &refreshes)?;
+        }
+        report_refreshes(&mut out, &patch_file, &refreshes, dry_run);
+        total += refreshes.len();
+    }
+
+    if out.human() {
+        if total == 0 {
+            println!("{} Patch files are up to date", "✓".green());
+        } else if dry_run {
+            println!("{} change(s) would be made", total);
+        } else {
+            println!("{} change(s) made", total);
+        }
+    }
+
+    out.finish(Summary::default(), true)
+}
+
+/// Emit `refreshes` of `patch_file` and, for humans, print them.
+fn report_refreshes(out: &mut Reporter, patch_file: &Path, refreshes: &[Refresh], dry_run: bool) {
+    for refresh in refreshes {
+        out.emit(Event::Refresh(RefreshReport::new(patch_file, refresh)));
+        if !out.human() {
+            continue;
+        }
+        let tag = if dry_run {
+            "[would refresh]"
+        } else {
+            "[refresh]"
+        };
+        match refresh {
+            Refresh::Search {
+                patch_id,
+                variant,
+                score,
+                ..
+            } => {
+                let variant = variant
+                    .as_ref()
+                    .map(|v| format!(", variant for {}", v))
+                    .unwrap_or_default();
+                println!(
+                    "{} {}: search text updated (fuzzy score {:.2}{})",
+                    tag.cyan(),
+                    patch_id,
+                    score,
+                    variant
+                );
+            }
+            Refresh::Version { patch_id, old, new } => {
+                let target = match patch_id {
+                    Some(id) => format!("{}: version", id),
+                    None => format!("{}: version_range", patch_file.display()),
+                };
+                println!("{} {} \"{}\" -> \"{}\"", tag.cyan(), target, old, new);
+            }
+        }
+    }
+}
+
+/// Filters for `list`; every set filter must accept a patch.
+struct ListFilter {
+    file: Option<String>,
+    query_type: Option<String>,
+    applies_to: Option<String>,
+}
+
+impl ListFilter {
+    fn accepts(&self, meta: &Metadata, patch: &PatchDefinition) -> Result<bool> {
+        if let Some(file) = &self.file {
+            if !patch.file.contains(file.as_str()) {
+                return Ok(false);
+            }
+        }
+        if let Some(query_type) = &self.query_type {
+            if patch.query.type_name() != query_type {
+                return Ok(false);
+            }
+        }
+        if let Some(version) = &self.applies_to {
+            if !matches_requirement(version, meta.version_range.as_deref())?
+                || patch.matching_variants(version)?.is_empty()
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn cmd_list(
+    workspace: Option<PathBuf>,
+    patches: Option<PathBuf>,
+    filter: &ListFilter,
+    select: &SelectArgs,
+    format: Format,
+) -> Result<()> {
+    let mut out = Reporter::new(format, "list");
+
+    if let Some(version) = &filter.applies_to {
+        // Without a requirement the version is never parsed; force it.
+        matches_requirement(version, Some("*"))?;
+    }
+
+    // Listing only reads patch files, so fall back to the current directory
+    // (and its ./patches) when no workspace can be found.
+    let workspace = match workspace {
+        Some(path) => resolve_workspace(Some(path))?,
+        None => resolve_workspace(None).or_else(|_| env::current_dir())?,
+    };
+    let selection = select.filter(&workspace)?;
+
+    let patch_files = if let Some(path) = patches {
+        vec![path]
+    } else {
+        discover_patch_files(&workspace)?
+    };
+
+    out.start(&workspace, None, false);
+
+    let mut listed = 0;
+    for patch_file in patch_files {
+        let config = load_from_path(&patch_file)?;
+        let mut matching = Vec::new();
+        for patch in &config.patches {
+            if selection.accepts(&config.meta, patch) && filter.accepts(&config.meta, patch)? {
+                matching.push(patch);
+            }
+        }
+        if matching.is_empty() {
+            continue;
+        }
+
+        out.emit(Event::Config(ConfigReport::new(&patch_file, &config.meta)));
+        if out.human() {
+            println!(
+                "{} ({})",
+                config.meta.name.bold(),
+                patch_file.display().to_string().dimmed()
+            );
+            if let Some(description) = &config.meta.description {
+                println!("  {}", description);
+            }
+            if let Some(range) = &config.meta.version_range {
+                println!("  version_range: {}", range);
+            }
+        }
+
+        for patch in matching {
+            listed += 1;
+            out.emit(Event::ListedPatch(ListedPatchReport::new(
+                &config.meta,
+                patch,
+            )));
+            if !out.human() {
+                continue;
+            }
+            let version = patch
+                .version
+                .as_deref()
+                .map(|v| format!("  version: {}", v))
+                .unwrap_or_default();
+            let variants: Vec<&str> = patch.variants.iter().map(|v| v.version.as_str()).collect();
+            let variants = if variants.is_empty() {
+                String::new()
+            } else {
+                format!("  variants: {}", variants.join("; "))
+            };
+            let tags: Vec<&str> = patch.tags(&config.meta).collect();
+            let tags = if tags.is_empty() {
+                String::new()
+            } else {
+                format!("  tags: {}", tags.join(", "))
+            };
+            println!(
+                "  - {}  {}  [{} → {}]{}{}{}",
+                patch.id.cyan(),
+                patch.file,
+                patch.query.type_name(),
+                patch.operation.type_name(),
+                version,
+                variants,
+                tags
+            );
+        }
+        if out.human() {
+            println!();
+        }
+    }
+
+    if out.human() {
+        println!("{} patches", listed);
+    }
+    out.finish(Summary::default(), true)
+}
@@ -0,0 +1,674 @@
+//! Machine-readable CLI reports (`--format json` and `--format ndjson`).
+//!
+//! `ndjson` streams one [`Event`] per line as each patch is processed; `json`
+//! prints a single [`Report`] folded from the same events once the command
+//! finishes. Field names and `snake_case` values are part of the schema and
+//! only change together with [`SCHEMA_VERSION`].
+
+use crate::compiler::CompileDiagnostic;
+use crate::config::{
+    ApplicationError, EditSpan, Metadata, Overlap, OverlapKind, PatchDefinition, PatchResult,
+    Refresh,
+};
+use crate::ledger::{LedgerAction, LedgerEntry, LedgerState};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// Version of the report schema; bumped on any incompatible change.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Outcome of one patch, mirroring the [`PatchResult`] variants plus `error`
+/// for an [`ApplicationError`].
+///
+/// For `status`, `verify` and dry runs `applied` means "would apply".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultKind {
+    Applied,
+    AlreadyApplied,
+    SkippedVersion,
+    Failed,
+    Error,
+}
+
+/// An [`ApplicationError`] as `kind` + `message` + variant-specific `details`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorReport {
+    /// [`ApplicationError::kind`], e.g. `no_match` or `ambiguous_match`
+    pub kind: String,
+    /// Human-readable message (the error's `Display`)
+    pub message: String,
+    /// Structured fields of the variant; an empty object when it has none
+    pub details: Value,
+}
+
+impl From<&ApplicationError> for ErrorReport {
+    fn from(error: &ApplicationError) -> Self {
+        let details = match error {
+            ApplicationError::Io { path, source } => {
+                json!({ "path": path, "io_kind": format!("{:?}", source.kind()) })
+            }
+            ApplicationError::AmbiguousMatch { file, count } => {
+                json!({ "file": file, "count": count })
+            }
+            ApplicationError::NoMatch { file, candidates } => json!({
+                "file": file,
+                "candidates": candidates
+                    .iter()
+                    .map(|c| json!({
+                        "file": c.file,
+                        "line": c.line,
+                        "score": c.score,
+                        "reason": c.reason,
+                        "text": c.text,
+                        "diff": c.diff,
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            ApplicationError::UnexpectedMatchCount {
+                file,
+                expected,
+                found,
+            } => json!({ "file": file, "expected": expected, "found": found }),
+            ApplicationError::FileMatchCount {
+                pattern,
+                min,
+                max,
+                found,
+            } => json!({ "pattern": pattern, "min": min, "max": max, "found": found }),
+            ApplicationError::PrerequisiteFailed {
+                requirement,
+                reason,
+            } => json!({ "requirement": requirement, "reason": reason }),
+            ApplicationError::ConflictingPatch { conflict } => json!({ "conflict": conflict }),
+            ApplicationError::AmbiguousVariant { version, matched } => {
+                json!({ "version": version, "matched": matched })
+            }
+            ApplicationError::AmbiguousRelocation { file, found } => {
+                json!({ "file": file, "found": found })
+            }
+            ApplicationError::TomlOperation { file, reason }
+            | ApplicationError::Irreversible { file, reason } => {
+                json!({ "file": file, "reason": reason })
+            }
+            ApplicationError::ParseErrorIntroduced { file, errors } => json!({
+                "file": file,
+                "errors": errors
+                    .iter()
+                    .map(|e| json!({ "line": e.line, "column": e.column, "context": e.context }))
+                    .collect::<Vec<_>>(),
+            }),
+            ApplicationError::Version(_)
+            | ApplicationError::Edit(_)
+            | ApplicationError::Safety(_) => json!({}),
+        };
+        Self {
+            kind: error.kind().to_string(),
+            message: error.to_string(),
+            details,
+        }
+    }
+}
+
+/// One patch's result.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatchReport {
+    /// `meta.name` of the patch config
+    pub config: String,
+    pub patch_id: String,
+    /// Target file, when the result or error names one
+    pub file: Option<PathBuf>,
+    pub result: ResultKind,
+    /// Reason for `skipped_version` and `failed`
+    pub reason: Option<String>,
+    /// Set when `result` is `error`
+    pub error: Option<ErrorReport>,
+    /// Similarity score when the target was only found by fuzzy matching
+    pub fuzzy_score: Option<f64>,
+    /// `version` of the variant used, for patches with variants
+    pub variant: Option<String>,
+    /// Declared `file` of a patch whose target was found in another file
+    /// (`file` is where it was found)
+    pub relocated_from: Option<PathBuf>,
+}
+
+impl PatchReport {
+    pub fn new(
+        config: &str,
+        patch_id: &str,
+        result: &Result<PatchResult, ApplicationError>,
+        fuzzy_score: Option<f64>,
+        variant: Option<String>,
+        relocated_from: Option<PathBuf>,
+    ) -> Self {
+        let (file, result, reason, error) = match result {
+            Ok(PatchResult::Applied { file }) => {
+                (Some(file.clone()), ResultKind::Applied, None, None)
+            }
+            Ok(PatchResult::AlreadyApplied { file }) => {
+                (Some(file.clone()), ResultKind::AlreadyApplied, None, None)
+            }
+            Ok(PatchResult::SkippedVersion { reason }) => {
+                (None, ResultKind::SkippedVersion, Some(reason.clone()), None)
+            }
+            Ok(PatchResult::Failed { file, reason }) => (
+                Some(file.clone()),
+                ResultKind::Failed,
+                Some(reason.clone()),
+                None,
+            ),
+            Err(e) => (
+                error_file(e),
+                ResultKind::Error,
+                None,
+                Some(ErrorReport::from(e)),
+            ),
+        };
+        Self {
+            config: config.to_string(),
+            patch_id: patch_id.to_string(),
+            file,
+            result,
+            reason,
+            error,
+            fuzzy_score,
+            variant,
+            relocated_from,
+        }
+    }
+}
+
+fn error_file(error: &ApplicationError) -> Option<PathBuf> {
+    match error {
+        ApplicationError::Io { path: file, .. }
+        | ApplicationError::AmbiguousMatch { file, .. }
+        | ApplicationError::NoMatch { file, .. }
+        | ApplicationError::AmbiguousRelocation { file, .. }
+        | ApplicationError::UnexpectedMatchCount { file, .. }
+        | ApplicationError::TomlOperation { file, .. }
+        | ApplicationError::Irreversible { file, .. }
+        | ApplicationError::ParseErrorIntroduced { file, .. } => Some(file.clone()),
+        ApplicationError::Version(_)
+        | ApplicationError::Edit(_)
+        | ApplicationError::Safety(_)
+        | ApplicationError::FileMatchCount { .. }
+        | ApplicationError::PrerequisiteFailed { .. }
+        | ApplicationError::ConflictingPatch { .. }
+        | ApplicationError::AmbiguousVariant { .. } => None,
+    }
+}
+
+/// Per-result totals.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    pub applied: usize,
+    pub already_applied: usize,
+    pub skipped_version: usize,
+    pub failed: usize,
+    pub error: usize,
+}
+
+impl Summary {
+    /// Count one patch result.
+    pub fn record(&mut self, result: ResultKind) {
+        match result {
+            ResultKind::Applied => self.applied += 1,
+            ResultKind::AlreadyApplied => self.already_applied += 1,
+            ResultKind::SkippedVersion => self.skipped_version += 1,
+            ResultKind::Failed => self.failed += 1,
+            ResultKind::Error => self.error += 1,
+        }
+    }
+
+    /// `failed` plus `error`.
+    pub fn unsuccessful(&self) -> usize {
+        self.failed + self.error
+    }
+}
+
+/// A patch config as it was loaded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigReport {
+    pub path: PathBuf,
+    /// `meta.name`
+    pub name: String,
+    pub description: Option<String>,
+    /// `meta.version_range`
+    pub version_range: Option<String>,
+}
+
+impl ConfigReport {
+    pub fn new(path: &Path, meta: &Metadata) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            name: meta.name.clone(),
+            description: meta.description.clone(),
+            version_range: meta.version_range.clone(),
+        }
+    }
+}
+
+/// A patch definition printed by `list`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListedPatchReport {
+    /// `meta.name` of the config defining the patch
+    pub config: String,
+    pub id: String,
+    pub file: String,
+    /// Query `type` tag, e.g. `ast-grep`
+    pub query_type: String,
+    /// Operation `type` tag, e.g. `replace`
+    pub operation_type: String,
+    /// Per-patch version requirement
+    pub version: Option<String>,
+    /// `version` of each `[[patches.variants]]` entry
+    pub variants: Vec<String>,
+    /// `meta.tags` followed by the patch's own tags
+    pub tags: Vec<String>,
+}
+
+impl ListedPatchReport {
+    pub fn new(meta: &Metadata, patch: &PatchDefinition) -> Self {
+        Self {
+            config: meta.name.clone(),
+            id: patch.id.clone(),
+            file: patch.file.clone(),
+            query_type: patch.query.type_name().to_string(),
+            operation_type: patch.operation.type_name().to_string(),
+            version: patch.version.clone(),
+            variants: patch
+                .versions()
+                .skip(1)
+                .filter_map(|variant| variant.version)
+                .collect(),
+            tags: patch.tags(meta).map(str::to_string).collect(),
+        }
+    }
+}
+
+/// A ledger entry (see [`crate::ledger::Ledger::entries`]).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerReport {
+    pub config: String,
+    pub patch_id: String,
+    pub file: PathBuf,
+    pub action: LedgerAction,
+    /// `intact`, `reverted`, `drifted`, `overwritten` or `missing`
+    pub state: String,
+    /// Patch that overwrote the region, when `state` is `overwritten`
+    pub overwritten_by: Option<String>,
+    pub workspace_version: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl From<&LedgerEntry> for LedgerReport {
+    fn from(entry: &LedgerEntry) -> Self {
+        let (state, overwritten_by) = match &entry.state {
+            LedgerState::Intact => ("intact", None),
+            LedgerState::Reverted => ("reverted", None),
+            LedgerState::Drifted => ("drifted", None),
+            LedgerState::Overwritten { by } => ("overwritten", Some(by.clone())),
+            LedgerState::Missing => ("missing", None),
+        };
+        let record = &entry.record;
+        Self {
+            config: record.config.clone(),
+            patch_id: record.patch_id.clone(),
+            file: record.file.clone(),
+            action: record.action,
+            state: state.to_string(),
+            overwritten_by,
+            workspace_version: record.workspace_version.clone(),
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+/// One side of an [`OverlapReport`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditSpanReport {
+    pub config: String,
+    pub patch_id: String,
+    pub byte_start: usize,
+    pub byte_end: usize,
+}
+
+impl From<&EditSpan> for EditSpanReport {
+    fn from(span: &EditSpan) -> Self {
+        Self {
+            config: span.config.clone(),
+            patch_id: span.patch_id.clone(),
+            byte_start: span.byte_start,
+            byte_end: span.byte_end,
+        }
+    }
+}
+
+/// Edits of two configs that overlap or touch (see
+/// [`crate::config::find_overlaps`]).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverlapReport {
+    /// Relative to the workspace root when inside it
+    pub file: PathBuf,
+    /// `overlapping` or `adjacent`
+    pub kind: String,
+    pub first: EditSpanReport,
+    pub second: EditSpanReport,
+}
+
+impl From<&Overlap> for OverlapReport {
+    fn from(overlap: &Overlap) -> Self {
+        let kind = match overlap.kind {
+            OverlapKind::Overlapping => "overlapping",
+            OverlapKind::Adjacent => "adjacent",
+        };
+        Self {
+            file: overlap.file.clone(),
+            kind: kind.to_string(),
+            first: EditSpanReport::from(&overlap.first),
+            second: EditSpanReport::from(&overlap.second),
+        }
+    }
+}
+
+/// A change `refresh` or `apply --refresh-patches` made (or, with
+/// `--dry-run`, would make) to a patch config file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RefreshReport {
+    /// Patch config file
+    pub path: PathBuf,
+    /// `None` for the config's `meta.version_range`
+    pub patch_id: Option<String>,
+    /// `search` or `version`
+    pub kind: String,
+    /// `version` of the variant whose search text changed
+    pub variant: Option<String>,
+    /// Fuzzy match score behind a `search` refresh
+    pub score: Option<f64>,
+    pub old: String,
+    pub new: String,
+}
+
+impl RefreshReport {
+    pub fn new(path: &Path, refresh: &Refresh) -> Self {
+        let (patch_id, kind, variant, score, old, new) = match refresh {
+            Refresh::Search {
+                patch_id,
+                variant,
+                score,
+                old,
+                new,
+            } => (
+                Some(patch_id.clone()),
+                "search",
+                variant.clone(),
+                Some(*score),
+                old,
+                new,
+            ),
+            Refresh::Version { patch_id, old, new } => {
+                (patch_id.clone(), "version", None, None, old, new)
+            }
+        };
+        Self {
+            path: path.to_path_buf(),
+            patch_id,
+            kind: kind.to_string(),
+            variant,
+            score,
+            old: old.clone(),
+            new: new.clone(),
+        }
+    }
+}
+
+/// Outcome of an `apply --atomic` transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionReport {
+    pub committed: bool,
+    /// Files the transaction writes (or would have written)
+    pub files: Vec<PathBuf>,
+    pub error: Option<String>,
+}
+
+/// A compile error found by `apply --check-build`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildErrorReport {
+    /// Error code, e.g. `E0308`
+    pub code: Option<String>,
+    pub message: String,
+    /// Primary file, relative to the workspace root when inside it
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    /// rustc's rendered diagnostic
+    pub rendered: Option<String>,
+}
+
+impl BuildErrorReport {
+    pub fn new(diagnostic: &CompileDiagnostic, workspace: &Path) -> Self {
+        let span = diagnostic.spans.first();
+        Self {
+            code: diagnostic.code.clone(),
+            message: diagnostic.message.clone(),
+            file: span.map(|s| {
+                s.file
+                    .strip_prefix(workspace)
+                    .unwrap_or(&s.file)
+                    .to_path_buf()
+            }),
+            line: span.map(|s| s.line_start),
+            rendered: diagnostic.rendered.clone(),
+        }
+    }
+}
+
+/// A patch whose written text contains a compile error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BrokenPatchReport {
+    pub config: String,
+    pub patch_id: String,
+    pub file: PathBuf,
+    pub line: usize,
+    /// Whether `--revert-broken` took the patch back out
+    pub reverted: bool,
+    pub error: BuildErrorReport,
+}
+
+/// Outcome of `apply --check-build`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildCheckReport {
+    /// Packages checked; empty when the whole workspace was
+    pub packages: Vec<String>,
+    /// `cargo check` reported no errors
+    pub clean: bool,
+    pub broken: Vec<BrokenPatchReport>,
+    /// Errors outside any patched text
+    pub unattributed: Vec<BuildErrorReport>,
+    /// Set when `cargo check` itself could not run
+    pub error: Option<String>,
+}
+
+/// One line of `--format ndjson` output, tagged by `event`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Always the first event.
+    Start {
+        schema_version: u32,
+        command: String,
+        workspace: PathBuf,
+        workspace_version: Option<String>,
+        /// Where `workspace_version` was read, e.g. `git tag rust-v0.93.0`
+        version_source: Option<String>,
+        dry_run: bool,
+    },
+    /// A patch config is about to be processed.
+    Config(ConfigReport),
+    Patch(PatchReport),
+    ListedPatch(ListedPatchReport),
+    Ledger(LedgerReport),
+    Transaction(TransactionReport),
+    BuildCheck(BuildCheckReport),
+    Overlap(OverlapReport),
+    Refresh(RefreshReport),
+    /// A problem that did not fail the run, e.g. a ledger that could not be
+    /// written.
+    Warning {
+        message: String,
+    },
+    /// Always the last event; `ok` mirrors the exit status.
+    Summary {
+        #[serde(flatten)]
+        summary: Summary,
+        ok: bool,
+    },
+}
+
+/// The single document printed by `--format json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub schema_version: u32,
+    pub command: String,
+    pub workspace: Option<PathBuf>,
+    pub workspace_version: Option<String>,
+    pub version_source: Option<String>,
+    pub dry_run: bool,
+    pub configs: Vec<ConfigReport>,
+    pub patches: Vec<PatchReport>,
+    pub listed_patches: Vec<ListedPatchReport>,
+    pub ledger: Vec<LedgerReport>,
+    pub transaction: Option<TransactionReport>,
+    pub build_check: Option<BuildCheckReport>,
+    pub overlaps: Vec<OverlapReport>,
+    pub refreshes: Vec<RefreshReport>,
+    pub warnings: Vec<String>,
+    pub summary: Summary,
+    pub ok: bool,
+}
+
+impl Report {
+    pub fn new(command: &str) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            command: command.to_string(),
+            workspace: None,
+            workspace_version: None,
+            version_source: None,
+            dry_run: false,
+            configs: Vec::new(),
+            patches: Vec::new(),
+            listed_patches: Vec::new(),
+            ledger: Vec::new(),
+            transaction: None,
+            build_check: None,
+            overlaps: Vec::new(),
+            refreshes: Vec::new(),
+            warnings: Vec::new(),
+            summary: Summary::default(),
+            ok: true,
+        }
+    }
+
+    /// Fold one streamed event into the report.
+    pub fn push(&mut self, event: Event) {
+        match event {
+            Event::Start {
+                schema_version,
+                command,
+                workspace,
+                workspace_version,
+                version_source,
+                dry_run,
+            } => {
+                self.schema_version = schema_version;
+                self.command = command;
+                self.workspace = Some(workspace);
+                self.workspace_version = workspace_version;
+                self.version_source = version_source;
+                self.dry_run = dry_run;
+            }
+            Event::Config(config) => self.configs.push(config),
+            Event::Patch(patch) => self.patches.push(patch),
+            Event::ListedPatch(patch) => self.listed_patches.push(patch),
+            Event::Ledger(entry) => self.ledger.push(entry),
+            Event::Transaction(transaction) => self.transaction = Some(transaction),
+            Event::BuildCheck(check) => self.build_check = Some(check),
+            Event::Overlap(overlap) => self.overlaps.push(overlap),
+            Event::Refresh(refresh) => self.refreshes.push(refresh),
+            Event::Warning { message } => self.warnings.push(message),
+            Event::Summary { summary, ok } => {
+                self.summary = summary;
+                self.ok = ok;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_report_carries_variant_details() {
+        let error = ApplicationError::AmbiguousMatch {
+            file: PathBuf::from("src/lib.rs"),
+            count: 3,
+        };
+        let report = PatchReport::new("cfg", "p", &Err(error), None, None, None);
+
+        assert_eq!(report.result, ResultKind::Error);
+        assert_eq!(report.file, Some(PathBuf::from("src/lib.rs")));
+        let error = report.error.unwrap();
+        assert_eq!(error.kind, "ambiguous_match");
+        assert_eq!(error.details, json!({ "file": "src/lib.rs", "count": 3 }));
+    }
+
+    #[test]
+    fn events_are_tagged_and_fold_into_report() {
+        let patch = PatchReport::new(
+            "cfg",
+            "p",
+            &Ok(PatchResult::Applied {
+                file: PathBuf::from("a.rs"),
+            }),
+            Some(0.9),
+            None,
+            None,
+        );
+        let mut summary = Summary::default();
+        summary.record(patch.result);
+        let events = [
+            Event::Start {
+                schema_version: SCHEMA_VERSION,
+                command: "apply".to_string(),
+                workspace: PathBuf::from("/ws"),
+                workspace_version: Some("0.1.0".to_string()),
+                version_source: Some("--version".to_string()),
+                dry_run: false,
+            },
+            Event::Patch(patch.clone()),
+            Event::Summary { summary, ok: true },
+        ];
+
+        let line = serde_json::to_value(&events[1]).unwrap();
+        assert_eq!(line["event"], "patch");
+        assert_eq!(line["result"], "applied");
+        assert_eq!(line["fuzzy_score"], 0.9);
+        let line = serde_json::to_value(&events[2]).unwrap();
+        assert_eq!(line["event"], "summary");
+        assert_eq!(line["applied"], 1);
+
+        let mut report = Report::new("apply");
+        for event in events {
+            let round_trip: Event =
+                serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+            report.push(round_trip);
+        }
+        assert_eq!(report.workspace, Some(PathBuf::from("/ws")));
+        assert_eq!(report.patches, vec![patch]);
+        assert_eq!(report.summary.applied, 1);
+        assert!(report.ok);
+    }
+}
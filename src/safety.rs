@@ -22,6 +22,24 @@ pub enum SafetyError {
     Canonicalize(#[from] std::io::Error),
 }
 
+impl Clone for SafetyError {
+    fn clone(&self) -> Self {
+        match self {
+            Self::OutsideWorkspace { path, workspace } => Self::OutsideWorkspace {
+                path: path.clone(),
+                workspace: workspace.clone(),
+            },
+            Self::ForbiddenPath { path, forbidden } => Self::ForbiddenPath {
+                path: path.clone(),
+                forbidden: forbidden.clone(),
+            },
+            Self::Canonicalize(e) => {
+                Self::Canonicalize(std::io::Error::new(e.kind(), e.to_string()))
+            }
+        }
+    }
+}
+
 impl WorkspaceGuard {
     /// Create a new workspace guard with the given root.
     ///
@@ -1,6 +1,7 @@
 use crate::cache;
 use crate::sg::errors::AstGrepError;
 use crate::sg::lang::rust;
+use ast_grep_core::meta_var::MetaVariable;
 use ast_grep_core::tree_sitter::StrDoc;
 use ast_grep_core::{AstGrep, NodeMatch};
 use ast_grep_language::SupportLang;
@@ -14,26 +15,34 @@ pub struct PatternMatch {
     pub byte_end: usize,
     /// The matched text
     pub text: String,
-    /// Captured metavariables: name -> text
-    /// Note: For byte spans of captures, use find_capture_span()
+    /// Captured metavariables: name -> text. A `$$$NAME` capture is the
+    /// source text from its first to its last node, separators included.
     pub captures: HashMap<String, String>,
+    /// Byte ranges of the captured nodes: name -> (start, end). An empty
+    /// `$$$NAME` capture has no span.
+    pub capture_spans: HashMap<String, (usize, usize)>,
 }
 
 impl PatternMatch {
-    /// Find the byte span of a capture within the matched text.
-    ///
-    /// This is an approximation that finds the first occurrence of the
-    /// captured text within the matched region.
+    /// Find the byte span of a capture in the source.
     pub fn find_capture_span(&self, name: &str) -> Option<(usize, usize)> {
-        let capture_text = self.captures.get(name)?;
-        // Find the capture text within the match
-        let offset = self.text.find(capture_text)?;
-        let start = self.byte_start + offset;
-        let end = start + capture_text.len();
-        Some((start, end))
+        self.capture_spans.get(name).copied()
     }
 }
 
+/// Names of the metavariables a pattern captures (`$NAME` and `$$$NAME`;
+/// anonymous `$_` / `$$$` are not included), sorted.
+pub fn pattern_metavars(pattern: &str) -> Result<Vec<String>, AstGrepError> {
+    let pat = cache::try_get_or_compile_pattern(pattern, rust()).map_err(|e| {
+        AstGrepError::InvalidPattern {
+            message: e.to_string(),
+        }
+    })?;
+    let mut names: Vec<String> = pat.defined_vars().into_iter().map(String::from).collect();
+    names.sort();
+    Ok(names)
+}
+
 /// Pattern matcher using ast-grep's metavariable syntax.
 ///
 /// # Metavariable Syntax
@@ -67,7 +76,11 @@ impl PatternMatcher {
 
     /// Find all matches for a pattern.
     pub fn find_all(&self, pattern: &str) -> Result<Vec<PatternMatch>, AstGrepError> {
-        let pat = cache::get_or_compile_pattern(pattern, rust());
+        let pat = cache::try_get_or_compile_pattern(pattern, rust()).map_err(|e| {
+            AstGrepError::InvalidPattern {
+                message: e.to_string(),
+            }
+        })?;
         let root = self.sg.root();
         let matches: Vec<_> = root.find_all(&pat).collect();
 
@@ -195,6 +208,7 @@ impl PatternMatcher {
                 byte_end,
                 text,
                 captures: HashMap::new(), // No captures for kind-based matching
+                capture_spans: HashMap::new(),
             });
         }
 
@@ -225,15 +239,45 @@ impl PatternMatcher {
         let byte_end = range.end;
         let text = self.source[byte_start..byte_end].to_string();
 
-        // Convert MetaVarEnv to HashMap<String, String>
-        let env = m.get_env().clone();
-        let captures: HashMap<String, String> = env.into();
+        // MetaVarEnv's own conversion renders multi-captures as "[a, b]";
+        // slice the source instead so captures can be spliced back verbatim.
+        let env = m.get_env();
+        let mut captures = HashMap::new();
+        let mut capture_spans = HashMap::new();
+        for var in env.get_matched_variables() {
+            match var {
+                MetaVariable::Capture(name, _) => {
+                    if let Some(node) = env.get_match(&name) {
+                        let range = node.range();
+                        captures.insert(
+                            name.clone(),
+                            self.source[range.start..range.end].to_string(),
+                        );
+                        capture_spans.insert(name, (range.start, range.end));
+                    }
+                }
+                MetaVariable::MultiCapture(name) => {
+                    let nodes = env.get_multiple_matches(&name);
+                    let text = match (nodes.first(), nodes.last()) {
+                        (Some(first), Some(last)) => {
+                            let span = (first.range().start, last.range().end);
+                            capture_spans.insert(name.clone(), span);
+                            self.source[span.0..span.1].to_string()
+                        }
+                        _ => String::new(),
+                    };
+                    captures.insert(name, text);
+                }
+                MetaVariable::Dropped(_) | MetaVariable::Multiple => {}
+            }
+        }
 
         PatternMatch {
             byte_start,
             byte_end,
             text,
             captures,
+            capture_spans,
         }
     }
 }
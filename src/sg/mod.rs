@@ -11,5 +11,5 @@ pub mod replacer;
 
 pub use errors::AstGrepError;
 pub use lang::{rust, SupportLang};
-pub use matcher::{PatternMatch, PatternMatcher};
-pub use replacer::{CaptureReplacer, Replacement};
+pub use matcher::{pattern_metavars, PatternMatch, PatternMatcher};
+pub use replacer::{expand_template, template_metavars, CaptureReplacer, Replacement, TemplateVar};
@@ -1,6 +1,7 @@
 use crate::edit::{Edit, EditVerification};
 use crate::sg::errors::AstGrepError;
 use crate::sg::matcher::{PatternMatch, PatternMatcher};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 /// A replacement operation with source and target.
@@ -28,6 +29,70 @@ impl Replacement {
     }
 }
 
+/// A `$NAME` or `$$$NAME` reference in a replacement template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TemplateVar<'t> {
+    /// Byte range of the reference, `$`s included
+    pub start: usize,
+    pub end: usize,
+    pub name: &'t str,
+}
+
+/// Every metavariable reference in `template`, in order.
+///
+/// Names follow ast-grep: an uppercase letter, then uppercase letters, digits
+/// or `_`. Anything else after a `$` (`$_`, `$x`, a lone `$`) is literal text.
+pub fn template_metavars(template: &str) -> Vec<TemplateVar<'_>> {
+    let bytes = template.as_bytes();
+    let mut vars = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'$' {
+            i += 1;
+            continue;
+        }
+        let dollars = if template[i..].starts_with("$$$") {
+            3
+        } else {
+            1
+        };
+        let name_start = i + dollars;
+        let name_len = template[name_start..]
+            .bytes()
+            .take_while(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || *b == b'_')
+            .count();
+        let name = &template[name_start..name_start + name_len];
+        if name.starts_with(|c: char| c.is_ascii_uppercase()) {
+            vars.push(TemplateVar {
+                start: i,
+                end: name_start + name_len,
+                name,
+            });
+            i = name_start + name_len;
+        } else {
+            i += dollars;
+        }
+    }
+    vars
+}
+
+/// Expand `$NAME` / `$$$NAME` references in `template` from `captures`.
+///
+/// References without a capture are left as written.
+pub fn expand_template(template: &str, captures: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut last = 0;
+    for var in template_metavars(template) {
+        if let Some(text) = captures.get(var.name) {
+            result.push_str(&template[last..var.start]);
+            result.push_str(text);
+            last = var.end;
+        }
+    }
+    result.push_str(&template[last..]);
+    result
+}
+
 /// Builder for capture-based replacements.
 ///
 /// Allows replacing specific captured metavariables or the entire match.
@@ -57,8 +122,9 @@ impl<'a> CaptureReplacer<'a> {
 
     /// Replace a specific captured metavariable with new text.
     ///
-    /// Note: This uses string search to find the capture position, which
-    /// may not be accurate if the captured text appears multiple times.
+    /// Only the captured node's span is replaced (for `$$$NAME`, from its
+    /// first to its last node). An empty `$$$NAME` capture has no span and
+    /// is reported as [`AstGrepError::MetavarNotFound`].
     pub fn replace_capture(
         &self,
         capture_name: &str,
@@ -89,7 +155,8 @@ impl<'a> CaptureReplacer<'a> {
 
     /// Replace using a template that references captures.
     ///
-    /// Template syntax: `$NAME` references a captured metavariable.
+    /// Template syntax: `$NAME` or `$$$NAME` references a captured
+    /// metavariable (see [`expand_template`]).
     ///
     /// # Example
     ///
@@ -98,23 +165,11 @@ impl<'a> CaptureReplacer<'a> {
     /// Template: fn renamed_$NAME($$$PARAMS) { $$$BODY }
     /// ```
     pub fn replace_with_template(&self, template: &str) -> Replacement {
-        let mut result = template.to_string();
-
-        // Replace all $NAME references with their captured values
-        for (name, capture_text) in &self.pattern_match.captures {
-            // Handle both $ and $$$ style placeholders
-            let placeholder = format!("${name}");
-            result = result.replace(&placeholder, capture_text);
-
-            let variadic_placeholder = format!("$$${name}");
-            result = result.replace(&variadic_placeholder, capture_text);
-        }
-
         Replacement {
             byte_start: self.pattern_match.byte_start,
             byte_end: self.pattern_match.byte_end,
             original: self.pattern_match.text.clone(),
-            replacement: result,
+            replacement: expand_template(template, &self.pattern_match.captures),
         }
     }
 
@@ -204,8 +259,54 @@ mod tests {
 
         let repl = replacer.replace_with_template("fn renamed_$NAME() { $$$BODY }");
 
-        // Note: The template expansion uses captured values
-        assert!(repl.replacement.contains("renamed_foo"));
+        assert_eq!(repl.replacement, "fn renamed_foo() { 42 }");
+    }
+
+    #[test]
+    fn template_keeps_multi_capture_separators() {
+        let source = "fn f() { call(a,  b, c); }";
+        let matcher = PatternMatcher::new(source);
+        let m = matcher.find_unique("call($$$ARGS)").unwrap();
+        let replacer = CaptureReplacer::new(&matcher, m);
+
+        let repl = replacer.replace_with_template("traced(\"call\", $$$ARGS)");
+
+        assert_eq!(repl.replacement, "traced(\"call\", a,  b, c)");
+    }
+
+    #[test]
+    fn replace_capture_uses_node_position() {
+        // `x` appears twice; the second argument must be the one replaced.
+        let source = "fn f() { call(x, x, y); }";
+        let matcher = PatternMatcher::new(source);
+        let m = matcher.find_unique("call($A, $B, $$$REST)").unwrap();
+        let replacer = CaptureReplacer::new(&matcher, m);
+
+        let repl = replacer.replace_capture("B", "z").unwrap();
+        assert_eq!(
+            (repl.byte_start, repl.byte_end),
+            (source.rfind("x").unwrap(), source.rfind("x").unwrap() + 1)
+        );
+        assert_eq!(repl.original, "x");
+
+        let rest = replacer.replace_capture("REST", "w").unwrap();
+        assert_eq!(&source[rest.byte_start..rest.byte_end], "y");
+        assert!(matches!(
+            replacer.replace_capture("MISSING", "w"),
+            Err(AstGrepError::MetavarNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn template_metavar_names() {
+        let names: Vec<_> = template_metavars("$A + $AB * $$$REST - $_ $x $ $1")
+            .iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, ["A", "AB", "REST"]);
+
+        let captures = HashMap::from([("A".to_string(), "1".to_string())]);
+        assert_eq!(expand_template("$A + $AB", &captures), "1 + $AB");
     }
 
     #[test]
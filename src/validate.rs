@@ -163,7 +163,10 @@ pub mod pooled {
                 .collect();
 
             if !new_errors.is_empty() {
-                let error_details = collect_errors(&edited_parsed, edited);
+                let error_details: Vec<_> = collect_errors(&edited_parsed, edited)
+                    .into_iter()
+                    .filter(|e| !original_errors.contains(&(e.byte_start, e.byte_end)))
+                    .collect();
                 return Err(ValidationError::ParseErrorIntroduced {
                     count: error_details.len(),
                     errors: error_details,
@@ -147,7 +147,7 @@ fn test_apply_idempotent() {
 #[test]
 fn test_apply_dry_run() {
     let workspace = setup_test_workspace();
-    let _original_content = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+    let original_content = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
 
     let output = Command::new("cargo")
         .args([
@@ -166,9 +166,8 @@ fn test_apply_dry_run() {
     // Check that dry-run flag is recognized
     assert!(stdout.contains("DRY RUN") || stdout.contains("showing what would be applied"));
 
-    // Note: Current implementation actually applies patches even in dry-run
-    // because patches are idempotent. This is documented behavior.
-    // In a future version, dry-run should not modify files.
+    let after = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+    assert_eq!(after, original_content, "dry run must not modify workspace");
 }
 
 #[test]
@@ -314,3 +313,1109 @@ fn test_missing_workspace() {
 
     assert!(!output.status.success());
 }
+
+#[test]
+fn test_apply_atomic_leaves_workspace_untouched_on_failure() {
+    let workspace = setup_test_workspace();
+    let original = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+
+    fs::write(
+        workspace.path().join("patches/text-patches.toml"),
+        r#"[meta]
+name = "text-patches"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"HELLO\");"
+
+[[patches]]
+id = "missing-target"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "fn does_not_exist() {}"
+
+[patches.operation]
+type = "replace"
+text = "fn exists() {}"
+"#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "apply",
+            "--atomic",
+            "--workspace",
+            workspace.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no files were written"));
+
+    let after = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+    assert_eq!(
+        after, original,
+        "atomic apply must not leave partial changes"
+    );
+}
+
+#[test]
+fn test_recover_without_journal() {
+    let workspace = setup_test_workspace();
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "recover",
+            "--workspace",
+            workspace.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("No interrupted transaction"));
+}
+
+#[test]
+fn test_revert_restores_original() {
+    let workspace = setup_test_workspace();
+    let original = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+
+    fs::write(
+        workspace.path().join("patches/test-patch.toml"),
+        r#"[meta]
+name = "text-patches"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"HELLO\");"
+"#,
+    )
+    .unwrap();
+
+    let run = |command: &str| {
+        Command::new("cargo")
+            .args([
+                "run",
+                "--quiet",
+                "--",
+                command,
+                "--workspace",
+                workspace.path().to_str().unwrap(),
+            ])
+            .output()
+            .unwrap()
+    };
+
+    assert!(run("apply").status.success());
+    assert_ne!(
+        fs::read_to_string(workspace.path().join("test.rs")).unwrap(),
+        original
+    );
+
+    let output = run("revert");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("greet-loudly: Reverted"));
+    assert_eq!(
+        fs::read_to_string(workspace.path().join("test.rs")).unwrap(),
+        original
+    );
+}
+
+#[test]
+fn test_status_ledger_reports_drift() {
+    let workspace = setup_test_workspace();
+
+    fs::write(
+        workspace.path().join("patches/test-patch.toml"),
+        r#"[meta]
+name = "text-patches"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"HELLO\");"
+"#,
+    )
+    .unwrap();
+
+    let run = |args: &[&str]| {
+        Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args(args)
+            .args(["--workspace", workspace.path().to_str().unwrap()])
+            .output()
+            .unwrap()
+    };
+
+    assert!(run(&["apply"]).status.success());
+    assert!(workspace
+        .path()
+        .join(".codex-patcher/ledger.jsonl")
+        .exists());
+
+    let output = run(&["status", "--ledger"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("greet-loudly [text-patches] test.rs: intact"));
+    assert!(!stdout.contains("Patch Status Report"));
+
+    let test_rs = workspace.path().join("test.rs");
+    let edited = fs::read_to_string(&test_rs)
+        .unwrap()
+        .replace("HELLO", "Howdy");
+    fs::write(&test_rs, edited).unwrap();
+
+    let output = run(&["status", "--ledger"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 drifted"));
+    assert!(stdout.contains("DRIFTED"));
+}
+
+#[test]
+fn test_apply_ndjson_streams_events() {
+    let workspace = setup_test_workspace();
+    fs::write(
+        workspace.path().join("patches/text-patch.toml"),
+        r#"[meta]
+name = "text-patches"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"HELLO\");"
+
+[[patches]]
+id = "missing"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "does not exist"
+
+[patches.operation]
+type = "replace"
+text = "never"
+"#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "--format",
+            "ndjson",
+            "apply",
+            "--workspace",
+            workspace.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("every stdout line is JSON"))
+        .collect();
+
+    assert_eq!(events.first().unwrap()["event"], "start");
+    assert_eq!(events.first().unwrap()["schema_version"], 1);
+    let summary = events.last().unwrap();
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["applied"], 1);
+    assert_eq!(summary["ok"], false);
+
+    let patch = |id: &str| {
+        events
+            .iter()
+            .find(|e| e["event"] == "patch" && e["patch_id"] == id)
+            .unwrap()
+    };
+    assert_eq!(patch("greet-loudly")["result"], "applied");
+    assert_eq!(patch("greet-loudly")["config"], "text-patches");
+    assert_eq!(patch("missing")["result"], "error");
+    assert_eq!(patch("missing")["error"]["kind"], "no_match");
+}
+
+#[test]
+fn test_verify_json_document() {
+    let workspace = setup_test_workspace();
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "verify",
+            "--format",
+            "json",
+            "--workspace",
+            workspace.path().to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("stdout is one JSON document");
+    assert_eq!(report["command"], "verify");
+    assert_eq!(report["configs"].as_array().unwrap().len(), 1);
+    assert_eq!(report["patches"][0]["patch_id"], "modify-hello");
+    assert_eq!(report["ok"], output.status.success());
+}
+
+#[test]
+fn test_list_applies_to_variants() {
+    let workspace = setup_test_workspace();
+    fs::remove_file(workspace.path().join("patches/test-patch.toml")).unwrap();
+    fs::write(
+        workspace.path().join("patches/variants.toml"),
+        r#"[meta]
+name = "variants"
+workspace_relative = true
+
+[[patches]]
+id = "greet"
+file = "test.rs"
+version = ">=0.90.0"
+
+[patches.query]
+type = "text"
+search = "Hello"
+
+[patches.operation]
+type = "replace"
+text = "Hi"
+
+[[patches.variants]]
+version = ">=0.80.0, <0.90.0"
+
+[patches.variants.query]
+type = "text"
+search = "Helo"
+
+[patches.variants.operation]
+type = "replace"
+text = "Hi"
+"#,
+    )
+    .unwrap();
+    let list = |args: &[&str]| {
+        Command::new("cargo")
+            .args(["run", "--quiet", "--", "list"])
+            .args(["--workspace", workspace.path().to_str().unwrap()])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    // 0.85.0 is only covered by the variant.
+    let output = list(&["--applies-to", "0.85.0", "--format", "json"]);
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let patch = &report["listed_patches"][0];
+    assert_eq!(patch["id"], "greet");
+    assert_eq!(patch["version"], ">=0.90.0");
+    assert_eq!(patch["variants"], serde_json::json!([">=0.80.0, <0.90.0"]));
+
+    let output = list(&["--applies-to", "0.70.0", "--format", "json"]);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(report["listed_patches"].as_array().unwrap().is_empty());
+
+    let output = list(&[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("variants: >=0.80.0, <0.90.0"), "{stdout}");
+}
+
+#[test]
+fn test_list_filters_patches() {
+    let workspace = setup_test_workspace();
+    fs::write(
+        workspace.path().join("patches/versioned.toml"),
+        r#"[meta]
+name = "versioned"
+version_range = ">=0.88.0"
+workspace_relative = true
+
+[[patches]]
+id = "old-only"
+file = "Cargo.toml"
+version = "<0.90.0"
+
+[patches.query]
+type = "toml"
+section = "package"
+key = "edition"
+
+[patches.operation]
+type = "replace-value"
+value = "\"2024\""
+
+[[patches]]
+id = "any-version"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "Hello"
+
+[patches.operation]
+type = "replace"
+text = "Hi"
+"#,
+    )
+    .unwrap();
+
+    let list = |filters: &[&str]| {
+        let output = Command::new("cargo")
+            .args(["run", "--quiet", "--", "list", "--format", "json"])
+            .args(["--workspace", workspace.path().to_str().unwrap()])
+            .args(filters)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        report["listed_patches"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["id"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(list(&[]), ["modify-hello", "old-only", "any-version"]);
+    assert_eq!(
+        list(&["--file", "test.rs"]),
+        ["modify-hello", "any-version"]
+    );
+    assert_eq!(list(&["--query-type", "toml"]), ["old-only"]);
+    assert_eq!(
+        list(&["--applies-to", "0.89.0"]),
+        ["modify-hello", "old-only", "any-version"]
+    );
+    assert_eq!(
+        list(&["--applies-to", "0.95.0"]),
+        ["modify-hello", "any-version"]
+    );
+    assert_eq!(list(&["--applies-to", "0.50.0"]), ["modify-hello"]);
+
+    let output = Command::new("cargo")
+        .args(["run", "--quiet", "--", "list", "--query-type", "toml"])
+        .args(["--workspace", workspace.path().to_str().unwrap()])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("version_range: >=0.88.0"));
+    assert!(stdout.contains("[toml → replace-value]"));
+    assert!(stdout.contains("version: <0.90.0"));
+    assert!(!stdout.contains("test-patches"));
+}
+
+#[test]
+fn test_selection_by_tag_id_and_profile() {
+    let workspace = setup_test_workspace();
+    fs::write(
+        workspace.path().join("patches/greetings.toml"),
+        r#"[meta]
+name = "greetings"
+tags = ["ui"]
+workspace_relative = true
+
+[[patches]]
+id = "hello"
+file = "test.rs"
+tags = ["loud"]
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"HELLO\");"
+
+[[patches]]
+id = "world"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"World\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"WORLD\");"
+"#,
+    )
+    .unwrap();
+    fs::write(
+        workspace.path().join("codex-patcher.toml"),
+        "[profiles.quiet]\nonly = [\"greetings\"]\nexclude = [\"hello\"]\n",
+    )
+    .unwrap();
+
+    let run = |args: &[&str]| {
+        let output = Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args(args)
+            .args(["--format", "json", "--workspace"])
+            .arg(workspace.path())
+            .output()
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+        (output, report)
+    };
+    let ids = |report: &serde_json::Value, key: &str, field: &str| {
+        report[key]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p[field].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let (_, report) = run(&["list", "--tag", "loud"]);
+    assert_eq!(ids(&report, "listed_patches", "id"), ["hello"]);
+    assert_eq!(
+        report["listed_patches"][0]["tags"],
+        serde_json::json!(["ui", "loud"])
+    );
+    let (_, report) = run(&["list", "--tag", "ui", "--exclude", "greetings:world"]);
+    assert_eq!(ids(&report, "listed_patches", "id"), ["hello"]);
+
+    let (_, report) = run(&["status", "--profile", "quiet"]);
+    assert_eq!(ids(&report, "patches", "patch_id"), ["world"]);
+
+    let (output, report) = run(&["apply", "--only", "hello"]);
+    assert!(output.status.success());
+    assert_eq!(ids(&report, "patches", "patch_id"), ["hello"]);
+    let patched = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+    assert!(patched.contains("HELLO") && patched.contains("println!(\"World\")"));
+
+    let (output, _) = run(&["verify", "--only", "no-such-patch"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no patches match the selection"));
+    let (output, _) = run(&["status", "--profile", "missing"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown profile missing"));
+}
+
+#[test]
+fn test_only_checks_deselected_requirements_on_disk() {
+    let workspace = setup_test_workspace();
+    fs::remove_file(workspace.path().join("patches/test-patch.toml")).unwrap();
+    fs::write(
+        workspace.path().join("patches/undo.toml"),
+        r#"[meta]
+name = "undo"
+workspace_relative = true
+
+[[patches]]
+id = "undo"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"Undo\");"
+"#,
+    )
+    .unwrap();
+    fs::write(
+        workspace.path().join("patches/ui.toml"),
+        r#"[meta]
+name = "ui"
+workspace_relative = true
+
+[[patches]]
+id = "ui"
+file = "test.rs"
+requires = ["undo"]
+
+[patches.query]
+type = "text"
+search = "println!(\"World\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"UI\");"
+"#,
+    )
+    .unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::new("cargo")
+            .args(["run", "--quiet", "--", "apply", "--format", "json"])
+            .args(args)
+            .arg("--workspace")
+            .arg(workspace.path())
+            .output()
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        (output, report)
+    };
+
+    // `undo` is not applied yet, so `ui` alone is refused.
+    for atomic in [&[][..], &["--atomic"][..]] {
+        let (output, report) = run(&[&["--only", "ui"][..], atomic].concat());
+        assert!(!output.status.success());
+        let error = &report["patches"][0]["error"];
+        assert_eq!(error["kind"], "prerequisite_failed", "{report}");
+        assert_eq!(error["details"]["reason"], "not selected and not applied");
+    }
+
+    let (output, _) = run(&["--only", "undo"]);
+    assert!(output.status.success());
+    for atomic in [&["--dry-run"][..], &["--atomic"][..]] {
+        let (output, report) = run(&[&["--only", "ui"][..], atomic].concat());
+        assert!(output.status.success(), "{report}");
+        assert_eq!(report["patches"][0]["patch_id"], "ui");
+        assert_eq!(report["patches"][0]["result"], "applied");
+    }
+    let patched = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+    assert!(
+        patched.contains("Undo") && patched.contains("UI"),
+        "{patched}"
+    );
+}
+
+#[test]
+fn test_version_sources() {
+    let workspace = setup_test_workspace();
+    let run = |args: &[&str]| {
+        let output = Command::new("cargo")
+            .args([
+                "run",
+                "--quiet",
+                "--",
+                "status",
+                "--format",
+                "json",
+                "--workspace",
+            ])
+            .arg(workspace.path())
+            .args(args)
+            .output()
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+        (output, report)
+    };
+
+    let (_, report) = run(&[]);
+    assert_eq!(report["workspace_version"], "0.88.0");
+    assert!(report["version_source"]
+        .as_str()
+        .unwrap()
+        .starts_with("cargo metadata"));
+
+    let (_, report) = run(&["--version", "0.93.0-alpha.4"]);
+    assert_eq!(report["workspace_version"], "0.93.0-alpha.4");
+    assert_eq!(report["version_source"], "--version");
+
+    fs::write(workspace.path().join("VERSION"), "0.91.0\n").unwrap();
+    fs::write(
+        workspace.path().join("codex-patcher.toml"),
+        "[version]\nsources = [\"file\", \"cargo\"]\n",
+    )
+    .unwrap();
+    let (_, report) = run(&[]);
+    assert_eq!(report["workspace_version"], "0.91.0");
+    assert!(report["version_source"]
+        .as_str()
+        .unwrap()
+        .starts_with("version file"));
+
+    let (output, _) = run(&["--version", "latest"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not a semver version"));
+}
+
+#[test]
+fn test_conflicts_reports_overlapping_configs() {
+    let workspace = setup_test_workspace();
+    let original = fs::read_to_string(workspace.path().join("test.rs")).unwrap();
+    fs::write(
+        workspace.path().join("patches/greeting.toml"),
+        r#"[meta]
+name = "greeting"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"HELLO\");"
+"#,
+    )
+    .unwrap();
+    fs::write(
+        workspace.path().join("patches/quotes.toml"),
+        r#"[meta]
+name = "quotes"
+workspace_relative = true
+
+[[patches]]
+id = "quote-hello"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "\"Hello\""
+
+[patches.operation]
+type = "replace"
+text = "'Hello'"
+"#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--quiet", "--", "conflicts", "--format", "json"])
+        .args(["--workspace", workspace.path().to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let overlap = &report["overlaps"][0];
+    assert_eq!(overlap["kind"], "overlapping");
+    assert_eq!(overlap["file"], "test.rs");
+    assert_eq!(overlap["first"]["patch_id"], "greet-loudly");
+    assert_eq!(overlap["second"]["config"], "quotes");
+
+    let output = Command::new("cargo")
+        .args(["run", "--quiet", "--", "apply", "--deny-overlaps"])
+        .args(["--workspace", workspace.path().to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("greeting:greet-loudly"));
+    assert!(stderr.contains("REFUSED"));
+    assert_eq!(
+        fs::read_to_string(workspace.path().join("test.rs")).unwrap(),
+        original
+    );
+}
+
+#[test]
+fn test_fix_applies_autofix_until_clean() {
+    let workspace = TempDir::new().unwrap();
+    fs::write(
+        workspace.path().join("Cargo.toml"),
+        r#"[package]
+name = "fix-fixture"
+version = "0.1.0"
+edition = "2021"
+
+[workspace]
+"#,
+    )
+    .unwrap();
+    fs::create_dir(workspace.path().join("src")).unwrap();
+    fs::write(
+        workspace.path().join("src/lib.rs"),
+        r#"pub struct Config {
+    pub name: String,
+    pub enabled: bool,
+}
+
+pub fn make() -> Config {
+    Config {
+        name: String::new(),
+    }
+}
+"#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "fix",
+            "--workspace",
+            workspace.path().to_str().unwrap(),
+            "--max-iterations",
+            "3",
+        ])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("Build is clean (1 file(s) fixed)"));
+    assert!(stdout.contains("+        enabled: false,"));
+    let fixed = fs::read_to_string(workspace.path().join("src/lib.rs")).unwrap();
+    assert!(fixed.contains("enabled: false"));
+}
+
+#[test]
+fn test_apply_check_build_reverts_broken_patch() {
+    let workspace = TempDir::new().unwrap();
+    fs::write(
+        workspace.path().join("Cargo.toml"),
+        r#"[package]
+name = "check-build-fixture"
+version = "0.1.0"
+edition = "2021"
+
+[workspace]
+"#,
+    )
+    .unwrap();
+    fs::create_dir(workspace.path().join("src")).unwrap();
+    fs::write(
+        workspace.path().join("src/lib.rs"),
+        "pub fn answer() -> u32 {\n    42\n}\n\npub fn other() -> u32 {\n    1\n}\n",
+    )
+    .unwrap();
+    fs::create_dir(workspace.path().join("patches")).unwrap();
+    fs::write(
+        workspace.path().join("patches/build.toml"),
+        r#"[meta]
+name = "build-patches"
+workspace_relative = true
+
+[[patches]]
+id = "good"
+file = "src/lib.rs"
+
+[patches.query]
+type = "text"
+search = "    1\n"
+
+[patches.operation]
+type = "replace"
+text = "    2\n"
+
+[[patches]]
+id = "bad"
+file = "src/lib.rs"
+
+[patches.query]
+type = "text"
+search = "    42\n"
+
+[patches.operation]
+type = "replace"
+text = "    \"forty-two\"\n"
+"#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .args([
+            "run",
+            "--quiet",
+            "--",
+            "--format",
+            "json",
+            "apply",
+            "--workspace",
+            workspace.path().to_str().unwrap(),
+            "--check-build",
+            "--revert-broken",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let check = &report["build_check"];
+    assert_eq!(check["packages"][0], "check-build-fixture");
+    assert_eq!(check["clean"], false);
+    assert_eq!(check["broken"].as_array().unwrap().len(), 1);
+    assert_eq!(check["broken"][0]["patch_id"], "bad");
+    assert_eq!(check["broken"][0]["line"], 2);
+    assert_eq!(check["broken"][0]["reverted"], true);
+    assert_eq!(check["broken"][0]["error"]["code"], "E0308");
+
+    let lib = fs::read_to_string(workspace.path().join("src/lib.rs")).unwrap();
+    assert!(lib.contains("    42\n"), "broken patch reverted");
+    assert!(lib.contains("    2\n"), "good patch kept");
+}
+
+#[test]
+fn test_refresh_rewrites_fuzzy_search_text() {
+    let workspace = setup_test_workspace();
+    fs::remove_file(workspace.path().join("patches/test-patch.toml")).unwrap();
+    let patch_file = workspace.path().join("patches/stale.toml");
+    fs::write(
+        &patch_file,
+        r#"[meta]
+name = "stale"
+workspace_relative = true
+
+# Upstream renamed the greeting since this was written.
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+version = ">=0.80.0, <0.85.0"
+
+[patches.query]
+type = "text"
+search = "    println!(\"Helo\");" # keep this comment
+fuzzy_threshold = 0.8
+
+[patches.operation]
+type = "replace"
+text = "    println!(\"HELLO\");"
+"#,
+    )
+    .unwrap();
+    let run = |args: &[&str]| {
+        Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args(args)
+            .arg("--workspace")
+            .arg(workspace.path())
+            .output()
+            .unwrap()
+    };
+
+    // The version requirement excludes 0.88.0, so only --widen-version
+    // makes the patch apply.
+    let output = run(&["refresh", "--dry-run", "--format", "json"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("\"event\":\"refresh\""), "{stdout}");
+
+    let output = run(&["refresh", "--widen-version", "--format", "json"]);
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let refreshes = report["refreshes"].as_array().unwrap();
+    assert_eq!(refreshes.len(), 2, "{report}");
+    assert_eq!(refreshes[0]["kind"], "version");
+    assert_eq!(refreshes[0]["new"], ">=0.80.0, <=0.88.0");
+    assert_eq!(refreshes[1]["kind"], "search");
+    assert_eq!(refreshes[1]["new"], "    println!(\"Hello\");");
+
+    let refreshed = fs::read_to_string(&patch_file).unwrap();
+    assert!(refreshed.contains("# Upstream renamed the greeting"));
+    assert!(refreshed.contains("search = '    println!(\"Hello\");' # keep this comment"));
+    assert!(refreshed.contains("version = \">=0.80.0, <=0.88.0\""));
+
+    // Exact now: nothing left to refresh, and apply no longer needs fuzz.
+    let output = run(&["refresh", "--widen-version"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("up to date"));
+    let output = run(&["apply", "--refresh-patches"]);
+    assert!(output.status.success());
+    assert!(fs::read_to_string(workspace.path().join("test.rs"))
+        .unwrap()
+        .contains("HELLO"));
+}
+
+#[test]
+fn test_no_match_reports_near_misses() {
+    let workspace = setup_test_workspace();
+    fs::remove_file(workspace.path().join("patches/test-patch.toml")).unwrap();
+    fs::write(
+        workspace.path().join("patches/drifted.toml"),
+        r#"[meta]
+name = "drifted"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "    println!(\"Helo\");"
+
+[patches.operation]
+type = "replace"
+text = "    println!(\"HELLO\");"
+"#,
+    )
+    .unwrap();
+    let run = |args: &[&str]| {
+        Command::new("cargo")
+            .args(["run", "--quiet", "--", "apply", "--dry-run", "--workspace"])
+            .arg(workspace.path())
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&["--format", "json"]);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let error = &report["patches"][0]["error"];
+    assert_eq!(error["kind"], "no_match");
+    let candidate = &error["details"]["candidates"][0];
+    assert_eq!(candidate["line"], 3);
+    assert_eq!(candidate["text"], "    println!(\"Hello\");");
+    assert_eq!(
+        candidate["diff"],
+        "-    println!(\"Helo\");\n+    println!(\"Hello\");\n"
+    );
+
+    let output = run(&[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Closest candidates:"), "{stderr}");
+    assert!(stderr.contains("test.rs:3"), "{stderr}");
+    assert!(!stderr.contains("Possible causes"), "{stderr}");
+}
+
+#[test]
+fn test_apply_reports_relocated_target() {
+    let workspace = setup_test_workspace();
+    fs::remove_file(workspace.path().join("patches/test-patch.toml")).unwrap();
+    fs::create_dir(workspace.path().join("moved")).unwrap();
+    fs::write(
+        workspace.path().join("moved/greet.rs"),
+        "fn greet() -> &'static str {\n    \"hello\"\n}\n",
+    )
+    .unwrap();
+    fs::write(
+        workspace.path().join("patches/moved.toml"),
+        r#"[meta]
+name = "moved"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "greet.rs"
+search_paths = ["moved/*.rs"]
+
+[patches.query]
+type = "ast-grep"
+pattern = '"hello"'
+
+[patches.operation]
+type = "replace"
+text = '"HELLO"'
+"#,
+    )
+    .unwrap();
+    let run = |args: &[&str]| {
+        Command::new("cargo")
+            .args(["run", "--quiet", "--", "apply", "--workspace"])
+            .arg(workspace.path())
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&["--dry-run", "--format", "json"]);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let patch = &report["patches"][0];
+    assert_eq!(patch["result"], "applied");
+    assert!(patch["file"].as_str().unwrap().ends_with("moved/greet.rs"));
+    assert!(patch["relocated_from"]
+        .as_str()
+        .unwrap()
+        .ends_with("greet.rs"));
+
+    let output = run(&[]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("target moved from"), "{stdout}");
+    let patched = fs::read_to_string(workspace.path().join("moved/greet.rs")).unwrap();
+    assert!(patched.contains("\"HELLO\""), "{patched}");
+}
+
+#[test]
+fn test_ledger_failure_is_reported_as_warning() {
+    let workspace = setup_test_workspace();
+    fs::write(
+        workspace.path().join("patches/test-patch.toml"),
+        r#"[meta]
+name = "greetings"
+workspace_relative = true
+
+[[patches]]
+id = "greet-loudly"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "println!(\"Hello\");"
+
+[patches.operation]
+type = "replace"
+text = "println!(\"HELLO\");"
+"#,
+    )
+    .unwrap();
+    // A file where the state directory belongs.
+    fs::write(workspace.path().join(".codex-patcher"), "").unwrap();
+    let run = |args: &[&str]| {
+        Command::new("cargo")
+            .args(["run", "--quiet", "--"])
+            .args(args)
+            .arg("--workspace")
+            .arg(workspace.path())
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&["apply", "--format", "json"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["patches"][0]["result"], "applied");
+    let warning = report["warnings"][0].as_str().unwrap();
+    assert!(warning.starts_with("ledger not updated"), "{warning}");
+
+    let output = run(&["revert"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: ledger not updated"), "{stderr}");
+}
@@ -3,8 +3,9 @@
 //! Tests version filtering, idempotency checks, and full patch application
 
 use codex_patcher::config::{
-    apply_patches, load_from_path, load_from_str, ApplicationError, HashAlgorithm, Metadata,
-    Operation, PatchConfig, PatchDefinition, PatchResult, Query, Verify,
+    apply_patches, load_from_path, load_from_str, ApplicationError, ApplyMode, HashAlgorithm,
+    Metadata, Occurrence, Operation, PatchConfig, PatchDefinition, PatchResult, Query, Selection,
+    Verify,
 };
 use std::fs;
 use std::path::PathBuf;
@@ -215,6 +216,7 @@ fn test_apply_patches_empty() {
             description: None,
             version_range: None,
             workspace_relative: false,
+            ..Default::default()
         },
         patches: vec![],
     };
@@ -231,12 +233,15 @@ fn test_apply_patches_file_not_found() {
             description: None,
             version_range: None,
             workspace_relative: false,
+            ..Default::default()
         },
         patches: vec![PatchDefinition {
             id: "patch-1".to_string(),
             file: "/nonexistent/file.rs".to_string(),
             query: Query::AstGrep {
                 pattern: "fn test() {}".to_string(),
+                capture: None,
+                selection: Selection::default(),
             },
             operation: Operation::Replace {
                 text: "fn test() { println!(\"hi\"); }".to_string(),
@@ -244,6 +249,15 @@ fn test_apply_patches_file_not_found() {
             verify: None,
             constraint: None,
             version: None,
+            min_matches: None,
+            max_matches: None,
+            requires: Vec::new(),
+            conflicts: Vec::new(),
+            mode: None,
+            tags: Vec::new(),
+            variants: Vec::new(),
+            search_paths: Vec::new(),
+            relocate: false,
         }],
     };
 
@@ -254,7 +268,7 @@ fn test_apply_patches_file_not_found() {
     assert_eq!(id, "patch-1");
     assert!(result.is_err());
 
-    if let Err(ApplicationError::NoMatch { file }) = result {
+    if let Err(ApplicationError::NoMatch { file, .. }) = result {
         assert!(file.to_string_lossy().contains("nonexistent"));
     } else {
         panic!("Expected NoMatch error");
@@ -278,12 +292,15 @@ fn test_idempotency_check_logic() {
             description: None,
             version_range: None,
             workspace_relative: true,
+            ..Default::default()
         },
         patches: vec![PatchDefinition {
             id: "patch-1".to_string(),
             file: "test.rs".to_string(),
             query: Query::AstGrep {
                 pattern: "fn hello() { $$$BODY }".to_string(),
+                capture: None,
+                selection: Selection::default(),
             },
             operation: Operation::Replace {
                 text: r#"fn hello() {
@@ -294,6 +311,15 @@ fn test_idempotency_check_logic() {
             verify: None,
             constraint: None,
             version: None,
+            min_matches: None,
+            max_matches: None,
+            requires: Vec::new(),
+            conflicts: Vec::new(),
+            mode: None,
+            tags: Vec::new(),
+            variants: Vec::new(),
+            search_paths: Vec::new(),
+            relocate: false,
         }],
     };
 
@@ -449,6 +475,421 @@ new_key = "renamed"
         .contains("query type 'ast-grep' does not support operation 'replace-key'"));
 }
 
+#[test]
+fn test_validation_rejects_function_context_on_toml_query() {
+    let toml = r#"
+[meta]
+name = "invalid-combo"
+
+[[patches]]
+id = "toml-scoped"
+file = "Cargo.toml"
+
+[patches.query]
+type = "toml"
+section = "package"
+key = "name"
+
+[patches.operation]
+type = "replace-value"
+value = "\"patched\""
+
+[patches.constraint]
+function_context = "main"
+"#;
+
+    let result = load_from_str(toml);
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("function_context is not supported on toml queries"));
+}
+
+#[test]
+fn test_validation_rejects_malformed_function_context() {
+    let toml = r#"
+[meta]
+name = "invalid-context"
+
+[[patches]]
+id = "bad-context"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "foo"
+
+[patches.operation]
+type = "replace"
+text = "bar"
+
+[patches.constraint]
+function_context = "a::b::c"
+"#;
+
+    let result = load_from_str(toml);
+    assert!(result.is_err());
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("function_context must be `name` or `Type::method`"));
+}
+
+fn text_patch_with_query_extra(extra: &str) -> String {
+    format!(
+        r#"
+[meta]
+name = "selection"
+
+[[patches]]
+id = "urls"
+file = "test.rs"
+
+[patches.query]
+type = "text"
+search = "http://old"
+{extra}
+
+[patches.operation]
+type = "replace"
+text = "https://new"
+"#
+    )
+}
+
+#[test]
+fn test_load_occurrence_selection() {
+    let config = load_from_str(&text_patch_with_query_extra(
+        "occurrence = \"all\"\nexpected_count = 3",
+    ))
+    .unwrap();
+    assert_eq!(
+        config.patches[0].query.selection(),
+        Some(&Selection {
+            occurrence: Some(Occurrence::All),
+            expected_count: Some(3),
+        })
+    );
+
+    let config = load_from_str(&text_patch_with_query_extra("occurrence = 2")).unwrap();
+    assert_eq!(
+        config.patches[0].query.selection().unwrap().occurrence,
+        Some(Occurrence::Nth(2))
+    );
+}
+
+#[test]
+fn test_validation_rejects_bad_occurrence_selection() {
+    for (extra, message) in [
+        ("occurrence = 0", "occurrence must be"),
+        ("occurrence = \"every\"", "occurrence must be"),
+        ("expected_count = 2", "expected_count requires occurrence"),
+        (
+            "occurrence = 3\nexpected_count = 2",
+            "occurrence 3 is out of range for expected_count 2",
+        ),
+        (
+            "occurrence = \"all\"\nfuzzy_threshold = 0.9",
+            "fuzzy matching cannot be combined with occurrence",
+        ),
+    ] {
+        let error = load_from_str(&text_patch_with_query_extra(extra))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(message), "{extra}: {error}");
+    }
+}
+
+fn ast_grep_replace(pattern: &str, text: &str) -> String {
+    format!(
+        r#"
+[meta]
+name = "templates"
+
+[[patches]]
+id = "template"
+file = "test.rs"
+
+[patches.query]
+type = "ast-grep"
+pattern = '{pattern}'
+
+[patches.operation]
+type = "replace"
+text = '{text}'
+"#
+    )
+}
+
+#[test]
+fn test_load_replacement_template() {
+    let config = load_from_str(&ast_grep_replace(
+        "reqwest::Client::builder().$$$REST",
+        "crate::http::client_builder().$$$REST",
+    ))
+    .expect("template referencing a pattern capture should load");
+    assert_eq!(config.patches.len(), 1);
+
+    for (pattern, text, message) in [
+        (
+            "call($A)",
+            "traced($A, $MISSING, $$$REST)",
+            "replacement references $MISSING, $REST not captured by the pattern",
+        ),
+        (
+            "call(a)",
+            "traced($A)",
+            "replacement references $A not captured by the pattern",
+        ),
+    ] {
+        let error = load_from_str(&ast_grep_replace(pattern, text))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(message), "{text}: {error}");
+    }
+}
+
+#[test]
+fn test_load_glob_file_with_match_bounds() {
+    let glob = text_patch_with_query_extra("").replace(
+        "file = \"test.rs\"",
+        "file = \"*/Cargo.toml\"\nmin_matches = 2\nmax_matches = 9",
+    );
+    let config = load_from_str(&glob).expect("glob file should load");
+    assert!(config.patches[0].is_glob());
+    assert_eq!(config.patches[0].min_matches, Some(2));
+    assert_eq!(config.patches[0].max_matches, Some(9));
+
+    for (file, message) in [
+        (
+            "file = \"test.rs\"\nmin_matches = 2",
+            "min_matches/max_matches require a glob pattern in file",
+        ),
+        (
+            "file = \"*/Cargo.toml\"\nmin_matches = 3\nmax_matches = 2",
+            "min_matches 3 is greater than max_matches 2",
+        ),
+        ("file = \"[*/Cargo.toml\"", "invalid file glob"),
+    ] {
+        let toml = text_patch_with_query_extra("").replace("file = \"test.rs\"", file);
+        let error = load_from_str(&toml).unwrap_err().to_string();
+        assert!(error.contains(message), "{file}: {error}");
+    }
+}
+
+#[test]
+fn test_load_relocation_search_paths() {
+    let toml = ast_grep_replace("retry($A, $B)", "5").replace(
+        "file = \"test.rs\"",
+        "file = \"test.rs\"\nsearch_paths = [\"core/src/**/*.rs\"]",
+    );
+    let config = load_from_str(&toml).expect("search_paths should load");
+    assert_eq!(config.patches[0].relocation_globs(), ["core/src/**/*.rs"]);
+
+    let relocate = ast_grep_replace("retry($A, $B)", "5")
+        .replace("file = \"test.rs\"", "file = \"test.rs\"\nrelocate = true");
+    let config = load_from_str(&relocate).expect("relocate should load");
+    assert_eq!(config.patches[0].relocation_globs(), ["**/*.rs"]);
+
+    for (toml, message) in [
+        (
+            text_patch_with_query_extra("")
+                .replace("file = \"test.rs\"", "file = \"test.rs\"\nrelocate = true"),
+            "search_paths/relocate require an ast-grep or tree-sitter query",
+        ),
+        (
+            relocate.replace("file = \"test.rs\"", "file = \"*/test.rs\""),
+            "search_paths/relocate cannot be combined with a glob file",
+        ),
+        (
+            toml.replace("core/src/**/*.rs", "[core"),
+            "invalid search_paths glob '[core'",
+        ),
+    ] {
+        let error = load_from_str(&toml).unwrap_err().to_string();
+        assert!(error.contains(message), "{error}");
+    }
+}
+
+#[test]
+fn test_load_insert_operations() {
+    for operation in [
+        "insert-before",
+        "insert-after",
+        "prepend-to-body",
+        "append-to-body",
+    ] {
+        let toml = text_patch_with_query_extra("")
+            .replace("type = \"replace\"", &format!("type = \"{operation}\""));
+        let config = load_from_str(&toml).expect("insert operation should load");
+        assert_eq!(config.patches[0].operation.type_name(), operation);
+        assert_eq!(
+            config.patches[0].operation.insertion_text(),
+            Some("https://new")
+        );
+    }
+
+    for (extra, message) in [
+        (
+            "fuzzy_threshold = 0.9",
+            "fuzzy matching cannot anchor insert operations",
+        ),
+        (
+            "[patches.verify]\nmethod = \"exact_match\"\nexpected_text = \"x\"",
+            "verify is not supported on insert operations",
+        ),
+    ] {
+        let toml = text_patch_with_query_extra("")
+            .replace("type = \"replace\"", "type = \"insert-after\"");
+        let toml = if extra.starts_with('[') {
+            format!("{toml}\n{extra}\n")
+        } else {
+            toml.replace(
+                "search = \"http://old\"",
+                &format!("search = \"http://old\"\n{extra}"),
+            )
+        };
+        let error = load_from_str(&toml).unwrap_err().to_string();
+        assert!(error.contains(message), "{extra}: {error}");
+    }
+}
+
+#[test]
+fn test_load_requires_and_conflicts() {
+    let toml = text_patch_with_query_extra("")
+        .replace(
+            "name = \"selection\"",
+            "name = \"selection\"\nrequires = [\"undo-command\"]",
+        )
+        .replace(
+            "file = \"test.rs\"",
+            "file = \"test.rs\"\nrequires = [\"privacy:telemetry\"]\nconflicts = [\"legacy-urls\"]",
+        );
+    let config = load_from_str(&toml).expect("references should load");
+    assert_eq!(config.meta.requires, ["undo-command"]);
+    assert_eq!(config.patches[0].requires, ["privacy:telemetry"]);
+    assert_eq!(config.patches[0].conflicts, ["legacy-urls"]);
+
+    for (extra, message) in [
+        (
+            "requires = [\"privacy:\"]",
+            "invalid reference \"privacy:\"",
+        ),
+        (
+            "requires = [\"a:b\"]\nconflicts = [\"a:b\"]",
+            "\"a:b\" is both required and conflicting",
+        ),
+    ] {
+        let toml = text_patch_with_query_extra("").replace(
+            "file = \"test.rs\"",
+            &format!("file = \"test.rs\"\n{extra}"),
+        );
+        let error = load_from_str(&toml).unwrap_err().to_string();
+        assert!(error.contains(message), "{extra}: {error}");
+    }
+}
+
+#[test]
+fn test_load_apply_mode() {
+    let config = load_from_str(&text_patch_with_query_extra("")).unwrap();
+    assert_eq!(config.meta.mode, ApplyMode::Batched);
+    assert_eq!(config.patches[0].mode(&config.meta), ApplyMode::Batched);
+
+    let toml = text_patch_with_query_extra("")
+        .replace(
+            "name = \"selection\"",
+            "name = \"selection\"\nmode = \"sequential\"",
+        )
+        .replace(
+            "file = \"test.rs\"",
+            "file = \"test.rs\"\nmode = \"batched\"",
+        );
+    let config = load_from_str(&toml).unwrap();
+    assert_eq!(config.meta.mode, ApplyMode::Sequential);
+    assert_eq!(config.patches[0].mode(&config.meta), ApplyMode::Batched);
+
+    let toml = text_patch_with_query_extra("").replace(
+        "file = \"test.rs\"",
+        "file = \"test.rs\"\nmode = \"chained\"",
+    );
+    assert!(load_from_str(&toml).is_err());
+}
+
+#[test]
+fn test_load_patch_variants() {
+    let toml = text_patch_with_query_extra("").replace(
+        "file = \"test.rs\"",
+        "file = \"test.rs\"\nversion = \">=0.108.0\"",
+    ) + r#"
+[[patches.variants]]
+version = ">=0.105.0, <0.108.0"
+
+[patches.variants.query]
+type = "text"
+search = "http://older"
+
+[patches.variants.operation]
+type = "replace"
+text = "https://new"
+"#;
+    let config = load_from_str(&toml).unwrap();
+    let variants = &config.patches[0].variants;
+    assert_eq!(variants.len(), 1);
+    assert_eq!(variants[0].version, ">=0.105.0, <0.108.0");
+    let versions: Vec<_> = config.patches[0].versions().collect();
+    assert_eq!(versions.len(), 2);
+    assert!(matches!(
+        &versions[1].query,
+        Query::Text { search, .. } if search == "http://older"
+    ));
+
+    // Every variant is validated like a patch.
+    let bad = toml.replace(
+        "search = \"http://older\"",
+        "search = \"http://older\"\nfuzzy_threshold = 2.0",
+    );
+    let error = load_from_str(&bad).unwrap_err().to_string();
+    assert!(error.contains("fuzzy_threshold"), "{error}");
+
+    let unversioned = toml.replace("version = \">=0.108.0\"\n", "");
+    let error = load_from_str(&unversioned).unwrap_err().to_string();
+    assert!(error.contains("needs its own `version`"), "{error}");
+}
+
+#[test]
+fn test_validation_rejects_unknown_capture() {
+    let ast_grep = ast_grep_replace("retry($A, $B)", "5").replace(
+        "pattern = 'retry($A, $B)'",
+        "pattern = 'retry($A, $B)'\ncapture = \"C\"",
+    );
+    let error = load_from_str(&ast_grep).unwrap_err().to_string();
+    assert!(
+        error.contains("capture \"C\" is not a metavariable of the pattern"),
+        "{error}"
+    );
+
+    for (pattern, message) in [
+        (
+            "(const_item value: (_) @value)",
+            "capture \"name\" is not a capture of the pattern",
+        ),
+        (
+            "const TIMEOUT",
+            "capture requires an S-expression tree-sitter pattern",
+        ),
+    ] {
+        let toml = ast_grep_replace("retry($A, $B)", "5")
+            .replace("type = \"ast-grep\"", "type = \"tree-sitter\"")
+            .replace(
+                "pattern = 'retry($A, $B)'",
+                &format!("pattern = '{pattern}'\ncapture = \"name\""),
+            );
+        let error = load_from_str(&toml).unwrap_err().to_string();
+        assert!(error.contains(message), "{pattern}: {error}");
+    }
+}
+
 #[test]
 fn test_patch_result_display() {
     let applied = PatchResult::Applied {
@@ -564,6 +1005,7 @@ fn test_apply_patches_append_section_batched() {
             description: None,
             version_range: None,
             workspace_relative: true,
+            ..Default::default()
         },
         patches: vec![PatchDefinition {
             id: "append-dev-profile".to_string(),
@@ -580,6 +1022,15 @@ fn test_apply_patches_append_section_batched() {
             verify: None,
             constraint: None,
             version: None,
+            min_matches: None,
+            max_matches: None,
+            requires: Vec::new(),
+            conflicts: Vec::new(),
+            mode: None,
+            tags: Vec::new(),
+            variants: Vec::new(),
+            search_paths: Vec::new(),
+            relocate: false,
         }],
     };
 
@@ -4,7 +4,9 @@
 //! equivalent coverage for `Query::AstGrep` and `Query::TreeSitter` through the
 //! full `apply_patches` pipeline: parse pattern → compute edit → batch apply.
 
-use codex_patcher::config::schema::{Metadata, Operation, PatchConfig, PatchDefinition, Query};
+use codex_patcher::config::schema::{
+    Metadata, Operation, PatchConfig, PatchDefinition, Query, Selection,
+};
 use codex_patcher::config::{apply_patches, ApplicationError, PatchResult};
 use std::fs;
 use tempfile::TempDir;
@@ -30,6 +32,7 @@ fn make_config(patches: Vec<PatchDefinition>) -> PatchConfig {
             description: None,
             version_range: None,
             workspace_relative: true,
+            ..Default::default()
         },
         patches,
     }
@@ -41,11 +44,22 @@ fn ast_grep_patch(id: &str, file: &str, pattern: &str, operation: Operation) ->
         file: file.to_string(),
         query: Query::AstGrep {
             pattern: pattern.to_string(),
+            capture: None,
+            selection: Selection::default(),
         },
         operation,
         verify: None,
         constraint: None,
         version: None,
+        min_matches: None,
+        max_matches: None,
+        requires: Vec::new(),
+        conflicts: Vec::new(),
+        mode: None,
+        tags: Vec::new(),
+        variants: Vec::new(),
+        search_paths: Vec::new(),
+        relocate: false,
     }
 }
 
@@ -55,11 +69,22 @@ fn tree_sitter_patch(id: &str, file: &str, pattern: &str, operation: Operation)
         file: file.to_string(),
         query: Query::TreeSitter {
             pattern: pattern.to_string(),
+            capture: None,
+            selection: Selection::default(),
         },
         operation,
         verify: None,
         constraint: None,
         version: None,
+        min_matches: None,
+        max_matches: None,
+        requires: Vec::new(),
+        conflicts: Vec::new(),
+        mode: None,
+        tags: Vec::new(),
+        variants: Vec::new(),
+        search_paths: Vec::new(),
+        relocate: false,
     }
 }
 
@@ -1,7 +1,9 @@
 //! Integration tests for unified patch system with per-patch version constraints
 //! and fuzzy matching fallback.
 
-use codex_patcher::config::schema::{Metadata, Operation, PatchConfig, PatchDefinition, Query};
+use codex_patcher::config::schema::{
+    Metadata, Operation, PatchConfig, PatchDefinition, Query, Selection,
+};
 use codex_patcher::config::{apply_patches, PatchResult};
 use std::fs;
 use tempfile::TempDir;
@@ -25,6 +27,7 @@ fn make_config(patches: Vec<PatchDefinition>) -> PatchConfig {
             description: Some("Test configuration".to_string()),
             version_range: None,
             workspace_relative: true,
+            ..Default::default()
         },
         patches,
     }
@@ -46,6 +49,7 @@ fn text_patch(
             search: search.to_string(),
             fuzzy_threshold,
             fuzzy_expansion: None,
+            selection: Selection::default(),
         },
         operation: Operation::Replace {
             text: replace.to_string(),
@@ -53,6 +57,15 @@ fn text_patch(
         verify: None,
         constraint: None,
         version: version.map(|s| s.to_string()),
+        min_matches: None,
+        max_matches: None,
+        requires: Vec::new(),
+        conflicts: Vec::new(),
+        mode: None,
+        tags: Vec::new(),
+        variants: Vec::new(),
+        search_paths: Vec::new(),
+        relocate: false,
     }
 }
 
@@ -336,6 +349,7 @@ fn elastic_patch(
             search: search.to_string(),
             fuzzy_threshold: Some(fuzzy_threshold),
             fuzzy_expansion: Some(fuzzy_expansion),
+            selection: Selection::default(),
         },
         operation: Operation::Replace {
             text: replace.to_string(),
@@ -343,6 +357,15 @@ fn elastic_patch(
         verify: None,
         constraint: None,
         version: None,
+        min_matches: None,
+        max_matches: None,
+        requires: Vec::new(),
+        conflicts: Vec::new(),
+        mode: None,
+        tags: Vec::new(),
+        variants: Vec::new(),
+        search_paths: Vec::new(),
+        relocate: false,
     }
 }
 
@@ -429,6 +452,7 @@ fn test_validate_rejects_fuzzy_expansion_over_200() {
             search: "fn foo() {}".to_string(),
             fuzzy_threshold: Some(0.85),
             fuzzy_expansion: Some(201),
+            selection: Selection::default(),
         },
         operation: Operation::Replace {
             text: "fn foo() { /* patched */ }".to_string(),
@@ -436,6 +460,15 @@ fn test_validate_rejects_fuzzy_expansion_over_200() {
         verify: None,
         constraint: None,
         version: None,
+        min_matches: None,
+        max_matches: None,
+        requires: Vec::new(),
+        conflicts: Vec::new(),
+        mode: None,
+        tags: Vec::new(),
+        variants: Vec::new(),
+        search_paths: Vec::new(),
+        relocate: false,
     }]);
 
     let err = config.validate().expect_err("should fail validation");
@@ -459,6 +492,7 @@ fn test_validate_accepts_fuzzy_expansion_at_boundary() {
             search: "fn foo() {}".to_string(),
             fuzzy_threshold: Some(0.85),
             fuzzy_expansion: Some(200),
+            selection: Selection::default(),
         },
         operation: Operation::Replace {
             text: "fn foo() { /* patched */ }".to_string(),
@@ -466,6 +500,15 @@ fn test_validate_accepts_fuzzy_expansion_at_boundary() {
         verify: None,
         constraint: None,
         version: None,
+        min_matches: None,
+        max_matches: None,
+        requires: Vec::new(),
+        conflicts: Vec::new(),
+        mode: None,
+        tags: Vec::new(),
+        variants: Vec::new(),
+        search_paths: Vec::new(),
+        relocate: false,
     }]);
 
     assert!(
@@ -484,6 +527,7 @@ fn test_validate_accepts_fuzzy_expansion_without_explicit_threshold() {
             search: "fn foo() {}".to_string(),
             fuzzy_threshold: None,
             fuzzy_expansion: Some(10),
+            selection: Selection::default(),
         },
         operation: Operation::Replace {
             text: "fn foo() { /* patched */ }".to_string(),
@@ -491,6 +535,15 @@ fn test_validate_accepts_fuzzy_expansion_without_explicit_threshold() {
         verify: None,
         constraint: None,
         version: None,
+        min_matches: None,
+        max_matches: None,
+        requires: Vec::new(),
+        conflicts: Vec::new(),
+        mode: None,
+        tags: Vec::new(),
+        variants: Vec::new(),
+        search_paths: Vec::new(),
+        relocate: false,
     }]);
 
     assert!(